@@ -0,0 +1,68 @@
+//
+// Request to response path of the exported functions, as the host calls them:
+// cargo bench --features bench
+//
+
+use std::ffi::{c_char, CStr, CString};
+use criterion::{criterion_group, criterion_main, Criterion};
+use hyper::HeaderMap;
+use hyper::header::HeaderValue;
+
+// a config.json whose server refuses the connections, none of the benchmarks
+// gets as far as sending; privacy mode keeps the headers of each request out
+// of stdout
+fn setup() {
+    let dir = std::env::temp_dir().join(format!("arp-gmail-bench-{}", std::process::id()));
+    std::fs::create_dir_all(dir.join("arp-gmail")).unwrap();
+    std::fs::write(dir.join("arp-gmail/config.json"), r#"{
+        "username": "bench@example.com",
+        "password": "x",
+        "server": "127.0.0.1",
+        "timeout_ms": 200,
+        "privacy": {}
+    }"#).unwrap();
+    std::env::set_var("PLUGINS_DIR", &dir);
+}
+
+fn headers(content_type: &'static str) -> HeaderMap {
+    let mut headers = HeaderMap::new();
+    headers.insert("content-type", HeaderValue::from_static(content_type));
+    headers.insert("user-agent", HeaderValue::from_static("bench"));
+    headers
+}
+
+// the call and the free() of the host
+fn call(
+    handler: extern "C" fn(*mut HeaderMap, *const c_char) -> *const c_char,
+    headers: &mut HeaderMap,
+    body: &CStr,
+) -> usize {
+    let response = handler(headers, body.as_ptr());
+    let len = unsafe { CStr::from_ptr(response) }.to_bytes().len();
+    arp_gmail::free(response.cast_mut());
+    len
+}
+
+fn benchmarks(c: &mut Criterion) {
+
+    setup();
+
+    let invalid = CString::new(r#"{"from": "a@example.com", "to": "b@example.com", "message": "no subject"}"#).unwrap();
+    let mut json = headers("application/json");
+    c.bench_function("sendmail invalid json", |b| b.iter(|| call(arp_gmail::sendmail, &mut json, &invalid)));
+
+    let form = CString::new("from=a%40example.com&to=b%40example.com&message=no+subject").unwrap();
+    let mut form_headers = headers("application/x-www-form-urlencoded");
+    c.bench_function("sendmail invalid form", |b| b.iter(|| call(arp_gmail::sendmail, &mut form_headers, &form)));
+
+    let empty = CString::new("").unwrap();
+    let mut plain = headers("application/json");
+    c.bench_function("health", |b| b.iter(|| call(arp_gmail::health, &mut plain, &empty)));
+
+    let mut page = headers("application/json");
+    page.insert("x-raw-query", HeaderValue::from_static("limit=20"));
+    c.bench_function("jobs list", |b| b.iter(|| call(arp_gmail::jobs_list, &mut page, &empty)));
+}
+
+criterion_group!(benches, benchmarks);
+criterion_main!(benches);
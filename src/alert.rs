@@ -0,0 +1,52 @@
+//
+// Operational alerts written to the log and posted to an optional webhook
+//
+
+use std::time::Duration;
+use serde::{Deserialize, Serialize};
+
+use crate::SMTP_CLIENT;
+
+fn default_timeout() -> u64 {
+    10
+}
+
+#[derive(Clone, Deserialize, Serialize)]
+pub struct AlertSettings {
+    // url receiving a POST with {"event", "message", "time", "request_id"}
+    pub webhook: Option<String>,
+    #[serde(default = "default_timeout")]
+    timeout_secs: u64,
+}
+
+// the webhook is called from its own thread, an alert never delays a send
+pub fn send(
+    event: &'static str,
+    message: &str,
+) {
+
+    log!("Alert {}: {}", event, message);
+
+    let (url, timeout) = match &SMTP_CLIENT.alerts {
+        Some(AlertSettings { webhook: Some(url), timeout_secs }) => (url.clone(), Duration::from_secs(*timeout_secs)),
+        _ => return,
+    };
+
+    let payload = serde_json::json!({
+        "event": event,
+        "message": message,
+        "time": crate::db::now(),
+        "request_id": crate::trace::current(),
+    });
+
+    let result = std::thread::Builder::new()
+        .name("arp-gmail-alert".to_string())
+        .spawn(move || {
+            if let Err(e) = crate::callbacks::post(&url, &payload, timeout) {
+                log!("Error posting alert to {}: {}", url, e);
+            }
+        });
+    if let Err(e) = result {
+        log!("Error starting the alert thread: {}", e);
+    }
+}
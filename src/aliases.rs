@@ -0,0 +1,69 @@
+//
+// The "send mail as" addresses of the Gmail accounts, read from the sendAs
+// settings of the Gmail API. Gmail rewrites a From that isn't one of the
+// verified ones
+//
+
+use serde::Serialize;
+use serde_json::Value;
+
+use crate::SendError;
+use crate::gmail_api;
+
+#[derive(Serialize)]
+pub struct Alias {
+    pub address: String,
+    pub display_name: Option<String>,
+    pub reply_to: Option<String>,
+    // the address of the account itself
+    pub primary: bool,
+    // the From of the messages sent from the web interface
+    pub default: bool,
+    // "accepted", a pending one is rewritten until the owner confirms it
+    pub verified: bool,
+}
+
+fn alias(send_as: &Value) -> Option<Alias> {
+    let text = |field: &str| send_as[field].as_str()
+        .filter(|value| !value.is_empty())
+        .map(str::to_string);
+    let primary = send_as["isPrimary"].as_bool().unwrap_or(false);
+
+    Some(Alias {
+        address: text("sendAsEmail")?,
+        display_name: text("displayName"),
+        reply_to: text("replyToAddress"),
+        primary,
+        default: send_as["isDefault"].as_bool().unwrap_or(false),
+        // the primary address has no verification status
+        verified: primary || send_as["verificationStatus"].as_str() == Some("accepted"),
+    })
+}
+
+// the aliases of a Gmail account, the primary address first
+pub fn list(account: &str) -> Result<Vec<Alias>, SendError> {
+
+    let settings = gmail_api::settings()?;
+    if crate::transport::Gmail::find(account).is_none() {
+        return Err(SendError::new("invalid_request", format!("Unknown Gmail account {:?}", account)));
+    }
+
+    let token = gmail_api::access_token(settings, account)?;
+    let reply: Value = crate::transport::agent()
+        .get(&gmail_api::url(settings, "settings/sendAs"))
+        .set("Authorization", &format!("Bearer {}", token))
+        .call()
+        .map_err(|e| {
+            gmail_api::revoked(account, &e);
+            gmail_api::api_error("The sendAs request", e)
+        })?
+        .into_json()
+        .map_err(|e| SendError::new("account_unavailable", format!("Invalid sendAs reply: {}", e)))?;
+
+    let mut aliases: Vec<Alias> = reply["sendAs"].as_array()
+        .map(|list| list.iter().filter_map(alias).collect())
+        .unwrap_or_default();
+    aliases.sort_by_key(|alias| !alias.primary);
+
+    Ok(aliases)
+}
@@ -0,0 +1,182 @@
+//
+// Antivirus scan of attachments before sending (clamd or an external command)
+//
+
+use std::io::{Read, Write};
+use std::process::{Command, Stdio};
+use std::time::{Duration, Instant};
+use serde::{Deserialize, Serialize};
+
+use crate::SendError;
+
+const CHUNK_SIZE: usize = 64 * 1024;
+
+fn default_timeout() -> u64 {
+    30
+}
+
+#[derive(Clone, Deserialize, Serialize)]
+pub struct AntivirusSettings {
+    // clamd socket: a unix socket path or host:port
+    clamd: Option<String>,
+    // external command that reads the attachment from stdin,
+    // exit status 0 is clean and 1 is infected (clamscan convention)
+    command: Option<Vec<String>>,
+    #[serde(default = "default_timeout")]
+    timeout_secs: u64,
+}
+
+// clamd INSTREAM protocol: length prefixed chunks terminated by a zero length chunk
+fn instream<S: Read + Write>(
+    mut stream: S,
+    data: &mut (dyn Read + Send),
+) -> std::io::Result<String> {
+
+    stream.write_all(b"zINSTREAM\0")?;
+    let mut chunk = vec![0; CHUNK_SIZE];
+    loop {
+        let n = data.read(&mut chunk)?;
+        if n == 0 {
+            break;
+        }
+        stream.write_all(&(n as u32).to_be_bytes())?;
+        stream.write_all(&chunk[..n])?;
+    }
+    stream.write_all(&0u32.to_be_bytes())?;
+    stream.flush()?;
+
+    let mut reply = String::new();
+    stream.read_to_string(&mut reply)?;
+
+    Ok(reply.trim_end_matches(['\0', '\n']).to_string())
+}
+
+fn scan_clamd(
+    socket: &str,
+    data: &mut (dyn Read + Send),
+    timeout: Duration,
+) -> Result<Option<String>, String> {
+
+    let reply = if socket.starts_with('/') {
+        #[cfg(unix)]
+        {
+            let stream = std::os::unix::net::UnixStream::connect(socket)
+                .map_err(|e| format!("clamd {}: {}", socket, e))?;
+            stream.set_read_timeout(Some(timeout)).ok();
+            stream.set_write_timeout(Some(timeout)).ok();
+            instream(stream, data)
+        }
+        #[cfg(not(unix))]
+        {
+            return Err(format!("clamd {}: unix sockets are not supported on this platform", socket));
+        }
+    } else {
+        let stream = std::net::TcpStream::connect(socket)
+            .map_err(|e| format!("clamd {}: {}", socket, e))?;
+        stream.set_read_timeout(Some(timeout)).ok();
+        stream.set_write_timeout(Some(timeout)).ok();
+        instream(stream, data)
+    }.map_err(|e| format!("clamd {}: {}", socket, e))?;
+
+    // "stream: OK" or "stream: Eicar-Signature FOUND"
+    let result = reply.strip_prefix("stream: ").unwrap_or(&reply);
+    if result == "OK" {
+        Ok(None)
+    } else if let Some(signature) = result.strip_suffix(" FOUND") {
+        Ok(Some(signature.to_string()))
+    } else {
+        Err(format!("clamd {}: {}", socket, reply))
+    }
+}
+
+fn scan_command(
+    command: &[String],
+    data: &mut (dyn Read + Send),
+    timeout: Duration,
+) -> Result<Option<String>, String> {
+
+    let (program, args) = command.split_first()
+        .ok_or("Antivirus command is empty")?;
+
+    let mut child = Command::new(program)
+        .args(args)
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::null())
+        .spawn()
+        .map_err(|e| format!("{}: {}", program, e))?;
+
+    // feed stdin from another thread so a full stdout pipe can't deadlock us
+    let mut stdin = child.stdin.take().unwrap();
+    let status = std::thread::scope(|scope| {
+        scope.spawn(move || {
+            let mut chunk = vec![0; CHUNK_SIZE];
+            while let Ok(n @ 1..) = data.read(&mut chunk) {
+                if stdin.write_all(&chunk[..n]).is_err() {
+                    break;
+                }
+            }
+        });
+
+        let deadline = Instant::now() + timeout;
+        loop {
+            match child.try_wait() {
+                Ok(Some(status)) => break Ok(status),
+                Ok(None) if Instant::now() < deadline => std::thread::sleep(Duration::from_millis(20)),
+                Ok(None) => {
+                    // the writer ends on the closed pipe
+                    let _ = child.kill();
+                    let _ = child.wait();
+                    break Err(format!("{}: timed out after {}s", program, timeout.as_secs()));
+                },
+                Err(e) => break Err(format!("{}: {}", program, e)),
+            }
+        }
+    })?;
+
+    let mut output = String::new();
+    if let Some(mut stdout) = child.stdout.take() {
+        let _ = stdout.read_to_string(&mut output);
+    }
+
+    match status.code() {
+        Some(0) => Ok(None),
+        Some(1) => {
+            // "stdin: Eicar-Signature FOUND"
+            let signature = output.lines()
+                .find_map(|line| line.strip_suffix(" FOUND"))
+                .map(|line| line.rsplit(": ").next().unwrap_or(line))
+                .unwrap_or(output.trim());
+            Ok(Some(if signature.is_empty() { "unknown".to_string() } else { signature.to_string() }))
+        },
+        _ => Err(format!("{}: {} {}", program, status, output.trim())),
+    }
+}
+
+pub fn scan(
+    settings: &AntivirusSettings,
+    filename: &str,
+    data: &mut (dyn Read + Send),
+) -> Result<(), SendError> {
+
+    let timeout = Duration::from_secs(settings.timeout_secs);
+
+    let result = match (&settings.clamd, &settings.command) {
+        (Some(socket), _) => scan_clamd(socket, data, timeout),
+        (None, Some(command)) => scan_command(command, data, timeout),
+        (None, None) => Err("Antivirus is enabled but neither clamd nor command is set".to_string()),
+    };
+
+    match result {
+        Ok(None) => Ok(()),
+        Ok(Some(signature)) => Err(SendError::new(
+            "virus_found",
+            format!("Attachment {} rejected by antivirus: {}", filename, signature),
+        )),
+        // refuse to send what we couldn't scan
+        Err(e) => Err(SendError::new(
+            "scan_failed",
+            format!("Antivirus scan of attachment {} failed: {}", filename, e),
+        )),
+    }
+}
@@ -0,0 +1,398 @@
+//
+// The attachments of a request in a ZIP encrypted with AES-256 (the WinZip
+// AE-2 format, opened by 7-Zip, WinZip, macOS and Windows 11), for the
+// partners whose policy requires encrypted documents; the password of the
+// request is never logged, and it is stored encrypted with the key of the
+// instance in the queued, held and recurring rows
+//
+
+use std::io::Write;
+use chrono::{Datelike, Timelike};
+use flate2::{write::DeflateEncoder, Compression};
+use lettre::message::{Attachment as MimeAttachment, Body, SinglePart};
+use lettre::message::header::{ContentTransferEncoding, ContentType};
+use openssl::hash::MessageDigest;
+use openssl::pkey::PKey;
+use openssl::sign::Signer;
+use openssl::symm::{Cipher, Crypter, Mode};
+use base64::Engine;
+use base64::engine::general_purpose::STANDARD;
+use once_cell::sync::OnceCell;
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+
+use crate::{Mail, SendError};
+
+// AES-256, its salt and the PBKDF2 iterations of the format
+const KEY_BYTES: usize = 32;
+const SALT_BYTES: usize = 16;
+const ITERATIONS: usize = 1000;
+// the truncated HMAC-SHA1 of the encrypted data
+const AUTH_CODE_BYTES: usize = 10;
+// the counter blocks encrypted at a time
+const CTR_CHUNK: usize = 16 * 1024;
+
+const MIN_PASSWORD_CHARS: usize = 8;
+
+// the password stored with a request: AES-256-GCM with the key of the
+// instance, kept in the plugin directory and made on the first use; the
+// instances sharing a store share the file
+const KEY_FILE: &str = "archive.key";
+const SEALED: &str = "sealed:";
+const NONCE_BYTES: usize = 12;
+const TAG_BYTES: usize = 16;
+
+// the password of a request shown by the routes listing them
+pub const MASKED: &str = "********";
+
+static KEY: OnceCell<[u8; KEY_BYTES]> = OnceCell::new();
+
+fn default_filename() -> String {
+    "attachments.zip".to_string()
+}
+
+// the "encrypt_attachments" of a request
+#[derive(Clone, Deserialize, Serialize)]
+pub struct ArchiveRequest {
+    #[serde(serialize_with = "seal", deserialize_with = "unseal")]
+    pub password: String,
+    #[serde(default = "default_filename")]
+    pub filename: String,
+}
+
+fn failed(message: impl Into<String>) -> SendError {
+    SendError::new("invalid_attachment", message)
+}
+
+fn crypto(e: openssl::error::ErrorStack) -> SendError {
+    failed(format!("The attachments can't be encrypted: {}", e))
+}
+
+fn key() -> Result<&'static [u8; KEY_BYTES], String> {
+
+    KEY.get_or_try_init(|| {
+        let path = crate::plugin_path()
+            .map_err(|e| e.to_string())?
+            .join(KEY_FILE);
+        let mut key = [0; KEY_BYTES];
+        let mut options = std::fs::OpenOptions::new();
+        options.write(true).create_new(true);
+        #[cfg(unix)]
+        std::os::unix::fs::OpenOptionsExt::mode(&mut options, 0o600);
+        match options.open(&path) {
+            Ok(mut file) => {
+                openssl::rand::rand_bytes(&mut key)
+                    .map_err(|e| e.to_string())?;
+                file.write_all(&key)
+                    .map_err(|e| format!("{}: {}", path.display(), e))?;
+            },
+            Err(e) if e.kind() == std::io::ErrorKind::AlreadyExists => {
+                let bytes = std::fs::read(&path)
+                    .map_err(|e| format!("{}: {}", path.display(), e))?;
+                key = bytes.try_into()
+                    .map_err(|_| format!("{}: not a key of {} bytes", path.display(), KEY_BYTES))?;
+            },
+            Err(e) => return Err(format!("{}: {}", path.display(), e)),
+        }
+        Ok(key)
+    })
+}
+
+// nonce, encrypted password and tag, the masked password as it is
+fn seal<S: Serializer>(
+    password: &str,
+    serializer: S,
+) -> Result<S::Ok, S::Error> {
+
+    use serde::ser::Error;
+
+    if password == MASKED {
+        return serializer.serialize_str(password);
+    }
+    let sealed = key().and_then(|key| {
+        let mut nonce = [0; NONCE_BYTES];
+        openssl::rand::rand_bytes(&mut nonce)
+            .map_err(|e| e.to_string())?;
+        let mut tag = [0; TAG_BYTES];
+        let data = openssl::symm::encrypt_aead(Cipher::aes_256_gcm(), key, Some(&nonce), &[], password.as_bytes(), &mut tag)
+            .map_err(|e| e.to_string())?;
+        Ok(format!("{}{}", SEALED, STANDARD.encode([&nonce[..], &data, &tag].concat())))
+    });
+    serializer.serialize_str(&sealed.map_err(|e| S::Error::custom(format!("The archive password can't be stored: {}", e)))?)
+}
+
+// the password of a stored request, or the one of a new request as it is
+fn unseal<'de, D: Deserializer<'de>>(deserializer: D) -> Result<String, D::Error> {
+
+    use serde::de::Error;
+
+    let value = String::deserialize(deserializer)?;
+    let Some(sealed) = value.strip_prefix(SEALED) else {
+        return Ok(value);
+    };
+    let opened = key().and_then(|key| {
+        let bytes = STANDARD.decode(sealed)
+            .map_err(|e| e.to_string())?;
+        if bytes.len() < NONCE_BYTES + TAG_BYTES {
+            return Err("too short".to_string());
+        }
+        let (nonce, rest) = bytes.split_at(NONCE_BYTES);
+        let (data, tag) = rest.split_at(rest.len() - TAG_BYTES);
+        let password = openssl::symm::decrypt_aead(Cipher::aes_256_gcm(), key, Some(nonce), &[], data, tag)
+            .map_err(|e| e.to_string())?;
+        String::from_utf8(password)
+            .map_err(|e| e.to_string())
+    });
+    opened.map_err(|e| D::Error::custom(format!("The archive password can't be read: {}", e)))
+}
+
+pub fn check(mail: &Mail) -> Result<(), SendError> {
+
+    let Some(request) = &mail.encrypt_attachments else {
+        return Ok(());
+    };
+    if request.password.chars().count() < MIN_PASSWORD_CHARS {
+        return Err(SendError::new(
+            "invalid_request",
+            format!("The password of \"encrypt_attachments\" must have at least {} characters", MIN_PASSWORD_CHARS),
+        ));
+    }
+    if request.filename.trim().is_empty() {
+        return Err(SendError::new("invalid_request", "The filename of \"encrypt_attachments\" is empty"));
+    }
+    if mail.attachments.as_ref().is_none_or(Vec::is_empty) && mail.pdf.is_none() {
+        return Err(SendError::new("invalid_request", "\"encrypt_attachments\" is set but the message has no attachments"));
+    }
+
+    Ok(())
+}
+
+// AES in counter mode with the little-endian counter of the format, from 1;
+// the keystream of a chunk of blocks at a time
+fn aes_ctr(
+    key: &[u8],
+    data: &mut [u8],
+) -> Result<(), SendError> {
+
+    let mut crypter = Crypter::new(Cipher::aes_256_ecb(), Mode::Encrypt, key, None)
+        .map_err(crypto)?;
+    crypter.pad(false);
+
+    let mut counters = [0; CTR_CHUNK];
+    let mut stream = [0; CTR_CHUNK + 16];
+    let mut block = 1u64;
+    for chunk in data.chunks_mut(CTR_CHUNK) {
+        let len = chunk.len().div_ceil(16) * 16;
+        for counter in counters[..len].chunks_mut(16) {
+            counter[..8].copy_from_slice(&block.to_le_bytes());
+            block += 1;
+        }
+        let n = crypter.update(&counters[..len], &mut stream)
+            .map_err(crypto)?;
+        for (byte, key) in chunk.iter_mut().zip(&stream[..n]) {
+            *byte ^= key;
+        }
+    }
+
+    Ok(())
+}
+
+// salt, password verifier, deflated and encrypted data, and authentication code
+fn encrypt(
+    password: &str,
+    content: &[u8],
+) -> Result<Vec<u8>, SendError> {
+
+    let mut salt = [0; SALT_BYTES];
+    openssl::rand::rand_bytes(&mut salt)
+        .map_err(crypto)?;
+    let mut keys = [0; 2 * KEY_BYTES + 2];
+    openssl::pkcs5::pbkdf2_hmac(password.as_bytes(), &salt, ITERATIONS, MessageDigest::sha1(), &mut keys)
+        .map_err(crypto)?;
+    let (encryption_key, rest) = keys.split_at(KEY_BYTES);
+    let (auth_key, verifier) = rest.split_at(KEY_BYTES);
+
+    let mut encoder = DeflateEncoder::new(Vec::new(), Compression::default());
+    let mut data = encoder.write_all(content)
+        .and_then(|_| encoder.finish())
+        .map_err(|e| failed(format!("The attachments can't be compressed: {}", e)))?;
+    aes_ctr(encryption_key, &mut data)?;
+
+    let key = PKey::hmac(auth_key)
+        .map_err(crypto)?;
+    let mut signer = Signer::new(MessageDigest::sha1(), &key)
+        .map_err(crypto)?;
+    signer.update(&data)
+        .map_err(crypto)?;
+    let auth_code = signer.sign_to_vec()
+        .map_err(crypto)?;
+
+    Ok([&salt[..], verifier, &data, &auth_code[..AUTH_CODE_BYTES]].concat())
+}
+
+// the extra field of the AES entries: AE-2, "AE", AES-256, deflated
+fn aes_extra() -> Vec<u8> {
+    let mut extra = Vec::with_capacity(11);
+    extra.extend_from_slice(&0x9901u16.to_le_bytes());
+    extra.extend_from_slice(&7u16.to_le_bytes());
+    extra.extend_from_slice(&2u16.to_le_bytes());
+    extra.extend_from_slice(b"AE");
+    extra.push(3);
+    extra.extend_from_slice(&8u16.to_le_bytes());
+    extra
+}
+
+// the names of the entries, "report (2).pdf" after a first "report.pdf"
+fn unique(
+    names: &[String],
+    filename: &str,
+) -> String {
+
+    let filename = filename.trim().replace(['/', '\\'], "_");
+    let (stem, extension) = match filename.rfind('.').filter(|&dot| dot > 0) {
+        Some(dot) => filename.split_at(dot),
+        None => (filename.as_str(), ""),
+    };
+    (1..)
+        .map(|n| match n {
+            1 => filename.clone(),
+            n => format!("{} ({}){}", stem, n, extension),
+        })
+        .find(|name| !names.contains(name))
+        .unwrap()
+}
+
+pub fn zip(
+    password: &str,
+    files: Vec<(String, Vec<u8>)>,
+) -> Result<Vec<u8>, SendError> {
+
+    let now = chrono::Local::now();
+    let time = ((now.hour() << 11) | (now.minute() << 5) | (now.second() / 2)) as u16;
+    let date = (((now.year().max(1980) as u32 - 1980) << 9) | (now.month() << 5) | now.day()) as u16;
+    let extra = aes_extra();
+
+    let mut names = Vec::new();
+    let mut archive = Vec::new();
+    let mut directory = Vec::new();
+    for (filename, content) in files {
+        let name = unique(&names, &filename);
+        let data = encrypt(password, &content)?;
+        let (Ok(compressed), Ok(size)) = (u32::try_from(data.len()), u32::try_from(content.len())) else {
+            return Err(failed(format!("The attachment {} is too big for the archive", name)));
+        };
+        let offset = archive.len() as u32;
+
+        // encrypted, UTF-8 name; method 99 and a CRC of zero for AE-2
+        let header = |signature: u32| {
+            let mut header = Vec::with_capacity(46);
+            header.extend_from_slice(&signature.to_le_bytes());
+            if signature == 0x02014b50 {
+                header.extend_from_slice(&51u16.to_le_bytes());
+            }
+            for field in [51u16, 0x0801, 99, time, date] {
+                header.extend_from_slice(&field.to_le_bytes());
+            }
+            for field in [0, compressed, size] {
+                header.extend_from_slice(&field.to_le_bytes());
+            }
+            header.extend_from_slice(&(name.len() as u16).to_le_bytes());
+            header.extend_from_slice(&(extra.len() as u16).to_le_bytes());
+            header
+        };
+
+        archive.extend(header(0x04034b50));
+        archive.extend_from_slice(name.as_bytes());
+        archive.extend_from_slice(&extra);
+        archive.extend(data);
+
+        // no comment, disk 0, no attributes, the offset of the local header
+        directory.extend(header(0x02014b50));
+        directory.extend_from_slice(&[0; 6]);
+        directory.extend_from_slice(&0u32.to_le_bytes());
+        directory.extend_from_slice(&offset.to_le_bytes());
+        directory.extend_from_slice(name.as_bytes());
+        directory.extend_from_slice(&extra);
+
+        names.push(name);
+    }
+
+    let offset = u32::try_from(archive.len())
+        .map_err(|_| failed("The attachments are too big for the archive"))?;
+    archive.extend_from_slice(&directory);
+    archive.extend_from_slice(&0x06054b50u32.to_le_bytes());
+    archive.extend_from_slice(&[0; 4]);
+    archive.extend_from_slice(&(names.len() as u16).to_le_bytes());
+    archive.extend_from_slice(&(names.len() as u16).to_le_bytes());
+    archive.extend_from_slice(&(directory.len() as u32).to_le_bytes());
+    archive.extend_from_slice(&offset.to_le_bytes());
+    archive.extend_from_slice(&0u16.to_le_bytes());
+
+    Ok(archive)
+}
+
+pub fn attachment(
+    request: &ArchiveRequest,
+    files: Vec<(String, Vec<u8>)>,
+) -> Result<SinglePart, SendError> {
+
+    let archive = zip(&request.password, files)?;
+    let content_type = ContentType::parse("application/zip")
+        .map_err(|e| failed(e.to_string()))?;
+    let body = Body::new_with_encoding(archive, ContentTransferEncoding::Base64)
+        .map_err(|_| failed("The archive can't be encoded"))?;
+
+    Ok(MimeAttachment::new(request.filename.trim().to_string()).body(body, content_type))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn stored_password_is_sealed() {
+        crate::tests::setup();
+        let request: ArchiveRequest = serde_json::from_str(r#"{ "password": "correct horse" }"#).unwrap();
+        let stored = serde_json::to_string(&request).unwrap();
+        assert!(!stored.contains("correct horse"), "{}", stored);
+        assert!(stored.contains(SEALED), "{}", stored);
+
+        let read: ArchiveRequest = serde_json::from_str(&stored).unwrap();
+        assert_eq!(read.password, "correct horse");
+        assert_eq!(read.filename, "attachments.zip");
+    }
+
+    #[test]
+    fn tampered_password_is_refused() {
+        crate::tests::setup();
+        let request = ArchiveRequest { password: "correct horse".to_string(), filename: default_filename() };
+        let stored = serde_json::to_value(&request).unwrap();
+        let sealed = stored["password"].as_str().unwrap();
+        let mut bytes = STANDARD.decode(&sealed[SEALED.len()..]).unwrap();
+        bytes[NONCE_BYTES] ^= 1;
+        let tampered = serde_json::json!({ "password": format!("{}{}", SEALED, STANDARD.encode(bytes)) });
+        assert!(serde_json::from_value::<ArchiveRequest>(tampered).is_err());
+    }
+
+    // the keystream of each block on its own, across the chunks
+    #[test]
+    fn counter_mode_across_chunks() {
+        let key = [7; KEY_BYTES];
+        let plain: Vec<u8> = (0..2 * CTR_CHUNK + 21).map(|i| i as u8).collect();
+        let mut data = plain.clone();
+        aes_ctr(&key, &mut data).unwrap();
+
+        for (i, (block, plain)) in data.chunks(16).zip(plain.chunks(16)).enumerate() {
+            let mut counter = [0; 16];
+            counter[..8].copy_from_slice(&(i as u64 + 1).to_le_bytes());
+            let stream = openssl::symm::encrypt(Cipher::aes_256_ecb(), &key, None, &counter).unwrap();
+            let expected: Vec<u8> = plain.iter().zip(&stream).map(|(byte, key)| byte ^ key).collect();
+            assert_eq!(block, &expected[..], "block {}", i + 1);
+        }
+    }
+
+    #[test]
+    fn masked_password_is_shown_as_it_is() {
+        let request = ArchiveRequest { password: MASKED.to_string(), filename: default_filename() };
+        assert_eq!(serde_json::to_value(&request).unwrap()["password"], MASKED);
+    }
+}
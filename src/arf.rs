@@ -0,0 +1,114 @@
+//
+// Abuse reports of the feedback loops in the Abuse Reporting Format (RFC
+// 5965): a multipart/report with a message/feedback-report part and the
+// original message, or its headers, from which the complainer and the
+// campaign are read
+//
+
+use mailparse::{MailHeaderMap, ParsedMail};
+
+pub struct Report {
+    // "abuse", "fraud", "not-spam", "auth-failure", "virus" or "other"
+    pub feedback_type: String,
+    // Original-Rcpt-To, or the To of the original message
+    pub recipient: Option<String>,
+    pub campaign: Option<String>,
+}
+
+fn address(value: &str) -> Option<String> {
+    match mailparse::addrparse(value).ok()?.first()? {
+        mailparse::MailAddr::Single(single) => Some(single.addr.clone()),
+        mailparse::MailAddr::Group(group) => group.addrs.first().map(|single| single.addr.clone()),
+    }
+}
+
+// the headers of the original message, the whole message or only its headers
+fn original(part: &ParsedMail) -> Option<Vec<(String, String)>> {
+    let raw = part.get_body_raw().ok()?;
+    let (headers, _) = mailparse::parse_headers(&raw).ok()?;
+    Some(headers.iter()
+        .map(|header| (header.get_key(), header.get_value()))
+        .collect())
+}
+
+pub fn parse(
+    raw: &[u8],
+    campaign_header: &str,
+) -> Result<Report, String> {
+
+    let parsed = mailparse::parse_mail(raw)
+        .map_err(|e| format!("Invalid report: {}", e))?;
+    if !parsed.ctype.mimetype.eq_ignore_ascii_case("multipart/report")
+        || !parsed.ctype.params.get("report-type").is_some_and(|kind| kind.eq_ignore_ascii_case("feedback-report")) {
+        return Err(format!("{} is not a feedback report", parsed.ctype.mimetype));
+    }
+
+    let part = |mimetypes: &[&str]| parsed.subparts.iter()
+        .find(|part| mimetypes.iter().any(|mimetype| part.ctype.mimetype.eq_ignore_ascii_case(mimetype)));
+
+    let feedback = part(&["message/feedback-report"])
+        .ok_or("The report has no message/feedback-report part".to_string())?
+        .get_body_raw()
+        .map_err(|e| format!("Invalid feedback report: {}", e))?;
+    let (fields, _) = mailparse::parse_headers(&feedback)
+        .map_err(|e| format!("Invalid feedback report: {}", e))?;
+
+    let feedback_type = fields.get_first_value("Feedback-Type")
+        .map(|kind| kind.trim().to_ascii_lowercase())
+        .ok_or("The feedback report has no Feedback-Type".to_string())?;
+
+    let original = part(&["message/rfc822", "text/rfc822-headers"])
+        .and_then(original)
+        .unwrap_or_default();
+    let find = |name: &str| original.iter()
+        .find(|(key, _)| key.eq_ignore_ascii_case(name))
+        .map(|(_, value)| value.trim().to_string())
+        .filter(|value| !value.is_empty());
+
+    // the ISPs often redact the To of the original, Original-Rcpt-To is the
+    // only address left then
+    let recipient = fields.get_first_value("Original-Rcpt-To")
+        .as_deref()
+        .and_then(address)
+        .or_else(|| find("To").as_deref().and_then(address));
+
+    Ok(Report {
+        feedback_type,
+        recipient,
+        campaign: find(campaign_header),
+    })
+}
+
+// whether the DKIM signature of the report was checked by the receiving
+// server of the instance: an Authentication-Results header of its authserv-id
+// with a dkim=pass of the domain of the From of the report
+pub fn authenticated(
+    raw: &[u8],
+    authserv_id: &str,
+) -> bool {
+
+    let Ok((headers, _)) = mailparse::parse_headers(raw) else {
+        return false;
+    };
+    let Some(domain) = headers.get_first_value("From")
+        .as_deref()
+        .and_then(address)
+        .and_then(|from| from.rsplit_once('@').map(|(_, domain)| domain.to_ascii_lowercase())) else {
+        return false;
+    };
+
+    headers.get_all_values("Authentication-Results")
+        .iter()
+        .any(|results| {
+            let mut results = results.split(';').map(str::trim);
+            let id = results.next()
+                .and_then(|id| id.split_whitespace().next())
+                .unwrap_or("");
+            id.eq_ignore_ascii_case(authserv_id) && results.any(|result| {
+                let mut words = result.split_whitespace();
+                words.next().is_some_and(|method| method.eq_ignore_ascii_case("dkim=pass"))
+                    && words.any(|property| property.strip_prefix("header.d=")
+                        .is_some_and(|d| d.eq_ignore_ascii_case(&domain)))
+            })
+        })
+}
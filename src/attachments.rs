@@ -0,0 +1,299 @@
+//
+// Attachments: files on the plugin host or inline base64 blobs. They are read
+// in chunks, but not streamed: lettre takes the body of a part as a Vec, so
+// the whole encoded copy of each attachment is held until the send
+//
+
+use std::io::Read;
+use std::path::PathBuf;
+use base64::Engine;
+use base64::engine::general_purpose::STANDARD;
+use base64::read::DecoderReader;
+use lettre::message::{Attachment as MimeAttachment, Body, SinglePart};
+use lettre::message::header::{ContentTransferEncoding, ContentType};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+
+use crate::{SendError, SmtpSettings, antivirus, images};
+
+#[derive(Clone, Default, Deserialize, Serialize)]
+pub struct AttachmentEntry {
+    // file relative to the attachments directory
+    path: Option<String>,
+    // inline blob encoded as base64
+    content: Option<String>,
+    filename: Option<String>,
+    content_type: Option<String>,
+    // expected hex encoded sha256 of the file/blob
+    sha256: Option<String>,
+}
+
+// an attachment is a bare path or an entry with more details
+#[derive(Clone, Deserialize, Serialize)]
+#[serde(untagged)]
+pub enum Attachment {
+    Path(String),
+    Entry(AttachmentEntry),
+}
+
+impl Attachment {
+    fn entry(&self) -> AttachmentEntry {
+        match self {
+            Attachment::Path(path) => AttachmentEntry {
+                path: Some(path.clone()),
+                ..Default::default()
+            },
+            Attachment::Entry(entry) => entry.clone(),
+        }
+    }
+}
+
+fn resolve_path(
+    attachments_dir: Option<&str>,
+    path: &str,
+) -> Result<PathBuf, SendError> {
+
+    let attachments_dir = attachments_dir.ok_or_else(|| SendError::new(
+        "invalid_attachment",
+        "File attachments are disabled: attachments_dir is not set",
+    ))?;
+
+    crate::resolve_path(attachments_dir, path)
+        .map_err(|e| SendError::new("invalid_attachment", format!("Attachment {}", e)))
+}
+
+// raw bytes of a base64 line, 76 characters once encoded
+const LINE_BYTES: usize = 57;
+// raw bytes read at a time, whole lines
+const CHUNK_SIZE: usize = LINE_BYTES * 1024;
+
+// fill the buffer unless the end of the input is reached first
+fn read_chunk(
+    reader: &mut impl Read,
+    buffer: &mut [u8],
+) -> std::io::Result<usize> {
+
+    let mut filled = 0;
+    while filled < buffer.len() {
+        match reader.read(&mut buffer[filled..]) {
+            Ok(0) => break,
+            Ok(n) => filled += n,
+            Err(e) if e.kind() == std::io::ErrorKind::Interrupted => {},
+            Err(e) => return Err(e),
+        }
+    }
+
+    Ok(filled)
+}
+
+// base64 body of the MIME part and sha256 of the content, read in chunks so
+// the raw content isn't held next to its encoding
+fn encode(
+    mut reader: impl Read,
+    size_hint: usize,
+) -> std::io::Result<(Vec<u8>, String)> {
+
+    let mut hasher = Sha256::new();
+    let mut encoded = Vec::with_capacity((size_hint / LINE_BYTES + 1) * 78);
+    let mut chunk = vec![0; CHUNK_SIZE];
+    let mut line = [0; 76];
+    loop {
+        let n = read_chunk(&mut reader, &mut chunk)?;
+        hasher.update(&chunk[..n]);
+        for raw in chunk[..n].chunks(LINE_BYTES) {
+            // lines separated by CRLF, the last one without it
+            if !encoded.is_empty() {
+                encoded.extend_from_slice(b"\r\n");
+            }
+            let len = STANDARD.encode_slice(raw, &mut line)
+                .map_err(std::io::Error::other)?;
+            encoded.extend_from_slice(&line[..len]);
+        }
+        if n < CHUNK_SIZE {
+            break;
+        }
+    }
+
+    Ok((encoded, format!("{:x}", hasher.finalize())))
+}
+
+// the content of an encoded body, decoded a line at a time for the antivirus
+struct Decoded<'a> {
+    encoded: &'a [u8],
+    line: Vec<u8>,
+    position: usize,
+}
+
+impl Read for Decoded<'_> {
+    fn read(&mut self, buffer: &mut [u8]) -> std::io::Result<usize> {
+        while self.position == self.line.len() {
+            if self.encoded.is_empty() {
+                return Ok(0);
+            }
+            let (line, rest) = match self.encoded.iter().position(|&b| b == b'\r') {
+                Some(end) => (&self.encoded[..end], self.encoded.get(end + 2..).unwrap_or_default()),
+                None => (self.encoded, &[][..]),
+            };
+            self.encoded = rest;
+            self.line = STANDARD.decode(line)
+                .map_err(std::io::Error::other)?;
+            self.position = 0;
+        }
+
+        let n = buffer.len().min(self.line.len() - self.position);
+        buffer[..n].copy_from_slice(&self.line[self.position..self.position + n]);
+        self.position += n;
+
+        Ok(n)
+    }
+}
+
+// the decoded size of an attachment, without reading it
+pub fn size(
+    attachment: &Attachment,
+    settings: &SmtpSettings,
+) -> Result<u64, SendError> {
+
+    let entry = attachment.entry();
+    match (&entry.path, &entry.content) {
+        (Some(path), None) => {
+            let file = resolve_path(settings.attachments_dir.as_deref(), path)?;
+            std::fs::metadata(&file)
+                .map(|metadata| metadata.len())
+                .map_err(|e| SendError::new("invalid_attachment", format!("Attachment {}: {}", path, e)))
+        },
+        (None, Some(content)) => {
+            let digits = content.bytes()
+                .filter(|byte| !byte.is_ascii_whitespace() && *byte != b'=')
+                .count() as u64;
+            Ok(digits * 3 / 4)
+        },
+        _ => Err(SendError::new(
+            "invalid_attachment",
+            "Attachment must have either a path or a content",
+        )),
+    }
+}
+
+// an attachment read, downscaled and scanned, sent as a part of its own or
+// in the encrypted archive
+pub struct Loaded {
+    pub filename: String,
+    content_type: ContentType,
+    encoded: Vec<u8>,
+}
+
+impl Loaded {
+    pub fn content(&self) -> std::io::Result<Vec<u8>> {
+        let mut content = Vec::with_capacity(self.encoded.len() / 4 * 3);
+        Decoded {
+            encoded: &self.encoded,
+            line: Vec::new(),
+            position: 0,
+        }.read_to_end(&mut content)?;
+        Ok(content)
+    }
+
+    pub fn part(self) -> SinglePart {
+        let body = Body::dangerous_pre_encoded(self.encoded, ContentTransferEncoding::Base64);
+        MimeAttachment::new(self.filename).body(body, self.content_type)
+    }
+}
+
+// the name and type of an attachment, the type guessed from the name when the
+// request has none
+pub fn name_and_type(attachment: &Attachment) -> (String, String) {
+    let entry = attachment.entry();
+    let name = entry.filename.or(entry.path).unwrap_or_default();
+    let content_type = entry.content_type.unwrap_or_else(|| mime_guess::from_path(&name)
+        .first_or_octet_stream()
+        .to_string());
+    (name, content_type)
+}
+
+pub fn load(
+    attachment: &Attachment,
+    settings: &SmtpSettings,
+) -> Result<Loaded, SendError> {
+
+    let entry = attachment.entry();
+
+    let (mut encoded, sha256, filename) = match (&entry.path, &entry.content) {
+        (Some(path), None) => {
+            let file = resolve_path(settings.attachments_dir.as_deref(), path)?;
+            let error = |e: std::io::Error| SendError::new("invalid_attachment", format!("Attachment {}: {}", path, e));
+            let reader = std::fs::File::open(&file)
+                .map_err(error)?;
+            let size = reader.metadata()
+                .map(|metadata| metadata.len() as usize)
+                .unwrap_or(0);
+            let (encoded, sha256) = encode(reader, size)
+                .map_err(error)?;
+            let filename = entry.filename.clone().unwrap_or_else(|| file
+                .file_name()
+                .map(|name| name.to_string_lossy().into_owned())
+                .unwrap_or("attachment".to_string()));
+            (encoded, sha256, filename)
+        },
+        (None, Some(content)) => {
+            let reader = DecoderReader::new(content.as_bytes(), &STANDARD);
+            let (encoded, sha256) = encode(reader, content.len() / 4 * 3)
+                .map_err(|e| SendError::new("invalid_attachment", format!("Invalid base64 attachment: {}", e)))?;
+            (encoded, sha256, entry.filename.clone().unwrap_or("attachment".to_string()))
+        },
+        _ => return Err(SendError::new(
+            "invalid_attachment",
+            "Attachment must have either a path or a content",
+        )),
+    };
+
+    // the file may still be written by another process
+    if let Some(expected) = &entry.sha256 {
+        if !sha256.eq_ignore_ascii_case(expected.trim()) {
+            return Err(SendError::new(
+                "checksum_mismatch",
+                format!("Checksum mismatch for attachment {}: expected {} got {}", filename, expected, sha256),
+            ));
+        }
+    }
+
+    let content_type = entry.content_type.clone()
+        .unwrap_or_else(|| mime_guess::from_path(&filename)
+            .first_or_octet_stream()
+            .to_string());
+
+    // the photos are downscaled after the checksum of the original
+    if let Some((images, format)) = settings.images.as_ref().zip(images::format(&content_type)) {
+        let mut image = Vec::new();
+        Decoded {
+            encoded: &encoded,
+            line: Vec::new(),
+            position: 0,
+        }.read_to_end(&mut image)
+            .map_err(|e| SendError::new("invalid_attachment", format!("Attachment {}: {}", filename, e)))?;
+        if let Some(smaller) = images::shrink(images, &filename, format, &image) {
+            encoded = encode(smaller.as_slice(), smaller.len())
+                .map_err(|e| SendError::new("invalid_attachment", format!("Attachment {}: {}", filename, e)))?
+                .0;
+        }
+    }
+
+    // what is scanned is exactly what is sent
+    if let Some(antivirus) = &settings.antivirus {
+        let mut content = Decoded {
+            encoded: &encoded,
+            line: Vec::new(),
+            position: 0,
+        };
+        antivirus::scan(antivirus, &filename, &mut content)?;
+    }
+
+    let content_type = ContentType::parse(&content_type)
+        .map_err(|e| SendError::new("invalid_attachment", format!("Invalid content type {:?}: {}", content_type, e)))?;
+
+    Ok(Loaded {
+        filename,
+        content_type,
+        encoded,
+    })
+}
@@ -0,0 +1,128 @@
+//
+// Audit log of the administrative actions, rows are only ever appended
+//
+
+use hyper::HeaderMap;
+use rusqlite::params;
+use serde::Serialize;
+
+use crate::db;
+use crate::page::Page;
+
+// the triggers refuse to change or remove a recorded action
+pub const SCHEMA: &str = "CREATE TABLE IF NOT EXISTS audit (
+    id INTEGER PRIMARY KEY AUTOINCREMENT,
+    created_at INTEGER NOT NULL,
+    caller TEXT,
+    tenant TEXT NOT NULL,
+    action TEXT NOT NULL,
+    target TEXT,
+    status TEXT NOT NULL,
+    message TEXT NOT NULL,
+    request_id TEXT NOT NULL
+);
+CREATE INDEX IF NOT EXISTS audit_action ON audit (action, created_at);
+CREATE TRIGGER IF NOT EXISTS audit_no_update BEFORE UPDATE ON audit
+BEGIN SELECT RAISE(ABORT, 'the audit log is append-only'); END;
+CREATE TRIGGER IF NOT EXISTS audit_no_delete BEFORE DELETE ON audit
+BEGIN SELECT RAISE(ABORT, 'the audit log is append-only'); END;";
+
+#[derive(Serialize)]
+pub struct Entry {
+    id: i64,
+    created_at: i64,
+    // the name of the api key, none when the routes are open
+    caller: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    tenant: Option<String>,
+    action: String,
+    target: Option<String>,
+    status: String,
+    message: String,
+    request_id: String,
+}
+
+// an action waiting for the database
+pub struct Row {
+    created_at: i64,
+    caller: Option<String>,
+    tenant: String,
+    action: String,
+    target: Option<String>,
+    status: String,
+    message: String,
+    request_id: String,
+}
+
+// an action the database can't write is kept until it can
+pub fn record(
+    headers: &HeaderMap,
+    action: &str,
+    target: Option<&str>,
+    status: &str,
+    message: &str,
+) {
+
+    crate::persistence::save(crate::persistence::Row::Audit(Row {
+        created_at: db::now(),
+        caller: crate::auth::caller(headers).map(str::to_string),
+        tenant: crate::tenant::key(),
+        action: action.to_string(),
+        // the addresses of the suppression edits, masked like in the history
+        target: target.map(crate::privacy::redact),
+        status: status.to_string(),
+        message: crate::privacy::redact(message),
+        request_id: crate::trace::current()
+            .unwrap_or_else(|| crate::trace::request_id(headers)),
+    }));
+}
+
+pub fn insert(row: &Row) -> Result<(), String> {
+    db::conn()?.execute(
+        "INSERT INTO audit (created_at, caller, tenant, action, target, status, message, request_id)
+        VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8)",
+        params![row.created_at, row.caller, row.tenant, row.action, row.target, row.status, row.message, row.request_id],
+    ).map_err(|e| e.to_string())?;
+
+    Ok(())
+}
+
+// the newest first, of an action if given
+pub fn list(
+    action: Option<&str>,
+    since: Option<i64>,
+    page: &Page,
+) -> Result<(Vec<Entry>, usize), String> {
+
+    let conn = db::conn()?;
+
+    let total: i64 = conn.query_row(
+        "SELECT COUNT(*) FROM audit WHERE (?1 IS NULL OR action = ?1) AND created_at >= ?2",
+        params![action, since.unwrap_or(0)],
+        |row| row.get(0),
+    ).map_err(|e| e.to_string())?;
+
+    let (limit, offset) = page.sql();
+    let mut stmt = conn.prepare(
+        "SELECT id, created_at, caller, tenant, action, target, status, message, request_id
+        FROM audit WHERE (?1 IS NULL OR action = ?1) AND created_at >= ?2
+        ORDER BY id DESC LIMIT ?3 OFFSET ?4"
+    ).map_err(|e| e.to_string())?;
+
+    let entries = stmt.query_map(params![action, since.unwrap_or(0), limit, offset], |row| Ok(Entry {
+        id: row.get(0)?,
+        created_at: row.get(1)?,
+        caller: row.get(2)?,
+        tenant: Some(row.get::<_, String>(3)?).filter(|tenant| !tenant.is_empty()),
+        action: row.get(4)?,
+        target: row.get(5)?,
+        status: row.get(6)?,
+        message: row.get(7)?,
+        request_id: row.get(8)?,
+    })).map_err(|e| e.to_string())?;
+
+    let entries = entries.collect::<Result<Vec<Entry>, _>>()
+        .map_err(|e| e.to_string())?;
+
+    Ok((entries, total as usize))
+}
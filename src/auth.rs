@@ -0,0 +1,134 @@
+//
+// API keys of the callers and the scopes they are allowed to use
+//
+
+use std::cell::Cell;
+use hyper::HeaderMap;
+use serde::{Deserialize, Serialize};
+
+use crate::{SendError, SMTP_CLIENT};
+
+fn default_scopes() -> Vec<String> {
+    vec!["send".to_string()]
+}
+
+thread_local! {
+    static CURRENT: Cell<Option<&'static ApiKey>> = const { Cell::new(None) };
+}
+
+#[derive(Clone, Deserialize, Serialize)]
+pub struct ApiKey {
+    pub name: String,
+    pub key: String,
+    // "send" for the mail routes, "bulk" for the mail routes in the bulk
+    // lane of the queue only, "admin" for every route
+    #[serde(default = "default_scopes")]
+    pub scopes: Vec<String>,
+    // the routes only see the data of the tenant
+    pub tenant: Option<String>,
+    // the recipients and attachments of the requests of the key
+    pub budget: Option<crate::budget::Budget>,
+}
+
+impl ApiKey {
+    fn grants(
+        &self,
+        scope: &str,
+    ) -> bool {
+        self.scopes.iter().any(|granted| granted == scope || granted == "admin")
+    }
+}
+
+// compares in constant time so the response time doesn't leak the key
+fn same_key(
+    a: &str,
+    b: &str,
+) -> bool {
+    a.len() == b.len()
+        && a.bytes().zip(b.bytes()).fold(0, |diff, (a, b)| diff | (a ^ b)) == 0
+}
+
+fn presented(headers: &HeaderMap) -> Option<&str> {
+    headers.get("authorization")
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.strip_prefix("Bearer "))
+        .or_else(|| headers.get("x-api-key").and_then(|value| value.to_str().ok()))
+        .map(|key| key.trim())
+}
+
+// the name of the key of the request, none if it has no valid key
+pub fn caller(headers: &HeaderMap) -> Option<&'static str> {
+    let presented = presented(headers)?;
+    SMTP_CLIENT.api_keys
+        .iter()
+        .flatten()
+        .find(|key| same_key(&key.key, presented))
+        .map(|key| key.name.as_str())
+}
+
+// the key of the request, for the rest of its handler
+pub fn current() -> Option<&'static ApiKey> {
+    CURRENT.with(Cell::get)
+}
+
+// the key of the current thread is restored when the scope is dropped
+pub struct Scope(Option<&'static ApiKey>);
+
+impl Drop for Scope {
+    fn drop(&mut self) {
+        CURRENT.with(|current| current.set(self.0));
+    }
+}
+
+// the sends of the scheduler on behalf of the key that set them up, none when
+// it was removed from the config
+pub fn enter(name: Option<&str>) -> Scope {
+    let key = name.and_then(|name| SMTP_CLIENT.api_keys.iter()
+        .flatten()
+        .find(|key| key.name == name));
+    Scope(CURRENT.with(|current| current.replace(key)))
+}
+
+// the key of the request sends in the bulk lane only
+pub fn bulk_only() -> bool {
+    current().is_some_and(|key| !key.grants("send") && key.grants("bulk"))
+}
+
+// without api_keys in the config the mail routes are open and the admin routes disabled
+pub fn authorize(
+    headers: &HeaderMap,
+    scope: &str,
+) -> Result<Option<&'static ApiKey>, SendError> {
+
+    CURRENT.with(|current| current.set(None));
+    let keys = match &SMTP_CLIENT.api_keys {
+        Some(keys) if !keys.is_empty() => keys,
+        _ if scope == "admin" => return Err(SendError::new(
+            "forbidden",
+            "Admin routes are disabled: no api key with the admin scope is set",
+        )),
+        _ => return Ok(None),
+    };
+
+    let presented = presented(headers)
+        .ok_or(SendError::new("unauthorized", "No api key"))?;
+    let key = keys.iter()
+        .find(|key| same_key(&key.key, presented))
+        .ok_or(SendError::new("unauthorized", "Invalid api key"))?;
+
+    let bulk = scope == "send" && key.grants("bulk");
+    if !key.grants(scope) && !bulk {
+        return Err(SendError::new("forbidden", format!("The api key {} has no {} scope", key.name, scope)));
+    }
+    // a key of a tenant missing from the config is refused rather than
+    // given the whole instance
+    if let Some(tenant) = &key.tenant {
+        if crate::tenant::find(tenant).is_none() {
+            return Err(SendError::new("forbidden", format!("The api key {} has an unknown tenant {}", key.name, tenant)));
+        }
+    }
+    crate::tenant::set(key.tenant.clone());
+    CURRENT.with(|current| current.set(Some(key)));
+
+    Ok(Some(key))
+}
@@ -0,0 +1,196 @@
+//
+// Budgets of the api keys: the recipients a key can send to per minute and per
+// day, and the size of the attachments of a request, so one service can't use
+// up the limits of the accounts shared by every caller
+//
+
+use std::sync::Mutex;
+use rusqlite::params;
+use serde::{Deserialize, Serialize};
+
+use crate::{Response, SendError, SMTP_CLIENT};
+use crate::attachments::Attachment;
+use crate::db;
+
+pub const SCHEMA: &str = "CREATE TABLE IF NOT EXISTS key_sends (
+    id INTEGER PRIMARY KEY AUTOINCREMENT,
+    key TEXT NOT NULL,
+    recipients INTEGER NOT NULL,
+    sent_at INTEGER NOT NULL
+);
+CREATE INDEX IF NOT EXISTS key_sends_key ON key_sends (key, sent_at);";
+
+const MINUTE: i64 = 60;
+const DAY: i64 = 86400;
+
+#[derive(Clone, Deserialize, Serialize)]
+pub struct Budget {
+    // recipients of the requests accepted in the last minute and 24 hours
+    pub per_minute: Option<u64>,
+    pub per_day: Option<u64>,
+    // the attachments of a request together, decoded
+    pub max_attachment_bytes: Option<u64>,
+}
+
+#[derive(Clone, Serialize)]
+pub struct Window {
+    limit: u64,
+    used: u64,
+    remaining: u64,
+    // when the oldest send of the window stops counting
+    #[serde(skip_serializing_if = "Option::is_none")]
+    reset_at: Option<i64>,
+}
+
+// the budget of the key after a request is accepted
+#[derive(Clone, Serialize)]
+pub struct Usage {
+    key: &'static str,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    per_minute: Option<Window>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    per_day: Option<Window>,
+}
+
+// a request counted against the budget, given back if it isn't sent
+pub struct Spent {
+    id: i64,
+    recipients: u64,
+    pub usage: Usage,
+}
+
+// held while a request is counted, the admissions are one at a time so two
+// requests can't both take the last of a budget
+static ADMISSION: Mutex<()> = Mutex::new(());
+
+fn current() -> Option<(&'static str, &'static Budget)> {
+    crate::auth::current()
+        .and_then(|key| key.budget.as_ref().map(|budget| (key.name.as_str(), budget)))
+}
+
+pub fn attachments(attachments: &[Attachment]) -> Result<(), SendError> {
+
+    let Some((key, Budget { max_attachment_bytes: Some(limit), .. })) = current() else {
+        return Ok(());
+    };
+
+    let mut total = 0;
+    for attachment in attachments {
+        total += crate::attachments::size(attachment, &SMTP_CLIENT)?;
+    }
+    match total > *limit {
+        true => Err(SendError::new(
+            "attachment_too_large",
+            format!("The attachments of the request are {} bytes, the api key {} allows {}", total, key, limit),
+        )),
+        false => Ok(()),
+    }
+}
+
+fn window(
+    conn: &rusqlite::Connection,
+    key: &str,
+    limit: Option<u64>,
+    secs: i64,
+) -> Result<Option<Window>, String> {
+
+    let Some(limit) = limit else {
+        return Ok(None);
+    };
+
+    let (used, oldest): (u64, Option<i64>) = conn.query_row(
+        "SELECT COALESCE(SUM(recipients), 0), MIN(sent_at) FROM key_sends WHERE key = ?1 AND sent_at > ?2",
+        params![key, db::now() - secs],
+        |row| Ok((row.get(0)?, row.get(1)?)),
+    ).map_err(|e| e.to_string())?;
+
+    Ok(Some(Window {
+        limit,
+        used,
+        remaining: limit.saturating_sub(used),
+        reset_at: oldest.map(|oldest| oldest + secs),
+    }))
+}
+
+// the recipients of an accepted request, Err when it would go over a budget
+pub fn spend(recipients: u64) -> Result<Option<Spent>, SendError> {
+
+    let Some((key, budget)) = current().filter(|(_, budget)| budget.per_minute.is_some() || budget.per_day.is_some()) else {
+        return Ok(None);
+    };
+
+    let _admission = ADMISSION.lock()
+        .unwrap_or_else(|e| e.into_inner());
+    let result = db::conn().and_then(|conn| {
+        for (limit, secs, name) in [(budget.per_minute, MINUTE, "minute"), (budget.per_day, DAY, "day")] {
+            if let Some(window) = window(&conn, key, limit, secs)?.filter(|window| recipients > window.remaining) {
+                return Ok(Err((window, name)));
+            }
+        }
+
+        let now = db::now();
+        conn.execute("DELETE FROM key_sends WHERE sent_at <= ?1", params![now - DAY])
+            .map_err(|e| e.to_string())?;
+        conn.execute(
+            "INSERT INTO key_sends (key, recipients, sent_at) VALUES (?1, ?2, ?3)",
+            params![key, recipients, now],
+        ).map_err(|e| e.to_string())?;
+        let id = conn.last_insert_rowid();
+
+        Ok(Ok(Spent {
+            id,
+            recipients,
+            usage: Usage {
+                key,
+                per_minute: window(&conn, key, budget.per_minute, MINUTE)?,
+                per_day: window(&conn, key, budget.per_day, DAY)?,
+            },
+        }))
+    });
+
+    match result {
+        Ok(Ok(spent)) => Ok(Some(spent)),
+        Ok(Err((window, name))) => Err(SendError::new(
+            "budget_exceeded",
+            format!(
+                "The api key {} has sent to {} of its {} recipients of the last {}, until {}",
+                key,
+                window.used,
+                window.limit,
+                name,
+                crate::quota::format_time(window.reset_at.unwrap_or(db::now())),
+            ),
+        ).retry_at(window.reset_at.unwrap_or(db::now()))),
+        // the accounting is only a budget, don't block sending on it
+        Err(e) => {
+            log!("Api key budget skipped: {}", e);
+            Ok(None)
+        },
+    }
+}
+
+// the request is given back its recipients if it failed
+pub fn settle(
+    spent: Option<Spent>,
+    response: &mut Response,
+) {
+
+    let Some(spent) = spent.filter(|_| response.status == "error") else {
+        return;
+    };
+
+    let result = db::conn().and_then(|conn| conn
+        .execute("DELETE FROM key_sends WHERE id = ?1", params![spent.id])
+        .map_err(|e| e.to_string()));
+    if let Err(e) = result {
+        log!("Error refunding the api key budget: {}", e);
+        return;
+    }
+
+    let mut usage = spent.usage;
+    for window in [&mut usage.per_minute, &mut usage.per_day].into_iter().flatten() {
+        window.used = window.used.saturating_sub(spent.recipients);
+        window.remaining = window.limit.saturating_sub(window.used);
+    }
+    response.budget = Some(usage);
+}
@@ -0,0 +1,134 @@
+//
+// Registry of the response buffers handed to the host: free() only releases a
+// pointer it finds here, a double free or a pointer the plugin didn't allocate
+// is logged and left alone instead of corrupting the allocator
+//
+
+use std::collections::{HashMap, VecDeque};
+use std::ffi::c_char;
+use std::sync::Mutex;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::{Duration, Instant};
+use once_cell::sync::Lazy;
+use serde::Serialize;
+
+// released pointers remembered to tell a double free from a foreign pointer
+const RECENT: usize = 1024;
+
+// a buffer the host hasn't freed after this long is counted as leaked
+const LEAKED_AFTER: Duration = Duration::from_secs(60);
+
+struct Buffer {
+    returned: Instant,
+    // the exported handler that returned it, for response_headers()
+    function: Option<&'static str>,
+}
+
+#[derive(Default)]
+struct Registry {
+    // address of each buffer the host has
+    outstanding: HashMap<usize, Buffer>,
+    recent: VecDeque<usize>,
+}
+
+static REGISTRY: Lazy<Mutex<Registry>> = Lazy::new(|| Mutex::new(Registry::default()));
+
+static RETURNED: AtomicU64 = AtomicU64::new(0);
+static FREED: AtomicU64 = AtomicU64::new(0);
+static DOUBLE_FREES: AtomicU64 = AtomicU64::new(0);
+static FOREIGN: AtomicU64 = AtomicU64::new(0);
+
+#[derive(Serialize)]
+pub struct Stats {
+    returned: u64,
+    freed: u64,
+    outstanding: usize,
+    // outstanding for more than a minute
+    leaked: usize,
+    double_frees: u64,
+    foreign: u64,
+}
+
+pub fn register(ptr: *mut c_char) -> *const c_char {
+
+    if let Ok(mut registry) = REGISTRY.lock() {
+        let address = ptr as usize;
+        // the allocator may hand out a released address again
+        registry.recent.retain(|&recent| recent != address);
+        registry.outstanding.insert(address, Buffer { returned: Instant::now(), function: None });
+    }
+    RETURNED.fetch_add(1, Ordering::Relaxed);
+
+    ptr
+}
+
+// the handler of an outstanding buffer, set after it returns
+pub fn tag(
+    ptr: *const c_char,
+    function: &'static str,
+) {
+    if let Some(buffer) = REGISTRY.lock().ok().as_mut().and_then(|registry| registry.outstanding.get_mut(&(ptr as usize))) {
+        buffer.function = Some(function);
+    }
+}
+
+// None if the host doesn't have the buffer, it may be freed or foreign
+pub fn function(ptr: *const c_char) -> Option<Option<&'static str>> {
+    REGISTRY.lock().ok()
+        .and_then(|registry| registry.outstanding.get(&(ptr as usize)).map(|buffer| buffer.function))
+}
+
+// true if the pointer is a buffer of the plugin the host still had
+pub fn release(ptr: *mut c_char) -> bool {
+
+    let address = ptr as usize;
+    let Ok(mut registry) = REGISTRY.lock() else {
+        // without the registry the pointer can't be checked, a leak is safer
+        // than a bad free
+        return false;
+    };
+
+    if registry.outstanding.remove(&address).is_some() {
+        if registry.recent.len() == RECENT {
+            registry.recent.pop_front();
+        }
+        registry.recent.push_back(address);
+        FREED.fetch_add(1, Ordering::Relaxed);
+        return true;
+    }
+
+    // not log!, free() can't unwind and the config it reads may be broken
+    match registry.recent.contains(&address) {
+        true => {
+            DOUBLE_FREES.fetch_add(1, Ordering::Relaxed);
+            eprintln!("arp-gmail: double free of the response buffer {:#x} refused", address);
+        },
+        false => {
+            FOREIGN.fetch_add(1, Ordering::Relaxed);
+            eprintln!("arp-gmail: free of the pointer {:#x} refused, it isn't a response buffer of the plugin", address);
+        },
+    }
+
+    false
+}
+
+pub fn stats() -> Stats {
+
+    let (outstanding, leaked) = REGISTRY.lock()
+        .map(|registry| (
+            registry.outstanding.len(),
+            registry.outstanding.values()
+                .filter(|buffer| buffer.returned.elapsed() > LEAKED_AFTER)
+                .count(),
+        ))
+        .unwrap_or_default();
+
+    Stats {
+        returned: RETURNED.load(Ordering::Relaxed),
+        freed: FREED.load(Ordering::Relaxed),
+        outstanding,
+        leaked,
+        double_frees: DOUBLE_FREES.load(Ordering::Relaxed),
+        foreign: FOREIGN.load(Ordering::Relaxed),
+    }
+}
@@ -0,0 +1,87 @@
+//
+// Templated mail to a list of recipients, each with its own locale and data
+//
+
+use serde::Deserialize;
+use serde_json::Value;
+
+use crate::{Mail, SendError};
+
+#[derive(Clone, Deserialize)]
+pub struct Recipient {
+    pub to: String,
+    // of the template variant, the locale of the request if not set
+    pub locale: Option<String>,
+    // merged over the data of the request
+    pub data: Option<Value>,
+}
+
+#[derive(Deserialize)]
+pub struct BulkRequest {
+    #[serde(flatten)]
+    pub mail: Mail,
+    #[serde(default)]
+    pub recipients: Vec<Recipient>,
+}
+
+// the keys of the recipient replace those of the request
+fn merge(
+    data: Option<&Value>,
+    recipient: Option<&Value>,
+) -> Option<Value> {
+
+    match (data, recipient) {
+        (Some(Value::Object(data)), Some(Value::Object(recipient))) => {
+            let mut merged = data.clone();
+            merged.extend(recipient.clone());
+            Some(Value::Object(merged))
+        },
+        (data, None) => data.cloned(),
+        (_, recipient) => recipient.cloned(),
+    }
+}
+
+pub fn check(mail: &Mail) -> Result<(), SendError> {
+    if mail.template.is_none() {
+        return Err(SendError::new("invalid_request", "No template"));
+    }
+    if mail.raw_mime.is_some() || mail.digest.is_some() {
+        return Err(SendError::new("invalid_request", "A templated send can't have raw_mime or digest"));
+    }
+    Ok(())
+}
+
+// the mail of the request to a recipient
+pub fn personal(
+    mail: &Mail,
+    recipient: Recipient,
+) -> Mail {
+    let mut personal = mail.clone();
+    personal.to = recipient.to;
+    personal.locale = recipient.locale.or(personal.locale);
+    personal.data = merge(mail.data.as_ref(), recipient.data.as_ref());
+    personal
+}
+
+// one mail per recipient, the request "to" and "locale" when there's no list
+pub fn mails(
+    mail: &Mail,
+    recipients: &[Recipient],
+) -> Result<Vec<Mail>, SendError> {
+
+    check(mail)?;
+
+    let recipients = match recipients.is_empty() {
+        true if mail.to.trim().is_empty() => return Err(SendError::new("invalid_request", "No recipients")),
+        true => vec![Recipient {
+            to: mail.to.clone(),
+            locale: None,
+            data: None,
+        }],
+        false => recipients.to_vec(),
+    };
+
+    Ok(recipients.into_iter()
+        .map(|recipient| personal(mail, recipient))
+        .collect())
+}
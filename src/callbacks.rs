@@ -0,0 +1,149 @@
+//
+// The webhook callbacks of the plugin (alerts, error reports), signed with the
+// secret of the first "callback_secrets" pattern matching their url: the
+// X-Webhook-Timestamp and X-Webhook-Signature of the events the plugin
+// receives, the hex HMAC-SHA256 of "<timestamp>.<body>"
+//
+
+use std::time::Duration;
+use hmac::{Hmac, Mac};
+use serde::{Deserialize, Serialize};
+use sha2::Sha256;
+
+use crate::SMTP_CLIENT;
+
+#[derive(Clone, Deserialize, Serialize)]
+pub struct CallbackSecret {
+    // the url of the callbacks, "*" matching any text:
+    // "https://hooks.example.com/*"
+    pub url: String,
+    pub secret: String,
+}
+
+// a "*" matches any text, the rest is literal
+pub fn matches(
+    pattern: &str,
+    url: &str,
+) -> bool {
+
+    let mut parts = pattern.split('*');
+    let first = parts.next().unwrap_or_default();
+    let Some(mut rest) = url.strip_prefix(first) else {
+        return false;
+    };
+    let parts: Vec<&str> = parts.collect();
+    let Some((last, middle)) = parts.split_last() else {
+        return rest.is_empty();
+    };
+    for part in middle {
+        match rest.find(part) {
+            Some(at) => rest = &rest[at + part.len()..],
+            None => return false,
+        }
+    }
+
+    rest.ends_with(last)
+}
+
+pub fn secret(url: &str) -> Option<&'static str> {
+    SMTP_CLIENT.callback_secrets.iter()
+        .flatten()
+        .find(|callback| matches(&callback.url, url))
+        .map(|callback| callback.secret.as_str())
+}
+
+fn sign(
+    secret: &str,
+    timestamp: &str,
+    body: &[u8],
+) -> Option<String> {
+    // an HMAC takes a key of any length, it doesn't fail
+    let mut mac = Hmac::<Sha256>::new_from_slice(secret.as_bytes()).ok()?;
+    mac.update(timestamp.as_bytes());
+    mac.update(b".");
+    mac.update(body);
+    Some(mac.finalize()
+        .into_bytes()
+        .iter()
+        .map(|byte| format!("{:02x}", byte))
+        .collect())
+}
+
+// POST the JSON, signed when a secret matches the url
+pub fn post(
+    url: &str,
+    payload: &serde_json::Value,
+    timeout: Duration,
+) -> Result<(), String> {
+
+    let body = serde_json::to_vec(payload)
+        .map_err(|e| e.to_string())?;
+    let mut request = ureq::post(url)
+        .timeout(timeout)
+        .set("Content-Type", "application/json");
+    if let Some(secret) = secret(url) {
+        let timestamp = crate::db::now().to_string();
+        let signature = sign(secret, &timestamp, &body)
+            .ok_or("The callback can't be signed")?;
+        request = request
+            .set("X-Webhook-Signature", &signature)
+            .set("X-Webhook-Timestamp", &timestamp);
+    }
+
+    request.send_bytes(&body)
+        .map(|_| ())
+        .map_err(|e| e.to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn patterns() {
+        let cases = [
+            // no "*", the whole url
+            ("https://hooks.example.com/a", "https://hooks.example.com/a", true),
+            ("https://hooks.example.com/a", "https://hooks.example.com/ab", false),
+            ("https://hooks.example.com/a", "https://hooks.example.com/", false),
+            // a trailing "*", any remainder or none
+            ("https://hooks.example.com/*", "https://hooks.example.com/alerts", true),
+            ("https://hooks.example.com/*", "https://hooks.example.com/", true),
+            ("https://hooks.example.com/*", "https://hooks.example.org/alerts", false),
+            ("a*", "a", true),
+            ("a*", "ba", false),
+            // "*" alone, every url
+            ("*", "", true),
+            ("*", "https://anything", true),
+            // a leading "*"
+            ("*/alerts", "https://hooks.example.com/alerts", true),
+            ("*/alerts", "https://hooks.example.com/alerts/2", false),
+            // the middle parts in order, none overlapping the last one
+            ("https://*.example.com/*/alerts", "https://eu.example.com/v1/alerts", true),
+            ("https://*.example.com/*/alerts", "https://eu.example.com/alerts", false),
+            ("a*b*b", "ab", false),
+            ("a*b*b", "abb", true),
+            ("a*bc*c", "abc", false),
+            ("a*bc*c", "abcc", true),
+            ("ab*ba", "aba", false),
+            ("ab*ba", "abba", true),
+            ("a**b", "ab", true),
+        ];
+        for (pattern, url, expected) in cases {
+            assert_eq!(matches(pattern, url), expected, "{:?} {:?}", pattern, url);
+        }
+    }
+
+    // the HMAC-SHA256 of Python's hmac module for the same input
+    #[test]
+    fn signature_vectors() {
+        assert_eq!(
+            sign("whsec-test", "1700000000", br#"{"alert":"queue_depth"}"#).as_deref(),
+            Some("12425a680ef66abeefacc84027148dee1405828449d14a667eaaf7b65dbd14d5"),
+        );
+        assert_eq!(
+            sign("", "0", b"").as_deref(),
+            Some("b849d5a581847b281957065739df36df2463d1977ea8d6e1e4e6cf33fadc68c3"),
+        );
+    }
+}
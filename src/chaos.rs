@@ -0,0 +1,117 @@
+//
+// Failure injection for the staging instances: temporary 4xx replies, added
+// latency, dropped connections and a full backlog of the send workers, to
+// check the retries of the callers and the backoff of the queue without
+// hitting the real relay. From the "chaos" of the config, replaced until the
+// next restart by POST /admin/chaos
+//
+
+use std::sync::Mutex;
+use std::time::Duration;
+use serde::{Deserialize, Serialize};
+
+use crate::{SendError, SMTP_CLIENT};
+use crate::pool::Failure;
+
+// the chances are from 0 to 1 for every send
+#[derive(Clone, Default, Deserialize, Serialize)]
+pub struct ChaosSettings {
+    // a temporary failure, as a 451 of the server
+    #[serde(default)]
+    pub smtp_4xx_rate: f64,
+    // added before the send, inside its timeout
+    #[serde(default)]
+    pub latency_ms: u64,
+    #[serde(default)]
+    pub latency_rate: f64,
+    // the connection to the server lost
+    #[serde(default)]
+    pub drop_rate: f64,
+    // every send refused as busy, the workers and their backlog full
+    #[serde(default)]
+    pub backlog_full: bool,
+}
+
+impl ChaosSettings {
+    fn enabled(&self) -> bool {
+        self.smtp_4xx_rate > 0.0 || (self.latency_ms > 0 && self.latency_rate > 0.0) || self.drop_rate > 0.0 || self.backlog_full
+    }
+}
+
+#[derive(Deserialize)]
+pub struct ChaosRequest {
+    // set or clear, back to the config
+    pub action: String,
+    pub chaos: Option<ChaosSettings>,
+}
+
+// the toggles set at runtime, the ones of the config until then
+static OVERRIDE: Mutex<Option<ChaosSettings>> = Mutex::new(None);
+
+pub fn current() -> ChaosSettings {
+    OVERRIDE.lock()
+        .unwrap_or_else(|e| e.into_inner())
+        .clone()
+        .or(SMTP_CLIENT.chaos.clone())
+        .unwrap_or_default()
+}
+
+pub fn check(settings: &ChaosSettings) -> Result<(), SendError> {
+    for (name, rate) in [("smtp_4xx_rate", settings.smtp_4xx_rate), ("latency_rate", settings.latency_rate), ("drop_rate", settings.drop_rate)] {
+        if !(0.0..=1.0).contains(&rate) {
+            return Err(SendError::new("invalid_request", format!("\"{}\" is a chance from 0 to 1", name)));
+        }
+    }
+    Ok(())
+}
+
+// the toggles until the next restart, back to the ones of the config on a clear
+pub fn handle(request: ChaosRequest) -> Result<ChaosSettings, SendError> {
+    let settings = match (request.action.as_str(), request.chaos) {
+        ("set", Some(settings)) => {
+            check(&settings)?;
+            Some(settings)
+        },
+        ("set", None) => return Err(SendError::new("invalid_request", "No \"chaos\" settings to set")),
+        ("clear", _) => None,
+        (action, _) => return Err(SendError::new("invalid_request", format!("Invalid action: {}", action))),
+    };
+    *OVERRIDE.lock().unwrap_or_else(|e| e.into_inner()) = settings;
+
+    let current = current();
+    if current.enabled() {
+        log!("Failure injection is on: {}", serde_json::to_string(&current).unwrap_or_default());
+    }
+    Ok(current)
+}
+
+// a uniform number in [0, 1)
+fn roll() -> f64 {
+    (uuid::Uuid::new_v4().as_u128() as u64 >> 11) as f64 / (1u64 << 53) as f64
+}
+
+pub fn backlog_full() -> bool {
+    current().backlog_full
+}
+
+// run on the worker before the send: the added latency, then the failure
+// that replaces the send
+pub fn inject() -> Option<Failure> {
+
+    let settings = current();
+    if !settings.enabled() {
+        return None;
+    }
+
+    if settings.latency_ms > 0 && roll() < settings.latency_rate {
+        std::thread::sleep(Duration::from_millis(settings.latency_ms));
+    }
+    if roll() < settings.drop_rate {
+        return Some(Failure::Connect("chaos: the connection was dropped".to_string()));
+    }
+    if roll() < settings.smtp_4xx_rate {
+        return Some(Failure::Injected("chaos: 451 4.3.0 Temporary failure injected, try again later".to_string()));
+    }
+
+    None
+}
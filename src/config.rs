@@ -0,0 +1,887 @@
+//
+// Strict validation of config.json: unknown keys, missing or invalid values and
+// the servers that can't be reached, at load and on GET /config/validate; and
+// the running config with its secrets masked on GET /config
+//
+
+use std::collections::HashSet;
+use std::net::{IpAddr, UdpSocket};
+use std::path::PathBuf;
+use std::time::Duration;
+use once_cell::sync::OnceCell;
+use serde::Serialize;
+use serde_json::{json, Value};
+
+use crate::policy::Action;
+use crate::transport::Provider;
+use crate::{SmtpSettings, SMTP_CLIENT};
+
+// port of the SMTPS relay of the Gmail accounts
+const SMTPS_PORT: u16 = 465;
+
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(5);
+
+// the string values masked on GET /config, by the name of their key
+const SECRETS: &[&str] = &["password", "secret", "key", "token", "salt", "dsn"];
+
+// the URLs with a password in their user info or query, and the webhooks with
+// a token anywhere in them
+const SECRET_URLS: &[&str] = &["/storage/url"];
+const SECRET_WEBHOOKS: &[&str] = &["/alerts/webhook", "/error_reporting/webhook"];
+
+// the file the running config was read from, with the time, its values and the
+// fingerprint of the key that signed it
+struct Loaded {
+    file: PathBuf,
+    at: i64,
+    values: Value,
+    signed_by: Option<String>,
+}
+
+static LOADED: OnceCell<Loaded> = OnceCell::new();
+
+#[derive(Serialize)]
+pub struct Issue {
+    // "accounts.1.api_key", empty for the whole file
+    path: String,
+    message: String,
+}
+
+// the errors stop the config from loading or break the sends, the warnings
+// may only be transient or intended
+#[derive(Default, Serialize)]
+pub struct Report {
+    errors: Vec<Issue>,
+    warnings: Vec<Issue>,
+}
+
+impl Report {
+    fn error(
+        &mut self,
+        path: impl Into<String>,
+        message: impl Into<String>,
+    ) {
+        self.errors.push(Issue { path: path.into(), message: message.into() });
+    }
+
+    fn warning(
+        &mut self,
+        path: impl Into<String>,
+        message: impl Into<String>,
+    ) {
+        self.warnings.push(Issue { path: path.into(), message: message.into() });
+    }
+
+    pub fn is_valid(&self) -> bool {
+        self.errors.is_empty()
+    }
+
+    // log! would load the config being reported on
+    pub fn log(&self) {
+        for (level, issues) in [("error", &self.errors), ("warning", &self.warnings)] {
+            for issue in issues {
+                match issue.path.as_str() {
+                    "" => eprintln!("arp-gmail: config {}: {}", level, issue.message),
+                    path => eprintln!("arp-gmail: config {} at {}: {}", level, path, issue.message),
+                }
+            }
+        }
+    }
+}
+
+// the config file as it is on disk now, not the one loaded at start
+fn parse(report: &mut Report) -> Option<SmtpSettings> {
+
+    let file = match crate::plugin_path() {
+        Ok(dir) => dir.join("config.json"),
+        Err(e) => {
+            report.error("", format!("Config file can't be read: {}", e));
+            return None;
+        },
+    };
+
+    // verified like the config loaded at start, a tampered file isn't checked
+    let text = match crate::signature::read(&file) {
+        Ok((text, Some(_))) => text,
+        Ok((text, None)) => {
+            if crate::signature::signature_file(&file).is_file() {
+                report.warning("", format!("config.json.sig isn't checked, {} is not set", crate::signature::KEY_VAR));
+            }
+            text
+        },
+        Err(e) => {
+            report.error("", e);
+            return None;
+        },
+    };
+
+    let mut unknown = Vec::new();
+    let mut deserializer = serde_json::Deserializer::from_str(&text);
+    let config = serde_ignored::deserialize(&mut deserializer, |path| unknown.push(path.to_string()))
+        .and_then(|config: SmtpSettings| deserializer.end().map(|_| config));
+
+    // "?" is the segment of an Option in the paths
+    for path in unknown {
+        report.error(path.replace(".?", ""), "Unknown key");
+    }
+    // the settings of a provider are flattened into its account, where the
+    // deserializer can't tell the unknown keys apart
+    if let Ok(value) = serde_json::from_str::<serde_json::Value>(&text) {
+        for (i, account) in value["accounts"].as_array().into_iter().flatten().enumerate() {
+            let Some(known) = account["provider"].as_str().and_then(provider_keys) else {
+                continue;
+            };
+            for key in account.as_object().into_iter().flat_map(|account| account.keys()) {
+                if !["name", "provider"].contains(&key.as_str()) && !known.contains(&key.as_str()) {
+                    report.error(format!("accounts.{}.{}", i, key), "Unknown key");
+                }
+            }
+        }
+        // and the settings of an action into its policy, with its conditions
+        for (i, rule) in value["policies"].as_array().into_iter().flatten().enumerate() {
+            let Some(known) = rule["action"].as_str().and_then(action_keys) else {
+                continue;
+            };
+            for key in rule.as_object().into_iter().flat_map(|rule| rule.keys()) {
+                if !["name", "match", "action"].contains(&key.as_str()) && !known.contains(&key.as_str()) {
+                    report.error(format!("policies.{}.{}", i, key), "Unknown key");
+                }
+            }
+            for key in rule["match"].as_object().into_iter().flat_map(|conditions| conditions.keys()) {
+                if !CONDITIONS.contains(&key.as_str()) {
+                    report.error(format!("policies.{}.match.{}", i, key), "Unknown key");
+                }
+            }
+        }
+    }
+
+    match config {
+        Ok(config) => Some(config),
+        Err(e) => {
+            report.error("", e.to_string());
+            None
+        },
+    }
+}
+
+// the keys of the account settings of each provider
+fn provider_keys(provider: &str) -> Option<&'static [&'static str]> {
+    Some(match provider {
+        "gmail" => &["username", "password", "server", "bind_address", "weight", "daily_limit"],
+        "sendgrid" => &["api_key", "endpoint"],
+        "mailgun" => &["api_key", "domain", "region", "endpoint"],
+        "ses" => &["access_key_id", "secret_access_key", "region", "endpoint"],
+        _ => return None,
+    })
+}
+
+// the keys of the settings of each policy action, and of its conditions
+fn action_keys(action: &str) -> Option<&'static [&'static str]> {
+    Some(match action {
+        "reject" => &["message"],
+        "require_approval" => &[],
+        "add_bcc" => &["address"],
+        "force_account" => &["account"],
+        "add_header" => &["header", "value"],
+        _ => return None,
+    })
+}
+
+const CONDITIONS: &[&str] = &["recipient_domains", "subject", "attachment_types", "min_body_bytes", "max_body_bytes"];
+
+fn one_of(
+    report: &mut Report,
+    path: &str,
+    value: Option<&str>,
+    allowed: &[&str],
+) {
+    if let Some(value) = value {
+        if !allowed.contains(&value) {
+            report.error(path, format!("Invalid value {:?}, expected one of {}", value, allowed.join(", ")));
+        }
+    }
+}
+
+fn directory(
+    report: &mut Report,
+    path: &str,
+    dir: Option<&str>,
+) {
+    let Some(dir) = dir else {
+        return;
+    };
+    let resolved = match std::path::Path::new(dir).is_absolute() {
+        true => std::path::PathBuf::from(dir),
+        false => match crate::plugin_path() {
+            Ok(plugin) => plugin.join(dir),
+            Err(_) => return,
+        },
+    };
+    if !resolved.is_dir() {
+        report.warning(path, format!("Directory {} doesn't exist", resolved.display()));
+    }
+}
+
+fn address(
+    report: &mut Report,
+    path: &str,
+    value: &str,
+) {
+    if let Err(e) = value.parse::<lettre::message::Mailbox>() {
+        report.error(path, format!("Invalid address {:?}: {}", value, e));
+    }
+}
+
+// binding a socket to the address only works on an address of the host
+fn bind_address(
+    report: &mut Report,
+    path: &str,
+    local: Option<IpAddr>,
+) {
+    if let Some(local) = local {
+        if let Err(e) = UdpSocket::bind((local, 0)) {
+            report.error(path, format!("{} isn't an address of this host: {}", local, e));
+        }
+    }
+}
+
+fn recipient_limits(
+    report: &mut Report,
+    path: &str,
+    limits: Option<&crate::ratelimit::RecipientLimits>,
+) {
+    let Some(limits) = limits else {
+        return;
+    };
+    if limits.per_hour == Some(0) || limits.per_day == Some(0) {
+        report.warning(path, "A limit of 0 refuses every send");
+    }
+    if let (Some(per_hour), Some(per_day)) = (limits.per_hour, limits.per_day) {
+        if per_hour > per_day {
+            report.warning(format!("{}.per_hour", path), format!("{} per hour can't be reached with {} per day", per_hour, per_day));
+        }
+    }
+}
+
+// whether the plugin was built with the provider
+fn built_in(provider: &Provider) -> bool {
+    match provider {
+        Provider::Gmail { .. } => true,
+        Provider::Sendgrid { .. } => cfg!(feature = "sendgrid"),
+        Provider::Mailgun { .. } => cfg!(feature = "mailgun"),
+        Provider::Ses { .. } => cfg!(feature = "ses"),
+    }
+}
+
+fn storage_backend(
+    report: &mut Report,
+    storage: &crate::storage::StorageSettings,
+    timeout_ms: u64,
+) {
+    match storage.backend.as_str() {
+        crate::storage::SQLITE => {},
+        crate::storage::POSTGRES => {
+            if !cfg!(feature = "postgres") {
+                report.error("storage.backend", "The postgres storage isn't built in, build the plugin with the \"postgres\" feature");
+            }
+            if storage.url.as_deref().is_none_or(|url| url.trim().is_empty()) {
+                report.error("storage.url", "The postgres storage needs the url of the database");
+            }
+        },
+        backend => report.error("storage.backend", format!("Unknown backend {:?}, expected \"sqlite\" or \"postgres\"", backend)),
+    }
+    if storage.lease_secs == 0 {
+        report.error("storage.lease_secs", "The lease can't be 0");
+    }
+    // another instance takes over a job whose send is still running
+    if storage.lease_secs.saturating_mul(1000) <= timeout_ms {
+        report.warning("storage.lease_secs", format!("The lease is shorter than the send timeout of {} ms", timeout_ms));
+    }
+    if storage.instance.as_deref().is_some_and(|instance| instance.trim().is_empty()) {
+        report.error("storage.instance", "The instance name is empty");
+    }
+}
+
+fn accounts(
+    report: &mut Report,
+    config: &SmtpSettings,
+) -> HashSet<String> {
+
+    let mut names = HashSet::from([crate::transport::GMAIL.to_string()]);
+
+    for (i, account) in config.accounts.iter().flatten().enumerate() {
+        let path = format!("accounts.{}", i);
+        if !names.insert(account.name.to_string()) {
+            report.error(format!("{}.name", path), format!("Account {} is defined twice", account.name));
+        }
+        if !built_in(&account.provider) {
+            report.error(format!("{}.provider", path), format!(
+                "The {} provider isn't built in, build the plugin with the \"{}\" feature",
+                account.provider.name(), account.provider.name(),
+            ));
+        }
+        match &account.provider {
+            Provider::Gmail { username, server, bind_address: local, .. } => {
+                address(report, &format!("{}.username", path), username);
+                if let Some(server) = server.as_deref().filter(|server| lettre::SmtpTransport::relay(server).is_err()) {
+                    report.error(format!("{}.server", path), format!("Invalid server name {:?}", server));
+                }
+                bind_address(report, &format!("{}.bind_address", path), *local);
+            },
+            Provider::Mailgun { region, .. } => one_of(report, &format!("{}.region", path), region.as_deref(), &["us", "eu"]),
+            Provider::Sendgrid { .. } | Provider::Ses { .. } => {},
+        }
+    }
+
+    let gmail: HashSet<&str> = std::iter::once(crate::transport::GMAIL)
+        .chain(config.accounts.iter().flatten()
+            .filter(|account| matches!(account.provider, Provider::Gmail { .. }))
+            .map(|account| account.name.as_str()))
+        .collect();
+    for account in config.gmail_api.iter().flat_map(|api| api.refresh_tokens.keys()) {
+        if !gmail.contains(account.as_str()) {
+            report.error(format!("gmail_api.refresh_tokens.{}", account), format!("Unknown Gmail account {}", account));
+        }
+    }
+    if let Some(api) = &config.gmail_api {
+        for (i, account) in api.send_accounts.iter().enumerate() {
+            let path = format!("gmail_api.send_accounts.{}", i);
+            if !gmail.contains(account.as_str()) {
+                report.error(path, format!("Unknown Gmail account {}", account));
+            } else if !api.refresh_tokens.contains_key(account) {
+                report.error(path, format!("The account {} has no refresh token", account));
+            }
+        }
+    }
+
+    for (i, rule) in config.policies.iter().flatten().enumerate() {
+        if let Action::ForceAccount { account } = &rule.action {
+            if !names.contains(account) {
+                report.error(format!("policies.{}.account", i), format!("Unknown account {}", account));
+            }
+        }
+    }
+
+    for (i, account) in config.imap.iter().flat_map(|imap| imap.accounts.iter()).enumerate() {
+        if !names.contains(account) {
+            report.error(format!("imap.accounts.{}", i), format!("Unknown account {}", account));
+        }
+    }
+
+    for (path, account) in [("default_account", &config.default_account), ("overflow_account", &config.overflow_account)] {
+        if let Some(account) = account.as_deref().filter(|account| !names.contains(*account)) {
+            report.error(path, format!("Unknown account {}", account));
+        }
+    }
+
+    names
+}
+
+fn tenants_and_keys(
+    report: &mut Report,
+    config: &SmtpSettings,
+    accounts: &HashSet<String>,
+) {
+
+    let mut tenants = HashSet::new();
+    for (i, tenant) in config.tenants.iter().flatten().enumerate() {
+        let path = format!("tenants.{}", i);
+        if !tenants.insert(tenant.name.as_str()) {
+            report.error(format!("{}.name", path), format!("Tenant {} is defined twice", tenant.name));
+        }
+        if tenant.name.is_empty() || tenant.name.starts_with('.') || tenant.name.contains(['/', '\\']) {
+            report.error(format!("{}.name", path), format!("Invalid tenant name {:?}", tenant.name));
+        }
+        if tenant.accounts.is_empty() {
+            report.error(format!("{}.accounts", path), format!("Tenant {} has no accounts", tenant.name));
+        }
+        for (j, account) in tenant.accounts.iter().enumerate() {
+            if !accounts.contains(account) {
+                report.error(format!("{}.accounts.{}", path, j), format!("Unknown account {}", account));
+            }
+        }
+        directory(report, &format!("{}.templates_dir", path), tenant.templates_dir.as_deref());
+        recipient_limits(report, &format!("{}.recipient_limits", path), tenant.recipient_limits.as_ref());
+    }
+
+    let mut names = HashSet::new();
+    let mut keys = HashSet::new();
+    for (i, key) in config.api_keys.iter().flatten().enumerate() {
+        let path = format!("api_keys.{}", i);
+        if !names.insert(key.name.as_str()) {
+            report.error(format!("{}.name", path), format!("Api key {} is defined twice", key.name));
+        }
+        if !keys.insert(key.key.as_str()) {
+            report.error(format!("{}.key", path), format!("The key of {} is used by another api key", key.name));
+        }
+        if key.key.len() < 16 {
+            report.warning(format!("{}.key", path), format!("The key of {} is shorter than 16 characters", key.name));
+        }
+        for (j, scope) in key.scopes.iter().enumerate() {
+            one_of(report, &format!("{}.scopes.{}", path, j), Some(scope), &["send", "bulk", "templates", "admin"]);
+        }
+        if let Some(budget) = &key.budget {
+            for (name, limit) in [("per_minute", budget.per_minute), ("per_day", budget.per_day)] {
+                if limit == Some(0) {
+                    report.warning(format!("{}.budget.{}", path, name), format!("A budget of 0 refuses every send of {}", key.name));
+                }
+            }
+        }
+        if let Some(tenant) = key.tenant.as_deref().filter(|tenant| !tenants.contains(tenant)) {
+            report.error(format!("{}.tenant", path), format!("Unknown tenant {}", tenant));
+        }
+    }
+}
+
+// the checks of the values that can be made without the network
+fn check(
+    report: &mut Report,
+    config: &SmtpSettings,
+) {
+
+    // a dev relay may take no credentials
+    if !(config.profile.as_deref() == Some(crate::profile::DEV) && config.username.is_empty()) {
+        address(report, "username", &config.username);
+    }
+    if lettre::SmtpTransport::relay(&config.server).is_err() {
+        report.error("server", format!("Invalid server name {:?}", config.server));
+    }
+    bind_address(report, "bind_address", config.bind_address);
+    if let Some(dns) = &config.dns {
+        for (i, server) in dns.servers.iter().enumerate() {
+            if crate::dns::server(server).is_none() {
+                report.error(format!("dns.servers.{}", i), format!("Invalid DNS server {:?}, expected an IP address with an optional port", server));
+            }
+        }
+        if dns.timeout_ms == 0 {
+            report.error("dns.timeout_ms", "A timeout of 0 fails every lookup");
+        }
+        for (name, addresses) in &dns.pin {
+            if addresses.is_empty() {
+                report.error(format!("dns.pin.{}", name), "No address to pin the name to");
+            }
+        }
+    }
+
+    if let Some(timezone) = &config.timezone {
+        if timezone.parse::<chrono_tz::Tz>().is_err() {
+            report.error("timezone", format!("Unknown timezone {}", timezone));
+        }
+    }
+    one_of(report, "from_policy", config.from_policy.as_deref(), &["enforce", "rewrite", "allow"]);
+    one_of(report, "profile", config.profile.as_deref(), &[crate::profile::GMAIL, crate::profile::DEV]);
+    match config.profile.as_deref() == Some(crate::profile::DEV) {
+        true => {
+            report.warning("profile", "The dev profile sends without TLS and without the Gmail limits, never set it in production");
+            if config.from_policy.is_some() {
+                report.warning("from_policy", "The dev profile sends every From as is, the policy is ignored");
+            }
+            if config.dev.as_ref().is_some_and(|dev| dev.port == 0) {
+                report.error("dev.port", "The port can't be 0");
+            }
+        },
+        false if config.dev.is_some() => report.warning("dev", "Only used with \"profile\": \"dev\""),
+        false => {},
+    }
+    one_of(report, "template_variables", config.template_variables.as_deref(), &["strict", "lenient"]);
+    if let Some(privacy) = &config.privacy {
+        one_of(report, "privacy.addresses", Some(&privacy.addresses), &["mask", "hash"]);
+    }
+
+    if let Some(digest) = &config.digest {
+        if digest.hourly_minute > 59 {
+            report.error("digest.hourly_minute", format!("Invalid minute {}", digest.hourly_minute));
+        }
+        if let Err(e) = chrono::NaiveTime::parse_from_str(&digest.daily_time, "%H:%M") {
+            report.error("digest.daily_time", format!("Invalid time {:?}, expected HH:MM: {}", digest.daily_time, e));
+        }
+    }
+    if let Some(tls) = &config.tls {
+        if let Some(Err(e)) = tls.min_version.as_deref().map(crate::tls::version) {
+            report.error("tls.min_version", e);
+        }
+        for (i, path) in tls.roots.iter().enumerate() {
+            if let Err(e) = crate::tls::root(path) {
+                report.error(format!("tls.roots.{}", i), format!("Invalid certificate: {}", e));
+            }
+        }
+        if !tls.system_roots && tls.roots.is_empty() {
+            report.error("tls.system_roots", "Without the system roots and with no \"roots\" no server is trusted");
+        }
+        for (i, pin) in tls.spki_pins.iter().enumerate() {
+            if let Err(e) = crate::tls::pin(pin) {
+                report.error(format!("tls.spki_pins.{}", i), e);
+            }
+        }
+        if tls.spki_pins.len() == 1 {
+            report.warning("tls.spki_pins", "A single pin fails every send when the server changes its key, add a backup pin");
+        }
+    }
+    if let Some(events) = &config.events {
+        if events.mailgun_signing_key.is_none() && events.sendgrid_public_key.is_none() && events.secret.is_none() {
+            report.warning("events", "No signing key is set, every event is refused");
+        }
+        if events.sendgrid_public_key.is_some() && !cfg!(feature = "sendgrid") {
+            report.warning("events.sendgrid_public_key", "The sendgrid events aren't built in, build the plugin with the \"sendgrid\" feature");
+        }
+        if events.secret.as_ref().is_some_and(|secret| secret.len() < 16) {
+            report.warning("events.secret", "The secret is shorter than 16 characters");
+        }
+    }
+    if let Some(consent) = &config.consent {
+        if config.events.is_none() {
+            report.warning("consent", "\"events\" is not set, only the unsubscribes added to the suppression list are recorded");
+        }
+        if consent.confirmation_template.is_some() && consent.confirmation_subject.trim().is_empty() {
+            report.error("consent.confirmation_subject", "The confirmation has no subject");
+        }
+    }
+    if config.pdf.as_ref().is_some_and(|pdf| pdf.command.is_empty()) {
+        report.error("pdf.command", "The command is empty");
+    }
+    if let Some(review) = &config.review {
+        if !review.external_recipients && review.max_attachment_bytes.is_none() && review.keywords.is_empty() {
+            report.warning("review", "No rule is set, no message is held for review");
+        }
+        if review.keywords.iter().any(|keyword| keyword.trim().is_empty()) {
+            report.error("review.keywords", "A keyword is empty, every message would be held");
+        }
+    }
+    let mut policies = HashSet::new();
+    for (i, rule) in config.policies.iter().flatten().enumerate() {
+        let path = format!("policies.{}", i);
+        if rule.name.trim().is_empty() || !policies.insert(rule.name.as_str()) {
+            report.error(format!("{}.name", path), format!("The name {:?} is empty or repeated", rule.name));
+        }
+        if let Some(Err(e)) = rule.conditions.subject.as_deref().map(regex::Regex::new) {
+            report.error(format!("{}.match.subject", path), format!("Invalid regular expression: {}", e));
+        }
+        match &rule.action {
+            Action::AddBcc { address: bcc } => address(report, &format!("{}.address", path), bcc),
+            Action::AddHeader { header, value } => {
+                if let Err(e) = crate::headers::check(header, value) {
+                    report.error(format!("{}.header", path), e.message);
+                }
+            },
+            Action::RequireApproval if config.review.is_none() => {
+                report.warning(format!("{}.action", path), "The message is held, though \"review\" isn't set");
+            },
+            _ => {},
+        }
+    }
+    let mut hooks = HashSet::new();
+    for (i, hook) in config.hooks.iter().flatten().enumerate() {
+        let path = format!("hooks.{}", i);
+        if hook.name.trim().is_empty() || !hooks.insert(hook.name.as_str()) {
+            report.error(format!("{}.name", path), format!("The name {:?} is empty or repeated", hook.name));
+        }
+        one_of(report, &format!("{}.stage", path), Some(&hook.stage), &[crate::hooks::PRE_SEND, crate::hooks::POST_SEND]);
+        one_of(report, &format!("{}.on_failure", path), Some(&hook.on_failure), &[crate::hooks::REJECT, crate::hooks::IGNORE]);
+        match (&hook.wasm, hook.command.is_empty()) {
+            (None, true) => report.error(format!("{}.command", path), "Neither a command nor a wasm module is set"),
+            (Some(_), false) => report.error(format!("{}.wasm", path), "Both a command and a wasm module are set"),
+            (Some(_), true) if hook.runtime.is_empty() => report.error(format!("{}.runtime", path), "The runtime is empty"),
+            _ => {},
+        }
+        if hook.timeout_secs == 0 {
+            report.error(format!("{}.timeout_secs", path), "The timeout can't be 0");
+        }
+    }
+    if let Some(images) = &config.images {
+        if images.command.is_empty() {
+            report.error("images.command", "The command is empty");
+        }
+        if !(1..=100).contains(&images.quality) {
+            report.error("images.quality", "The quality is from 1 to 100");
+        }
+        if images.max_width == 0 || images.max_height == 0 {
+            report.error("images", "The dimensions can't be 0");
+        }
+    }
+    if let Some(connect) = &config.connect {
+        one_of(report, "connect.prefer", connect.prefer.as_deref(), &["ipv4", "ipv6"]);
+    }
+    recipient_limits(report, "recipient_limits", config.recipient_limits.as_ref());
+    for (i, callback) in config.callback_secrets.iter().flatten().enumerate() {
+        if callback.url.trim().is_empty() {
+            report.error(format!("callback_secrets[{}].url", i), "The url pattern is empty");
+        }
+        if callback.secret.len() < 16 {
+            report.warning(format!("callback_secrets[{}].secret", i), "The secret is shorter than 16 characters");
+        }
+    }
+    // once signing is set up, a callback without a secret is a mistake
+    if config.callback_secrets.as_ref().is_some_and(|secrets| !secrets.is_empty()) {
+        let webhooks = [
+            ("alerts.webhook", config.alerts.as_ref().and_then(|alerts| alerts.webhook.as_deref())),
+            ("error_reporting.webhook", config.error_reporting.as_ref().and_then(|report| report.webhook.as_deref())),
+        ];
+        for (path, url) in webhooks {
+            let unsigned = url.filter(|url| !config.callback_secrets.iter().flatten().any(|callback| crate::callbacks::matches(&callback.url, url)));
+            if let Some(url) = unsigned {
+                report.warning(path, format!("No callback secret matches {}, its callbacks aren't signed", url));
+            }
+        }
+    }
+    if let Some(chaos) = &config.chaos {
+        if let Err(error) = crate::chaos::check(chaos) {
+            report.error("chaos", error.message);
+        }
+        report.warning("chaos", "Failures are injected into the sends, never set it in production");
+    }
+    if let Some(latency) = &config.latency {
+        if latency.slo_p95_ms.is_none() && latency.lanes.is_empty() {
+            report.warning("latency", "No SLO is set, the latency is tracked without alerts");
+        }
+        for lane in latency.lanes.keys().filter(|lane| ![crate::queue::TRANSACTIONAL, crate::queue::BULK].contains(&lane.as_str())) {
+            report.error(format!("latency.lanes.{}", lane), "Unknown lane, expected \"transactional\" or \"bulk\"");
+        }
+        if latency.window_secs == 0 {
+            report.error("latency.window_secs", "The window can't be 0");
+        }
+    }
+    if config.invalid_recipients.as_ref().is_some_and(|invalid| invalid.cache_secs == 0) {
+        report.error("invalid_recipients.cache_secs", "The period can't be 0, remove the section to turn it off");
+    }
+    if let Some(storage) = &config.storage {
+        storage_backend(report, storage, config.timeout_ms.unwrap_or(crate::pool::DEFAULT_TIMEOUT_MS));
+        for path in crate::storage::unshared(config) {
+            report.error(path, "Counted by each instance, it can't be used with the postgres storage");
+        }
+    }
+    if let Some(persistence) = &config.persistence {
+        one_of(report, "persistence.mode", Some(&persistence.mode), &[crate::persistence::BUFFER, crate::persistence::STRICT]);
+        if persistence.max_buffered == 0 {
+            report.error("persistence.max_buffered", "The buffer can't be 0");
+        }
+    }
+
+    for (path, dir) in [
+        ("attachments_dir", &config.attachments_dir),
+        ("content_dir", &config.content_dir),
+        ("templates_dir", &config.templates_dir),
+        ("eml_dir", &config.eml_dir),
+    ] {
+        directory(report, path, dir.as_deref());
+    }
+
+    for (i, recipient) in config.test_recipients.iter().flatten().enumerate() {
+        address(report, &format!("test_recipients.{}", i), recipient);
+    }
+    if let Some(read_receipt_to) = &config.read_receipt_to {
+        address(report, "read_receipt_to", read_receipt_to);
+    }
+
+    let accounts = accounts(report, config);
+    tenants_and_keys(report, config, &accounts);
+}
+
+// a connection to the SMTP server of every Gmail account
+fn reachable(
+    report: &mut Report,
+    config: &SmtpSettings,
+) {
+
+    // from the local address each account connects from
+    let mut servers = vec![("server".to_string(), config.server.to_string(), config.bind_address)];
+    for (i, account) in config.accounts.iter().flatten().enumerate() {
+        if let Provider::Gmail { server, bind_address, .. } = &account.provider {
+            if server.is_some() || bind_address.is_some() {
+                servers.push((
+                    format!("accounts.{}.server", i),
+                    server.clone().unwrap_or(config.server.to_string()),
+                    bind_address.or(config.bind_address),
+                ));
+            }
+        }
+    }
+
+    let dns = config.dns.clone().unwrap_or_default();
+    let port = match config.profile.as_deref() {
+        Some(crate::profile::DEV) => config.dev.clone().unwrap_or_default().port,
+        _ => SMTPS_PORT,
+    };
+    let mut tried = HashSet::new();
+    for (path, server, local) in servers {
+        if !tried.insert((server.to_string(), local)) {
+            continue;
+        }
+        let connected = crate::dns::lookup_from(&dns, &server, port, local)
+            .and_then(|addrs| crate::connect::establish(config.connect.as_ref(), &addrs, local, CONNECT_TIMEOUT));
+        if let Err(e) = connected {
+            report.warning(path, format!("Can't connect to {}:{}: {}", server, port, e));
+        }
+    }
+}
+
+pub fn validate(connect: bool) -> Report {
+
+    let mut report = Report::default();
+    if let Some(config) = parse(&mut report) {
+        check(&mut report, &config);
+        if connect {
+            reachable(&mut report, &config);
+        }
+    }
+
+    report
+}
+
+// called when the plugin is loaded, the connections are tried in the background
+pub fn validate_at_load() {
+
+    let mut report = Report::default();
+    let Some(config) = parse(&mut report) else {
+        report.log();
+        return;
+    };
+    check(&mut report, &config);
+    report.log();
+
+    let result = std::thread::Builder::new()
+        .name("arp-gmail-config".to_string())
+        .spawn(move || {
+            let mut report = Report::default();
+            reachable(&mut report, &config);
+            report.log();
+        });
+    if let Err(e) = result {
+        eprintln!("arp-gmail: error starting the config check thread: {}", e);
+    }
+}
+
+pub fn loaded(
+    file: PathBuf,
+    text: &str,
+    signed_by: Option<String>,
+) {
+    let _ = LOADED.set(Loaded {
+        file,
+        at: chrono::Utc::now().timestamp(),
+        values: serde_json::from_str(text).unwrap_or_default(),
+        signed_by,
+    });
+}
+
+// a secret, or every string of a map of them like the refresh tokens per account
+fn mask_all(value: &mut Value) {
+    match value {
+        Value::String(text) if !text.is_empty() => *value = Value::String("***".to_string()),
+        Value::Object(entries) => entries.values_mut().for_each(mask_all),
+        _ => {},
+    }
+}
+
+// the scheme, the host and the path of a URL are kept
+fn mask_url(url: &str) -> String {
+
+    let Some((scheme, rest)) = url.split_once("://") else {
+        return "***".to_string();
+    };
+    let (rest, query) = match rest.split_once('?') {
+        Some((rest, _)) => (rest, "?***"),
+        None => (rest, ""),
+    };
+    let (authority, path) = rest.split_at(rest.find('/').unwrap_or(rest.len()));
+    let host = match authority.rsplit_once('@') {
+        Some((_, host)) => format!("***@{}", host),
+        None => authority.to_string(),
+    };
+
+    format!("{}://{}{}{}", scheme, host, path, query)
+}
+
+fn mask(value: &mut Value) {
+    match value {
+        Value::Object(entries) => {
+            for (key, value) in entries.iter_mut() {
+                let key = key.to_lowercase();
+                let secret = SECRETS.iter().any(|secret| key.contains(secret));
+                match value {
+                    Value::String(_) | Value::Object(_) if secret => mask_all(value),
+                    _ => mask(value),
+                }
+            }
+        },
+        Value::Array(values) => values.iter_mut().for_each(mask),
+        _ => {},
+    }
+}
+
+// where each value comes from: "file", "default" when the file doesn't set it,
+// "unset" when neither does
+fn sources(
+    prefix: &str,
+    value: &Value,
+    file: Option<&Value>,
+    origins: &mut serde_json::Map<String, Value>,
+) {
+
+    let children: Vec<(String, &Value)> = match value {
+        Value::Object(entries) => entries.iter()
+            .map(|(key, value)| (key.to_string(), value))
+            .collect(),
+        Value::Array(values) if values.iter().any(Value::is_object) => values.iter()
+            .enumerate()
+            .map(|(i, value)| (i.to_string(), value))
+            .collect(),
+        _ => {
+            let origin = match (file, value) {
+                (Some(_), _) => "file",
+                (None, Value::Null) => "unset",
+                (None, _) => "default",
+            };
+            origins.insert(prefix.to_string(), Value::String(origin.to_string()));
+            return;
+        },
+    };
+
+    for (key, child) in children {
+        let path = match prefix {
+            "" => key.to_string(),
+            prefix => format!("{}.{}", prefix, key),
+        };
+        let in_file = file.and_then(|file| match file {
+            Value::Array(values) => key.parse::<usize>().ok().and_then(|i| values.get(i)),
+            file => file.get(&key),
+        });
+        sources(&path, child, in_file, origins);
+    }
+}
+
+// the config in use, resolved with its defaults, which may differ from the
+// file if it was edited after the plugin was loaded
+pub fn effective() -> Value {
+
+    let mut config = serde_json::to_value(&*SMTP_CLIENT)
+        .unwrap_or_default();
+
+    let mut origins = serde_json::Map::new();
+    let loaded = LOADED.get();
+    sources("", &config, loaded.map(|loaded| &loaded.values), &mut origins);
+    mask(&mut config);
+    for pointer in SECRET_URLS {
+        if let Some(Value::String(url)) = config.pointer_mut(pointer) {
+            *url = mask_url(url);
+        }
+    }
+    for pointer in SECRET_WEBHOOKS {
+        if let Some(webhook) = config.pointer_mut(pointer) {
+            mask_all(webhook);
+        }
+    }
+
+    json!({
+        "config": config,
+        "sources": {
+            "file": loaded.map(|loaded| loaded.file.display().to_string()),
+            "loaded_at": loaded.map(|loaded| loaded.at),
+            "env": {
+                "PLUGINS_DIR": std::env::var("PLUGINS_DIR").ok(),
+                crate::signature::KEY_VAR: std::env::var(crate::signature::KEY_VAR).ok(),
+            },
+            "signed_by": loaded.and_then(|loaded| loaded.signed_by.clone()),
+        },
+        "origins": origins,
+    })
+}
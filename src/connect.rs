@@ -0,0 +1,199 @@
+//
+// TCP connections to the SMTP servers: from the bind address, and over the
+// addresses of both families raced a short delay apart (happy eyeballs, RFC
+// 8305) so a broken IPv6 route costs the delay instead of the whole timeout
+//
+
+use std::net::{IpAddr, SocketAddr, TcpStream};
+use std::sync::{mpsc, Arc};
+use std::time::{Duration, Instant};
+use serde::{Deserialize, Serialize};
+
+use crate::SMTP_CLIENT;
+
+fn default_happy_eyeballs() -> bool {
+    true
+}
+
+fn default_attempt_delay_ms() -> u64 {
+    250
+}
+
+#[derive(Clone, Deserialize, Serialize)]
+pub struct ConnectSettings {
+    // race the addresses instead of trying each for the whole timeout
+    #[serde(default = "default_happy_eyeballs")]
+    pub happy_eyeballs: bool,
+    // the head start of an attempt before the next address is tried
+    #[serde(default = "default_attempt_delay_ms")]
+    pub attempt_delay_ms: u64,
+    // "ipv4" or "ipv6", the family tried first, the one of the first address
+    // of the lookup if not set
+    pub prefer: Option<String>,
+}
+
+pub fn settings() -> Option<&'static ConnectSettings> {
+    SMTP_CLIENT.connect.as_ref()
+}
+
+// a connection to the address from the local one, if set
+pub fn tcp(
+    addr: &SocketAddr,
+    local: Option<IpAddr>,
+    timeout: Duration,
+) -> std::io::Result<TcpStream> {
+
+    let Some(local) = local else {
+        return TcpStream::connect_timeout(addr, timeout);
+    };
+
+    let socket = socket2::Socket::new(socket2::Domain::for_address(*addr), socket2::Type::STREAM, None)?;
+    socket.bind(&SocketAddr::new(local, 0).into())?;
+    socket.connect_timeout(&(*addr).into(), timeout)?;
+
+    Ok(socket.into())
+}
+
+// the families alternate, the preferred one first
+pub fn order(
+    addresses: &[SocketAddr],
+    prefer: Option<&str>,
+) -> Vec<SocketAddr> {
+
+    let ipv4_first = match prefer {
+        Some(prefer) => prefer == "ipv4",
+        None => addresses.first().is_some_and(SocketAddr::is_ipv4),
+    };
+    let (first, second): (Vec<SocketAddr>, Vec<SocketAddr>) = addresses.iter()
+        .partition(|addr| addr.is_ipv4() == ipv4_first);
+
+    let mut ordered = Vec::with_capacity(addresses.len());
+    let (mut first, mut second) = (first.into_iter(), second.into_iter());
+    loop {
+        match (first.next(), second.next()) {
+            (None, None) => return ordered,
+            (a, b) => ordered.extend(a.into_iter().chain(b)),
+        }
+    }
+}
+
+// an attempt runs on its own thread, the result is sent back even after the
+// race is over and dropped there
+fn attempt<T: Send + 'static>(
+    addr: SocketAddr,
+    timeout: Duration,
+    connect: &Arc<dyn Fn(SocketAddr, Duration) -> Result<T, String> + Send + Sync>,
+    sender: &mpsc::Sender<(SocketAddr, Result<T, String>)>,
+) -> std::io::Result<()> {
+    let sender = sender.clone();
+    let connect = connect.clone();
+    std::thread::Builder::new()
+        .name("arp-gmail-connect".to_string())
+        .spawn(move || {
+            let _ = sender.send((addr, connect(addr, timeout)));
+        })
+        .map(|_| ())
+}
+
+// the next address is tried when the attempts in flight have had the delay
+// or have all failed, the first to connect wins
+pub fn race(
+    addresses: &[SocketAddr],
+    local: Option<IpAddr>,
+    timeout: Duration,
+    delay: Duration,
+) -> Result<(SocketAddr, TcpStream), String> {
+    race_with(addresses, timeout, delay, move |addr, left| {
+        tcp(&addr, local, left).map_err(|e| e.to_string())
+    })
+}
+
+// the race of any connection, opened by "connect" with the time left; lettre
+// takes no connected socket, the SMTP sessions are raced instead of the sockets
+// they would be opened on
+pub fn race_with<T: Send + 'static>(
+    addresses: &[SocketAddr],
+    timeout: Duration,
+    delay: Duration,
+    connect: impl Fn(SocketAddr, Duration) -> Result<T, String> + Send + Sync + 'static,
+) -> Result<(SocketAddr, T), String> {
+
+    let connect: Arc<dyn Fn(SocketAddr, Duration) -> Result<T, String> + Send + Sync> = Arc::new(connect);
+    let deadline = Instant::now() + timeout;
+    let (sender, receiver) = mpsc::channel();
+    let mut pending = addresses.iter().copied().peekable();
+    let mut next = pending.next();
+    let mut running = 0;
+    let mut errors = Vec::new();
+
+    loop {
+        let left = deadline.saturating_duration_since(Instant::now());
+        if let Some(addr) = next.take() {
+            match attempt(addr, left, &connect, &sender) {
+                Ok(()) => running += 1,
+                Err(e) => errors.push(format!("{}: {}", addr, e)),
+            }
+        }
+        if running == 0 {
+            match pending.next() {
+                Some(addr) => {
+                    next = Some(addr);
+                    continue;
+                },
+                None if errors.is_empty() => return Err("no address to connect to".to_string()),
+                None => return Err(errors.join(", ")),
+            }
+        }
+        if left.is_zero() {
+            errors.push(format!("timed out after {} ms", timeout.as_millis()));
+            return Err(errors.join(", "));
+        }
+
+        let wait = match pending.peek() {
+            Some(_) => delay.min(left),
+            None => left,
+        };
+        match receiver.recv_timeout(wait) {
+            Ok((addr, Ok(stream))) => return Ok((addr, stream)),
+            Ok((addr, Err(e))) => {
+                running -= 1;
+                errors.push(format!("{}: {}", addr, e));
+                next = pending.next();
+            },
+            Err(_) => next = pending.next(),
+        }
+    }
+}
+
+// the first address that connects, raced or one after the other
+pub fn establish(
+    settings: Option<&ConnectSettings>,
+    addresses: &[SocketAddr],
+    local: Option<IpAddr>,
+    timeout: Duration,
+) -> Result<(SocketAddr, TcpStream), String> {
+
+    let ordered = order(addresses, settings.and_then(|settings| settings.prefer.as_deref()));
+    if let Some(settings) = settings.filter(|settings| settings.happy_eyeballs) {
+        return race(&ordered, local, timeout, Duration::from_millis(settings.attempt_delay_ms));
+    }
+
+    let deadline = Instant::now() + timeout;
+    let mut errors = Vec::new();
+    for addr in ordered {
+        let left = deadline.saturating_duration_since(Instant::now());
+        if left.is_zero() {
+            errors.push(format!("timed out after {} ms", timeout.as_millis()));
+            break;
+        }
+        match tcp(&addr, local, left) {
+            Ok(stream) => return Ok((addr, stream)),
+            Err(e) => errors.push(format!("{}: {}", addr, e)),
+        }
+    }
+
+    match errors.is_empty() {
+        true => Err("no address to connect to".to_string()),
+        false => Err(errors.join(", ")),
+    }
+}
@@ -0,0 +1,259 @@
+//
+// The consent trail of the recipients: every unsubscribe received by /events
+// and every edit of an unsubscribed entry of the suppression list, appended
+// with the time it happened, its source and the confirmation email queued to
+// the address from the "consent" template of the config
+//
+
+use std::cell::{Cell, RefCell};
+use rusqlite::params;
+use serde::{Deserialize, Serialize};
+
+use crate::{db, Mail, Response, SMTP_CLIENT};
+use crate::page::Page;
+
+pub const UNSUBSCRIBED: &str = "unsubscribed";
+pub const RESUBSCRIBED: &str = "resubscribed";
+
+// the triggers refuse to change or remove a recorded change
+pub const SCHEMA: &str = "CREATE TABLE IF NOT EXISTS consent (
+    id INTEGER PRIMARY KEY AUTOINCREMENT,
+    created_at INTEGER NOT NULL,
+    occurred_at INTEGER NOT NULL,
+    tenant TEXT NOT NULL,
+    address TEXT NOT NULL,
+    change TEXT NOT NULL,
+    source TEXT NOT NULL,
+    request_id TEXT,
+    confirmation_job INTEGER
+);
+CREATE INDEX IF NOT EXISTS consent_address ON consent (address, created_at);
+CREATE TRIGGER IF NOT EXISTS consent_no_update BEFORE UPDATE ON consent
+BEGIN SELECT RAISE(ABORT, 'the consent trail is append-only'); END;
+CREATE TRIGGER IF NOT EXISTS consent_no_delete BEFORE DELETE ON consent
+BEGIN SELECT RAISE(ABORT, 'the consent trail is append-only'); END;";
+
+#[derive(Clone, Deserialize, Serialize)]
+pub struct ConsentSettings {
+    // the template of the email confirming an unsubscribe, with the variables
+    // "address", "unsubscribed_at" and "source"; none is sent if not set
+    pub confirmation_template: Option<String>,
+    #[serde(default)]
+    pub confirmation_subject: String,
+    // the From of the confirmation, the one of the defaults if not set
+    pub confirmation_from: Option<String>,
+}
+
+#[derive(Serialize)]
+pub struct Entry {
+    id: i64,
+    created_at: i64,
+    // the time of the unsubscribe reported by the provider, or created_at
+    occurred_at: i64,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    tenant: Option<String>,
+    address: String,
+    change: String,
+    // "events.mailgun", "suppressions"
+    source: String,
+    request_id: Option<String>,
+    // the queue job of the confirmation email
+    confirmation_job: Option<i64>,
+}
+
+thread_local! {
+    // the time of the unsubscribe a confirmation is submitted for
+    static CONFIRMATION: Cell<Option<i64>> = const { Cell::new(None) };
+    static CONFIRMING: RefCell<Option<String>> = const { RefCell::new(None) };
+}
+
+// the confirmation of the current thread is restored when the scope is dropped
+pub struct Scope(Option<i64>);
+
+impl Drop for Scope {
+    fn drop(&mut self) {
+        CONFIRMATION.with(|confirmation| confirmation.set(self.0));
+    }
+}
+
+fn enter(occurred_at: Option<i64>) -> Scope {
+    Scope(CONFIRMATION.with(|confirmation| confirmation.replace(occurred_at)))
+}
+
+// set on the mail by the plugin, never the one of the body
+pub fn current() -> Option<i64> {
+    CONFIRMATION.with(Cell::get)
+}
+
+// the address a confirmation is sent to, for the rest of its send
+pub struct Confirming(Option<String>);
+
+impl Drop for Confirming {
+    fn drop(&mut self) {
+        let previous = self.0.take();
+        CONFIRMING.with(|confirming| *confirming.borrow_mut() = previous);
+    }
+}
+
+pub fn confirming(mail: &Mail) -> Confirming {
+    let address = mail.consent.map(|_| mail.to.trim().to_lowercase());
+    Confirming(CONFIRMING.with(|confirming| confirming.replace(address)))
+}
+
+// the unsubscribe of the address doesn't stop its own confirmation
+pub fn exempt(address: &str) -> bool {
+    CONFIRMING.with(|confirming| confirming.borrow().as_deref() == Some(address))
+}
+
+// an address already unsubscribed isn't confirmed again when a provider
+// repeats the event
+pub fn unsubscribed_already(address: &str) -> bool {
+    let address = address.trim().to_lowercase();
+    crate::storage::store()
+        .and_then(|store| store.suppression(&crate::tenant::key(), &address))
+        .is_ok_and(|reason| reason.flatten().as_deref() == Some(UNSUBSCRIBED))
+}
+
+// submitted and queued like any other email: rendered, checked by the
+// policies, retried by the queue and kept in the history; the job
+fn confirm(
+    settings: &ConsentSettings,
+    address: &str,
+    occurred_at: i64,
+    source: &str,
+) -> Result<Option<i64>, String> {
+
+    let Some(template) = &settings.confirmation_template else {
+        return Ok(None);
+    };
+    let mut mail = Mail {
+        from: settings.confirmation_from.clone().unwrap_or_default(),
+        to: address.to_string(),
+        subject: settings.confirmation_subject.clone(),
+        template: Some(template.clone()),
+        data: Some(serde_json::json!({
+            "address": address,
+            "unsubscribed_at": crate::quota::format_time(occurred_at),
+            "source": source,
+        })),
+        automated: Some(true),
+        queue: Some(true),
+        request_id: crate::trace::current(),
+        ..Default::default()
+    };
+    crate::identity::apply(&mut mail);
+    if mail.from.is_empty() {
+        mail.from = SMTP_CLIENT.username.clone();
+    }
+
+    let mut response = Response::new();
+    let _scope = enter(Some(occurred_at));
+    crate::submit(mail, None, &mut response);
+    match response.status.as_str() {
+        "queued" | "pending" => Ok(response.job),
+        _ => Err(response.message),
+    }
+}
+
+fn insert(
+    address: &str,
+    change: &str,
+    source: &str,
+    occurred_at: i64,
+    confirmation_job: Option<i64>,
+) -> Result<(), String> {
+    db::conn()?.execute(
+        "INSERT INTO consent (created_at, occurred_at, tenant, address, change, source, request_id, confirmation_job)
+        VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8)",
+        params![
+            db::now(),
+            occurred_at,
+            crate::tenant::key(),
+            address,
+            change,
+            source,
+            crate::trace::current(),
+            confirmation_job,
+        ],
+    ).map_err(|e| e.to_string())?;
+
+    Ok(())
+}
+
+// once the address is on the suppression list, true when a confirmation was
+// submitted; a change that can't be recorded or confirmed is logged, the
+// suppression stands
+pub fn unsubscribe(
+    address: &str,
+    source: &str,
+    occurred_at: Option<i64>,
+    confirmed: bool,
+) -> bool {
+
+    let occurred_at = occurred_at.unwrap_or_else(db::now);
+    let settings = SMTP_CLIENT.consent.clone();
+    let job = match settings.filter(|_| confirmed) {
+        Some(settings) => confirm(&settings, address, occurred_at, source)
+            .unwrap_or_else(|e| {
+                log!("The unsubscribe of {} isn't confirmed: {}", crate::privacy::redact(address), e);
+                None
+            }),
+        None => None,
+    };
+
+    if let Err(e) = insert(address, UNSUBSCRIBED, source, occurred_at, job) {
+        log!("Error recording the unsubscribe of {}: {}", crate::privacy::redact(address), e);
+    }
+
+    job.is_some()
+}
+
+pub fn resubscribe(
+    address: &str,
+    source: &str,
+) {
+    if let Err(e) = insert(address, RESUBSCRIBED, source, db::now(), None) {
+        log!("Error recording the resubscribe of {}: {}", crate::privacy::redact(address), e);
+    }
+}
+
+// the newest first, of an address if given
+pub fn list(
+    address: Option<&str>,
+    since: Option<i64>,
+    page: &Page,
+) -> Result<(Vec<Entry>, usize), String> {
+
+    let conn = db::conn()?;
+    let address = address.map(|address| address.trim().to_lowercase());
+
+    let total: i64 = conn.query_row(
+        "SELECT COUNT(*) FROM consent WHERE (?1 IS NULL OR address = ?1) AND created_at >= ?2",
+        params![address, since.unwrap_or(0)],
+        |row| row.get(0),
+    ).map_err(|e| e.to_string())?;
+
+    let (limit, offset) = page.sql();
+    let mut stmt = conn.prepare(
+        "SELECT id, created_at, occurred_at, tenant, address, change, source, request_id, confirmation_job
+        FROM consent WHERE (?1 IS NULL OR address = ?1) AND created_at >= ?2
+        ORDER BY id DESC LIMIT ?3 OFFSET ?4"
+    ).map_err(|e| e.to_string())?;
+
+    let entries = stmt.query_map(params![address, since.unwrap_or(0), limit, offset], |row| Ok(Entry {
+        id: row.get(0)?,
+        created_at: row.get(1)?,
+        occurred_at: row.get(2)?,
+        tenant: Some(row.get::<_, String>(3)?).filter(|tenant| !tenant.is_empty()),
+        address: row.get(4)?,
+        change: row.get(5)?,
+        source: row.get(6)?,
+        request_id: row.get(7)?,
+        confirmation_job: row.get(8)?,
+    })).map_err(|e| e.to_string())?;
+
+    let entries = entries.collect::<Result<Vec<Entry>, _>>()
+        .map_err(|e| e.to_string())?;
+
+    Ok((entries, total as usize))
+}
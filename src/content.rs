@@ -0,0 +1,137 @@
+//
+// Message body from the request, a file on the plugin host or a named snippet,
+// and the charset and transfer encoding of its MIME part
+//
+
+use lettre::message::{Body, SinglePart};
+use lettre::message::header::{ContentTransferEncoding, ContentType};
+
+use crate::{Mail, SendError, SmtpSettings};
+
+// longest line of a 7bit or 8bit body without its CRLF (RFC 5322)
+const MAX_LINE: usize = 998;
+
+pub fn message_body(
+    mail: &Mail,
+    settings: &SmtpSettings,
+) -> Result<String, SendError> {
+
+    match (mail.message.is_empty(), &mail.message_file, &mail.snippet) {
+        (_, None, None) => Ok(mail.message.clone()),
+        (true, Some(path), None) => {
+            let content_dir = settings.content_dir.as_deref().ok_or_else(|| SendError::new(
+                "invalid_message",
+                "Message files are disabled: content_dir is not set",
+            ))?;
+            let file = crate::resolve_path(content_dir, path)
+                .map_err(|e| SendError::new("invalid_message", format!("Message file {}", e)))?;
+            std::fs::read_to_string(&file)
+                .map_err(|e| SendError::new("invalid_message", format!("Message file {}: {}", path, e)))
+        },
+        (true, None, Some(name)) => settings.snippets.get(name)
+            .cloned()
+            .ok_or_else(|| SendError::new("invalid_message", format!("Unknown snippet: {}", name))),
+        _ => Err(SendError::new(
+            "invalid_message",
+            "Only one of message, message_file or snippet can be set",
+        )),
+    }
+}
+
+fn invalid(message: String) -> SendError {
+    SendError::new("invalid_message", message)
+}
+
+// the text in the charset, only the ones legacy systems still ask for
+fn encode_text(
+    text: &str,
+    charset: &str,
+) -> Result<(&'static str, Vec<u8>), SendError> {
+
+    match charset.to_ascii_lowercase().as_str() {
+        "utf-8" | "utf8" => Ok(("utf-8", text.as_bytes().to_vec())),
+        "us-ascii" | "ascii" => match text.is_ascii() {
+            true => Ok(("us-ascii", text.as_bytes().to_vec())),
+            false => Err(invalid("The message has characters outside US-ASCII".to_string())),
+        },
+        "iso-8859-1" | "latin1" | "latin-1" => text.chars()
+            .map(|c| u8::try_from(c as u32).ok())
+            .collect::<Option<Vec<u8>>>()
+            .map(|bytes| ("iso-8859-1", bytes))
+            .ok_or_else(|| invalid("The message has characters outside ISO-8859-1".to_string())),
+        _ => Err(invalid(format!("Unsupported charset: {}", charset))),
+    }
+}
+
+// a 7bit or 8bit body is sent as is, it must fit the limits of SMTP
+fn unencoded(
+    bytes: Vec<u8>,
+    encoding: ContentTransferEncoding,
+) -> Result<Body, SendError> {
+
+    if encoding == ContentTransferEncoding::SevenBit && !bytes.is_ascii() {
+        return Err(invalid("The message can't be sent as 7bit: it isn't US-ASCII".to_string()));
+    }
+    if bytes.contains(&0) || bytes.split(|&b| b == b'\n').any(|line| line.len() > MAX_LINE + 1) {
+        return Err(invalid(format!(
+            "The message can't be sent as {}: it has NUL bytes or lines over {} characters",
+            encoding, MAX_LINE,
+        )));
+    }
+
+    Ok(Body::dangerous_pre_encoded(bytes, encoding))
+}
+
+// text part of the message, utf-8 with the encoding chosen by lettre unless
+// the request sets "charset" or "transfer_encoding"
+fn part(
+    mail: &Mail,
+    text: &str,
+    subtype: &str,
+) -> Result<SinglePart, SendError> {
+
+    if mail.charset.is_none() && mail.transfer_encoding.is_none() {
+        let content_type = match subtype {
+            "html" => ContentType::TEXT_HTML,
+            _ => ContentType::TEXT_PLAIN,
+        };
+        return Ok(SinglePart::builder()
+            .header(content_type)
+            .body(text.to_string()));
+    }
+
+    // lines end with CRLF, whatever the request used
+    let text = text
+        .replace("\r\n", "\n")
+        .replace('\n', "\r\n");
+    let (charset, bytes) = encode_text(&text, mail.charset.as_deref().unwrap_or("utf-8"))?;
+    let content_type = ContentType::parse(&format!("text/{}; charset={}", subtype, charset))
+        .map_err(|e| invalid(format!("Invalid charset {}: {}", charset, e)))?;
+
+    let body = match mail.transfer_encoding.as_deref().map(str::to_ascii_lowercase).as_deref() {
+        // legacy systems expect quoted-printable with a non utf-8 charset
+        Some("quoted-printable") | None => Body::new_with_encoding(bytes, ContentTransferEncoding::QuotedPrintable)
+            .map_err(|_| invalid("The message can't be sent as quoted-printable".to_string()))?,
+        Some("base64") => Body::new_with_encoding(bytes, ContentTransferEncoding::Base64)
+            .map_err(|_| invalid("The message can't be sent as base64".to_string()))?,
+        Some("8bit") => unencoded(bytes, ContentTransferEncoding::EightBit)?,
+        Some("7bit") => unencoded(bytes, ContentTransferEncoding::SevenBit)?,
+        Some(other) => return Err(invalid(format!("Unsupported transfer_encoding: {}", other))),
+    };
+
+    Ok(SinglePart::builder()
+        .header(content_type)
+        .body(body))
+}
+
+pub fn text_part(mail: &Mail) -> Result<SinglePart, SendError> {
+    part(mail, &mail.message, "plain")
+}
+
+// the html alternative of the text, in the same charset and encoding
+pub fn html_part(
+    mail: &Mail,
+    html: &str,
+) -> Result<SinglePart, SendError> {
+    part(mail, html, "html")
+}
@@ -0,0 +1,43 @@
+//
+// Date header of the messages in the timezone of the request or the config
+//
+
+use chrono::Utc;
+use chrono_tz::Tz;
+use lettre::Message;
+use lettre::message::header::{HeaderName, HeaderValue};
+
+use crate::{SendError, SMTP_CLIENT};
+
+fn timezone(timezone: Option<&str>) -> Result<Option<Tz>, SendError> {
+    match timezone.or(SMTP_CLIENT.timezone.as_deref()) {
+        Some(name) => name.trim().parse::<Tz>()
+            .map(Some)
+            .map_err(|_| SendError::new("invalid_request", format!("Unknown timezone: {}", name))),
+        None => Ok(None),
+    }
+}
+
+// RFC 5322 date of now, in UTC unless a timezone is set
+pub fn now(timezone_name: Option<&str>) -> Result<String, SendError> {
+    Ok(match timezone(timezone_name)? {
+        Some(timezone) => Utc::now().with_timezone(&timezone).to_rfc2822(),
+        None => Utc::now().to_rfc2822(),
+    })
+}
+
+// the builder always adds a Date in UTC, replaced when a timezone is set
+pub fn set(
+    email: &mut Message,
+    timezone_name: Option<&str>,
+) -> Result<(), SendError> {
+
+    if timezone(timezone_name)?.is_some() {
+        email.headers_mut().insert_raw(HeaderValue::new(
+            HeaderName::new_from_ascii_str("Date"),
+            now(timezone_name)?,
+        ));
+    }
+
+    Ok(())
+}
@@ -0,0 +1,134 @@
+//
+// SQLite database of the plugin state (history, digests, ...)
+//
+
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Mutex, MutexGuard};
+use once_cell::sync::OnceCell;
+use rusqlite::Connection;
+
+use crate::SMTP_CLIENT;
+
+// small persisted values of the plugin state
+const STATE: &str = "CREATE TABLE IF NOT EXISTS state (
+    key TEXT PRIMARY KEY,
+    value TEXT NOT NULL
+);";
+
+const SCHEMA: &[&str] = &[
+    STATE,
+    crate::history::SCHEMA,
+    crate::digest::SCHEMA,
+    crate::recurring::SCHEMA,
+    crate::queue::SCHEMA,
+    crate::quota::SCHEMA,
+    crate::ratelimit::SCHEMA,
+    crate::duplicates::SCHEMA,
+    crate::jobs::SCHEMA,
+    crate::suppressions::SCHEMA,
+    crate::audit::SCHEMA,
+    crate::consent::SCHEMA,
+    crate::budget::SCHEMA,
+    crate::review::SCHEMA,
+    crate::invalid::SCHEMA,
+];
+
+// columns added after the first release, applied once in order
+// and tracked with the user_version of the database
+const MIGRATIONS: &[&str] = &[
+    "ALTER TABLE queue ADD COLUMN deferrals INTEGER NOT NULL DEFAULT 0",
+    "ALTER TABLE history ADD COLUMN request_id TEXT",
+    "ALTER TABLE jobs ADD COLUMN cancelled INTEGER NOT NULL DEFAULT 0",
+    "ALTER TABLE jobs ADD COLUMN cancelled_at INTEGER",
+    "ALTER TABLE sends ADD COLUMN account TEXT NOT NULL DEFAULT 'gmail';
+    CREATE INDEX IF NOT EXISTS sends_account ON sends (account, sent_at);",
+    "ALTER TABLE history ADD COLUMN tenant TEXT NOT NULL DEFAULT '';
+    ALTER TABLE jobs ADD COLUMN tenant TEXT NOT NULL DEFAULT '';
+    ALTER TABLE digest ADD COLUMN tenant TEXT NOT NULL DEFAULT '';
+    ALTER TABLE recipient_sends ADD COLUMN tenant TEXT NOT NULL DEFAULT '';",
+    "ALTER TABLE queue ADD COLUMN lane TEXT NOT NULL DEFAULT 'transactional';
+    UPDATE queue SET lane = 'bulk' WHERE json_extract(mail, '$.batch') IS NOT NULL;
+    CREATE INDEX IF NOT EXISTS queue_lane ON queue (lane, next_attempt);",
+    "ALTER TABLE deadletter ADD COLUMN status TEXT NOT NULL DEFAULT 'failed'",
+    "ALTER TABLE queue ADD COLUMN locked_by TEXT;
+    ALTER TABLE queue ADD COLUMN locked_until INTEGER;",
+    // the name of the api key that created the recurring email, its budget
+    // counts the runs
+    "ALTER TABLE recurring ADD COLUMN api_key TEXT",
+];
+
+// opened again on the next use after a failure, a database that was locked or
+// on a full disk at the start is used once it recovers
+static DB: OnceCell<Mutex<Connection>> = OnceCell::new();
+
+// the failure is logged once until the database opens
+static DISABLED: AtomicBool = AtomicBool::new(false);
+
+fn open() -> Result<Connection, Box<dyn std::error::Error>> {
+
+    let path = SMTP_CLIENT.database.as_deref().unwrap_or("arp-gmail.db");
+    let path = match std::path::Path::new(path).is_absolute() {
+        true => std::path::PathBuf::from(path),
+        false => crate::plugin_path()?.join(path),
+    };
+
+    let conn = Connection::open(path)?;
+    for schema in SCHEMA {
+        conn.execute_batch(schema)?;
+    }
+
+    let version: usize = conn.query_row("PRAGMA user_version", [], |row| row.get(0))?;
+    for (i, migration) in MIGRATIONS.iter().enumerate().skip(version) {
+        conn.execute_batch(migration)?;
+        conn.pragma_update(None, "user_version", i + 1)?;
+    }
+
+    Ok(conn)
+}
+
+pub fn conn() -> Result<MutexGuard<'static, Connection>, String> {
+    let db = DB.get_or_try_init(|| match open() {
+        Ok(conn) => {
+            if DISABLED.swap(false, Ordering::Relaxed) {
+                log!("The database is enabled again");
+            }
+            Ok(Mutex::new(conn))
+        },
+        Err(e) => {
+            if !DISABLED.swap(true, Ordering::Relaxed) {
+                log!("Error: database is disabled: {}", e);
+            }
+            Err("Database is disabled".to_string())
+        },
+    })?;
+
+    // a panic while the lock was held rolled back its statement, the
+    // connection is still usable
+    Ok(db.lock().unwrap_or_else(|e| e.into_inner()))
+}
+
+pub fn now() -> i64 {
+    std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .map(|d| d.as_secs() as i64)
+        .unwrap_or(0)
+}
+
+pub fn state(key: &str) -> Option<String> {
+    conn().ok()?
+        .query_row("SELECT value FROM state WHERE key = ?1", [key], |row| row.get(0))
+        .ok()
+}
+
+pub fn set_state(
+    key: &str,
+    value: &str,
+) -> Result<(), String> {
+    conn()?.execute(
+        "INSERT INTO state (key, value) VALUES (?1, ?2)
+        ON CONFLICT(key) DO UPDATE SET value = excluded.value",
+        [key, value],
+    ).map_err(|e| e.to_string())?;
+
+    Ok(())
+}
@@ -0,0 +1,236 @@
+//
+// Digest mode: messages are accumulated per recipient and sent as one email
+//
+
+use std::collections::BTreeMap;
+use chrono::{DateTime, NaiveTime, Timelike};
+use rusqlite::params;
+use serde::{Deserialize, Serialize};
+
+use crate::{Mail, Response, SendError, SMTP_CLIENT};
+use crate::db;
+
+pub const SCHEMA: &str = "CREATE TABLE IF NOT EXISTS digest (
+    id INTEGER PRIMARY KEY AUTOINCREMENT,
+    period TEXT NOT NULL,
+    recipient TEXT NOT NULL,
+    sender TEXT NOT NULL,
+    subject TEXT NOT NULL,
+    message TEXT NOT NULL,
+    created_at INTEGER NOT NULL
+);
+CREATE TABLE IF NOT EXISTS digest_runs (
+    period TEXT PRIMARY KEY,
+    last_run INTEGER NOT NULL
+);";
+
+const PERIODS: &[&str] = &["hourly", "daily"];
+
+fn default_daily_time() -> String {
+    "08:00".to_string()
+}
+
+fn default_subject() -> String {
+    "Digest: {count} messages".to_string()
+}
+
+#[derive(Clone, Deserialize, Serialize)]
+pub struct DigestSettings {
+    // minute of every hour when the hourly digests are sent (UTC)
+    #[serde(default)]
+    pub hourly_minute: u32,
+    // time of the day when the daily digests are sent (UTC)
+    #[serde(default = "default_daily_time")]
+    pub daily_time: String,
+    // sender of the digests, the account username if not set
+    from: Option<String>,
+    // {count} is replaced by the number of messages
+    #[serde(default = "default_subject")]
+    subject: String,
+}
+
+struct Item {
+    sender: String,
+    subject: String,
+    message: String,
+    created_at: i64,
+}
+
+pub fn add(
+    mail: &Mail,
+    period: &str,
+) -> Result<i64, SendError> {
+
+    if SMTP_CLIENT.digest.is_none() {
+        return Err(SendError::new("invalid_request", "Digest mode is disabled: digest is not set"));
+    }
+    if !PERIODS.contains(&period) {
+        return Err(SendError::new(
+            "invalid_request",
+            format!("Invalid digest {:?}: expected one of {}", period, PERIODS.join(", ")),
+        ));
+    }
+    if mail.attachments.as_ref().is_some_and(|list| !list.is_empty()) {
+        return Err(SendError::new("invalid_request", "Attachments can't be sent in a digest"));
+    }
+
+    let conn = db::conn()
+        .map_err(|e| SendError::new("internal_error", e))?;
+    conn.execute(
+        "INSERT INTO digest (period, recipient, sender, subject, message, created_at, tenant)
+        VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7)",
+        params![period, mail.to, mail.from, mail.subject, mail.message, db::now(), crate::tenant::key()],
+    ).map_err(|e| SendError::new("internal_error", e.to_string()))?;
+
+    Ok(conn.last_insert_rowid())
+}
+
+// most recent scheduled time of a period
+fn due_at(
+    settings: &DigestSettings,
+    period: &str,
+    now: i64,
+) -> Result<i64, String> {
+
+    let (offset, interval) = match period {
+        "hourly" => (settings.hourly_minute as i64 * 60, 3600),
+        _ => {
+            let time = NaiveTime::parse_from_str(&settings.daily_time, "%H:%M")
+                .map_err(|e| format!("Invalid digest daily_time {:?}: {}", settings.daily_time, e))?;
+            (time.num_seconds_from_midnight() as i64, 86400)
+        },
+    };
+
+    Ok(now - (now - offset).rem_euclid(interval))
+}
+
+fn format_time(time: i64) -> String {
+    DateTime::from_timestamp(time, 0)
+        .map(|time| time.format("%Y-%m-%d %H:%M UTC").to_string())
+        .unwrap_or_default()
+}
+
+fn compose(
+    settings: &DigestSettings,
+    recipient: &str,
+    items: &[Item],
+) -> Mail {
+
+    let mut message = String::new();
+    for (i, item) in items.iter().enumerate() {
+        message.push_str(&format!(
+            "--- {}. {}\nFrom: {}\nDate: {}\n\n{}\n\n",
+            i + 1,
+            item.subject,
+            item.sender,
+            format_time(item.created_at),
+            item.message.trim_end(),
+        ));
+    }
+
+    Mail {
+        from: settings.from.clone().unwrap_or(SMTP_CLIENT.username.clone()),
+        to: recipient.to_string(),
+        subject: settings.subject.replace("{count}", &items.len().to_string()),
+        message,
+        automated: Some(true),
+        ..Default::default()
+    }
+}
+
+fn flush(
+    settings: &DigestSettings,
+    period: &str,
+    due: i64,
+) -> Result<(), String> {
+
+    // collect the pending items before releasing the database for the sends
+    let pending = {
+        let conn = db::conn()?;
+        let mut stmt = conn.prepare(
+            "SELECT id, recipient, sender, subject, message, created_at, tenant
+            FROM digest WHERE period = ?1 AND created_at < ?2 ORDER BY id"
+        ).map_err(|e| e.to_string())?;
+        let rows = stmt.query_map(params![period, due], |row| Ok((
+            row.get::<_, i64>(0)?,
+            (row.get::<_, String>(6)?, row.get::<_, String>(1)?),
+            Item {
+                sender: row.get(2)?,
+                subject: row.get(3)?,
+                message: row.get(4)?,
+                created_at: row.get(5)?,
+            },
+        ))).map_err(|e| e.to_string())?;
+
+        // a digest per tenant of the recipient
+        let mut pending: BTreeMap<(String, String), Vec<(i64, Item)>> = BTreeMap::new();
+        for row in rows {
+            let (id, recipient, item) = row.map_err(|e| e.to_string())?;
+            pending.entry(recipient).or_default().push((id, item));
+        }
+        pending
+    };
+
+    for ((tenant, recipient), entries) in pending {
+        let (ids, items): (Vec<i64>, Vec<Item>) = entries.into_iter().unzip();
+        let mut mail = compose(settings, &recipient, &items);
+        mail.tenant = Some(tenant).filter(|tenant| !tenant.is_empty());
+        let _tenant = crate::tenant::enter(mail.tenant.clone());
+
+        let mut response = Response::new();
+        let sent = match crate::build_message(&mail) {
+            Ok(email) => crate::deliver(&mail, &email, &mut response).is_ok(),
+            Err(error) => {
+                response.error(error);
+                false
+            },
+        };
+
+        // failed items are kept for the next run
+        if !sent {
+            log!("Error sending {} digest to {}: {}", period, recipient, response.message);
+            continue;
+        }
+
+        let conn = db::conn()?;
+        for id in ids {
+            conn.execute("DELETE FROM digest WHERE id = ?1", params![id])
+                .map_err(|e| e.to_string())?;
+        }
+    }
+
+    db::conn()?.execute(
+        "INSERT INTO digest_runs (period, last_run) VALUES (?1, ?2)
+        ON CONFLICT(period) DO UPDATE SET last_run = excluded.last_run",
+        params![period, due],
+    ).map_err(|e| e.to_string())?;
+
+    Ok(())
+}
+
+// called periodically by the scheduler
+pub fn run() {
+
+    let settings = match &SMTP_CLIENT.digest {
+        Some(settings) => settings,
+        None => return,
+    };
+
+    for period in PERIODS {
+        let result = due_at(settings, period, db::now()).and_then(|due| {
+            let last_run: i64 = db::conn()?
+                .query_row(
+                    "SELECT COALESCE(MAX(last_run), 0) FROM digest_runs WHERE period = ?1",
+                    params![period],
+                    |row| row.get(0),
+                ).map_err(|e| e.to_string())?;
+            match last_run < due {
+                true => flush(settings, period, due),
+                false => Ok(()),
+            }
+        });
+        if let Err(e) = result {
+            log!("Error running {} digest: {}", period, e);
+        }
+    }
+}
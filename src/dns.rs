@@ -0,0 +1,459 @@
+//
+// Resolution of the SMTP servers: the resolver of the host or the DNS servers
+// of the config, with a timeout of its own, and the addresses pinned to a name
+// that skip the lookup. Only the addresses change, the TLS session is still
+// with the name of the server
+//
+
+use std::collections::HashMap;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr, ToSocketAddrs, UdpSocket};
+use std::sync::mpsc;
+use std::time::{Duration, Instant};
+use serde::{Deserialize, Serialize};
+
+use crate::SMTP_CLIENT;
+
+const DNS_PORT: u16 = 53;
+
+const TYPE_A: u16 = 1;
+const TYPE_AAAA: u16 = 28;
+
+fn default_timeout_ms() -> u64 {
+    5000
+}
+
+#[derive(Clone, Deserialize, Serialize)]
+pub struct DnsSettings {
+    // "10.0.0.53" or "[fd00::53]:5353", tried in order, the resolver of the
+    // host if empty
+    #[serde(default)]
+    pub servers: Vec<String>,
+    // the whole lookup of a name, all the servers together
+    #[serde(default = "default_timeout_ms")]
+    pub timeout_ms: u64,
+    // "smtp.gmail.com": ["142.250.102.108"], used without a lookup
+    #[serde(default)]
+    pub pin: HashMap<String, Vec<IpAddr>>,
+}
+
+impl Default for DnsSettings {
+    fn default() -> Self {
+        DnsSettings {
+            servers: Vec::new(),
+            timeout_ms: default_timeout_ms(),
+            pin: HashMap::new(),
+        }
+    }
+}
+
+// a server of "servers", port 53 if it has none
+pub fn server(server: &str) -> Option<SocketAddr> {
+    server.parse::<SocketAddr>()
+        .ok()
+        .or_else(|| server.parse::<IpAddr>().ok().map(|ip| SocketAddr::new(ip, DNS_PORT)))
+}
+
+// whether the resolution of the config replaces the one of the transport
+pub fn configured() -> bool {
+    SMTP_CLIENT.dns.is_some()
+}
+
+pub fn settings() -> DnsSettings {
+    SMTP_CLIENT.dns.clone().unwrap_or_default()
+}
+
+fn pinned<'a>(
+    settings: &'a DnsSettings,
+    host: &str,
+) -> Option<&'a Vec<IpAddr>> {
+    let host = host.trim_end_matches('.');
+    settings.pin.iter()
+        .find(|(name, _)| name.trim_end_matches('.').eq_ignore_ascii_case(host))
+        .map(|(_, addresses)| addresses)
+}
+
+// the addresses of the host, the IPv6 ones first like the resolver of the host
+pub fn lookup(
+    settings: &DnsSettings,
+    host: &str,
+    port: u16,
+) -> Result<Vec<SocketAddr>, String> {
+
+    let timeout = Duration::from_millis(settings.timeout_ms);
+
+    let addresses = if let Ok(ip) = host.parse::<IpAddr>() {
+        vec![ip]
+    } else if let Some(addresses) = pinned(settings, host) {
+        addresses.clone()
+    } else if !settings.servers.is_empty() {
+        query_servers(&settings.servers, host, timeout)?
+    } else {
+        return system(host, port, timeout);
+    };
+
+    match addresses.is_empty() {
+        true => Err(format!("{}: no address", host)),
+        false => Ok(addresses.into_iter()
+            .map(|ip| SocketAddr::new(ip, port))
+            .collect()),
+    }
+}
+
+// the addresses of the family of the local one the connection is made from
+pub fn lookup_from(
+    settings: &DnsSettings,
+    host: &str,
+    port: u16,
+    local: Option<IpAddr>,
+) -> Result<Vec<SocketAddr>, String> {
+
+    let addresses: Vec<SocketAddr> = lookup(settings, host, port)?
+        .into_iter()
+        .filter(|addr| local.is_none_or(|local| local.is_ipv4() == addr.is_ipv4()))
+        .collect();
+
+    match (addresses.is_empty(), local) {
+        (true, Some(local)) => Err(format!("{}: no address of the family of {}", host, local)),
+        _ => Ok(addresses),
+    }
+}
+
+// the lookup of the host can't be cancelled, on a timeout its thread is left
+// to finish on its own
+fn system(
+    host: &str,
+    port: u16,
+    timeout: Duration,
+) -> Result<Vec<SocketAddr>, String> {
+
+    let (sender, receiver) = mpsc::channel();
+    let name = host.to_string();
+    std::thread::Builder::new()
+        .name("arp-gmail-dns".to_string())
+        .spawn(move || {
+            let _ = sender.send((name.as_str(), port)
+                .to_socket_addrs()
+                .map(Iterator::collect::<Vec<SocketAddr>>));
+        })
+        .map_err(|e| format!("{}: error starting the lookup: {}", host, e))?;
+
+    match receiver.recv_timeout(timeout) {
+        Ok(Ok(addresses)) if !addresses.is_empty() => Ok(addresses),
+        Ok(Ok(_)) => Err(format!("{}: no address", host)),
+        Ok(Err(e)) => Err(format!("{}: {}", host, e)),
+        Err(_) => Err(format!("{}: DNS lookup timed out after {} ms", host, timeout.as_millis())),
+    }
+}
+
+// each server has its share of the timeout, the next one is tried when it
+// doesn't answer or fails
+fn query_servers(
+    servers: &[String],
+    host: &str,
+    timeout: Duration,
+) -> Result<Vec<IpAddr>, String> {
+
+    let share = timeout / servers.len().max(1) as u32;
+    let mut errors = Vec::new();
+
+    for server in servers {
+        let Some(addr) = self::server(server) else {
+            errors.push(format!("invalid DNS server {:?}", server));
+            continue;
+        };
+        match query(addr, host, share) {
+            Ok(addresses) => return Ok(addresses),
+            Err(e) => errors.push(format!("{}: {}", addr, e)),
+        }
+    }
+
+    Err(format!("{}: DNS lookup failed: {}", host, errors.join(", ")))
+}
+
+fn question(
+    id: u16,
+    host: &str,
+    qtype: u16,
+) -> Result<Vec<u8>, String> {
+
+    // recursion desired, one question
+    let mut packet = Vec::with_capacity(host.len() + 18);
+    packet.extend_from_slice(&id.to_be_bytes());
+    packet.extend_from_slice(&[0x01, 0x00, 0, 1, 0, 0, 0, 0, 0, 0]);
+
+    for label in host.trim_end_matches('.').split('.') {
+        if label.is_empty() || label.len() > 63 {
+            return Err(format!("invalid name {:?}", host));
+        }
+        packet.push(label.len() as u8);
+        packet.extend_from_slice(label.as_bytes());
+    }
+    packet.push(0);
+    packet.extend_from_slice(&qtype.to_be_bytes());
+    packet.extend_from_slice(&1u16.to_be_bytes());
+
+    Ok(packet)
+}
+
+// the offset after a name, compressed or not
+fn skip_name(
+    packet: &[u8],
+    mut offset: usize,
+) -> Option<usize> {
+    loop {
+        let len = *packet.get(offset)? as usize;
+        match len {
+            0 => return Some(offset + 1),
+            len if len & 0xc0 == 0xc0 => return Some(offset + 2),
+            len => offset += 1 + len,
+        }
+    }
+}
+
+fn u16_at(
+    packet: &[u8],
+    offset: usize,
+) -> Option<u16> {
+    Some(u16::from_be_bytes([*packet.get(offset)?, *packet.get(offset + 1)?]))
+}
+
+// the A and AAAA records of the answer, the CNAME records before them are
+// followed by the server
+fn answer(packet: &[u8]) -> Result<Vec<IpAddr>, String> {
+
+    let malformed = || "malformed reply".to_string();
+
+    let flags = u16_at(packet, 2).ok_or_else(malformed)?;
+    if flags & 0x0200 != 0 {
+        return Err("truncated reply".to_string());
+    }
+    match flags & 0x000f {
+        0 => {},
+        2 => return Err("server failure".to_string()),
+        3 => return Err("no such name".to_string()),
+        5 => return Err("refused".to_string()),
+        rcode => return Err(format!("error {}", rcode)),
+    }
+
+    let questions = u16_at(packet, 4).ok_or_else(malformed)?;
+    let answers = u16_at(packet, 6).ok_or_else(malformed)?;
+    let mut offset = 12;
+    for _ in 0..questions {
+        offset = skip_name(packet, offset).ok_or_else(malformed)? + 4;
+    }
+
+    let mut addresses = Vec::new();
+    for _ in 0..answers {
+        offset = skip_name(packet, offset).ok_or_else(malformed)?;
+        let rtype = u16_at(packet, offset).ok_or_else(malformed)?;
+        let len = u16_at(packet, offset + 8).ok_or_else(malformed)? as usize;
+        let data = packet.get(offset + 10..offset + 10 + len).ok_or_else(malformed)?;
+        match (rtype, data.len()) {
+            (TYPE_A, 4) => addresses.push(IpAddr::V4(Ipv4Addr::new(data[0], data[1], data[2], data[3]))),
+            (TYPE_AAAA, 16) => {
+                let mut octets = [0; 16];
+                octets.copy_from_slice(data);
+                addresses.push(IpAddr::V6(Ipv6Addr::from(octets)));
+            },
+            _ => {},
+        }
+        offset += 10 + len;
+    }
+
+    Ok(addresses)
+}
+
+fn type_name(qtype: u16) -> &'static str {
+    match qtype {
+        TYPE_AAAA => "AAAA",
+        _ => "A",
+    }
+}
+
+// the A and AAAA questions asked together over UDP, a failed or missing
+// reply to one of them is only an error when the other has no address
+fn query(
+    server: SocketAddr,
+    host: &str,
+    timeout: Duration,
+) -> Result<Vec<IpAddr>, String> {
+
+    let deadline = Instant::now() + timeout;
+    let local: SocketAddr = match server {
+        SocketAddr::V4(_) => (Ipv4Addr::UNSPECIFIED, 0).into(),
+        SocketAddr::V6(_) => (Ipv6Addr::UNSPECIFIED, 0).into(),
+    };
+    let socket = UdpSocket::bind(local).map_err(|e| e.to_string())?;
+    socket.connect(server).map_err(|e| e.to_string())?;
+
+    let id = *uuid::Uuid::new_v4().as_bytes();
+    let id = u16::from_be_bytes([id[0], id[1]]);
+    let mut pending = vec![(id, TYPE_AAAA), (id.wrapping_add(1), TYPE_A)];
+    for (id, qtype) in &pending {
+        socket.send(&question(*id, host, *qtype)?).map_err(|e| e.to_string())?;
+    }
+
+    let mut addresses = Vec::new();
+    let mut replies: Vec<(u16, Vec<IpAddr>)> = Vec::new();
+    let mut errors = Vec::new();
+    let mut packet = [0; 1232];
+    while !pending.is_empty() {
+        let left = deadline.saturating_duration_since(Instant::now());
+        if left.is_zero() {
+            for (_, qtype) in &pending {
+                errors.push(format!("{}: no reply in {} ms", type_name(*qtype), timeout.as_millis()));
+            }
+            break;
+        }
+        socket.set_read_timeout(Some(left)).map_err(|e| e.to_string())?;
+        let len = match socket.recv(&mut packet) {
+            Ok(len) => len,
+            Err(e) if matches!(e.kind(), std::io::ErrorKind::WouldBlock | std::io::ErrorKind::TimedOut) => continue,
+            Err(e) => return Err(e.to_string()),
+        };
+        // a late reply to an earlier query of the socket is skipped
+        let Some(position) = u16_at(&packet[..len], 0)
+            .and_then(|reply| pending.iter().position(|(id, _)| *id == reply)) else {
+            continue;
+        };
+        let (_, qtype) = pending.remove(position);
+        match answer(&packet[..len]) {
+            Ok(found) => replies.push((qtype, found)),
+            Err(e) => errors.push(format!("{}: {}", type_name(qtype), e)),
+        }
+    }
+
+    // the IPv6 addresses first
+    replies.sort_by_key(|(qtype, _)| *qtype != TYPE_AAAA);
+    for (_, found) in replies {
+        addresses.extend(found);
+    }
+    if addresses.is_empty() && !errors.is_empty() {
+        return Err(errors.join(", "));
+    }
+
+    Ok(addresses)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const HOST: &str = "mx.example.com";
+
+    // a reply to the A question of HOST, the answers named by a pointer to the
+    // question when compressed
+    fn reply(
+        id: u16,
+        flags: u16,
+        records: &[(u16, &[u8])],
+        compressed: bool,
+    ) -> Vec<u8> {
+        let mut packet = question(id, HOST, TYPE_A).unwrap();
+        let name = packet[12..packet.len() - 4].to_vec();
+        packet[2..4].copy_from_slice(&flags.to_be_bytes());
+        packet[6..8].copy_from_slice(&(records.len() as u16).to_be_bytes());
+        for (rtype, data) in records {
+            match compressed {
+                true => packet.extend_from_slice(&[0xc0, 12]),
+                false => packet.extend_from_slice(&name),
+            }
+            packet.extend_from_slice(&rtype.to_be_bytes());
+            packet.extend_from_slice(&[0, 1, 0, 0, 0x0e, 0x10]);
+            packet.extend_from_slice(&(data.len() as u16).to_be_bytes());
+            packet.extend_from_slice(data);
+        }
+        packet
+    }
+
+    const V6: [u8; 16] = [0x20, 0x01, 0x0d, 0xb8, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 1];
+
+    #[test]
+    fn compressed_and_plain_names() {
+        let cname = [3, b'w', b'w', b'w', 0xc0, 12];
+        let records: &[(u16, &[u8])] = &[(5, &cname), (TYPE_A, &[192, 0, 2, 1]), (TYPE_AAAA, &V6)];
+        let expected = vec![IpAddr::V4(Ipv4Addr::new(192, 0, 2, 1)), IpAddr::V6(Ipv6Addr::from(V6))];
+
+        assert_eq!(answer(&reply(1, 0x8180, records, true)), Ok(expected.clone()));
+        assert_eq!(answer(&reply(1, 0x8180, records, false)), Ok(expected));
+    }
+
+    #[test]
+    fn truncated_and_failed_replies() {
+        let records: &[(u16, &[u8])] = &[(TYPE_A, &[192, 0, 2, 1])];
+        assert_eq!(answer(&reply(1, 0x8380, records, true)), Err("truncated reply".to_string()));
+        assert_eq!(answer(&reply(1, 0x8182, &[], true)), Err("server failure".to_string()));
+        assert_eq!(answer(&reply(1, 0x8183, &[], true)), Err("no such name".to_string()));
+        assert_eq!(answer(&reply(1, 0x8185, &[], true)), Err("refused".to_string()));
+    }
+
+    #[test]
+    fn short_packets() {
+        let malformed = Err("malformed reply".to_string());
+        let full = reply(1, 0x8180, &[(TYPE_A, &[192, 0, 2, 1])], true);
+
+        assert_eq!(answer(&[]), malformed);
+        assert_eq!(answer(&full[..3]), malformed);
+        // in the question, the answer name, its type and the address
+        for len in [13, full.len() - 16, full.len() - 12, full.len() - 1] {
+            assert_eq!(answer(&full[..len]), malformed, "{} bytes", len);
+        }
+        // a record length past the end
+        let mut long = full.clone();
+        let at = long.len() - 6;
+        long[at..at + 2].copy_from_slice(&5u16.to_be_bytes());
+        assert_eq!(answer(&long), malformed);
+        // a label past the end
+        assert_eq!(answer(&[0, 1, 0x81, 0x80, 0, 1, 0, 0, 0, 0, 0, 0, 9, b'a']), malformed);
+    }
+
+    // the flags and the address of the reply to a question, none when the
+    // server doesn't answer it
+    type Reply = Option<(u16, &'static [u8])>;
+
+    // a server answering each question with the reply of its type
+    fn server(replies: fn(u16) -> Reply) -> SocketAddr {
+        let socket = UdpSocket::bind((Ipv4Addr::LOCALHOST, 0)).unwrap();
+        let addr = socket.local_addr().unwrap();
+        std::thread::spawn(move || {
+            let mut packet = [0; 512];
+            for _ in 0..2 {
+                let Ok((len, from)) = socket.recv_from(&mut packet) else {
+                    return;
+                };
+                let id = u16_at(&packet, 0).unwrap();
+                let qtype = u16_at(&packet, len - 4).unwrap();
+                if let Some((flags, data)) = replies(qtype) {
+                    let records: &[(u16, &[u8])] = if data.is_empty() { &[] } else { &[(qtype, data)] };
+                    socket.send_to(&reply(id, flags, records, true), from).unwrap();
+                }
+            }
+        });
+        addr
+    }
+
+    #[test]
+    fn one_failed_question_is_not_an_error() {
+        let timeout = Duration::from_millis(300);
+        let v4 = vec![IpAddr::V4(Ipv4Addr::new(192, 0, 2, 1))];
+
+        let failed_v6 = server(|qtype| match qtype {
+            TYPE_AAAA => Some((0x8182, &[])),
+            _ => Some((0x8180, &[192, 0, 2, 1])),
+        });
+        assert_eq!(query(failed_v6, HOST, timeout), Ok(v4.clone()));
+
+        let no_v6_reply = server(|qtype| match qtype {
+            TYPE_AAAA => None,
+            _ => Some((0x8180, &[192, 0, 2, 1])),
+        });
+        assert_eq!(query(no_v6_reply, HOST, timeout), Ok(v4));
+
+        let both_failed = server(|qtype| match qtype {
+            TYPE_AAAA => Some((0x8182, &[])),
+            _ => Some((0x8180 | 0x0200, &[192, 0, 2, 1])),
+        });
+        let error = query(both_failed, HOST, timeout).unwrap_err();
+        assert_eq!(error, "AAAA: server failure, A: truncated reply");
+    }
+}
@@ -0,0 +1,75 @@
+//
+// Suppression of identical messages resubmitted to the same recipient
+//
+
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+
+use crate::{Mail, SMTP_CLIENT};
+use crate::{db, storage};
+
+pub const SCHEMA: &str = "CREATE TABLE IF NOT EXISTS sent_hashes (
+    hash TEXT NOT NULL,
+    message_id TEXT,
+    sent_at INTEGER NOT NULL
+);
+CREATE INDEX IF NOT EXISTS sent_hashes_hash ON sent_hashes (hash, sent_at);";
+
+#[derive(Clone, Deserialize, Serialize)]
+pub struct DuplicateSettings {
+    // an identical message is refused for this long after it was sent
+    window_secs: u64,
+}
+
+pub struct Duplicate {
+    pub message_id: Option<String>,
+    pub sent_at: i64,
+}
+
+// hash of (recipient, subject, body)
+fn hash(mail: &Mail) -> String {
+    let mut hasher = Sha256::new();
+    for part in [mail.to.trim().to_lowercase().as_str(), &mail.subject, &mail.message] {
+        hasher.update(part.as_bytes());
+        hasher.update([0]);
+    }
+    format!("{:x}", hasher.finalize())
+}
+
+// the earlier send of the same message inside the window
+pub fn find(mail: &Mail) -> Option<Duplicate> {
+
+    let settings = SMTP_CLIENT.duplicates.as_ref()?;
+
+    let result = storage::store()
+        .and_then(|store| store.sent_hash(&hash(mail), db::now() - settings.window_secs as i64));
+
+    match result {
+        Ok(duplicate) => duplicate,
+        Err(e) => {
+            log!("Duplicate check skipped: {}", e);
+            None
+        },
+    }
+}
+
+pub fn record(
+    mail: &Mail,
+    message_id: Option<&str>,
+) {
+
+    let settings = match &SMTP_CLIENT.duplicates {
+        Some(settings) => settings,
+        None => return,
+    };
+
+    let result = storage::store().and_then(|store| store.record_hash(
+        &hash(mail),
+        message_id,
+        db::now(),
+        db::now() - settings.window_secs as i64,
+    ));
+    if let Err(e) = result {
+        log!("Error recording the message hash: {}", e);
+    }
+}
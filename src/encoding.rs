@@ -0,0 +1,78 @@
+//
+// Text of the request bodies: UTF-8, or ISO-8859-1 when the caller declares it
+// in the content type or the config accepts it from the legacy callers
+//
+
+use std::borrow::Cow;
+
+use crate::{SendError, SMTP_CLIENT};
+
+// "application/json; charset=ISO-8859-1" is ("application/json", Some("iso-8859-1"))
+pub fn media_type(content_type: &str) -> (&str, Option<String>) {
+
+    let mut parts = content_type.split(';');
+    let media_type = parts.next().unwrap_or_default().trim();
+    let charset = parts
+        .filter_map(|part| part.split_once('='))
+        .find(|(name, _)| name.trim().eq_ignore_ascii_case("charset"))
+        .map(|(_, value)| value.trim().trim_matches('"').to_lowercase());
+
+    (media_type, charset)
+}
+
+fn latin1(body: &[u8]) -> String {
+    // every byte is the code point of the same value
+    body.iter().map(|&byte| byte as char).collect()
+}
+
+pub fn decode<'a>(
+    charset: Option<&str>,
+    body: &'a [u8],
+) -> Result<Cow<'a, str>, SendError> {
+
+    match charset {
+        None | Some("utf-8") | Some("utf8") => match std::str::from_utf8(body) {
+            Ok(text) => Ok(Cow::Borrowed(text)),
+            Err(_) if SMTP_CLIENT.latin1_bodies.unwrap_or(false) => Ok(Cow::Owned(latin1(body))),
+            Err(e) => Err(SendError::new("invalid_encoding", format!(
+                "The body isn't valid UTF-8 at byte {}, a body in ISO-8859-1 must say so with \
+                \"charset=iso-8859-1\" in its content type",
+                e.valid_up_to(),
+            ))),
+        },
+        Some("iso-8859-1") | Some("latin1") | Some("latin-1") => Ok(Cow::Owned(latin1(body))),
+        Some(charset) => Err(SendError::new(
+            "invalid_encoding",
+            format!("Unsupported charset {:?}, the body must be UTF-8 or ISO-8859-1", charset),
+        )),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn media_types() {
+        assert_eq!(media_type("application/json"), ("application/json", None));
+        assert_eq!(media_type(" text/plain ; Charset=\"ISO-8859-1\""), ("text/plain", Some("iso-8859-1".to_string())));
+        assert_eq!(media_type("application/x-www-form-urlencoded; boundary=x; charset=utf-8"), ("application/x-www-form-urlencoded", Some("utf-8".to_string())));
+    }
+
+    #[test]
+    fn decoded_bodies() {
+        crate::tests::setup();
+        let decoded = |charset: Option<&str>, body: &[u8]| decode(charset, body)
+            .map(Cow::into_owned)
+            .map_err(|error| error.code);
+
+        assert_eq!(decoded(None, "olá".as_bytes()), Ok("olá".to_string()));
+        assert!(matches!(decode(Some("utf8"), b"abc"), Ok(Cow::Borrowed("abc"))));
+        assert_eq!(decoded(Some("iso-8859-1"), b"ol\xe1"), Ok("olá".to_string()));
+        assert_eq!(decoded(Some("latin1"), b"\xa3\xff"), Ok("£ÿ".to_string()));
+        // ISO-8859-1 must be declared unless the config accepts it
+        assert_eq!(decoded(None, b"ol\xe1"), Err("invalid_encoding"));
+        assert_eq!(decoded(Some("utf-8"), b"ol\xe1"), Err("invalid_encoding"));
+        assert_eq!(decoded(Some("windows-1252"), b"abc"), Err("invalid_encoding"));
+    }
+}
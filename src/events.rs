@@ -0,0 +1,553 @@
+//
+// Events posted to /events by the sending providers: the bounces, complaints
+// and unsubscribes are added to the suppression list once the signature of
+// the payload is checked, in the formats of Mailgun and SendGrid, the ARF
+// reports of the feedback loops or a generic one for the other senders; the
+// unsubscribes are recorded in the consent trail and confirmed
+//
+
+use std::collections::HashMap;
+use std::sync::Mutex;
+use hmac::{Hmac, Mac};
+use hyper::HeaderMap;
+use once_cell::sync::Lazy;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use sha2::Sha256;
+
+use crate::{SendError, SMTP_CLIENT};
+use crate::db;
+
+fn default_tolerance_secs() -> u64 {
+    300
+}
+
+fn default_arf_campaign_header() -> String {
+    "X-Campaign-Id".to_string()
+}
+
+#[derive(Clone, Deserialize, Serialize)]
+pub struct EventsSettings {
+    // the HTTP webhook signing key of the Mailgun account
+    pub mailgun_signing_key: Option<String>,
+    // the verification key of the SendGrid Event Webhook, base64 DER
+    pub sendgrid_public_key: Option<String>,
+    // the HMAC-SHA256 secret of the generic format
+    pub secret: Option<String>,
+    // older signatures are refused so a captured payload can't be replayed,
+    // and a Mailgun token is refused the second time within it
+    #[serde(default = "default_tolerance_secs")]
+    pub tolerance_secs: u64,
+    // the header of the original message of an ARF report naming its campaign
+    #[serde(default = "default_arf_campaign_header")]
+    pub arf_campaign_header: String,
+    // the authserv-id of the Authentication-Results the receiving server adds,
+    // an ARF report must have a DKIM signature it checked
+    pub arf_authserv_id: Option<String>,
+}
+
+// a recipient to suppress, with the reason of the suppression list
+struct Event {
+    address: String,
+    reason: &'static str,
+    campaign: Option<String>,
+    // when the provider received it, unix time
+    at: Option<i64>,
+    // of the consent trail, "events.<provider>" if not set
+    source: Option<String>,
+}
+
+#[derive(Serialize)]
+pub struct Received {
+    // the events of the payload
+    pub events: usize,
+    pub suppressed: Vec<String>,
+    // the unsubscribes confirmed, the repeated ones aren't
+    pub confirmed: Vec<String>,
+    // the campaigns of the complaints, once each
+    pub campaigns: Vec<String>,
+    // deliveries, opens, temporary bounces and invalid addresses
+    pub ignored: usize,
+}
+
+fn invalid(message: impl Into<String>) -> SendError {
+    SendError::new("invalid_request", message)
+}
+
+fn refused(message: impl Into<String>) -> SendError {
+    SendError::new("invalid_signature", message)
+}
+
+fn header<'a>(
+    headers: &'a HeaderMap,
+    name: &str,
+) -> Result<&'a str, SendError> {
+    headers.get(name)
+        .and_then(|value| value.to_str().ok())
+        .map(str::trim)
+        .ok_or_else(|| refused(format!("No {} header", name)))
+}
+
+fn hex(text: &str) -> Option<Vec<u8>> {
+    if !text.len().is_multiple_of(2) {
+        return None;
+    }
+    (0..text.len())
+        .step_by(2)
+        .map(|i| text.get(i..i + 2).and_then(|byte| u8::from_str_radix(byte, 16).ok()))
+        .collect()
+}
+
+// the signature is checked in constant time
+fn hmac(
+    secret: &str,
+    payload: &[&[u8]],
+    signature: &str,
+) -> Result<(), SendError> {
+
+    let signature = hex(signature)
+        .ok_or_else(|| refused("The signature isn't hex"))?;
+    let mut mac = Hmac::<Sha256>::new_from_slice(secret.as_bytes())
+        .map_err(|e| SendError::new("internal_error", e.to_string()))?;
+    for part in payload {
+        mac.update(part);
+    }
+
+    mac.verify_slice(&signature)
+        .map_err(|_| refused("The signature doesn't match"))
+}
+
+fn fresh(
+    settings: &EventsSettings,
+    timestamp: &str,
+) -> Result<(), SendError> {
+    let timestamp: i64 = timestamp.parse()
+        .map_err(|_| refused(format!("Invalid timestamp {:?}", timestamp)))?;
+    match (db::now() - timestamp).unsigned_abs() > settings.tolerance_secs {
+        true => Err(refused(format!("The signature is older than {} seconds", settings.tolerance_secs))),
+        false => Ok(()),
+    }
+}
+
+// the Mailgun tokens of the signatures within the tolerance, when they were
+// received; of this instance only
+static TOKENS: Lazy<Mutex<HashMap<String, i64>>> = Lazy::new(|| Mutex::new(HashMap::new()));
+
+fn unused(
+    settings: &EventsSettings,
+    token: &str,
+) -> Result<(), SendError> {
+
+    let now = db::now();
+    let mut tokens = TOKENS.lock().unwrap_or_else(|e| e.into_inner());
+    tokens.retain(|_, received| (now - *received).unsigned_abs() <= settings.tolerance_secs);
+    match tokens.insert(token.to_string(), now) {
+        Some(_) => Err(refused("The token of the signature was already used")),
+        None => Ok(()),
+    }
+}
+
+fn disabled(
+    provider: &str,
+    setting: &str,
+) -> SendError {
+    SendError::new("forbidden", format!("The {} events are disabled: \"events.{}\" is not set", provider, setting))
+}
+
+fn text<'a>(
+    value: &'a Value,
+    field: &str,
+) -> &'a str {
+    value.get(field)
+        .and_then(Value::as_str)
+        .unwrap_or("")
+}
+
+// the unix time of an event, a number or a string of one
+fn occurred(value: &Value) -> Option<i64> {
+    match value {
+        Value::Number(number) => number.as_f64().map(|time| time as i64),
+        Value::String(text) => text.trim().parse::<f64>().ok().map(|time| time as i64),
+        _ => None,
+    }
+}
+
+fn event(
+    address: &str,
+    reason: Option<&'static str>,
+) -> Option<Event> {
+    reason.map(|reason| Event {
+        address: address.to_string(),
+        reason,
+        campaign: None,
+        at: None,
+        source: None,
+    })
+}
+
+// { "signature": { "timestamp", "token", "signature" }, "event-data": { ... } }
+fn mailgun(
+    settings: &EventsSettings,
+    body: &[u8],
+) -> Result<Vec<Option<Event>>, SendError> {
+
+    let key = settings.mailgun_signing_key.as_deref()
+        .ok_or_else(|| disabled("mailgun", "mailgun_signing_key"))?;
+    let payload: Value = serde_json::from_slice(body)
+        .map_err(|e| invalid(format!("Invalid JSON: {}", e)))?;
+
+    let signature = &payload["signature"];
+    let timestamp = text(signature, "timestamp");
+    let token = text(signature, "token");
+    hmac(key, &[timestamp.as_bytes(), token.as_bytes()], text(signature, "signature"))?;
+    fresh(settings, timestamp)?;
+    unused(settings, token)?;
+
+    let data = &payload["event-data"];
+    let reason = match (text(data, "event"), text(data, "severity")) {
+        ("failed", "permanent") => Some("bounced"),
+        ("complained", _) => Some("complaint"),
+        ("unsubscribed", _) => Some("unsubscribed"),
+        _ => None,
+    };
+
+    Ok(vec![event(text(data, "recipient"), reason).map(|event| Event {
+        at: occurred(&data["timestamp"]),
+        ..event
+    })])
+}
+
+// an array of events signed with ECDSA over the timestamp and the body
+#[cfg(feature = "sendgrid")]
+fn sendgrid(
+    settings: &EventsSettings,
+    headers: &HeaderMap,
+    body: &[u8],
+) -> Result<Vec<Option<Event>>, SendError> {
+
+    use base64::Engine;
+    use p256::ecdsa::{Signature, VerifyingKey, signature::Verifier};
+    use p256::pkcs8::DecodePublicKey;
+
+    let key = settings.sendgrid_public_key.as_deref()
+        .ok_or_else(|| disabled("sendgrid", "sendgrid_public_key"))?;
+    let key = base64::engine::general_purpose::STANDARD.decode(key.trim())
+        .ok()
+        .and_then(|der| VerifyingKey::from_public_key_der(&der).ok())
+        .ok_or_else(|| SendError::new("internal_error", "Invalid events.sendgrid_public_key"))?;
+
+    let timestamp = header(headers, "x-twilio-email-event-webhook-timestamp")?;
+    let signature = base64::engine::general_purpose::STANDARD
+        .decode(header(headers, "x-twilio-email-event-webhook-signature")?)
+        .ok()
+        .and_then(|der| Signature::from_der(&der).ok())
+        .ok_or_else(|| refused("Invalid signature"))?;
+    key.verify(&[timestamp.as_bytes(), body].concat(), &signature)
+        .map_err(|_| refused("The signature doesn't match"))?;
+    fresh(settings, timestamp)?;
+
+    let events: Vec<Value> = serde_json::from_slice(body)
+        .map_err(|e| invalid(format!("Invalid JSON: {}", e)))?;
+
+    Ok(events.iter()
+        .map(|data| {
+            let reason = match (text(data, "event"), text(data, "type")) {
+                ("bounce", "blocked") => None,
+                ("bounce", _) => Some("bounced"),
+                ("spamreport", _) => Some("complaint"),
+                ("unsubscribe" | "group_unsubscribe", _) => Some("unsubscribed"),
+                _ => None,
+            };
+            event(text(data, "email"), reason).map(|event| Event {
+                at: occurred(&data["timestamp"]),
+                ..event
+            })
+        })
+        .collect())
+}
+
+#[cfg(not(feature = "sendgrid"))]
+fn sendgrid(
+    _settings: &EventsSettings,
+    _headers: &HeaderMap,
+    _body: &[u8],
+) -> Result<Vec<Option<Event>>, SendError> {
+    Err(invalid("The sendgrid events aren't built in, build the plugin with the \"sendgrid\" feature"))
+}
+
+// { "events": [ { "type": "bounce", "address": "...", "permanent": true } ] }
+// signed with X-Events-Signature, the hex HMAC-SHA256 of "<timestamp>.<body>";
+// an unsubscribe can have its "timestamp" and "source", "list-unsubscribe"
+// for the One-Click posts a collector forwards
+fn generic(
+    settings: &EventsSettings,
+    headers: &HeaderMap,
+    body: &[u8],
+) -> Result<Vec<Option<Event>>, SendError> {
+
+    let secret = settings.secret.as_deref()
+        .ok_or_else(|| disabled("generic", "secret"))?;
+    let timestamp = header(headers, "x-events-timestamp")?;
+    hmac(secret, &[timestamp.as_bytes(), b".", body], header(headers, "x-events-signature")?)?;
+    fresh(settings, timestamp)?;
+
+    let payload: Value = serde_json::from_slice(body)
+        .map_err(|e| invalid(format!("Invalid JSON: {}", e)))?;
+    let events = payload["events"].as_array()
+        .ok_or_else(|| invalid("No \"events\" array"))?;
+
+    Ok(events.iter()
+        .map(|data| {
+            let permanent = data.get("permanent").and_then(Value::as_bool).unwrap_or(true);
+            let reason = match text(data, "type") {
+                "bounce" if permanent => Some("bounced"),
+                "complaint" => Some("complaint"),
+                "unsubscribe" => Some("unsubscribed"),
+                _ => None,
+            };
+            event(text(data, "address"), reason).map(|event| Event {
+                at: occurred(&data["timestamp"]),
+                source: Some(text(data, "source").trim())
+                    .filter(|source| !source.is_empty())
+                    .map(str::to_string),
+                ..event
+            })
+        })
+        .collect())
+}
+
+// the raw report as the feedback loop sent it, forwarded by the mail system
+// and signed like the generic format
+fn arf(
+    settings: &EventsSettings,
+    headers: &HeaderMap,
+    body: &[u8],
+) -> Result<Vec<Option<Event>>, SendError> {
+
+    let secret = settings.secret.as_deref()
+        .ok_or_else(|| disabled("arf", "secret"))?;
+    let timestamp = header(headers, "x-events-timestamp")?;
+    hmac(secret, &[timestamp.as_bytes(), b".", body], header(headers, "x-events-signature")?)?;
+    fresh(settings, timestamp)?;
+
+    if let Some(authserv_id) = &settings.arf_authserv_id {
+        if !crate::arf::authenticated(body, authserv_id) {
+            return Err(refused(format!("The report has no DKIM signature checked by {}", authserv_id)));
+        }
+    }
+
+    let report = crate::arf::parse(body, &settings.arf_campaign_header)
+        .map_err(invalid)?;
+    let reason = match report.feedback_type.as_str() {
+        "abuse" | "fraud" => Some("complaint"),
+        _ => None,
+    };
+
+    Ok(vec![report.recipient.and_then(|recipient| event(&recipient, reason)).map(|event| Event {
+        campaign: report.campaign,
+        ..event
+    })])
+}
+
+// the suppressions are of the instance, a provider account isn't a tenant's
+pub fn receive(
+    provider: &str,
+    headers: &HeaderMap,
+    body: &[u8],
+) -> Result<Received, SendError> {
+
+    let settings = SMTP_CLIENT.events.as_ref()
+        .ok_or_else(|| SendError::new("forbidden", "The events are disabled: \"events\" is not set"))?;
+
+    let events = match provider {
+        "mailgun" => mailgun(settings, body)?,
+        "sendgrid" => sendgrid(settings, headers, body)?,
+        "generic" => generic(settings, headers, body)?,
+        "arf" => arf(settings, headers, body)?,
+        provider => return Err(invalid(format!(
+            "Unknown provider {:?}, expected mailgun, sendgrid, arf or generic",
+            provider,
+        ))),
+    };
+
+    let _tenant = crate::tenant::enter(None);
+    let mut received = Received {
+        events: events.len(),
+        suppressed: Vec::new(),
+        confirmed: Vec::new(),
+        campaigns: Vec::new(),
+        ignored: 0,
+    };
+    for event in events {
+        let Some(event) = event else {
+            received.ignored += 1;
+            continue;
+        };
+        let unsubscribe = event.reason == crate::consent::UNSUBSCRIBED;
+        // a provider repeating the event isn't confirmed twice
+        let repeated = unsubscribe && crate::consent::unsubscribed_already(&event.address);
+        match crate::suppressions::add(&event.address, event.reason) {
+            Ok(address) => {
+                if unsubscribe && !repeated {
+                    let source = event.source.unwrap_or_else(|| format!("events.{}", provider));
+                    if crate::consent::unsubscribe(&address, &source, event.at, true) {
+                        received.confirmed.push(crate::privacy::redact(&address));
+                    }
+                }
+                if let Some(campaign) = event.campaign.filter(|campaign| !received.campaigns.contains(campaign)) {
+                    log!("Complaint of {} about the campaign {}", address, campaign);
+                    received.campaigns.push(campaign);
+                }
+                received.suppressed.push(crate::privacy::redact(&address));
+            },
+            Err(error) => {
+                log!("The event of {} isn't suppressed: {}", event.address, error.message);
+                received.ignored += 1;
+            },
+        }
+    }
+
+    Ok(received)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use hyper::header::HeaderValue;
+
+    const SECRET: &str = "events-secret";
+
+    fn settings() -> EventsSettings {
+        EventsSettings {
+            mailgun_signing_key: Some(SECRET.to_string()),
+            sendgrid_public_key: None,
+            secret: Some(SECRET.to_string()),
+            tolerance_secs: 300,
+            arf_campaign_header: default_arf_campaign_header(),
+            arf_authserv_id: None,
+        }
+    }
+
+    fn mac(parts: &[&[u8]]) -> String {
+        let mut mac = Hmac::<Sha256>::new_from_slice(SECRET.as_bytes()).unwrap();
+        for part in parts {
+            mac.update(part);
+        }
+        mac.finalize().into_bytes().iter().map(|byte| format!("{:02x}", byte)).collect()
+    }
+
+    fn code(result: Result<Vec<Option<Event>>, SendError>) -> String {
+        match result {
+            Ok(_) => "ok".to_string(),
+            Err(error) => format!("{}: {}", error.code, error.message),
+        }
+    }
+
+    fn mailgun_body(
+        timestamp: i64,
+        token: &str,
+        signature: &str,
+    ) -> Vec<u8> {
+        serde_json::to_vec(&serde_json::json!({
+            "signature": { "timestamp": timestamp.to_string(), "token": token, "signature": signature },
+            "event-data": { "event": "failed", "severity": "permanent", "recipient": "a@example.com" },
+        })).unwrap()
+    }
+
+    #[test]
+    fn mailgun_signatures() {
+        let settings = settings();
+        let now = db::now();
+        let sign = |timestamp: i64, token: &str| mac(&[timestamp.to_string().as_bytes(), token.as_bytes()]);
+
+        let valid = mailgun_body(now, "token-valid", &sign(now, "token-valid"));
+        assert_eq!(code(mailgun(&settings, &valid)), "ok");
+        // the same token again within the tolerance
+        assert_eq!(code(mailgun(&settings, &valid)), "invalid_signature: The token of the signature was already used");
+
+        let tampered = mailgun_body(now, "token-other", &sign(now, "token-tampered"));
+        assert_eq!(code(mailgun(&settings, &tampered)), "invalid_signature: The signature doesn't match");
+
+        let stale = now - 301;
+        let old = mailgun_body(stale, "token-stale", &sign(stale, "token-stale"));
+        assert_eq!(code(mailgun(&settings, &old)), "invalid_signature: The signature is older than 300 seconds");
+
+        let not_hex = mailgun_body(now, "token-hex", &sign(now, "token-hex").replace(|c: char| c.is_ascii_digit(), "z"));
+        assert_eq!(code(mailgun(&settings, &not_hex)), "invalid_signature: The signature isn't hex");
+    }
+
+    fn generic_headers(
+        timestamp: i64,
+        signature: &str,
+    ) -> HeaderMap {
+        let mut headers = HeaderMap::new();
+        headers.insert("x-events-timestamp", HeaderValue::from_str(&timestamp.to_string()).unwrap());
+        headers.insert("x-events-signature", HeaderValue::from_str(signature).unwrap());
+        headers
+    }
+
+    #[test]
+    fn generic_signatures() {
+        let settings = settings();
+        let now = db::now();
+        let body = br#"{ "events": [ { "type": "complaint", "address": "a@example.com" } ] }"#;
+        let sign = |timestamp: i64, body: &[u8]| mac(&[timestamp.to_string().as_bytes(), b".", body]);
+
+        let valid = generic_headers(now, &sign(now, body));
+        assert_eq!(code(generic(&settings, &valid, body)), "ok");
+
+        let tampered = br#"{ "events": [ { "type": "complaint", "address": "b@example.com" } ] }"#;
+        assert_eq!(code(generic(&settings, &valid, tampered)), "invalid_signature: The signature doesn't match");
+
+        let stale = now - 301;
+        let old = generic_headers(stale, &sign(stale, body));
+        assert_eq!(code(generic(&settings, &old, body)), "invalid_signature: The signature is older than 300 seconds");
+
+        let not_hex = generic_headers(now, &format!("{}g", &sign(now, body)[1..]));
+        assert_eq!(code(generic(&settings, &not_hex, body)), "invalid_signature: The signature isn't hex");
+        let odd = generic_headers(now, &sign(now, body)[1..]);
+        assert_eq!(code(generic(&settings, &odd, body)), "invalid_signature: The signature isn't hex");
+    }
+
+    #[cfg(feature = "sendgrid")]
+    #[test]
+    fn sendgrid_signatures() {
+        use base64::Engine;
+        use openssl::ec::{EcGroup, EcKey};
+        use openssl::hash::MessageDigest;
+        use openssl::pkey::PKey;
+        use openssl::sign::Signer;
+
+        let encode = |bytes: &[u8]| base64::engine::general_purpose::STANDARD.encode(bytes);
+        let group = EcGroup::from_curve_name(openssl::nid::Nid::X9_62_PRIME256V1).unwrap();
+        let key = PKey::from_ec_key(EcKey::generate(&group).unwrap()).unwrap();
+        let settings = EventsSettings {
+            sendgrid_public_key: Some(encode(&key.public_key_to_der().unwrap())),
+            ..settings()
+        };
+        let sign = |timestamp: i64, body: &[u8]| {
+            let mut signer = Signer::new(MessageDigest::sha256(), &key).unwrap();
+            signer.update(timestamp.to_string().as_bytes()).unwrap();
+            signer.update(body).unwrap();
+            encode(&signer.sign_to_vec().unwrap())
+        };
+        let headers = |timestamp: i64, signature: &str| {
+            let mut headers = HeaderMap::new();
+            headers.insert("x-twilio-email-event-webhook-timestamp", HeaderValue::from_str(&timestamp.to_string()).unwrap());
+            headers.insert("x-twilio-email-event-webhook-signature", HeaderValue::from_str(signature).unwrap());
+            headers
+        };
+        let now = db::now();
+        let body = br#"[ { "event": "spamreport", "email": "a@example.com" } ]"#;
+
+        assert_eq!(code(sendgrid(&settings, &headers(now, &sign(now, body)), body)), "ok");
+
+        let tampered = br#"[ { "event": "spamreport", "email": "b@example.com" } ]"#;
+        assert_eq!(code(sendgrid(&settings, &headers(now, &sign(now, body)), tampered)), "invalid_signature: The signature doesn't match");
+
+        let stale = now - 301;
+        assert_eq!(code(sendgrid(&settings, &headers(stale, &sign(stale, body)), body)), "invalid_signature: The signature is older than 300 seconds");
+
+        assert_eq!(code(sendgrid(&settings, &headers(now, "not base64!"), body)), "invalid_signature: Invalid signature");
+    }
+}
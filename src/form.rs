@@ -0,0 +1,215 @@
+//
+// application/x-www-form-urlencoded bodies of /sendmail, for the HTML forms and
+// the legacy callers: each field is the field of the JSON body of the same name
+//
+
+use std::collections::BTreeMap;
+use serde::de::{self, value::MapDeserializer, DeserializeOwned, IntoDeserializer, Visitor};
+use serde::forward_to_deserialize_any;
+
+use crate::SendError;
+
+// a text field, or the fields of name[key]=value as an object
+enum Field {
+    Text(String),
+    Fields(serde_json::Map<String, serde_json::Value>),
+}
+
+fn json<'de, V: Visitor<'de>>(
+    value: serde_json::Value,
+    visitor: V,
+) -> Result<V::Value, de::value::Error> {
+    de::Deserializer::deserialize_any(value, visitor)
+        .map_err(de::Error::custom)
+}
+
+// the text is read as the type of the field: "true", "on" and "1" are true, the
+// lists and objects (attachments, data) are JSON
+impl<'de> de::Deserializer<'de> for Field {
+    type Error = de::value::Error;
+
+    fn deserialize_any<V: Visitor<'de>>(
+        self,
+        visitor: V,
+    ) -> Result<V::Value, Self::Error> {
+        match self {
+            Field::Fields(fields) => json(serde_json::Value::Object(fields), visitor),
+            Field::Text(text) if text.starts_with(['{', '[']) => match serde_json::from_str(&text) {
+                Ok(value) => json(value, visitor),
+                Err(_) => visitor.visit_string(text),
+            },
+            Field::Text(text) => visitor.visit_string(text),
+        }
+    }
+
+    fn deserialize_string<V: Visitor<'de>>(
+        self,
+        visitor: V,
+    ) -> Result<V::Value, Self::Error> {
+        match self {
+            Field::Text(text) => visitor.visit_string(text),
+            fields => fields.deserialize_any(visitor),
+        }
+    }
+
+    fn deserialize_str<V: Visitor<'de>>(
+        self,
+        visitor: V,
+    ) -> Result<V::Value, Self::Error> {
+        self.deserialize_string(visitor)
+    }
+
+    fn deserialize_bool<V: Visitor<'de>>(
+        self,
+        visitor: V,
+    ) -> Result<V::Value, Self::Error> {
+        match &self {
+            Field::Text(text) => match text.to_lowercase().as_str() {
+                "true" | "on" | "yes" | "1" => visitor.visit_bool(true),
+                "false" | "off" | "no" | "0" => visitor.visit_bool(false),
+                _ => Err(de::Error::custom(format!("invalid boolean {:?}", text))),
+            },
+            Field::Fields(_) => self.deserialize_any(visitor),
+        }
+    }
+
+    fn deserialize_u64<V: Visitor<'de>>(
+        self,
+        visitor: V,
+    ) -> Result<V::Value, Self::Error> {
+        match &self {
+            Field::Text(text) => visitor.visit_u64(text.trim()
+                .parse()
+                .map_err(|_| de::Error::custom(format!("invalid number {:?}", text)))?),
+            Field::Fields(_) => self.deserialize_any(visitor),
+        }
+    }
+
+    fn deserialize_i64<V: Visitor<'de>>(
+        self,
+        visitor: V,
+    ) -> Result<V::Value, Self::Error> {
+        match &self {
+            Field::Text(text) => visitor.visit_i64(text.trim()
+                .parse()
+                .map_err(|_| de::Error::custom(format!("invalid number {:?}", text)))?),
+            Field::Fields(_) => self.deserialize_any(visitor),
+        }
+    }
+
+    fn deserialize_option<V: Visitor<'de>>(
+        self,
+        visitor: V,
+    ) -> Result<V::Value, Self::Error> {
+        visitor.visit_some(self)
+    }
+
+    forward_to_deserialize_any! {
+        i8 i16 i32 i128 u8 u16 u32 u128 f32 f64 char bytes byte_buf unit unit_struct
+        newtype_struct seq tuple tuple_struct map struct enum identifier ignored_any
+    }
+}
+
+impl<'de> IntoDeserializer<'de, de::value::Error> for Field {
+    type Deserializer = Self;
+
+    fn into_deserializer(self) -> Self {
+        self
+    }
+}
+
+// the empty fields of a form are left out and the last of a repeated one wins,
+// like the hidden "false" before a checkbox
+pub fn parse<T: DeserializeOwned>(body: &str) -> Result<T, SendError> {
+
+    let mut fields: BTreeMap<String, Field> = BTreeMap::new();
+
+    for (name, value) in form_urlencoded::parse(body.as_bytes()) {
+        if value.is_empty() {
+            continue;
+        }
+        let nested = name.strip_suffix(']')
+            .and_then(|name| name.split_once('['))
+            .filter(|(name, key)| !name.is_empty() && !key.is_empty());
+        match nested {
+            Some((name, key)) => match fields.entry(name.to_string())
+                .or_insert_with(|| Field::Fields(serde_json::Map::new())) {
+                Field::Fields(object) => {
+                    object.insert(key.to_string(), serde_json::Value::String(value.to_string()));
+                },
+                Field::Text(_) => return Err(SendError::new(
+                    "invalid_request",
+                    format!("The field {} is both a value and a list of fields", name),
+                )),
+            },
+            None => match fields.get(name.as_ref()) {
+                Some(Field::Fields(_)) => return Err(SendError::new(
+                    "invalid_request",
+                    format!("The field {} is both a value and a list of fields", name),
+                )),
+                _ => {
+                    fields.insert(name.to_string(), Field::Text(value.to_string()));
+                },
+            },
+        }
+    }
+
+    T::deserialize(MapDeserializer::new(fields.into_iter()))
+        .map_err(|e: de::value::Error| SendError::new("invalid_request", format!("Invalid form: {}", e)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde::Deserialize;
+
+    #[derive(Debug, Default, Deserialize, PartialEq)]
+    struct Fields {
+        to: Option<String>,
+        queue: Option<bool>,
+        priority: Option<u64>,
+        offset: Option<i64>,
+        attachments: Option<Vec<String>>,
+        data: Option<BTreeMap<String, String>>,
+    }
+
+    fn fields(body: &str) -> Result<Fields, String> {
+        parse(body).map_err(|error| format!("{}: {}", error.code, error.message))
+    }
+
+    #[test]
+    fn fields_of_the_form() {
+        let cases = [
+            ("to=a%40example.com", Fields { to: Some("a@example.com".to_string()), ..Default::default() }),
+            // left out when empty, the last value of a repeated one
+            ("to=&queue=false&queue=on", Fields { queue: Some(true), ..Default::default() }),
+            ("queue=0&priority=+3+", Fields { queue: Some(false), priority: Some(3), ..Default::default() }),
+            ("offset=-2", Fields { offset: Some(-2), ..Default::default() }),
+            // a list as JSON, an object as name[key]
+            ("attachments=%5B%22q3.pdf%22%5D", Fields { attachments: Some(vec!["q3.pdf".to_string()]), ..Default::default() }),
+            ("data%5Bname%5D=Ann&data[order]=42", Fields {
+                data: Some(BTreeMap::from([("name".to_string(), "Ann".to_string()), ("order".to_string(), "42".to_string())])),
+                ..Default::default()
+            }),
+            // text that starts like JSON but isn't stays text
+            ("to=%5Bnot+json", Fields { to: Some("[not json".to_string()), ..Default::default() }),
+            ("", Fields::default()),
+        ];
+        for (body, expected) in cases {
+            assert_eq!(fields(body), Ok(expected), "{}", body);
+        }
+    }
+
+    #[test]
+    fn invalid_forms() {
+        let cases = [
+            ("queue=maybe", "invalid_request: Invalid form: invalid boolean \"maybe\""),
+            ("priority=high", "invalid_request: Invalid form: invalid number \"high\""),
+            ("data=x&data[name]=Ann", "invalid_request: The field data is both a value and a list of fields"),
+            ("data[name]=Ann&data=x", "invalid_request: The field data is both a value and a list of fields"),
+        ];
+        for (body, expected) in cases {
+            assert_eq!(fields(body), Err(expected.to_string()), "{}", body);
+        }
+    }
+}
@@ -0,0 +1,223 @@
+//
+// Forward (resend) an existing RFC822 .eml message to new recipients and
+// relay a message prebuilt by the caller
+//
+
+use base64::Engine;
+use base64::engine::general_purpose::STANDARD;
+use lettre::address::Envelope;
+use lettre::message::Mailboxes;
+use serde::Deserialize;
+
+use crate::{Mail, SendError, SmtpSettings};
+
+#[derive(Deserialize)]
+pub struct Forward {
+    // .eml file relative to the eml directory
+    path: Option<String>,
+    // the .eml file encoded as base64
+    eml: Option<String>,
+    // comma separated recipients
+    to: String,
+    cc: Option<String>,
+    bcc: Option<String>,
+    // abort the send after this many milliseconds
+    pub timeout_ms: Option<u64>,
+    // of the Resent-Date, the config timezone if not set
+    timezone: Option<String>,
+}
+
+pub struct Resent {
+    pub envelope: Envelope,
+    pub message: Vec<u8>,
+    pub message_id: String,
+    pub subject: String,
+}
+
+fn parse_mailboxes(addresses: &str) -> Result<Mailboxes, SendError> {
+    addresses.parse()
+        .map_err(|e| SendError::new("invalid_address", format!("Invalid address {:?}: {}", addresses, e)))
+}
+
+fn address_list(mailboxes: &Mailboxes) -> String {
+    mailboxes.iter()
+        .map(|mailbox| mailbox.email.to_string())
+        .collect::<Vec<String>>()
+        .join(", ")
+}
+
+fn load_eml(
+    forward: &Forward,
+    settings: &SmtpSettings,
+) -> Result<Vec<u8>, SendError> {
+
+    match (&forward.path, &forward.eml) {
+        (Some(path), None) => {
+            let eml_dir = settings.eml_dir.as_deref().ok_or_else(|| SendError::new(
+                "invalid_message",
+                "Forwarding files is disabled: eml_dir is not set",
+            ))?;
+            let file = crate::resolve_path(eml_dir, path)
+                .map_err(|e| SendError::new("invalid_message", format!("Message file {}", e)))?;
+            std::fs::read(&file)
+                .map_err(|e| SendError::new("invalid_message", format!("Message file {}: {}", path, e)))
+        },
+        (None, Some(eml)) => STANDARD.decode(eml.trim())
+            .map_err(|e| SendError::new("invalid_message", format!("Invalid base64 message: {}", e))),
+        _ => Err(SendError::new(
+            "invalid_message",
+            "Forward must have either a path or an eml",
+        )),
+    }
+}
+
+fn invalid(e: impl std::fmt::Display) -> SendError {
+    SendError::new("invalid_message", format!("Invalid RFC822 message: {}", e))
+}
+
+// copy the original headers byte for byte, without the Bcc, and the body
+fn copy_without_bcc(
+    raw: &[u8],
+    message: &mut Vec<u8>,
+) -> Result<(), SendError> {
+
+    let mut ix = 0;
+    while ix < raw.len() && raw[ix] != b'\n' && raw[ix] != b'\r' {
+        let (header, size) = mailparse::parse_header(&raw[ix..])
+            .map_err(invalid)?;
+        if !header.get_key_ref().eq_ignore_ascii_case("bcc") {
+            message.extend_from_slice(&raw[ix..ix + size]);
+        }
+        ix += size;
+    }
+    message.extend_from_slice(&raw[ix..]);
+
+    Ok(())
+}
+
+fn header(
+    headers: &[mailparse::MailHeader],
+    name: &str,
+) -> Option<String> {
+    headers.iter()
+        .find(|header| header.get_key_ref().eq_ignore_ascii_case(name))
+        .map(|header| header.get_value())
+}
+
+// the original message is relayed unchanged except for the Bcc header,
+// the new recipients are recorded in Resent-* headers (RFC 5322 section 3.6.6)
+pub fn resent_message(
+    forward: &Forward,
+    settings: &SmtpSettings,
+) -> Result<Resent, SendError> {
+
+    if forward.to.trim().is_empty() {
+        return Err(SendError::new("invalid_address", "No to address"));
+    }
+
+    let from = crate::parse_mailbox(&settings.username)?;
+    let to = parse_mailboxes(&forward.to)?;
+    let cc = forward.cc.as_deref().map(parse_mailboxes).transpose()?;
+    let bcc = forward.bcc.as_deref().map(parse_mailboxes).transpose()?;
+
+    let raw = load_eml(forward, settings)?;
+
+    let parsed = mailparse::parse_mail(&raw)
+        .map_err(invalid)?;
+    if header(&parsed.headers, "from").is_none() {
+        return Err(invalid("no From header"));
+    }
+    let subject = header(&parsed.headers, "subject")
+        .unwrap_or_default();
+
+    let message_id = format!("<{}@{}>", uuid::Uuid::new_v4(), from.email.domain());
+    let mut message = format!(
+        "Resent-From: {}\r\nResent-Date: {}\r\nResent-Message-ID: {}\r\nResent-To: {}\r\n",
+        from.email,
+        crate::date::now(forward.timezone.as_deref())?,
+        message_id,
+        address_list(&to),
+    ).into_bytes();
+    if let Some(cc) = &cc {
+        message.extend_from_slice(format!("Resent-Cc: {}\r\n", address_list(cc)).as_bytes());
+    }
+
+    copy_without_bcc(&raw, &mut message)?;
+
+    let recipients = to.iter()
+        .chain(cc.iter().flat_map(|cc| cc.iter()))
+        .chain(bcc.iter().flat_map(|bcc| bcc.iter()))
+        .map(|mailbox| mailbox.email.clone())
+        .collect();
+
+    let envelope = Envelope::new(Some(from.email), recipients)
+        .map_err(|e| SendError::new("invalid_address", format!("Invalid envelope: {}", e)))?;
+
+    Ok(Resent {
+        envelope,
+        message,
+        message_id,
+        subject,
+    })
+}
+
+// the raw_mime of a request: relayed unchanged except for the Bcc header,
+// to the recipients of the request or else those of its headers
+pub fn prebuilt(
+    mail: &Mail,
+    settings: &SmtpSettings,
+) -> Result<Resent, SendError> {
+
+    let raw = STANDARD.decode(mail.raw_mime.as_deref().unwrap_or_default().trim())
+        .map_err(|e| SendError::new("invalid_message", format!("Invalid base64 message: {}", e)))?;
+    let parsed = mailparse::parse_mail(&raw)
+        .map_err(invalid)?;
+
+    let from = header(&parsed.headers, "from")
+        .ok_or_else(|| invalid("no From header"))?;
+    let from = parse_mailboxes(&from)?
+        .into_single()
+        .ok_or_else(|| invalid("the From header must have a single address"))?;
+    crate::identity::check_prebuilt(&from.email)?;
+
+    let lists = match mail.to.trim().is_empty() {
+        true => ["to", "cc", "bcc"].map(|name| header(&parsed.headers, name)),
+        false => [Some(mail.to.clone()), mail.cc.clone(), mail.bcc.clone()],
+    };
+    let mut recipients = Vec::new();
+    for list in lists.iter().flatten().filter(|list| !list.trim().is_empty()) {
+        recipients.extend(parse_mailboxes(list)?
+            .into_iter()
+            .map(|mailbox| mailbox.email));
+    }
+    if recipients.is_empty() {
+        return Err(SendError::new("invalid_address", "No to address"));
+    }
+
+    // the headers relays refuse a message without
+    let account = crate::parse_mailbox(&settings.username)?;
+    let mut message = Vec::with_capacity(raw.len());
+    if header(&parsed.headers, "date").is_none() {
+        let date = crate::date::now(mail.timezone.as_deref())?;
+        message.extend_from_slice(format!("Date: {}\r\n", date).as_bytes());
+    }
+    let message_id = match header(&parsed.headers, "message-id") {
+        Some(message_id) => message_id.trim().to_string(),
+        None => {
+            let message_id = format!("<{}@{}>", uuid::Uuid::new_v4(), account.email.domain());
+            message.extend_from_slice(format!("Message-ID: {}\r\n", message_id).as_bytes());
+            message_id
+        },
+    };
+    copy_without_bcc(&raw, &mut message)?;
+
+    let envelope = Envelope::new(Some(account.email), recipients)
+        .map_err(|e| SendError::new("invalid_address", format!("Invalid envelope: {}", e)))?;
+
+    Ok(Resent {
+        envelope,
+        message,
+        message_id,
+        subject: header(&parsed.headers, "subject").unwrap_or_default(),
+    })
+}
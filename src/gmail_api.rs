@@ -0,0 +1,339 @@
+//
+// The Gmail API of the accounts with an OAuth refresh token: the access
+// tokens, the sends of the accounts that go through it instead of SMTP and the
+// labels applied to their messages, whose ids are returned to the caller
+//
+
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use base64::{engine::general_purpose, Engine as _};
+use lettre::address::Envelope;
+use mailparse::MailHeaderMap;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+
+use crate::{SendError, SMTP_CLIENT};
+use crate::db;
+use crate::pool::Failure;
+use crate::transport::{self, Transport};
+
+fn default_token_endpoint() -> String {
+    "https://oauth2.googleapis.com/token".to_string()
+}
+
+fn default_endpoint() -> String {
+    "https://gmail.googleapis.com".to_string()
+}
+
+#[derive(Clone, Deserialize, Serialize)]
+pub struct GmailApiSettings {
+    // the OAuth client of the Google Cloud project
+    pub client_id: String,
+    pub client_secret: String,
+    // per account name, "gmail" for the account of the config, granted the
+    // gmail.settings.basic or gmail.readonly scope, and gmail.send and
+    // gmail.labels for the accounts of "send_accounts"
+    #[serde(default)]
+    pub refresh_tokens: HashMap<String, String>,
+    // the Gmail accounts that send through the API instead of SMTP
+    #[serde(default)]
+    pub send_accounts: Vec<String>,
+    #[serde(default = "default_token_endpoint")]
+    pub token_endpoint: String,
+    #[serde(default = "default_endpoint")]
+    pub endpoint: String,
+}
+
+// the ids of a message sent through the API, to find it in the mailbox
+#[derive(Clone, Serialize)]
+pub struct Thread {
+    pub message_id: String,
+    pub thread_id: String,
+}
+
+// where the transport leaves the ids of the message, read after the send
+#[derive(Clone, Default)]
+pub struct Ids(Arc<Mutex<Option<Thread>>>);
+
+impl Ids {
+    pub fn take(&self) -> Option<Thread> {
+        self.0.lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .take()
+    }
+}
+
+// the access tokens and when they expire, per account
+static TOKENS: Mutex<Option<HashMap<String, (String, i64)>>> = Mutex::new(None);
+
+// the ids of the labels per account and name, created on their first use
+static LABELS: Mutex<Option<HashMap<(String, String), String>>> = Mutex::new(None);
+
+pub fn settings() -> Result<&'static GmailApiSettings, SendError> {
+    SMTP_CLIENT.gmail_api.as_ref()
+        .ok_or_else(|| SendError::new("forbidden", "The Gmail API is disabled: \"gmail_api\" is not set"))
+}
+
+// whether the sends of the account go through the API
+pub fn sends(account: &str) -> bool {
+    SMTP_CLIENT.gmail_api.as_ref()
+        .is_some_and(|settings| settings.send_accounts.iter().any(|name| name == account))
+}
+
+fn unavailable(message: impl Into<String>) -> SendError {
+    SendError::new("account_unavailable", message)
+}
+
+pub fn api_error(
+    what: &str,
+    error: ureq::Error,
+) -> SendError {
+    match error {
+        ureq::Error::Status(status, response) => unavailable(format!(
+            "{} failed ({}): {}",
+            what,
+            status,
+            response.into_string().unwrap_or_default().chars().take(500).collect::<String>(),
+        )),
+        ureq::Error::Transport(e) => SendError::new("connection_failed", format!("{} failed: {}", what, e)),
+    }
+}
+
+pub fn access_token(
+    settings: &GmailApiSettings,
+    account: &str,
+) -> Result<String, SendError> {
+
+    let now = db::now();
+    if let Some((token, _)) = TOKENS.lock()
+        .unwrap_or_else(|e| e.into_inner())
+        .get_or_insert_with(HashMap::new)
+        .get(account)
+        .filter(|(_, expires_at)| *expires_at > now) {
+        return Ok(token.clone());
+    }
+
+    let refresh_token = settings.refresh_tokens.get(account)
+        .ok_or_else(|| unavailable(format!("No refresh token for the account {} in \"gmail_api.refresh_tokens\"", account)))?;
+    let reply: Value = transport::agent()
+        .post(&settings.token_endpoint)
+        .send_form(&[
+            ("grant_type", "refresh_token"),
+            ("client_id", &settings.client_id),
+            ("client_secret", &settings.client_secret),
+            ("refresh_token", refresh_token),
+        ])
+        .map_err(|e| api_error("The OAuth token refresh", e))?
+        .into_json()
+        .map_err(|e| unavailable(format!("Invalid OAuth token reply: {}", e)))?;
+
+    let token = reply["access_token"].as_str()
+        .ok_or_else(|| unavailable("The OAuth token reply has no access_token"))?
+        .to_string();
+    // a minute early, so a token doesn't expire on its way
+    let expires_at = now + reply["expires_in"].as_i64().unwrap_or(3600) - 60;
+    TOKENS.lock()
+        .unwrap_or_else(|e| e.into_inner())
+        .get_or_insert_with(HashMap::new)
+        .insert(account.to_string(), (token.clone(), expires_at));
+
+    Ok(token)
+}
+
+// a revoked token is fetched again on the next request
+pub fn revoked(
+    account: &str,
+    error: &ureq::Error,
+) {
+    if matches!(error, ureq::Error::Status(401, _)) {
+        if let Some(tokens) = TOKENS.lock().unwrap_or_else(|e| e.into_inner()).as_mut() {
+            tokens.remove(account);
+        }
+    }
+}
+
+pub fn url(
+    settings: &GmailApiSettings,
+    path: &str,
+) -> String {
+    format!("{}/gmail/v1/users/me/{}", settings.endpoint.trim_end_matches('/'), path)
+}
+
+// the names of "labels" of a request, "automated/billing" nests billing
+// under automated
+pub fn check_labels(labels: &[String]) -> Result<(), SendError> {
+    match labels.iter().find(|label| label.trim().is_empty() || label.len() > 225 || label.chars().any(char::is_control)) {
+        Some(label) => Err(SendError::new("invalid_request", format!("Invalid label {:?}", label))),
+        None => Ok(()),
+    }
+}
+
+pub struct GmailApi {
+    name: String,
+    labels: Vec<String>,
+    ids: Ids,
+}
+
+impl GmailApi {
+    pub fn new(name: &str) -> Self {
+        GmailApi {
+            name: name.to_string(),
+            labels: Vec::new(),
+            ids: Ids::default(),
+        }
+    }
+
+    fn label_id(
+        &self,
+        settings: &GmailApiSettings,
+        token: &str,
+        name: &str,
+    ) -> Result<String, SendError> {
+
+        let key = (self.name.clone(), name.to_string());
+        if let Some(id) = LABELS.lock().unwrap_or_else(|e| e.into_inner()).get_or_insert_with(HashMap::new).get(&key) {
+            return Ok(id.clone());
+        }
+
+        let list: Value = transport::agent()
+            .get(&url(settings, "labels"))
+            .set("Authorization", &format!("Bearer {}", token))
+            .call()
+            .map_err(|e| api_error("The labels request", e))?
+            .into_json()
+            .map_err(|e| unavailable(format!("Invalid labels reply: {}", e)))?;
+        let found = list["labels"].as_array()
+            .into_iter()
+            .flatten()
+            .find(|label| label["name"].as_str().is_some_and(|label| label.eq_ignore_ascii_case(name)))
+            .and_then(|label| label["id"].as_str())
+            .map(str::to_string);
+
+        let id = match found {
+            Some(id) => id,
+            None => {
+                let created: Value = transport::agent()
+                    .post(&url(settings, "labels"))
+                    .set("Authorization", &format!("Bearer {}", token))
+                    .send_json(serde_json::json!({
+                        "name": name,
+                        "labelListVisibility": "labelShow",
+                        "messageListVisibility": "show",
+                    }))
+                    .map_err(|e| api_error(&format!("The creation of the label {}", name), e))?
+                    .into_json()
+                    .map_err(|e| unavailable(format!("Invalid label reply: {}", e)))?;
+                created["id"].as_str()
+                    .ok_or_else(|| unavailable("The label reply has no id"))?
+                    .to_string()
+            },
+        };
+        LABELS.lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .get_or_insert_with(HashMap::new)
+            .insert(key, id.clone());
+
+        Ok(id)
+    }
+
+    fn apply_labels(
+        &self,
+        settings: &GmailApiSettings,
+        token: &str,
+        message_id: &str,
+    ) -> Result<(), SendError> {
+
+        let ids = self.labels.iter()
+            .map(|label| self.label_id(settings, token, label.trim()))
+            .collect::<Result<Vec<String>, SendError>>()?;
+        transport::agent()
+            .post(&url(settings, &format!("messages/{}/modify", message_id)))
+            .set("Authorization", &format!("Bearer {}", token))
+            .send_json(serde_json::json!({ "addLabelIds": ids }))
+            .map_err(|e| api_error("The labelling of the message", e))?;
+
+        Ok(())
+    }
+}
+
+fn failure(error: SendError) -> Failure {
+    match error.code {
+        "connection_failed" => Failure::Connect(error.message),
+        _ => Failure::Account(error.message),
+    }
+}
+
+// the API sends to the To, Cc and Bcc of the message, the recipients of the
+// envelope that aren't in them are added as Bcc
+fn with_bcc(
+    envelope: &Envelope,
+    raw: &[u8],
+) -> Vec<u8> {
+
+    let headers = mailparse::parse_headers(raw)
+        .map(|(headers, _)| ["To", "Cc", "Bcc"].iter()
+            .flat_map(|name| headers.get_all_values(name))
+            .collect::<Vec<String>>()
+            .join(", ")
+            .to_lowercase())
+        .unwrap_or_default();
+    let hidden: Vec<String> = envelope.to().iter()
+        .map(|address| address.to_string())
+        .filter(|address| !headers.contains(&address.to_lowercase()))
+        .collect();
+
+    match hidden.is_empty() {
+        true => raw.to_vec(),
+        false => [format!("Bcc: {}\r\n", hidden.join(", ")).as_bytes(), raw].concat(),
+    }
+}
+
+impl Transport for GmailApi {
+    fn quota_account(&self) -> Option<&str> {
+        Some(&self.name)
+    }
+
+    fn gmail(
+        &mut self,
+        labels: &[String],
+        ids: Ids,
+    ) {
+        self.labels = labels.to_vec();
+        self.ids = ids;
+    }
+
+    fn send(
+        &self,
+        envelope: &Envelope,
+        raw: &[u8],
+    ) -> Result<String, Failure> {
+
+        let settings = settings().map_err(failure)?;
+        let token = access_token(settings, &self.name).map_err(failure)?;
+        let reply: Value = transport::agent()
+            .post(&url(settings, "messages/send"))
+            .set("Authorization", &format!("Bearer {}", token))
+            .send_json(serde_json::json!({ "raw": general_purpose::URL_SAFE.encode(with_bcc(envelope, raw)) }))
+            .map_err(|e| {
+                revoked(&self.name, &e);
+                transport::api_error("gmail", e)
+            })?
+            .into_json()
+            .map_err(|e| Failure::Account(format!("Invalid Gmail API reply: {}", e)))?;
+
+        let thread = Thread {
+            message_id: reply["id"].as_str().unwrap_or_default().to_string(),
+            thread_id: reply["threadId"].as_str().unwrap_or_default().to_string(),
+        };
+        // the message is sent, a label that can't be applied doesn't fail it
+        if !self.labels.is_empty() {
+            if let Err(e) = self.apply_labels(settings, &token, &thread.message_id) {
+                log!("The labels of the message {} sent from the {} account aren't applied: {}", thread.message_id, self.name, e.message);
+            }
+        }
+        let reply = format!("Gmail API message {} in thread {}", thread.message_id, thread.thread_id);
+        *self.ids.0.lock().unwrap_or_else(|e| e.into_inner()) = Some(thread);
+
+        Ok(reply)
+    }
+}
@@ -0,0 +1,104 @@
+//
+// Extra headers of the messages built by the plugin
+//
+
+use email_encoding::headers::writer::EmailWriter;
+use lettre::Message;
+use lettre::message::header::{HeaderName, HeaderValue};
+
+use crate::{Mail, SendError, SMTP_CLIENT};
+
+// set from the request fields, the config can't replace them
+const RESERVED: &[&str] = &[
+    "from", "sender", "reply-to", "to", "cc", "bcc", "subject", "date",
+    "message-id", "mime-version", "content-type", "content-transfer-encoding",
+];
+
+pub fn check(
+    name: &str,
+    value: &str,
+) -> Result<(), SendError> {
+
+    let valid = !name.is_empty()
+        && name.bytes().all(|b| b.is_ascii_graphic() && b != b':');
+    if !valid || RESERVED.contains(&name.to_ascii_lowercase().as_str()) {
+        return Err(SendError::new("invalid_header", format!("The header {:?} can't be set", name)));
+    }
+    if value.contains(['\r', '\n']) {
+        return Err(SendError::new("invalid_header", format!("The header {} has a line break", name)));
+    }
+
+    Ok(())
+}
+
+fn insert(
+    email: &mut Message,
+    name: &str,
+    value: &str,
+) -> Result<(), SendError> {
+
+    check(name, value)?;
+    let name = HeaderName::new_from_ascii(name.to_string())
+        .map_err(|e| SendError::new("invalid_header", format!("The header {:?} can't be set: {}", name, e)))?;
+    email.headers_mut().insert_raw(HeaderValue::new(name, value.to_string()));
+
+    Ok(())
+}
+
+// lettre encodes the words of a subject one by one and leaves the spaces after
+// them out of the encoded words, where RFC 2047 drops them between two of them:
+// a subject with a run of spaces is encoded whole so the decoded one keeps them
+fn subject(
+    email: &mut Message,
+    subject: &str,
+) {
+    if subject.is_ascii() || !subject.contains("  ") {
+        return;
+    }
+
+    let mut encoded = String::new();
+    let written = email_encoding::headers::rfc2047::encode(
+        subject,
+        &mut EmailWriter::new(&mut encoded, "Subject: ".len(), 0, false),
+    );
+    if written.is_ok() {
+        email.headers_mut().insert_raw(HeaderValue::dangerous_new_pre_encoded(
+            HeaderName::new_from_ascii_str("Subject"),
+            subject.to_string(),
+            encoded,
+        ));
+    }
+}
+
+// the headers of the config, on every message: X-Mailer, X-Environment...,
+// those of the content policies and those asked by the request
+pub fn apply(
+    email: &mut Message,
+    mail: &Mail,
+) -> Result<(), SendError> {
+
+    subject(email, &mail.subject);
+    for (name, value) in &SMTP_CLIENT.headers {
+        insert(email, name, value)?;
+    }
+    for (name, value) in &mail.policy_headers {
+        insert(email, name, value)?;
+    }
+
+    // no out-of-office or auto-reply in response (RFC 3834), loops stop here
+    if mail.automated.unwrap_or(false) {
+        insert(email, "Auto-Submitted", "auto-generated")?;
+        insert(email, "Precedence", "bulk")?;
+        insert(email, "X-Auto-Response-Suppress", "All")?;
+    }
+
+    // the recipient may decline to send the MDN (RFC 8098)
+    if mail.request_read_receipt.unwrap_or(false) {
+        let address = SMTP_CLIENT.read_receipt_to.as_deref()
+            .unwrap_or(&mail.from);
+        let mailbox = crate::parse_mailbox(address)?;
+        insert(email, "Disposition-Notification-To", &mailbox.to_string())?;
+    }
+
+    Ok(())
+}
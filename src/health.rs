@@ -0,0 +1,225 @@
+//
+// Health of the plugin: the quick answer of /health, and the self-test of
+// /health?deep=true that checks each step a send depends on, also run at load
+// with "self_test"
+//
+
+use std::sync::atomic::{AtomicI64, AtomicU64, Ordering};
+use std::time::Instant;
+use lettre::transport::smtp::authentication::{Credentials, DEFAULT_MECHANISMS};
+use lettre::transport::smtp::client::SmtpConnection;
+use lettre::transport::smtp::extension::ClientId;
+use once_cell::sync::Lazy;
+use serde::Serialize;
+
+use crate::pool::Failure;
+use crate::transport::{Gmail, Sent};
+
+// port of the SMTPS relay of the Gmail accounts
+
+// the time of the last send that went out, kept in the database across the
+// restarts, and the sends that failed since
+static LAST_SENT: Lazy<AtomicI64> = Lazy::new(|| AtomicI64::new(crate::db::state("last_sent")
+    .and_then(|at| at.parse().ok())
+    .unwrap_or(0)));
+static FAILURES: AtomicU64 = AtomicU64::new(0);
+
+// the figures of /health to alert on, mail not going out rather than single
+// errors
+#[derive(Serialize)]
+pub struct Stats {
+    // none without the database
+    queue: Option<crate::queue::Depth>,
+    pool: crate::pool::Usage,
+    last_sent_at: Option<i64>,
+    last_sent_secs: Option<i64>,
+    consecutive_failures: u64,
+    // the history and audit rows waiting for the database
+    persistence: crate::persistence::Status,
+}
+
+#[derive(Serialize)]
+pub struct Check {
+    // dns, connect, tls, auth, templates or database
+    name: &'static str,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    account: Option<String>,
+    pub ok: bool,
+    duration_ms: u128,
+    detail: String,
+    // what to look at when the check failed
+    #[serde(skip_serializing_if = "Option::is_none")]
+    hint: Option<&'static str>,
+}
+
+fn timed<T>(
+    name: &'static str,
+    account: Option<&str>,
+    hint: &'static str,
+    check: impl FnOnce() -> Result<(T, String), String>,
+    checks: &mut Vec<Check>,
+) -> Option<T> {
+
+    let started = Instant::now();
+    let result = check();
+    let (value, ok, detail) = match result {
+        Ok((value, detail)) => (Some(value), true, detail),
+        Err(detail) => (None, false, detail),
+    };
+
+    checks.push(Check {
+        name,
+        account: account.map(str::to_string),
+        ok,
+        duration_ms: started.elapsed().as_millis(),
+        detail,
+        hint: (!ok).then_some(hint),
+    });
+
+    value
+}
+
+// the suppressed, rate limited and known invalid recipients are refused by the plugin, they
+// say nothing of the sends
+pub fn record(result: &Result<Sent, Failure>) {
+    match result {
+        Ok(_) => {
+            let now = crate::db::now();
+            LAST_SENT.store(now, Ordering::Relaxed);
+            FAILURES.store(0, Ordering::Relaxed);
+            let _ = crate::db::set_state("last_sent", &now.to_string());
+        },
+        Err(Failure::Suppressed(_) | Failure::RecipientLimited(_) | Failure::KnownInvalid(_)) => {},
+        Err(_) => {
+            FAILURES.fetch_add(1, Ordering::Relaxed);
+        },
+    }
+}
+
+pub fn stats() -> Stats {
+
+    let last_sent = Some(LAST_SENT.load(Ordering::Relaxed))
+        .filter(|at| *at > 0);
+
+    Stats {
+        queue: crate::queue::depth().ok(),
+        pool: crate::pool::usage(),
+        last_sent_at: last_sent,
+        last_sent_secs: last_sent.map(|at| (crate::db::now() - at).max(0)),
+        consecutive_failures: FAILURES.load(Ordering::Relaxed),
+        persistence: crate::persistence::status(),
+    }
+}
+
+// each step needs the one before, the test stops at the first that fails
+fn account(
+    account: &Gmail,
+    checks: &mut Vec<Check>,
+) {
+
+    let name = Some(account.name.as_str());
+    let timeout = crate::pool::timeout(None);
+
+    let Some(addresses) = timed("dns", name, "Check the server name and the DNS resolver of the host, or the \"dns\" settings", || {
+        let addresses = crate::dns::lookup_from(&crate::dns::settings(), &account.server, crate::profile::port(), account.bind_address)?;
+        let ips: Vec<String> = addresses.iter()
+            .map(|addr| addr.ip().to_string())
+            .collect();
+        Ok((addresses, format!("{} is {}", account.server, ips.join(", "))))
+    }, checks) else {
+        return;
+    };
+
+    // the address the sends would connect to
+    let Some(addr) = timed("connect", name, "Check that the firewall lets the host reach port 465, from the bind address if one is set", || {
+        let (addr, stream) = crate::connect::establish(crate::connect::settings(), &addresses, account.bind_address, timeout)?;
+        let detail = match stream.local_addr() {
+            Ok(local) => format!("Connected to {} from {}", addr, local.ip()),
+            Err(_) => format!("Connected to {}", addr),
+        };
+        Ok((addr, detail))
+    }, checks) else {
+        return;
+    };
+
+    let connected = match crate::profile::dev() {
+        // a dev relay speaks plain SMTP
+        true => timed("smtp", name, "Start the dev relay and check the port of \"dev\"", || {
+            SmtpConnection::connect(addr, Some(timeout), &ClientId::default(), None, account.bind_address)
+                .map(|connection| (connection, format!("Plain SMTP session with the dev relay {}", account.server)))
+                .map_err(|e| e.to_string())
+        }, checks),
+        false => timed("tls", name, "The server must accept implicit TLS (SMTPS) on port 465 with a certificate of its name, within the \"tls\" policy", || {
+            let tls = crate::tls::parameters(&account.server)?;
+            let mut connection = SmtpConnection::connect(addr, Some(timeout), &ClientId::default(), Some(&tls), account.bind_address)
+                .map_err(|e| format!("{} ({})", e, crate::tls::describe()))?;
+            if let Err(e) = crate::tls::check_pins(&connection) {
+                connection.abort();
+                return Err(e);
+            }
+            Ok((connection, format!("TLS session with {} ({})", account.server, crate::tls::describe())))
+        }, checks),
+    };
+    let Some(mut connection) = connected else {
+        return;
+    };
+
+    if !crate::profile::authenticates(&account.username) {
+        let _ = connection.quit();
+        return;
+    }
+    let credentials = Credentials::new(account.username.to_string(), account.password.to_string());
+    timed("auth", name, "Use an app password of the account, its Gmail password is refused when 2-step verification is on", || {
+        connection.auth(DEFAULT_MECHANISMS, &credentials)
+            .map(|response| ((), format!("Logged in as {}: {}", account.username, response.code())))
+            .map_err(|e| e.to_string())
+    }, checks);
+    let _ = connection.quit();
+}
+
+pub fn self_test() -> Vec<Check> {
+
+    let mut checks = Vec::new();
+
+    for gmail in Gmail::all() {
+        account(&gmail, &mut checks);
+    }
+
+    timed("templates", None, "Create the templates directory and let the user of the host read it", || {
+        let dir = crate::templates::dir()
+            .map_err(|e| e.message)?;
+        let count = std::fs::read_dir(&dir)
+            .map_err(|e| format!("{}: {}", dir.display(), e))?
+            .count();
+        Ok(((), format!("{} entries in {}", count, dir.display())))
+    }, &mut checks);
+
+    timed("database", None, "Check the database path and that the user of the host can write to its directory", || {
+        crate::db::set_state("self_test", &crate::db::now().to_string())?;
+        Ok(((), "Written".to_string()))
+    }, &mut checks);
+
+    checks
+}
+
+// at load the results are only logged
+pub fn self_test_at_load() {
+
+    let result = std::thread::Builder::new()
+        .name("arp-gmail-self-test".to_string())
+        .spawn(|| {
+            for check in self_test() {
+                let step = match &check.account {
+                    Some(account) => format!("{} of {}", check.name, account),
+                    None => check.name.to_string(),
+                };
+                match check.hint {
+                    None => log!("Self-test {} passed: {}", step, check.detail),
+                    Some(hint) => log!("Self-test {} failed: {}. {}", step, check.detail, hint),
+                }
+            }
+        });
+    if let Err(e) = result {
+        log!("Error starting the self-test thread: {}", e);
+    }
+}
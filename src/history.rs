@@ -0,0 +1,126 @@
+//
+// History of sent messages, kept by the storage of the plugin
+//
+
+use serde::{Deserialize, Serialize};
+
+use crate::SMTP_CLIENT;
+use crate::{db, storage};
+use crate::page::Page;
+
+pub const SCHEMA: &str = "CREATE TABLE IF NOT EXISTS history (
+    id INTEGER PRIMARY KEY AUTOINCREMENT,
+    message_id TEXT,
+    created_at INTEGER NOT NULL,
+    sender TEXT NOT NULL,
+    recipients TEXT NOT NULL,
+    subject TEXT NOT NULL,
+    status TEXT NOT NULL,
+    response TEXT NOT NULL,
+    eml BLOB
+);";
+
+fn default_max_eml_bytes() -> usize {
+    10 * 1024 * 1024
+}
+
+#[derive(Clone, Deserialize, Serialize)]
+pub struct HistorySettings {
+    // keep the raw MIME of sent messages
+    #[serde(default)]
+    store_eml: bool,
+    // bigger messages are recorded without the raw MIME
+    #[serde(default = "default_max_eml_bytes")]
+    max_eml_bytes: usize,
+}
+
+pub struct Record<'a> {
+    pub message_id: Option<&'a str>,
+    pub from: &'a str,
+    pub to: &'a str,
+    pub subject: &'a str,
+    pub status: &'a str,
+    pub response: &'a str,
+    pub eml: Option<&'a [u8]>,
+}
+
+#[derive(Serialize)]
+pub struct Entry {
+    pub id: i64,
+    pub message_id: Option<String>,
+    pub created_at: i64,
+    pub from: String,
+    pub to: String,
+    pub subject: String,
+    pub status: String,
+    pub response: String,
+    pub eml_size: Option<i64>,
+    pub request_id: Option<String>,
+}
+
+// returns the id of the record, history failures never fail a send
+pub fn record(record: Record) -> Option<i64> {
+
+    let settings = SMTP_CLIENT.history.as_ref()?;
+
+    // the raw MIME has the bodies, it isn't kept in privacy mode
+    let eml = record.eml
+        .filter(|eml| settings.store_eml && eml.len() <= settings.max_eml_bytes)
+        .filter(|_| !crate::privacy::enabled());
+    let subject = match crate::privacy::store_subjects() {
+        true => record.subject,
+        false => "",
+    };
+
+    crate::persistence::save(crate::persistence::Row::History(crate::persistence::History {
+        message_id: record.message_id.map(str::to_string),
+        created_at: db::now(),
+        sender: record.from.to_string(),
+        recipients: crate::privacy::redact(record.to),
+        subject: subject.to_string(),
+        status: record.status.to_string(),
+        response: crate::privacy::redact(record.response),
+        eml: eml.map(<[u8]>::to_vec),
+        request_id: crate::trace::current(),
+        tenant: crate::tenant::key(),
+    }))
+}
+
+// an entry of record(), or one that waited for the database
+pub fn insert(row: &crate::persistence::History) -> Result<i64, String> {
+    storage::store()?.insert_history(&storage::HistoryRow {
+        message_id: row.message_id.as_deref(),
+        created_at: row.created_at,
+        sender: &row.sender,
+        recipients: &row.recipients,
+        subject: &row.subject,
+        status: &row.status,
+        response: &row.response,
+        eml: row.eml.as_deref(),
+        request_id: row.request_id.as_deref(),
+        tenant: &row.tenant,
+    })
+}
+
+fn enabled() -> Result<(), String> {
+    match SMTP_CLIENT.history {
+        Some(_) => Ok(()),
+        None => Err("History is disabled".to_string()),
+    }
+}
+
+// a page of the entries of the tenant of the request, all of them for the
+// instance, newest first, and the count of every entry
+pub fn list(page: &Page) -> Result<(Vec<Entry>, usize), String> {
+
+    enabled()?;
+    let (limit, offset) = page.sql();
+    storage::store()?.history(&crate::tenant::key(), limit, offset)
+}
+
+// raw MIME of a sent message, None if it wasn't stored
+pub fn eml(id: i64) -> Result<Option<Vec<u8>>, String> {
+
+    enabled()?;
+    storage::store()?.history_eml(&crate::tenant::key(), id)
+}
@@ -0,0 +1,245 @@
+//
+// Hook scripts around the send pipeline: external commands, or WASI modules
+// run by a runtime, reading the mail as JSON on stdin. The pre_send hooks run
+// before the content policies and can change the mail, add headers, reject it
+// or annotate the answer; the post_send hooks get the result of the send and
+// can only annotate it
+//
+
+use std::collections::BTreeMap;
+use std::time::Duration;
+use serde::{Deserialize, Serialize};
+use serde_json::{Map, Value};
+
+use crate::{Mail, SendError, SMTP_CLIENT};
+
+pub const PRE_SEND: &str = "pre_send";
+pub const POST_SEND: &str = "post_send";
+
+pub const REJECT: &str = "reject";
+pub const IGNORE: &str = "ignore";
+
+// the mail with its attachments, and what a hook writes back
+const MAX_OUTPUT_BYTES: usize = 32 * 1024 * 1024;
+
+fn default_runtime() -> Vec<String> {
+    vec!["wasmtime".to_string(), "run".to_string()]
+}
+
+fn default_timeout_secs() -> u64 {
+    5
+}
+
+fn default_on_failure() -> String {
+    REJECT.to_string()
+}
+
+#[derive(Clone, Deserialize, Serialize)]
+pub struct Hook {
+    // of the logs and the errors
+    pub name: String,
+    // "pre_send" or "post_send"
+    pub stage: String,
+    // the program and its arguments
+    #[serde(default)]
+    pub command: Vec<String>,
+    // a WASI module instead of a command, relative to the plugin directory
+    pub wasm: Option<String>,
+    // the runtime of the module and its arguments, the module is the last one
+    #[serde(default = "default_runtime")]
+    pub runtime: Vec<String>,
+    #[serde(default = "default_timeout_secs")]
+    pub timeout_secs: u64,
+    // a pre_send hook that fails, times out or writes an invalid answer:
+    // "reject" fails the send, "ignore" sends the mail as it was
+    #[serde(default = "default_on_failure")]
+    pub on_failure: String,
+}
+
+#[derive(Serialize)]
+struct Input<'a> {
+    stage: &'a str,
+    hook: &'a str,
+    mail: &'a Mail,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    result: Option<&'a Value>,
+}
+
+// the answer of a hook on stdout, none leaves the mail as it is
+#[derive(Default, Deserialize)]
+struct Output {
+    // "continue" (the default) or "reject"
+    action: Option<String>,
+    reason: Option<String>,
+    // the fields of the mail the hook replaces
+    mail: Option<Map<String, Value>>,
+    // added to the message like the ones of the content policies
+    #[serde(default)]
+    headers: BTreeMap<String, String>,
+    // returned in the "annotations" of the answer
+    #[serde(default)]
+    annotations: Map<String, Value>,
+}
+
+fn hooks(stage: &'static str) -> impl Iterator<Item = &'static Hook> {
+    SMTP_CLIENT.hooks.iter()
+        .flatten()
+        .filter(move |hook| hook.stage == stage)
+}
+
+// the prebuilt and forwarded messages have no mail to give the hooks, they
+// aren't sent
+pub fn pre_send_enabled() -> bool {
+    hooks(PRE_SEND).next().is_some()
+}
+
+fn command(hook: &Hook) -> Result<Vec<String>, String> {
+    let Some(module) = &hook.wasm else {
+        return Ok(hook.command.clone());
+    };
+    let module = crate::plugin_path()
+        .map_err(|e| e.to_string())?
+        .join(module);
+    Ok(hook.runtime.iter()
+        .cloned()
+        .chain([module.display().to_string()])
+        .collect())
+}
+
+fn run(
+    hook: &Hook,
+    mail: &Mail,
+    result: Option<&Value>,
+) -> Result<Output, String> {
+
+    let input = serde_json::to_vec(&Input {
+        stage: &hook.stage,
+        hook: &hook.name,
+        mail,
+        result,
+    }).map_err(|e| e.to_string())?;
+
+    let output = crate::pipe::run(&command(hook)?, &input, Duration::from_secs(hook.timeout_secs), MAX_OUTPUT_BYTES)?;
+    if output.trim_ascii().is_empty() {
+        return Ok(Output::default());
+    }
+    serde_json::from_slice(&output)
+        .map_err(|e| format!("Invalid answer: {}", e))
+}
+
+// the fields of the answer over the ones of the mail, except those the
+// plugin sets
+fn merge(
+    mail: &Mail,
+    fields: Map<String, Value>,
+) -> Result<Mail, String> {
+
+    let mut value = serde_json::to_value(mail)
+        .map_err(|e| e.to_string())?;
+    if let Some(object) = value.as_object_mut() {
+        object.extend(fields);
+    }
+    let mut changed: Mail = serde_json::from_value(value)
+        .map_err(|e| format!("Invalid mail: {}", e))?;
+
+    changed.tenant = mail.tenant.clone();
+    changed.request_id = mail.request_id.clone();
+    changed.batch = mail.batch;
+    changed.policy_headers = mail.policy_headers.clone();
+    changed.accepted_ms = mail.accepted_ms;
+    changed.consent = mail.consent;
+    // a debug send is only for the keys with the admin scope
+    changed.debug = mail.debug;
+    changed.raw_mime = None;
+
+    Ok(changed)
+}
+
+// the changed mail, or the reason of a rejection
+fn answer(
+    mail: &Mail,
+    output: &mut Output,
+) -> Result<Result<Option<Mail>, String>, String> {
+
+    for (name, value) in &output.headers {
+        crate::headers::check(name, value)
+            .map_err(|e| e.message)?;
+    }
+    match output.action.as_deref() {
+        None | Some("continue") => {},
+        Some("reject") => return Ok(Err(output.reason.take()
+            .unwrap_or("the message isn't allowed".to_string()))),
+        Some(action) => return Err(format!("Invalid action {:?}, expected \"continue\" or \"reject\"", action)),
+    }
+
+    match output.mail.take() {
+        Some(fields) => merge(mail, fields).map(|mail| Ok(Some(mail))),
+        None => Ok(Ok(None)),
+    }
+}
+
+// in order, each one gets the mail the one before it changed; the annotations
+// of the hooks
+pub fn pre_send(mail: &mut Mail) -> Result<Map<String, Value>, SendError> {
+
+    let mut annotations = Map::new();
+    for hook in hooks(PRE_SEND) {
+        let mut output = match run(hook, mail, None) {
+            Ok(output) => output,
+            Err(e) => {
+                failed(hook, &e)?;
+                continue;
+            },
+        };
+        match answer(mail, &mut output) {
+            Ok(Ok(changed)) => {
+                if let Some(changed) = changed {
+                    *mail = changed;
+                }
+                mail.policy_headers.extend(output.headers);
+                annotations.extend(output.annotations);
+            },
+            Ok(Err(reason)) => return Err(SendError::new(
+                "hook_rejected",
+                format!("Refused by the hook {}: {}", hook.name, reason),
+            )),
+            Err(e) => failed(hook, &e)?,
+        }
+    }
+
+    Ok(annotations)
+}
+
+fn failed(
+    hook: &Hook,
+    error: &str,
+) -> Result<(), SendError> {
+
+    crate::telemetry::count("arp_gmail.hooks.failed", &[("hook", &hook.name)]);
+    if hook.on_failure == IGNORE {
+        log!("The hook {} failed, the mail is sent as it was: {}", hook.name, error);
+        return Ok(());
+    }
+
+    Err(SendError::new("hook_failed", format!("The hook {} failed: {}", hook.name, error)))
+}
+
+// the message is sent or refused by now, a failure is only logged
+pub fn post_send(
+    mail: &Mail,
+    result: &Value,
+) -> Map<String, Value> {
+
+    let mut annotations = Map::new();
+    for hook in hooks(POST_SEND) {
+        match run(hook, mail, Some(result)) {
+            Ok(output) => annotations.extend(output.annotations),
+            Err(e) => {
+                crate::telemetry::count("arp_gmail.hooks.failed", &[("hook", &hook.name)]);
+                log!("The hook {} failed: {}", hook.name, e);
+            },
+        }
+    }
+
+    annotations
+}
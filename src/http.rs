@@ -0,0 +1,184 @@
+//
+// The HTTP status and headers of the responses, for the hosts that ask
+// response_headers() instead of guessing them from the response_type of the
+// route: Content-Type, Retry-After of the refusals that pass and X-Request-Id;
+// the status of an NDJSON response is the one of its summary line
+//
+
+use std::ffi::{c_char, CStr};
+use serde::{Deserialize, Serialize};
+
+use crate::{buffers, ROUTES};
+
+// Retry-After of a refusal that doesn't know when it ends
+const RETRY_SECS: i64 = 60;
+
+const JSON: &str = "application/json";
+const TEXT: &str = "text/plain; charset=utf-8";
+
+// the text routes that aren't plain text
+const CONTENT_TYPES: &[(&str, &str)] = &[
+    ("history_eml", "message/rfc822"),
+    ("jobs_report_csv", "text/csv; charset=utf-8"),
+];
+
+#[derive(Serialize)]
+pub struct Meta {
+    status: u16,
+    headers: std::collections::BTreeMap<&'static str, String>,
+}
+
+// the fields of a JSON response that set its status and headers
+#[derive(Default, Deserialize)]
+struct Head {
+    status: Option<String>,
+    code: Option<String>,
+    retryable: Option<bool>,
+    retry_after: Option<i64>,
+    request_id: Option<String>,
+}
+
+fn error_status(
+    function: Option<&str>,
+    head: &Head,
+) -> u16 {
+    match head.code.as_deref() {
+        Some("unauthorized") => 401,
+        Some("forbidden") => 403,
+        Some("not_found") => 404,
+        Some("expired") => 410,
+        Some("policy_violation" | "hook_rejected" | "suppressed" | "known_invalid_recipient") => 422,
+        Some("budget_exceeded" | "quota_exceeded" | "recipient_rate_limited") => 429,
+        Some("paused" | "busy" | "shutting_down" | "account_unavailable" | "not_configured" | "storage_unavailable") => 503,
+        Some("timeout") => 504,
+        Some(code) if code.starts_with("invalid_") || code == "template_error" => 400,
+        Some("internal_error") => 500,
+        // a failed send, the relay refused it or couldn't be reached
+        _ if head.retryable.is_some() => 502,
+        _ if function == Some("health") => 503,
+        _ => 500,
+    }
+}
+
+pub fn meta(
+    function: Option<&str>,
+    response: &[u8],
+) -> Meta {
+
+    let route = ROUTES.iter().find(|route| Some(route.function) == function);
+    let text = route.is_some_and(|route| route.response_type == "text");
+
+    let mut headers = std::collections::BTreeMap::new();
+    if text {
+        let content_type = CONTENT_TYPES.iter()
+            .find(|(name, _)| Some(*name) == function)
+            .map_or(TEXT, |(_, content_type)| content_type);
+        // the errors of the text routes are JSON or an "Error: " line
+        let status = match response.first() {
+            Some(b'{') => None,
+            _ if response.starts_with(b"Error: ") => Some((400, TEXT)),
+            _ => Some((200, content_type)),
+        };
+        if let Some((status, content_type)) = status {
+            headers.insert("Content-Type", content_type.to_string());
+            return Meta { status, headers };
+        }
+    }
+
+    // a JSON response is a single line, NDJSON ends every line with a newline
+    // and has the summary last
+    let ndjson = response.ends_with(b"\n");
+    let response = match ndjson {
+        true => response[..response.len() - 1].rsplit(|byte| *byte == b'\n').next().unwrap_or_default(),
+        false => response,
+    };
+    headers.insert("Content-Type", if ndjson { crate::ndjson::MEDIA_TYPE } else { JSON }.to_string());
+    let head: Head = serde_json::from_slice(response).unwrap_or_default();
+    let status = match head.status.as_deref() {
+        Some("error") => error_status(function, &head),
+        Some("queued" | "pending") => 202,
+        _ => 200,
+    };
+    // a 429 always has one, a 503 when it is retryable
+    if status == 429 || (status == 503 && head.retryable == Some(true)) {
+        headers.insert("Retry-After", head.retry_after.unwrap_or(RETRY_SECS).to_string());
+    }
+    if let Some(request_id) = head.request_id {
+        headers.insert("X-Request-Id", request_id);
+    }
+
+    Meta { status, headers }
+}
+
+// the metadata of a response the host still has, None for a freed or foreign
+// pointer
+pub fn of(ptr: *const c_char) -> Option<Meta> {
+    if std::ptr::eq(ptr, crate::FALLBACK.as_ptr()) {
+        return Some(meta(None, crate::FALLBACK.to_bytes()));
+    }
+    let function = buffers::function(ptr)?;
+    let response = unsafe { CStr::from_ptr(ptr) };
+    Some(meta(function, response.to_bytes()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn header(
+        meta: &Meta,
+        name: &str,
+    ) -> Option<String> {
+        meta.headers.get(name).cloned()
+    }
+
+    #[test]
+    fn status_and_retry_after() {
+        let cases: &[(&str, &str, u16, Option<&str>)] = &[
+            ("sendmail", r#"{"status":"success"}"#, 200, None),
+            ("sendmail", r#"{"status":"queued","job":1}"#, 202, None),
+            ("sendmail", r#"{"status":"pending"}"#, 202, None),
+            ("sendmail", r#"{"status":"error","code":"unauthorized"}"#, 401, None),
+            ("sendmail", r#"{"status":"error","code":"forbidden"}"#, 403, None),
+            ("jobs", r#"{"status":"error","code":"not_found"}"#, 404, None),
+            ("sendmail", r#"{"status":"error","code":"expired"}"#, 410, None),
+            ("sendmail", r#"{"status":"error","code":"invalid_request"}"#, 400, None),
+            ("sendmail", r#"{"status":"error","code":"template_error"}"#, 400, None),
+            ("sendmail", r#"{"status":"error","code":"suppressed"}"#, 422, None),
+            // a 429 always says when to retry, the default when the refusal doesn't know
+            ("sendmail", r#"{"status":"error","code":"quota_exceeded","retry_after":120}"#, 429, Some("120")),
+            ("sendmail", r#"{"status":"error","code":"budget_exceeded"}"#, 429, Some("60")),
+            // a 503 only when it is retryable
+            ("sendmail", r#"{"status":"error","code":"paused","retryable":true,"retry_after":5}"#, 503, Some("5")),
+            ("sendmail", r#"{"status":"error","code":"not_configured"}"#, 503, None),
+            ("sendmail", r#"{"status":"error","code":"timeout"}"#, 504, None),
+            ("sendmail", r#"{"status":"error","code":"internal_error"}"#, 500, None),
+            // a failed send, or an error without a code
+            ("sendmail", r#"{"status":"error","code":"smtp_error","retryable":false}"#, 502, None),
+            ("health", r#"{"status":"error"}"#, 503, None),
+            ("sendmail", r#"{"status":"error"}"#, 500, None),
+        ];
+        for (function, response, status, retry_after) in cases {
+            let meta = meta(Some(function), response.as_bytes());
+            assert_eq!(meta.status, *status, "{}", response);
+            assert_eq!(header(&meta, "Retry-After").as_deref(), *retry_after, "{}", response);
+            assert_eq!(header(&meta, "Content-Type").as_deref(), Some(JSON), "{}", response);
+        }
+    }
+
+    #[test]
+    fn text_and_ndjson_responses() {
+        let eml = meta(Some("history_eml"), b"From: a@example.com\r\n\r\nhi");
+        assert_eq!((eml.status, header(&eml, "Content-Type").as_deref()), (200, Some("message/rfc822")));
+        let failed = meta(Some("history_eml"), b"Error: no such entry");
+        assert_eq!((failed.status, header(&failed, "Content-Type").as_deref()), (400, Some(TEXT)));
+        let json = meta(Some("history_eml"), br#"{"status":"error","code":"not_found","request_id":"r1"}"#);
+        assert_eq!((json.status, header(&json, "X-Request-Id").as_deref()), (404, Some("r1")));
+
+        // the status of the summary line
+        let ndjson = meta(Some("sendbulk"), b"{\"status\":\"success\"}\n{\"status\":\"error\",\"code\":\"busy\",\"retryable\":true}\n");
+        assert_eq!(ndjson.status, 503);
+        assert_eq!(header(&ndjson, "Content-Type").as_deref(), Some(crate::ndjson::MEDIA_TYPE));
+        assert_eq!(header(&ndjson, "Retry-After").as_deref(), Some("60"));
+    }
+}
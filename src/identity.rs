@@ -0,0 +1,124 @@
+//
+// Sender identity: the default From, sender name and Reply-To of the config
+// and the From/Sender header pair of a message
+//
+
+use lettre::Address;
+use lettre::message::Mailbox;
+use serde::{Deserialize, Serialize};
+
+use crate::{parse_mailbox, Mail, SendError, SMTP_CLIENT};
+
+// used when the request omits them
+#[derive(Clone, Deserialize, Serialize)]
+pub struct Defaults {
+    from: Option<String>,
+    sender_name: Option<String>,
+    reply_to: Option<String>,
+}
+
+fn is_unset(field: &Option<String>) -> bool {
+    field.as_deref()
+        .is_none_or(|value| value.trim().is_empty())
+}
+
+// fill the missing fields of a request, before it is validated or stored
+pub fn apply(mail: &mut Mail) {
+
+    let defaults = match &SMTP_CLIENT.defaults {
+        Some(defaults) => defaults,
+        None => return,
+    };
+
+    if mail.from.trim().is_empty() {
+        if let Some(from) = &defaults.from {
+            mail.from = from.clone();
+        }
+    }
+    // a name given in the from address wins over the default one
+    let named = mail.from.parse::<Mailbox>()
+        .is_ok_and(|from| from.name.is_some());
+    if is_unset(&mail.sender_name) && !named {
+        mail.sender_name = defaults.sender_name.clone();
+    }
+    if is_unset(&mail.reply_to) {
+        mail.reply_to = defaults.reply_to.clone();
+    }
+}
+
+// headers of the sender of a message
+pub struct Headers {
+    pub from: Mailbox,
+    pub sender: Option<Mailbox>,
+    pub reply_to: Option<Mailbox>,
+}
+
+fn parse_optional(field: &Option<String>) -> Result<Option<Mailbox>, SendError> {
+    match field.as_deref().map(str::trim) {
+        Some(address) if !address.is_empty() => parse_mailbox(address).map(Some),
+        _ => Ok(None),
+    }
+}
+
+// a dev relay sends any From, it has no account to match
+fn policy() -> &'static str {
+    match crate::profile::dev() {
+        true => "allow",
+        false => SMTP_CLIENT.from_policy.as_deref().unwrap_or("allow"),
+    }
+}
+
+// Gmail replaces a From that isn't the account or one of its aliases
+fn is_account(address: &Address) -> bool {
+    parse_mailbox(&SMTP_CLIENT.username)
+        .map_or(true, |account| account.email.to_string().eq_ignore_ascii_case(address.as_ref()))
+}
+
+fn mismatch(address: &Address) -> SendError {
+    SendError::new(
+        "from_mismatch",
+        format!("The from address {} is not the account {}", address, SMTP_CLIENT.username),
+    )
+}
+
+// a prebuilt message is relayed unchanged, "rewrite" can only pass it as is
+pub fn check_prebuilt(from: &Address) -> Result<(), SendError> {
+    match policy() == "enforce" && !is_account(from) {
+        true => Err(mismatch(from)),
+        false => Ok(()),
+    }
+}
+
+// "Name <from>", the Sender when someone else sends on its behalf and the
+// Reply-To, after the from_policy is applied
+pub fn headers(mail: &Mail) -> Result<Headers, SendError> {
+
+    let mut from = parse_mailbox(&mail.from)?;
+    if let Some(name) = mail.sender_name.as_deref().map(str::trim).filter(|name| !name.is_empty()) {
+        from.name = Some(name.to_string());
+    }
+    let mut reply_to = parse_optional(&mail.reply_to)?;
+
+    if !is_account(&from.email) {
+        match policy() {
+            "enforce" => return Err(mismatch(&from.email)),
+            "rewrite" => {
+                log!("From {} rewritten to {}, replies go to the original address", from.email, SMTP_CLIENT.username);
+                if reply_to.is_none() {
+                    reply_to = Some(Mailbox::new(None, from.email.clone()));
+                }
+                from.email = parse_mailbox(&SMTP_CLIENT.username)?.email;
+            },
+            _ => {},
+        }
+    }
+
+    let sender = parse_optional(&mail.sender_email)?
+        .filter(|sender| sender.email != from.email);
+
+    Ok(Headers {
+        from,
+        sender,
+        reply_to,
+    })
+}
@@ -0,0 +1,127 @@
+//
+// Downscaling of the JPEG and PNG attachments bigger than the dimensions of
+// the config, re-encoded by an external command (ImageMagick by default) so a
+// photo of a phone camera fits in the size limit of Gmail
+//
+
+use std::time::Duration;
+use serde::{Deserialize, Serialize};
+
+fn default_max_dimension() -> u32 {
+    1600
+}
+
+fn default_quality() -> u8 {
+    85
+}
+
+fn default_command() -> Vec<String> {
+    ["convert", "{format}:-", "-auto-orient", "-resize", "{width}x{height}>", "-quality", "{quality}", "{format}:-"]
+        .iter()
+        .map(|arg| arg.to_string())
+        .collect()
+}
+
+fn default_timeout_secs() -> u64 {
+    30
+}
+
+#[derive(Clone, Deserialize, Serialize)]
+pub struct ImageSettings {
+    #[serde(default = "default_max_dimension")]
+    pub max_width: u32,
+    #[serde(default = "default_max_dimension")]
+    pub max_height: u32,
+    // of the JPEG encoder, the zlib level for PNG with ImageMagick
+    #[serde(default = "default_quality")]
+    pub quality: u8,
+    // an image within the dimensions is still re-encoded when it's bigger
+    pub max_bytes: Option<usize>,
+    // reads the image on stdin and writes it to stdout, {format} {width}
+    // {height} and {quality} replaced in its arguments
+    #[serde(default = "default_command")]
+    pub command: Vec<String>,
+    #[serde(default = "default_timeout_secs")]
+    pub timeout_secs: u64,
+}
+
+// "jpeg" or "png", the formats re-encoded
+pub fn format(content_type: &str) -> Option<&'static str> {
+    match content_type.split(';').next().map(str::trim) {
+        Some(mime) if mime.eq_ignore_ascii_case("image/jpeg") || mime.eq_ignore_ascii_case("image/jpg") => Some("jpeg"),
+        Some(mime) if mime.eq_ignore_ascii_case("image/png") => Some("png"),
+        _ => None,
+    }
+}
+
+// the width and height in the header of the image: the IHDR chunk of a PNG,
+// the start of frame of a JPEG
+pub fn dimensions(image: &[u8]) -> Option<(u32, u32)> {
+
+    let u16_at = |offset: usize| Some(u16::from_be_bytes([*image.get(offset)?, *image.get(offset + 1)?]));
+    let u32_at = |offset: usize| Some(u32::from_be_bytes(image.get(offset..offset + 4)?.try_into().ok()?));
+
+    if image.starts_with(b"\x89PNG\r\n\x1a\n") {
+        return Some((u32_at(16)?, u32_at(20)?));
+    }
+    if !image.starts_with(&[0xff, 0xd8]) {
+        return None;
+    }
+
+    let mut offset = 2;
+    loop {
+        if *image.get(offset)? != 0xff {
+            return None;
+        }
+        let marker = *image.get(offset + 1)?;
+        match marker {
+            // padding, and the markers without a length
+            0xff => offset += 1,
+            0xd0..=0xd7 | 0x01 => offset += 2,
+            0xc0..=0xcf if !matches!(marker, 0xc4 | 0xc8 | 0xcc) => {
+                return Some((u16_at(offset + 7)? as u32, u16_at(offset + 5)? as u32));
+            },
+            _ => offset += 2 + u16_at(offset + 2)? as usize,
+        }
+    }
+}
+
+// the smaller image, none when it is within the limits or the command fails,
+// the original is sent then
+pub fn shrink(
+    settings: &ImageSettings,
+    filename: &str,
+    format: &str,
+    image: &[u8],
+) -> Option<Vec<u8>> {
+
+    let (width, height) = dimensions(image)?;
+    let oversized = width > settings.max_width || height > settings.max_height;
+    let heavy = settings.max_bytes.is_some_and(|max_bytes| image.len() > max_bytes);
+    if !oversized && !heavy {
+        return None;
+    }
+
+    let command: Vec<String> = settings.command.iter()
+        .map(|arg| arg.replace("{format}", format)
+            .replace("{width}", &settings.max_width.to_string())
+            .replace("{height}", &settings.max_height.to_string())
+            .replace("{quality}", &settings.quality.to_string()))
+        .collect();
+    let timeout = Duration::from_secs(settings.timeout_secs);
+
+    match crate::pipe::run(&command, image, timeout, image.len().max(1024 * 1024)) {
+        Ok(smaller) if dimensions(&smaller).is_some() && smaller.len() < image.len() => {
+            log!("Attachment {} re-encoded from {}x{} and {} bytes to {} bytes", filename, width, height, image.len(), smaller.len());
+            Some(smaller)
+        },
+        Ok(_) => {
+            log!("Attachment {} is sent as is, its re-encoding isn't a smaller {}", filename, format);
+            None
+        },
+        Err(e) => {
+            log!("Attachment {} is sent as is, it can't be re-encoded: {}", filename, e);
+            None
+        },
+    }
+}
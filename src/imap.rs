@@ -0,0 +1,218 @@
+//
+// Copies of the sent messages appended over IMAP to a folder of a mailbox,
+// for the servers that don't save them to Sent like Gmail does. The appends
+// run on a thread of their own after the send, a failed one is logged and
+// doesn't fail the send
+//
+
+use std::io::{BufRead, BufReader, Write};
+use std::sync::{mpsc, Arc, Mutex};
+use std::time::Duration;
+use once_cell::sync::Lazy;
+use serde::{Deserialize, Serialize};
+
+use crate::SMTP_CLIENT;
+use crate::shutdown;
+
+fn default_port() -> u16 {
+    993
+}
+
+fn default_folder() -> String {
+    "Sent".to_string()
+}
+
+fn default_backlog() -> usize {
+    100
+}
+
+#[derive(Clone, Deserialize, Serialize)]
+pub struct ImapSettings {
+    // the IMAPS server of the mailbox, implicit TLS
+    pub server: String,
+    #[serde(default = "default_port")]
+    pub port: u16,
+    pub username: String,
+    pub password: String,
+    #[serde(default = "default_folder")]
+    pub folder: String,
+    // the accounts whose sends are copied, every one if empty
+    #[serde(default)]
+    pub accounts: Vec<String>,
+    // copies waiting for the thread, more are dropped and logged
+    #[serde(default = "default_backlog")]
+    pub backlog: usize,
+}
+
+type Copy = (String, Arc<Vec<u8>>);
+
+// started on the first copy, none if the thread couldn't be started
+static COPIES: Lazy<Option<Mutex<mpsc::SyncSender<Copy>>>> = Lazy::new(|| {
+
+    let settings = SMTP_CLIENT.imap.as_ref()?;
+    let (sender, receiver) = mpsc::sync_channel::<Copy>(settings.backlog.max(1));
+    let result = std::thread::Builder::new()
+        .name("arp-gmail-imap".to_string())
+        .spawn(move || work(settings, &receiver));
+    match result {
+        Ok(handle) => {
+            shutdown::register("imap", handle);
+            Some(Mutex::new(sender))
+        },
+        Err(e) => {
+            log!("Error starting the IMAP thread: {}", e);
+            None
+        },
+    }
+});
+
+// the copies queued when the shutdown starts are still appended
+fn work(
+    settings: &ImapSettings,
+    receiver: &mpsc::Receiver<Copy>,
+) {
+    loop {
+        let (account, raw) = match receiver.recv_timeout(Duration::from_millis(100)) {
+            Ok(copy) => copy,
+            Err(mpsc::RecvTimeoutError::Timeout) if !shutdown::stopping() => continue,
+            Err(mpsc::RecvTimeoutError::Timeout) => match receiver.try_recv() {
+                Ok(copy) => copy,
+                Err(_) => return,
+            },
+            Err(mpsc::RecvTimeoutError::Disconnected) => return,
+        };
+        if let Err(e) = append(settings, &raw) {
+            log!("The copy of a message sent from the {} account isn't in {}: {}", account, settings.folder, e);
+        }
+    }
+}
+
+// queued after a send of an account the copies are made for
+pub fn copy(
+    account: &str,
+    raw: Arc<Vec<u8>>,
+) {
+
+    let Some(settings) = SMTP_CLIENT.imap.as_ref() else {
+        return;
+    };
+    if !settings.accounts.is_empty() && !settings.accounts.iter().any(|name| name == account) {
+        return;
+    }
+
+    let Some(copies) = COPIES.as_ref() else {
+        return;
+    };
+    let result = copies.lock()
+        .unwrap_or_else(|e| e.into_inner())
+        .try_send((account.to_string(), raw));
+    if result.is_err() {
+        log!("The IMAP backlog is full, the copy of a message sent from the {} account is dropped", account);
+    }
+}
+
+// "text" with the quotes and backslashes escaped, the password isn't in the error
+fn quoted(text: &str) -> Result<String, String> {
+    match text.contains(['\r', '\n', '\0']) || !text.is_ascii() {
+        true => Err("a line break or a non-ASCII character can't be sent in an IMAP string".to_string()),
+        false => Ok(format!("\"{}\"", text.replace('\\', "\\\\").replace('"', "\\\""))),
+    }
+}
+
+struct Session<S: std::io::Read + Write> {
+    stream: BufReader<S>,
+    tag: u32,
+}
+
+impl<S: std::io::Read + Write> Session<S> {
+    fn line(&mut self) -> Result<String, String> {
+        let mut line = String::new();
+        match self.stream.read_line(&mut line) {
+            Ok(0) => Err("the server closed the connection".to_string()),
+            Ok(_) => Ok(line.trim_end().to_string()),
+            Err(e) => Err(e.to_string()),
+        }
+    }
+
+    fn write(
+        &mut self,
+        data: &[u8],
+    ) -> Result<(), String> {
+        self.stream.get_mut()
+            .write_all(data)
+            .and_then(|_| self.stream.get_mut().flush())
+            .map_err(|e| e.to_string())
+    }
+
+    fn send(
+        &mut self,
+        command: &str,
+    ) -> Result<String, String> {
+        self.tag += 1;
+        let tag = format!("a{}", self.tag);
+        self.write(format!("{} {}\r\n", tag, command).as_bytes())?;
+        Ok(tag)
+    }
+
+    // the untagged replies are skipped until the one of the tag
+    fn done(
+        &mut self,
+        tag: &str,
+    ) -> Result<(), String> {
+        loop {
+            let line = self.line()?;
+            let Some(status) = line.strip_prefix(tag).and_then(|rest| rest.strip_prefix(' ')) else {
+                continue;
+            };
+            return match status.get(..2).is_some_and(|ok| ok.eq_ignore_ascii_case("OK")) {
+                true => Ok(()),
+                false => Err(status.to_string()),
+            };
+        }
+    }
+}
+
+fn append(
+    settings: &ImapSettings,
+    raw: &[u8],
+) -> Result<(), String> {
+
+    let timeout = crate::pool::timeout(None);
+    let addresses = crate::dns::lookup(&crate::dns::settings(), &settings.server, settings.port)?;
+    let (_, tcp) = crate::connect::establish(crate::connect::settings(), &addresses, SMTP_CLIENT.bind_address, timeout)?;
+    tcp.set_read_timeout(Some(timeout))
+        .and_then(|_| tcp.set_write_timeout(Some(timeout)))
+        .map_err(|e| e.to_string())?;
+    let stream = crate::tls::connector()?
+        .connect(&settings.server, tcp)
+        .map_err(|e| format!("TLS error: {}", e))?;
+
+    let mut session = Session {
+        stream: BufReader::new(stream),
+        tag: 0,
+    };
+    let greeting = session.line()?;
+    if !greeting.starts_with("* OK") {
+        return Err(format!("unexpected greeting {:?}", greeting));
+    }
+
+    let tag = session.send(&format!("LOGIN {} {}", quoted(&settings.username)?, quoted(&settings.password)?))?;
+    session.done(&tag)
+        .map_err(|e| format!("login failed: {}", e))?;
+
+    // the copy is already read, like the ones Gmail saves
+    let tag = session.send(&format!("APPEND {} (\\Seen) {{{}}}", quoted(&settings.folder)?, raw.len()))?;
+    let line = session.line()?;
+    if !line.starts_with('+') {
+        return Err(format!("append refused: {}", line));
+    }
+    session.write(raw)?;
+    session.write(b"\r\n")?;
+    session.done(&tag)
+        .map_err(|e| format!("append failed: {}", e))?;
+
+    let tag = session.send("LOGOUT")?;
+    let _ = session.done(&tag);
+
+    Ok(())
+}
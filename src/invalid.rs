@@ -0,0 +1,121 @@
+//
+// Negative cache of the recipients a server rejected as unknown (5.1.x): the
+// sends to them are refused for a while instead of hitting the dead mailbox
+// again, which costs quota and hurts the reputation of the sender
+//
+
+use rusqlite::params;
+use serde::{Deserialize, Serialize};
+
+use crate::{db, outcome, SMTP_CLIENT};
+use crate::pool::Failure;
+
+pub const SCHEMA: &str = "CREATE TABLE IF NOT EXISTS invalid_recipients (
+    address TEXT PRIMARY KEY,
+    reply TEXT NOT NULL,
+    failed_at INTEGER NOT NULL
+);";
+
+#[derive(Clone, Deserialize, Serialize)]
+pub struct InvalidRecipientSettings {
+    // a rejected recipient is refused for this long, a week by default
+    #[serde(default = "default_cache_secs")]
+    pub cache_secs: u64,
+}
+
+fn default_cache_secs() -> u64 {
+    7 * 86400
+}
+
+fn key(address: &str) -> String {
+    address.trim().to_lowercase()
+}
+
+// Err with the first recipient rejected inside the period; the sends aren't
+// blocked when the database can't be read
+pub fn check(recipients: &[lettre::Address]) -> Result<(), String> {
+
+    let Some(settings) = &SMTP_CLIENT.invalid_recipients else {
+        return Ok(());
+    };
+    let conn = match db::conn() {
+        Ok(conn) => conn,
+        Err(e) => {
+            log!("Invalid recipient check skipped: {}", e);
+            return Ok(());
+        },
+    };
+
+    for recipient in recipients {
+        let found: Option<(String, i64)> = conn.query_row(
+            "SELECT reply, failed_at FROM invalid_recipients WHERE address = ?1 AND failed_at > ?2",
+            params![key(recipient.as_ref()), db::now() - settings.cache_secs as i64],
+            |row| Ok((row.get(0)?, row.get(1)?)),
+        ).ok();
+        if let Some((reply, failed_at)) = found {
+            return Err(format!(
+                "{} was rejected as unknown on {}: {}",
+                recipient,
+                crate::quota::format_time(failed_at),
+                reply,
+            ));
+        }
+    }
+
+    Ok(())
+}
+
+// the recipients the reply rejects: the one of a single recipient send, or
+// the ones the reply names
+fn rejected<'a>(
+    recipients: &'a [lettre::Address],
+    reply: &str,
+) -> Vec<&'a lettre::Address> {
+    if let [recipient] = recipients {
+        return vec![recipient];
+    }
+    let reply = reply.to_lowercase();
+    recipients.iter()
+        .filter(|recipient| reply.contains(&key(recipient.as_ref())))
+        .collect()
+}
+
+// a permanent "user unknown" of the server is remembered for the recipients
+pub fn record(
+    recipients: &[lettre::Address],
+    failure: &Failure,
+) {
+
+    let Some(settings) = &SMTP_CLIENT.invalid_recipients else {
+        return;
+    };
+    let Failure::Smtp(error) = failure else {
+        return;
+    };
+    let outcome = outcome::classify(failure);
+    if outcome.code != "recipient_rejected" || outcome.retryable {
+        return;
+    }
+
+    let reply = match error.status() {
+        Some(code) => format!("{} {}", code, outcome::reply_text(error).trim()),
+        None => outcome::reply_text(error).trim().to_string(),
+    };
+    let result = db::conn().and_then(|conn| {
+        // the entries past the period go with the new ones
+        conn.execute(
+            "DELETE FROM invalid_recipients WHERE failed_at <= ?1",
+            params![db::now() - settings.cache_secs as i64],
+        ).map_err(|e| e.to_string())?;
+        for recipient in rejected(recipients, &reply) {
+            conn.execute(
+                "INSERT OR REPLACE INTO invalid_recipients (address, reply, failed_at) VALUES (?1, ?2, ?3)",
+                params![key(recipient.as_ref()), reply, db::now()],
+            ).map_err(|e| e.to_string())?;
+        }
+        Ok(())
+    });
+    if let Err(e) = result {
+        log!("Error recording the invalid recipients: {}", e);
+    }
+}
@@ -0,0 +1,388 @@
+//
+// Bulk jobs: the progress of the mails of a /sendbulk or /sendmerge request
+// as the queue sends them
+//
+
+use rusqlite::params;
+use serde::{Deserialize, Serialize};
+
+use crate::{db, SendError};
+use crate::page::Page;
+
+pub const SCHEMA: &str = "CREATE TABLE IF NOT EXISTS jobs (
+    id INTEGER PRIMARY KEY AUTOINCREMENT,
+    kind TEXT NOT NULL,
+    total INTEGER NOT NULL,
+    sent INTEGER NOT NULL DEFAULT 0,
+    failed INTEGER NOT NULL DEFAULT 0,
+    created_at INTEGER NOT NULL,
+    started_at INTEGER,
+    updated_at INTEGER NOT NULL,
+    finished_at INTEGER
+);
+CREATE TABLE IF NOT EXISTS job_results (
+    id INTEGER PRIMARY KEY AUTOINCREMENT,
+    job_id INTEGER NOT NULL,
+    recipient TEXT NOT NULL,
+    status TEXT NOT NULL,
+    code TEXT,
+    response TEXT NOT NULL,
+    message_id TEXT,
+    created_at INTEGER NOT NULL
+);
+CREATE INDEX IF NOT EXISTS job_results_job_id ON job_results (job_id);";
+
+#[derive(Serialize)]
+pub struct Job {
+    id: i64,
+    // "sendbulk" or "sendmerge"
+    kind: String,
+    total: i64,
+    sent: i64,
+    failed: i64,
+    // removed from the queue by a cancel
+    cancelled: i64,
+    remaining: i64,
+    // done percentage
+    progress: u8,
+    // "running", "finished" or "cancelled"
+    status: &'static str,
+    created_at: i64,
+    started_at: Option<i64>,
+    updated_at: i64,
+    finished_at: Option<i64>,
+    cancelled_at: Option<i64>,
+    // seconds to the end at the rate so far
+    #[serde(skip_serializing_if = "Option::is_none")]
+    eta_secs: Option<i64>,
+}
+
+// the outcome of a recipient of the job
+#[derive(Serialize)]
+pub struct JobResult {
+    recipient: String,
+    // "sent", "failed" or "cancelled"
+    status: String,
+    code: Option<String>,
+    // the reply of the server or the error
+    response: String,
+    message_id: Option<String>,
+    time: i64,
+}
+
+#[derive(Deserialize)]
+pub struct JobRequest {
+    // cancel
+    pub action: String,
+    pub id: i64,
+}
+
+fn db_error(e: impl ToString) -> SendError {
+    SendError::new("internal_error", e.to_string())
+}
+
+pub fn create(
+    kind: &str,
+    total: usize,
+) -> Result<i64, SendError> {
+
+    if !crate::storage::jobs_shared() {
+        return Err(SendError::new(
+            "not_configured",
+            "The bulk jobs aren't shared by the instances, they can't be queued with the postgres storage",
+        ));
+    }
+    let conn = db::conn().map_err(db_error)?;
+    conn.execute(
+        "INSERT INTO jobs (kind, total, created_at, updated_at, tenant) VALUES (?1, ?2, ?3, ?3, ?4)",
+        params![kind, total as i64, db::now(), crate::tenant::key()],
+    ).map_err(db_error)?;
+
+    Ok(conn.last_insert_rowid())
+}
+
+// the progress is informative, a failed update doesn't fail the send
+fn update<P: rusqlite::Params>(
+    batch: i64,
+    sql: &str,
+    params: P,
+) {
+    let result = db::conn()
+        .and_then(|conn| conn.execute(sql, params).map_err(|e| e.to_string()));
+    if let Err(e) = result {
+        log!("Error updating the progress of job {}: {}", batch, e);
+    }
+}
+
+// the first mail of the job is being sent, the rate is counted from here
+pub fn started(batch: Option<i64>) {
+    if let Some(batch) = batch {
+        update(batch, "UPDATE jobs SET started_at = COALESCE(started_at, ?2) WHERE id = ?1",
+            params![batch, db::now()]);
+    }
+}
+
+fn done(
+    batch: Option<i64>,
+    sent: usize,
+    failed: usize,
+) {
+    if let Some(batch) = batch {
+        update(batch, "UPDATE jobs SET sent = sent + ?3, failed = failed + ?4, updated_at = ?2,
+            finished_at = CASE WHEN sent + failed + ?3 + ?4 >= total THEN ?2 ELSE finished_at END
+            WHERE id = ?1",
+            params![batch, db::now(), sent as i64, failed as i64]);
+    }
+}
+
+pub fn sent(batch: Option<i64>) {
+    done(batch, 1, 0);
+}
+
+// refused when the job was created or moved to the dead-letter store
+pub fn failed(
+    batch: Option<i64>,
+    count: usize,
+) {
+    done(batch, 0, count);
+}
+
+// the final outcome of a mail of the job, kept for its report; the address
+// and the response are redacted in privacy mode
+pub fn record(
+    batch: Option<i64>,
+    to: &str,
+    status: &str,
+    code: Option<&str>,
+    response: &str,
+    message_id: Option<&str>,
+) {
+    if let Some(batch) = batch {
+        update(batch, "INSERT INTO job_results (job_id, recipient, status, code, response, message_id, created_at)
+            VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7)",
+            params![
+                batch,
+                crate::privacy::redact(to),
+                status,
+                code,
+                crate::privacy::redact(response),
+                message_id,
+                db::now(),
+            ]);
+    }
+}
+
+// a dead-letter entry of the job is queued again
+pub fn requeued(batch: Option<i64>) {
+    if let Some(batch) = batch {
+        update(batch, "UPDATE jobs SET failed = MAX(failed - 1, 0), updated_at = ?2, finished_at = NULL
+            WHERE id = ?1",
+            params![batch, db::now()]);
+    }
+}
+
+fn entry(row: &rusqlite::Row) -> rusqlite::Result<Job> {
+
+    let (total, sent, failed, cancelled): (i64, i64, i64, i64) = (row.get(2)?, row.get(3)?, row.get(4)?, row.get(9)?);
+    let started_at: Option<i64> = row.get(6)?;
+    let updated_at: i64 = row.get(7)?;
+    let finished_at: Option<i64> = row.get(8)?;
+    let cancelled_at: Option<i64> = row.get(10)?;
+
+    let done = sent + failed;
+    let remaining = (total - done - cancelled).max(0);
+    let eta_secs = match (started_at, finished_at) {
+        (Some(started_at), None) if done > 0 => Some((updated_at - started_at).max(0) * remaining / done),
+        _ => None,
+    };
+
+    Ok(Job {
+        id: row.get(0)?,
+        kind: row.get(1)?,
+        total,
+        sent,
+        failed,
+        cancelled,
+        remaining,
+        progress: match total {
+            0 => 100,
+            _ => ((done + cancelled).min(total) * 100 / total) as u8,
+        },
+        status: match (cancelled_at, finished_at) {
+            (Some(_), _) => "cancelled",
+            (None, Some(_)) => "finished",
+            (None, None) => "running",
+        },
+        created_at: row.get(5)?,
+        started_at,
+        updated_at,
+        finished_at,
+        cancelled_at,
+        eta_secs,
+    })
+}
+
+const COLUMNS: &str = "id, kind, total, sent, failed, created_at, started_at, updated_at, finished_at,
+    cancelled, cancelled_at";
+
+// the jobs of the tenant of the request, all of them for the instance
+pub fn list(page: &Page) -> Result<(Vec<Job>, usize), String> {
+
+    let conn = db::conn()?;
+
+    let total: i64 = conn.query_row(
+        "SELECT COUNT(*) FROM jobs WHERE ?1 = '' OR tenant = ?1",
+        params![crate::tenant::key()],
+        |row| row.get(0),
+    ).map_err(|e| e.to_string())?;
+
+    let (limit, offset) = page.sql();
+    let mut stmt = conn.prepare(&format!("SELECT {} FROM jobs WHERE ?1 = '' OR tenant = ?1 ORDER BY id DESC LIMIT ?2 OFFSET ?3", COLUMNS))
+        .map_err(|e| e.to_string())?;
+
+    let entries = stmt.query_map(params![crate::tenant::key(), limit, offset], entry)
+        .map_err(|e| e.to_string())?;
+
+    let entries = entries.collect::<Result<Vec<Job>, _>>()
+        .map_err(|e| e.to_string())?;
+
+    Ok((entries, total as usize))
+}
+
+pub fn by_id(id: i64) -> Result<Option<Job>, String> {
+
+    let conn = db::conn()?;
+
+    let mut stmt = conn.prepare(&format!("SELECT {} FROM jobs WHERE id = ?1 AND (?2 = '' OR tenant = ?2)", COLUMNS))
+        .map_err(|e| e.to_string())?;
+
+    let mut entries = stmt.query_map(params![id, crate::tenant::key()], entry)
+        .map_err(|e| e.to_string())?;
+
+    entries.next()
+        .transpose()
+        .map_err(|e| e.to_string())
+}
+
+// the worker skips the mails of a cancelled job it has already picked up
+pub fn cancelled(batch: Option<i64>) -> bool {
+    let batch = match batch {
+        Some(batch) => batch,
+        None => return false,
+    };
+    db::conn()
+        .ok()
+        .and_then(|conn| conn.query_row(
+            "SELECT cancelled_at IS NOT NULL FROM jobs WHERE id = ?1",
+            params![batch],
+            |row| row.get::<_, bool>(0),
+        ).ok())
+        .unwrap_or(false)
+}
+
+// the mails not sent yet are removed from the queue, the sent ones are kept
+fn cancel(id: i64) -> Result<String, SendError> {
+
+    let conn = db::conn().map_err(db_error)?;
+
+    let (sent, cancelled_at): (i64, Option<i64>) = conn.query_row(
+        "SELECT sent, cancelled_at FROM jobs WHERE id = ?1 AND (?2 = '' OR tenant = ?2)",
+        params![id, crate::tenant::key()],
+        |row| Ok((row.get(0)?, row.get(1)?)),
+    ).map_err(|e| match e {
+        rusqlite::Error::QueryReturnedNoRows => SendError::new("not_found", format!("No job with id {}", id)),
+        e => db_error(e),
+    })?;
+    if cancelled_at.is_some() {
+        return Err(SendError::new("invalid_request", format!("Job {} is already cancelled", id)));
+    }
+
+    // the jobs of the queue are in the storage, not under the lock of the database
+    drop(conn);
+    let pending = crate::storage::store()
+        .and_then(|store| store.cancel_batch(id))
+        .map_err(db_error)?;
+    let removed = pending.len();
+    let conn = db::conn().map_err(db_error)?;
+    conn.execute(
+        "UPDATE jobs SET cancelled = ?2, cancelled_at = ?3, updated_at = ?3,
+        finished_at = COALESCE(finished_at, ?3) WHERE id = ?1",
+        params![id, removed as i64, db::now()],
+    ).map_err(db_error)?;
+
+    drop(conn);
+
+    for to in pending {
+        record(Some(id), &to, "cancelled", None, "Removed from the queue by a cancel", None);
+    }
+
+    Ok(format!("Job {} cancelled: {} emails already sent, {} removed from the queue", id, sent, removed))
+}
+
+pub fn handle(request: &JobRequest) -> Result<String, SendError> {
+    match request.action.as_str() {
+        "cancel" => cancel(request.id),
+        action => Err(SendError::new("invalid_request", format!("Invalid action: {}", action))),
+    }
+}
+
+// the outcomes of the recipients of a job, in the order they were known
+// without a page the whole report, for the CSV export
+pub fn results(
+    id: i64,
+    page: Option<&Page>,
+) -> Result<(Vec<JobResult>, usize), String> {
+
+    let conn = db::conn()?;
+
+    let total: i64 = conn.query_row(
+        "SELECT COUNT(*) FROM job_results WHERE job_id = ?1
+        AND EXISTS (SELECT 1 FROM jobs WHERE jobs.id = job_id AND (?2 = '' OR jobs.tenant = ?2))",
+        params![id, crate::tenant::key()],
+        |row| row.get(0),
+    ).map_err(|e| e.to_string())?;
+
+    // a LIMIT of -1 is no limit in SQLite
+    let (limit, offset) = page.map(Page::sql).unwrap_or((-1, 0));
+    let mut stmt = conn.prepare(
+        "SELECT recipient, status, code, response, message_id, created_at
+        FROM job_results WHERE job_id = ?1
+        AND EXISTS (SELECT 1 FROM jobs WHERE jobs.id = job_id AND (?2 = '' OR jobs.tenant = ?2))
+        ORDER BY id LIMIT ?3 OFFSET ?4"
+    ).map_err(|e| e.to_string())?;
+
+    let entries = stmt.query_map(params![id, crate::tenant::key(), limit, offset], |row| Ok(JobResult {
+        recipient: row.get(0)?,
+        status: row.get(1)?,
+        code: row.get(2)?,
+        response: row.get(3)?,
+        message_id: row.get(4)?,
+        time: row.get(5)?,
+    })).map_err(|e| e.to_string())?;
+
+    let entries = entries.collect::<Result<Vec<JobResult>, _>>()
+        .map_err(|e| e.to_string())?;
+
+    Ok((entries, total as usize))
+}
+
+// one line per recipient, for the spreadsheet of an audit
+pub fn csv(results: &[JobResult]) -> Result<Vec<u8>, String> {
+
+    let mut writer = csv::Writer::from_writer(Vec::new());
+    writer.write_record(["recipient", "status", "code", "response", "message_id", "time"])
+        .map_err(|e| e.to_string())?;
+    for result in results {
+        writer.write_record([
+            result.recipient.as_str(),
+            result.status.as_str(),
+            result.code.as_deref().unwrap_or(""),
+            result.response.as_str(),
+            result.message_id.as_deref().unwrap_or(""),
+            &crate::quota::format_time(result.time),
+        ]).map_err(|e| e.to_string())?;
+    }
+
+    writer.into_inner()
+        .map_err(|e| e.to_string())
+}
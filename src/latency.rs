@@ -0,0 +1,169 @@
+//
+// Delivery latency: the time from the acceptance of a request to the 250 of
+// the server, per lane of the queue, with rolling percentiles and an alert
+// when the p95 of a lane is over its SLO
+//
+
+use std::collections::{BTreeMap, HashMap, VecDeque};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+use serde::{Deserialize, Serialize};
+
+use crate::{Mail, SMTP_CLIENT};
+
+// samples kept per lane, the oldest go first past it
+const MAX_SAMPLES: usize = 10_000;
+
+fn default_window_secs() -> u64 {
+    900
+}
+
+fn default_min_samples() -> usize {
+    20
+}
+
+#[derive(Clone, Default, Deserialize, Serialize)]
+pub struct LatencySettings {
+    // the p95 of every lane, in milliseconds
+    pub slo_p95_ms: Option<u64>,
+    // the SLO of a lane over the one of every lane, "bulk": 600000
+    #[serde(default)]
+    pub lanes: HashMap<String, u64>,
+    // the percentiles are of the sends of this period
+    #[serde(default = "default_window_secs")]
+    pub window_secs: u64,
+    // fewer sends in the window don't raise an alert
+    #[serde(default = "default_min_samples")]
+    pub min_samples: usize,
+}
+
+#[derive(Default)]
+struct Lane {
+    // when each send ended and its latency in milliseconds
+    samples: VecDeque<(Instant, u64)>,
+    // over its SLO since the last alert, the next one waits for a recovery
+    breached: bool,
+}
+
+static LANES: Mutex<BTreeMap<String, Lane>> = Mutex::new(BTreeMap::new());
+
+#[derive(Serialize)]
+pub struct Percentiles {
+    samples: usize,
+    p50_ms: u64,
+    p95_ms: u64,
+    p99_ms: u64,
+    max_ms: u64,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    slo_p95_ms: Option<u64>,
+    breached: bool,
+}
+
+pub fn now_ms() -> i64 {
+    chrono::Utc::now().timestamp_millis()
+}
+
+fn settings() -> LatencySettings {
+    SMTP_CLIENT.latency.clone().unwrap_or_default()
+}
+
+fn slo(
+    settings: &LatencySettings,
+    lane: &str,
+) -> Option<u64> {
+    settings.lanes.get(lane).copied().or(settings.slo_p95_ms)
+}
+
+// the value below which the share of the sorted latencies is
+fn percentile(
+    sorted: &[u64],
+    share: f64,
+) -> u64 {
+    match sorted.len() {
+        0 => 0,
+        n => sorted[((n as f64 * share).ceil() as usize).clamp(1, n) - 1],
+    }
+}
+
+fn percentiles(
+    lane: &Lane,
+    slo_p95_ms: Option<u64>,
+) -> Percentiles {
+    let mut sorted: Vec<u64> = lane.samples.iter().map(|(_, ms)| *ms).collect();
+    sorted.sort_unstable();
+    Percentiles {
+        samples: sorted.len(),
+        p50_ms: percentile(&sorted, 0.50),
+        p95_ms: percentile(&sorted, 0.95),
+        p99_ms: percentile(&sorted, 0.99),
+        max_ms: sorted.last().copied().unwrap_or(0),
+        slo_p95_ms,
+        breached: lane.breached,
+    }
+}
+
+// the latency of a message the server accepted; the messages the plugin
+// didn't accept from a request, digests and recurring ones, aren't counted
+pub fn record(mail: &Mail) {
+
+    let Some(accepted_ms) = mail.accepted_ms else {
+        return;
+    };
+    let latency = (now_ms() - accepted_ms).max(0) as u64;
+    let lane = mail.priority.clone().unwrap_or(crate::queue::TRANSACTIONAL.to_string());
+    let settings = settings();
+
+    let alert = {
+        let mut lanes = LANES.lock()
+            .unwrap_or_else(|e| e.into_inner());
+        let entry = lanes.entry(lane.clone()).or_default();
+        let now = Instant::now();
+        let window = Duration::from_secs(settings.window_secs);
+        while entry.samples.front().is_some_and(|(at, _)| now.duration_since(*at) > window)
+            || entry.samples.len() >= MAX_SAMPLES {
+            entry.samples.pop_front();
+        }
+        entry.samples.push_back((now, latency));
+
+        let Some(slo) = slo(&settings, &lane) else {
+            return;
+        };
+        let current = percentiles(entry, Some(slo));
+        match (current.samples >= settings.min_samples && current.p95_ms > slo, entry.breached) {
+            (true, false) => {
+                entry.breached = true;
+                Some(format!(
+                    "The p95 delivery latency of the {} lane is {} ms over the last {} sends, above its SLO of {} ms",
+                    lane, current.p95_ms, current.samples, slo,
+                ))
+            },
+            (false, true) if current.p95_ms <= slo => {
+                entry.breached = false;
+                log!("The p95 delivery latency of the {} lane is back to {} ms, within its SLO of {} ms", lane, current.p95_ms, slo);
+                None
+            },
+            _ => None,
+        }
+    };
+
+    // not under the lock, sending it logs and spawns the webhook
+    if let Some(message) = alert {
+        crate::alert::send("latency_slo", &message);
+    }
+}
+
+// the percentiles of every lane that sent in the window
+pub fn stats() -> BTreeMap<String, Percentiles> {
+
+    let settings = settings();
+    let window = Duration::from_secs(settings.window_secs);
+    let mut lanes = LANES.lock()
+        .unwrap_or_else(|e| e.into_inner());
+
+    lanes.iter_mut()
+        .map(|(name, lane)| {
+            lane.samples.retain(|(at, _)| at.elapsed() <= window);
+            (name.clone(), percentiles(lane, slo(&settings, name)))
+        })
+        .collect()
+}
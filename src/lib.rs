@@ -2,6 +2,102 @@
 // Shared library for sending mail via Gmail
 //
 
+// the exported functions follow the plugin ABI of the host (raw pointers in, raw pointer out),
+// so their signatures can't be marked unsafe
+#![allow(clippy::not_unsafe_ptr_arg_deref)]
+
+// eprintln prefixed with the request id of the current thread,
+// the addresses are redacted in privacy mode
+macro_rules! log {
+    ($($arg:tt)*) => {{
+        let message = crate::privacy::redact(&format!($($arg)*));
+        match crate::trace::current() {
+            Some(id) => eprintln!("[{}] {}", id, message),
+            None => eprintln!("{}", message),
+        }
+    }};
+}
+
+mod alert;
+mod aliases;
+mod antivirus;
+mod archive;
+mod arf;
+mod attachments;
+mod audit;
+mod auth;
+mod budget;
+mod buffers;
+mod bulk;
+mod callbacks;
+mod chaos;
+mod config;
+mod connect;
+mod consent;
+mod content;
+mod date;
+mod db;
+mod digest;
+mod dns;
+mod duplicates;
+mod encoding;
+mod events;
+mod form;
+mod forward;
+mod gmail_api;
+mod headers;
+mod health;
+mod history;
+mod hooks;
+mod http;
+mod identity;
+mod images;
+mod imap;
+mod invalid;
+mod jobs;
+mod latency;
+#[cfg(feature = "mailgun")]
+mod mailgun;
+mod merge;
+#[cfg(test)]
+mod mime_tests;
+mod ndjson;
+mod outcome;
+mod page;
+mod pdf;
+mod persistence;
+mod pipe;
+mod policy;
+mod pool;
+#[cfg(feature = "postgres")]
+mod postgres;
+mod privacy;
+mod profile;
+mod queue;
+mod quota;
+mod ratelimit;
+mod recurring;
+mod report;
+mod review;
+mod scheduler;
+#[cfg(feature = "sendgrid")]
+mod sendgrid;
+#[cfg(feature = "ses")]
+mod ses;
+mod shutdown;
+mod signature;
+mod spam;
+mod storage;
+mod suppressions;
+mod telemetry;
+mod templates;
+mod tenant;
+mod tls;
+mod trace;
+mod transcript;
+mod transport;
+mod vcard;
+
 use core::panic;
 use std::ffi::{
     c_char,
@@ -10,14 +106,13 @@ use std::ffi::{
 };
 use serde::{Deserialize, Serialize};
 use hyper::HeaderMap;
-use lettre::transport::smtp;
 use lettre::Message;
-use lettre::message::{SinglePart, header::ContentType};
-use lettre::SmtpTransport;
-use lettre::Transport;
+use lettre::message::{Mailbox, MultiPart, SinglePart, header::ContentType};
 use once_cell::sync::Lazy;
 
-static VERSION: &'static str = "0.1.0";
+use page::Page;
+
+static VERSION: &str = "0.1.0";
 
 // mandatory struct
 #[derive(Debug, Serialize)]
@@ -36,6 +131,281 @@ static ROUTES: &[PluginRoute] = &[
         method_router: "post",
         response_type: "json",
     },
+    PluginRoute {
+        // canned message to the configured test recipients
+        path: "/sendtest",
+        function: "sendtest",
+        method_router: "post",
+        response_type: "json",
+    },
+    PluginRoute {
+        // a template to each recipient of a list, in its locale
+        path: "/sendtemplate",
+        function: "sendtemplate",
+        method_router: "post",
+        response_type: "json",
+    },
+    PluginRoute {
+        // the same, queued
+        path: "/sendbulk",
+        function: "sendbulk",
+        method_router: "post",
+        response_type: "json",
+    },
+    PluginRoute {
+        // a template to each row of a CSV, queued or previewed
+        path: "/sendmerge",
+        function: "sendmerge",
+        method_router: "post",
+        response_type: "json",
+    },
+    PluginRoute {
+        path: "/forward",
+        function: "forward",
+        method_router: "post",
+        response_type: "json",
+    },
+    PluginRoute {
+        path: "/history",
+        function: "history",
+        method_router: "get",
+        response_type: "json",
+    },
+    PluginRoute {
+        // apply the retention periods of the privacy settings
+        path: "/history/purge",
+        function: "history_purge",
+        method_router: "post",
+        response_type: "json",
+    },
+    PluginRoute {
+        // GET /history/eml?id=1
+        path: "/history/eml",
+        function: "history_eml",
+        method_router: "get",
+        response_type: "text",
+    },
+    PluginRoute {
+        path: "/recurring",
+        function: "recurring_list",
+        method_router: "get",
+        response_type: "json",
+    },
+    PluginRoute {
+        // create, pause, resume or delete
+        path: "/recurring",
+        function: "recurring",
+        method_router: "post",
+        response_type: "json",
+    },
+    PluginRoute {
+        path: "/deadletter",
+        function: "deadletter_list",
+        method_router: "get",
+        response_type: "json",
+    },
+    PluginRoute {
+        // GET /deadletter/entry?id=1
+        path: "/deadletter/entry",
+        function: "deadletter_entry",
+        method_router: "get",
+        response_type: "json",
+    },
+    PluginRoute {
+        // requeue or delete
+        path: "/deadletter",
+        function: "deadletter",
+        method_router: "post",
+        response_type: "json",
+    },
+    PluginRoute {
+        // the messages held for review
+        path: "/pending",
+        function: "pending_list",
+        method_router: "get",
+        response_type: "json",
+    },
+    PluginRoute {
+        // GET /pending/entry?id=1
+        path: "/pending/entry",
+        function: "pending_entry",
+        method_router: "get",
+        response_type: "json",
+    },
+    PluginRoute {
+        // approve or reject, for the admin keys
+        path: "/pending",
+        function: "pending",
+        method_router: "post",
+        response_type: "json",
+    },
+    PluginRoute {
+        path: "/templates",
+        function: "templates_list",
+        method_router: "get",
+        response_type: "json",
+    },
+    PluginRoute {
+        // GET /templates/entry?name=welcome.html
+        path: "/templates/entry",
+        function: "templates_entry",
+        method_router: "get",
+        response_type: "json",
+    },
+    PluginRoute {
+        // upload, validate or delete
+        path: "/templates",
+        function: "templates",
+        method_router: "post",
+        response_type: "json",
+    },
+    PluginRoute {
+        // progress of the /sendbulk and /sendmerge jobs
+        path: "/jobs",
+        function: "jobs_list",
+        method_router: "get",
+        response_type: "json",
+    },
+    PluginRoute {
+        // GET /jobs/entry?id=1
+        path: "/jobs/entry",
+        function: "jobs_entry",
+        method_router: "get",
+        response_type: "json",
+    },
+    PluginRoute {
+        // GET /jobs/report?id=1 outcome of each recipient
+        path: "/jobs/report",
+        function: "jobs_report",
+        method_router: "get",
+        response_type: "json",
+    },
+    PluginRoute {
+        // the same as a CSV
+        path: "/jobs/report/csv",
+        function: "jobs_report_csv",
+        method_router: "get",
+        response_type: "text",
+    },
+    PluginRoute {
+        // cancel
+        path: "/jobs",
+        function: "jobs",
+        method_router: "post",
+        response_type: "json",
+    },
+    PluginRoute {
+        // addresses never sent to, of the tenant of the key
+        path: "/suppressions",
+        function: "suppressions_list",
+        method_router: "get",
+        response_type: "json",
+    },
+    PluginRoute {
+        // add or remove an address
+        path: "/suppressions",
+        function: "suppressions",
+        method_router: "post",
+        response_type: "json",
+    },
+    PluginRoute {
+        // POST /events?provider=mailgun, the webhooks of the providers
+        path: "/events",
+        function: "events",
+        method_router: "post",
+        response_type: "json",
+    },
+    PluginRoute {
+        // GET /aliases?account=name, the send-as addresses of a Gmail account
+        path: "/aliases",
+        function: "aliases",
+        method_router: "get",
+        response_type: "json",
+    },
+    PluginRoute {
+        path: "/quota",
+        function: "quota",
+        method_router: "get",
+        response_type: "json",
+    },
+    PluginRoute {
+        // the rolling percentiles of the delivery latency of each lane
+        path: "/latency",
+        function: "latency",
+        method_router: "get",
+        response_type: "json",
+    },
+    PluginRoute {
+        // stop dispatching the queue, the jobs are still accepted and kept
+        path: "/admin/pause",
+        function: "admin_pause",
+        method_router: "post",
+        response_type: "json",
+    },
+    PluginRoute {
+        path: "/admin/resume",
+        function: "admin_resume",
+        method_router: "post",
+        response_type: "json",
+    },
+    PluginRoute {
+        // the failures injected into the sends
+        path: "/admin/chaos",
+        function: "admin_chaos",
+        method_router: "get",
+        response_type: "json",
+    },
+    PluginRoute {
+        // {"action": "set", "chaos": {...}} until the next restart, or
+        // {"action": "clear"}
+        path: "/admin/chaos",
+        function: "admin_chaos_set",
+        method_router: "post",
+        response_type: "json",
+    },
+    PluginRoute {
+        // the running config, its secrets masked
+        path: "/config",
+        function: "config_inspect",
+        method_router: "get",
+        response_type: "json",
+    },
+    PluginRoute {
+        // the config.json on disk checked, GET /config/validate?connect=false
+        // skips the connections to the servers
+        path: "/config/validate",
+        function: "config_validate",
+        method_router: "get",
+        response_type: "json",
+    },
+    PluginRoute {
+        // GET /audit?action=admin.pause&since=1700000000&limit=100
+        path: "/audit",
+        function: "audit_list",
+        method_router: "get",
+        response_type: "json",
+    },
+    PluginRoute {
+        // GET /consent?address=someone@example.com&since=1700000000
+        path: "/consent",
+        function: "consent_list",
+        method_router: "get",
+        response_type: "json",
+    },
+    PluginRoute {
+        // GET /health?deep=true runs the self-test
+        path: "/health",
+        function: "health",
+        method_router: "get",
+        response_type: "json",
+    },
+    PluginRoute {
+        // counters of the response buffers, with "debug_buffers"
+        path: "/metrics",
+        function: "metrics",
+        method_router: "get",
+        response_type: "json",
+    },
     PluginRoute {
         path: "/about",
         function: "about",
@@ -44,50 +414,443 @@ static ROUTES: &[PluginRoute] = &[
     },
 ];
 
-#[derive(Clone, Deserialize, Serialize)]
+#[derive(Clone, Default, Deserialize, Serialize)]
 struct Mail {
+    #[serde(default)]
     from: String,
+    #[serde(default)]
     to: String,
     cc: Option<String>,
     bcc: Option<String>,
     reply_to: Option<String>,
+    // display name of the From address
     sender_name: Option<String>,
+    // Sender header, when the message is sent on behalf of the From address
     sender_email: Option<String>,
+    #[serde(default)]
     subject: String,
+    #[serde(default)]
     message: String,
-    attachments: Option<Vec<String>>,
+    // body read from a file inside the content directory
+    message_file: Option<String>,
+    // body from a named snippet of the config
+    snippet: Option<String>,
+    // subject and body rendered from <template>.html and <template>.txt
+    template: Option<String>,
+    // variables of the template
+    data: Option<serde_json::Value>,
+    // of the template variant, "pt" picks welcome.pt.html
+    locale: Option<String>,
+    // "strict" fails the render on a missing variable, "lenient" renders
+    // it empty
+    template_variables: Option<String>,
+    // html alternative of the message
+    html: Option<String>,
+    // move the <style> rules of the html template to style attributes,
+    // over the choice of the template and the config
+    inline_css: Option<bool>,
+    attachments: Option<Vec<attachments::Attachment>>,
+    // send even if the spam score is above the threshold
+    force: Option<bool>,
+    // accumulate in the "hourly" or "daily" digest of the recipient
+    digest: Option<String>,
+    // send from the queue, retrying temporary failures
+    queue: Option<bool>,
+    // unix time after which a queued message isn't sent, it is moved to the
+    // dead-letter store as expired
+    expires_at: Option<i64>,
+    // the lane of the queue, "transactional" (the default) or "bulk"
+    priority: Option<String>,
+    // a PDF attachment rendered from an HTML document or the html body
+    pdf: Option<pdf::PdfRequest>,
+    // a vCard of the sender attached as a .vcf
+    contact_card: Option<vcard::ContactCard>,
+    // the attachments and the PDF in a ZIP encrypted with the password
+    encrypt_attachments: Option<archive::ArchiveRequest>,
+    // Gmail labels of the message, when its account sends through the API
+    labels: Option<Vec<String>>,
+    // abort the send after this many milliseconds
+    timeout_ms: Option<u64>,
+    // the SMTP dialog in the response, for the api keys with the admin scope
+    debug: Option<bool>,
+    // "gmail" or one of the accounts of the config, the default account if not set
+    account: Option<String>,
+    // the tenant of the api key of the request, set by the plugin
+    tenant: Option<String>,
+    // charset of the body: "utf-8" (the default), "iso-8859-1" or "us-ascii"
+    charset: Option<String>,
+    // "quoted-printable", "base64", "8bit" or "7bit" instead of the
+    // encoding chosen for the body
+    transfer_encoding: Option<String>,
+    // of the Date header, "Europe/Lisbon", the config timezone if not set
+    timezone: Option<String>,
+    // notification sent by a program: no auto-replies in response
+    automated: Option<bool>,
+    // ask for a read receipt (MDN) sent to read_receipt_to
+    request_read_receipt: Option<bool>,
+    // complete RFC822 message encoded as base64, relayed as is
+    raw_mime: Option<String>,
+    // set from the X-Request-Id header, kept with the queued jobs
+    request_id: Option<String>,
+    // bulk job of the mail, counted in its progress, set by the plugin
+    batch: Option<i64>,
+    // the headers added by the content policies and the hooks, set by the
+    // plugin
+    #[serde(default)]
+    policy_headers: Vec<(String, String)>,
+    // when the plugin accepted the request, in milliseconds, set by the plugin
+    accepted_ms: Option<i64>,
+    // the unsubscribe the mail confirms, it is sent to the suppressed
+    // address; set by the plugin
+    consent: Option<i64>,
 }
 
-#[derive(Clone, Deserialize)]
+#[derive(Clone, Deserialize, Serialize)]
 struct SmtpSettings {
     username: String,
     password: String,
     server: String,
+    // "gmail", the default, or "dev" for a local relay without TLS, quota or
+    // From rules: MailHog, Mailpit, smtp4dev
+    profile: Option<String>,
+    // the port of the dev relay
+    dev: Option<profile::DevSettings>,
+    // local address the SMTP connections are made from, on a host with more
+    // than one, the one of the route to the server if not set
+    bind_address: Option<std::net::IpAddr>,
+    // DNS servers, timeout and pinned addresses of the SMTP servers
+    dns: Option<dns::DnsSettings>,
+    // order and racing of the addresses of the SMTP servers
+    connect: Option<connect::ConnectSettings>,
+    // minimum version, trusted roots and key pins of the TLS of the SMTP servers
+    tls: Option<tls::TlsSettings>,
+    // directory on the plugin host where attachment paths are resolved,
+    // relative paths are relative to the plugin directory
+    attachments_dir: Option<String>,
+    // scan every attachment before sending
+    antivirus: Option<antivirus::AntivirusSettings>,
+    // the dimensions the JPEG and PNG attachments are downscaled to
+    images: Option<images::ImageSettings>,
+    // the command rendering the PDF attachments from HTML
+    pdf: Option<pdf::PdfSettings>,
+    // the rules of the messages held until an admin approves them
+    review: Option<review::ReviewSettings>,
+    // rules rejecting, holding or changing the messages, applied in order
+    policies: Option<Vec<policy::Rule>>,
+    // commands or WASI modules run before and after the sends
+    hooks: Option<Vec<hooks::Hook>>,
+    // spam score check of the built message before sending
+    spam_check: Option<spam::SpamCheckSettings>,
+    // directory on the plugin host where message files are resolved
+    content_dir: Option<String>,
+    // directory of the message templates, templates/mail if not set
+    templates_dir: Option<String>,
+    // inline the css of the html templates, unless a template or the request
+    // says otherwise
+    inline_css: Option<bool>,
+    // missing template variables: "strict" fails the render, "lenient" (the
+    // default) renders them empty
+    template_variables: Option<String>,
+    // locales tried when a template has no variant for the one of the
+    // recipient, before the template without a locale
+    locale_fallback: Option<Vec<String>>,
+    // directory on the plugin host where .eml files to forward are resolved
+    eml_dir: Option<String>,
+    // SQLite database of the plugin state, relative to the plugin directory
+    database: Option<String>,
+    // the store of the queue, history, suppressions and sent hashes, shared by
+    // the instances with postgres
+    storage: Option<storage::StorageSettings>,
+    // the history and audit rows kept in memory while the database can't write
+    // them, or the sends stopped until it can with "strict"
+    persistence: Option<persistence::PersistenceSettings>,
+    // history of sent messages
+    history: Option<history::HistorySettings>,
+    // the mailbox folder the sent messages are copied to over IMAP
+    imap: Option<imap::ImapSettings>,
+    // schedule of the digests
+    digest: Option<digest::DigestSettings>,
+    // retries of queued messages
+    queue: Option<queue::QueueSettings>,
+    // pooled SMTP connections and their keepalive
+    pool: Option<pool::PoolSettings>,
+    // other Gmail accounts and accounts of the HTTP API providers the
+    // requests can send from
+    accounts: Option<Vec<transport::Account>>,
+    // weight of the Gmail account of the config and backoff of the accounts
+    // sharing the sends
+    rotation: Option<transport::RotationSettings>,
+    // OAuth client and refresh tokens of the Gmail API, for the aliases and
+    // the accounts that send through it
+    gmail_api: Option<gmail_api::GmailApiSettings>,
+    // account of the requests that don't name one, the Gmail account if not set
+    default_account: Option<String>,
+    // account of the requests that don't name one while the Gmail account is
+    // at its daily limit or cooling down
+    overflow_account: Option<String>,
+    // how long shutdown() waits for the workers to finish
+    shutdown_timeout_secs: Option<u64>,
+    // of the Date header of the messages, UTC if not set
+    timezone: Option<String>,
+    // default timeout of a send in milliseconds
+    timeout_ms: Option<u64>,
+    // cool-down after a Gmail sending limit error
+    quota: Option<quota::QuotaSettings>,
+    // webhook receiving the operational alerts
+    alerts: Option<alert::AlertSettings>,
+    // the secrets signing the webhook callbacks, by url pattern
+    callback_secrets: Option<Vec<callbacks::CallbackSecret>>,
+    // the signing keys of the bounce and unsubscribe webhooks of the providers
+    events: Option<events::EventsSettings>,
+    // the confirmation emails of the unsubscribes
+    consent: Option<consent::ConsentSettings>,
+    // OTLP export of spans and counters
+    telemetry: Option<telemetry::TelemetrySettings>,
+    // redaction of addresses and subjects, retention of the stored data
+    privacy: Option<privacy::PrivacySettings>,
+    // Sentry or webhook receiving the panics and repeated send failures
+    error_reporting: Option<report::ReportSettings>,
+    // emails a single recipient can receive per hour and per day
+    recipient_limits: Option<ratelimit::RecipientLimits>,
+    // window refusing an identical message to the same recipient
+    duplicates: Option<duplicates::DuplicateSettings>,
+    // failures injected into the sends, for the staging instances only
+    chaos: Option<chaos::ChaosSettings>,
+    // SLO of the p95 delivery latency, per lane of the queue
+    latency: Option<latency::LatencySettings>,
+    // period refusing the recipients a server rejected as unknown
+    invalid_recipients: Option<invalid::InvalidRecipientSettings>,
+    // a from address other than the account: "enforce" refuses the email,
+    // "rewrite" sends it from the account with a Reply-To to the original
+    // address, "allow" (the default) passes it as is
+    from_policy: Option<String>,
+    // from, sender_name and reply_to of the requests that omit them
+    defaults: Option<identity::Defaults>,
+    // the only addresses /sendtest sends to
+    test_recipients: Option<Vec<String>>,
+    // read the request bodies that aren't valid UTF-8 as ISO-8859-1, for the
+    // legacy callers that can't declare their charset
+    latin1_bodies: Option<bool>,
+    // run the self-test of /health?deep=true when the plugin is loaded and
+    // log its results
+    self_test: Option<bool>,
+    // count the response buffers the host hasn't freed on GET /metrics
+    debug_buffers: Option<bool>,
+    // keys of the callers, the routes are open if not set
+    api_keys: Option<Vec<auth::ApiKey>>,
+    // the products sharing the instance, named by the tenant of their api keys
+    tenants: Option<Vec<tenant::TenantSettings>>,
+    // where the read receipts are sent, the from address if not set
+    read_receipt_to: Option<String>,
+    // headers added to every message built by the plugin
+    #[serde(default)]
+    headers: std::collections::BTreeMap<String, String>,
+    // named reusable message bodies
+    #[serde(default)]
+    snippets: std::collections::HashMap<String, String>,
 }
 
 #[derive(Clone, Serialize)]
 struct Response {
     status: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    code: Option<String>,
     message: String,
+    // whether a failed send may succeed if retried later
+    #[serde(skip_serializing_if = "Option::is_none")]
+    retryable: Option<bool>,
+    // seconds before a refused request may be retried, the Retry-After of
+    // response_headers()
+    #[serde(skip_serializing_if = "Option::is_none")]
+    retry_after: Option<i64>,
+    // history record of the message
+    #[serde(skip_serializing_if = "Option::is_none")]
+    id: Option<i64>,
+    // queue job of the message
+    #[serde(skip_serializing_if = "Option::is_none")]
+    job: Option<i64>,
+    // the entry of a message held for review
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pending: Option<i64>,
+    // the content policies that matched the message
+    #[serde(skip_serializing_if = "Option::is_none")]
+    policies: Option<Vec<String>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    spam: Option<spam::SpamReport>,
+    // usage of the daily sending limit after a successful send
+    #[serde(skip_serializing_if = "Option::is_none")]
+    quota: Option<quota::Usage>,
+    // the budget of the api key after the request
+    #[serde(skip_serializing_if = "Option::is_none")]
+    budget: Option<budget::Usage>,
+    // the account that sent the message, when there are several
+    #[serde(skip_serializing_if = "Option::is_none")]
+    account: Option<String>,
+    // message id of the identical message sent earlier
+    #[serde(skip_serializing_if = "Option::is_none")]
+    duplicate_of: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    request_id: Option<String>,
+    // the SMTP dialog of a debug send
+    #[serde(skip_serializing_if = "Option::is_none")]
+    transcript: Option<Vec<transcript::Line>>,
+    // the message and thread of a send through the Gmail API
+    #[serde(skip_serializing_if = "Option::is_none")]
+    gmail: Option<gmail_api::Thread>,
+    // what the hooks noted about the message
+    #[serde(skip_serializing_if = "Option::is_none")]
+    annotations: Option<serde_json::Map<String, serde_json::Value>>,
 }
 
-static SMTP_CLIENT: Lazy<SmtpSettings> = Lazy::new(|| {
+// error with a machine readable code that is returned to the caller
+#[derive(Debug)]
+struct SendError {
+    code: &'static str,
+    message: String,
+    // when a refusal over a limit ends
+    retry_at: Option<i64>,
+}
 
-    let config_file = match || -> Result<std::path::PathBuf, Box<dyn std::error::Error>> {
+impl SendError {
+    fn new(code: &'static str, message: impl Into<String>) -> Self {
+        SendError {
+            code,
+            message: message.into(),
+            retry_at: None,
+        }
+    }
+
+    fn retry_at(mut self, time: i64) -> Self {
+        self.retry_at = Some(time);
+        self
+    }
+}
+
+// seconds from now until the time, at least one
+fn seconds_until(time: i64) -> i64 {
+    (time - db::now()).max(1)
+}
+
+impl Response {
+    fn new() -> Self {
+        Response {
+            status: "error".to_string(),
+            code: None,
+            message: "Internal plugin error".to_string(),
+            retryable: None,
+            retry_after: None,
+            id: None,
+            job: None,
+            pending: None,
+            policies: None,
+            spam: None,
+            quota: None,
+            budget: None,
+            account: None,
+            duplicate_of: None,
+            request_id: None,
+            transcript: None,
+            gmail: None,
+            annotations: None,
+        }
+    }
+
+    fn error(&mut self, error: SendError) {
+        self.code = Some(error.code.to_string());
+        self.message = error.message;
+        self.retry_after = error.retry_at.map(seconds_until);
+    }
 
-        let plugins_dir = std::env::var("PLUGINS_DIR")
-            .map(|val| val.is_empty()
-                .then_some("plugins".to_string()
-            )
-            .or(Some(val)).unwrap())
-            .unwrap_or("plugins".to_string());
+    fn annotate(&mut self, annotations: serde_json::Map<String, serde_json::Value>) {
+        if !annotations.is_empty() {
+            self.annotations.get_or_insert_with(serde_json::Map::new).extend(annotations);
+        }
+    }
+
+    // the usage of the Gmail account that sent the message, none on a dev relay
+    fn sent_from(
+        &mut self,
+        sent: &transport::Sent,
+    ) {
+        self.quota = transport::Gmail::find(&sent.account)
+            .filter(|_| !profile::dev())
+            .and_then(|account| quota::usage(&account.name).ok());
+        if SMTP_CLIENT.accounts.is_some() {
+            self.account = Some(sent.account.clone());
+        }
+        self.gmail = sent.gmail.clone();
+    }
 
-        let plugins_path = std::path::Path::new(&plugins_dir);
-        if !plugins_path.is_dir() {
-            return Err(format!("Error: PLUGINS_DIR does not exist or is not set correctly: {}", plugins_dir).into());
+    // code and retryable of a failed send
+    fn failure(
+        &mut self,
+        failure: &pool::Failure,
+        message: String,
+    ) {
+        let outcome = outcome::classify(failure);
+        if matches!(failure, pool::Failure::Smtp(_) | pool::Failure::Dns(_) | pool::Failure::Connect(_) | pool::Failure::Tls(_) | pool::Failure::Timeout(_) | pool::Failure::Api(_)) {
+            report::smtp_failure(outcome.code, &message);
         }
+        self.code = Some(outcome.code.to_string());
+        self.retryable = Some(outcome.retryable);
+        self.retry_after = match failure {
+            pool::Failure::CoolingDown(until) | pool::Failure::LimitReached(until) => Some(seconds_until(*until)),
+            _ => None,
+        };
+        self.message = message;
+    }
+}
+
+// directory of this plugin inside PLUGINS_DIR
+fn plugin_path() -> Result<std::path::PathBuf, Box<dyn std::error::Error>> {
 
-        let config_file = plugins_path.join("arp-gmail/config.json");
+    let plugins_dir = std::env::var("PLUGINS_DIR")
+        .map(|val| if val.is_empty() { "plugins".to_string() } else { val })
+        .unwrap_or("plugins".to_string());
+
+    let plugins_path = std::path::Path::new(&plugins_dir);
+    if !plugins_path.is_dir() {
+        return Err(format!("Error: PLUGINS_DIR does not exist or is not set correctly: {}", plugins_dir).into());
+    }
+
+    Ok(plugins_path.join("arp-gmail"))
+}
+
+// resolve a caller supplied path inside a configured directory,
+// relative directories are relative to the plugin directory
+fn resolve_path(
+    dir: &str,
+    path: &str,
+) -> Result<std::path::PathBuf, String> {
+
+    let base = match std::path::Path::new(dir).is_absolute() {
+        true => std::path::PathBuf::from(dir),
+        false => plugin_path()
+            .map_err(|e| e.to_string())?
+            .join(dir),
+    };
+    let base = base.canonicalize()
+        .map_err(|e| format!("directory {}: {}", base.display(), e))?;
+
+    let file = base.join(path).canonicalize()
+        .map_err(|e| format!("{}: {}", path, e))?;
+
+    // don't let the caller read files outside the directory
+    if !file.starts_with(&base) || !file.is_file() {
+        return Err(format!("not found: {}", path));
+    }
+
+    Ok(file)
+}
+
+// the exported functions are called from any thread of the host at once: the
+// config is read-only once loaded, the rest of the shared state (the SMTP pool,
+// the workers, the queue, the database, the buffers) is behind its own lock
+static SMTP_CLIENT: Lazy<SmtpSettings> = Lazy::new(|| {
+
+    let config_file = match || -> Result<std::path::PathBuf, Box<dyn std::error::Error>> {
+
+        let config_file = plugin_path()?.join("config.json");
         if !config_file.is_file() {
             return Err("Error: Config file not found: arp-gmail/config.json".into());
         }
@@ -99,149 +862,2732 @@ static SMTP_CLIENT: Lazy<SmtpSettings> = Lazy::new(|| {
         },
     };
 
-    let file = std::fs::File::open(config_file).unwrap();
-    let reader = std::io::BufReader::new(file);
+    // a tampered config isn't loaded, log! would load it
+    let (text, signed_by) = match signature::read(&config_file) {
+        Ok(read) => read,
+        Err(e) => panic!("Error: {}", e),
+    };
+    if let Some(fingerprint) = &signed_by {
+        eprintln!("arp-gmail: config.json signed by the key {}", fingerprint);
+    }
 
     // Deserialize the JSON data into the struct
-    match serde_json::from_reader(reader) {
-        Ok(config) => config,
+    match serde_json::from_str::<SmtpSettings>(&text) {
+        Ok(config) => {
+            // the instances sharing the store would each count their own limits
+            if let Some(path) = storage::unshared(&config).first() {
+                panic!("Error: \"{}\" is counted by each instance, it can't be used with the postgres storage", path);
+            }
+            // kept to tell the values of the file from the defaults
+            config::loaded(config_file, &text, signed_by);
+            config
+        },
         Err(e) => {
             panic!("Error parsing config.json: {}", e);
         },
     }
 });
 
-fn to_c_response(r: &Response) -> *const c_char {
-    let pretty_json = serde_json::to_string_pretty(&r)
-        .unwrap();
-    let c_response = CString::new(pretty_json)
-        .unwrap();
+// returned when a response can't be built, it isn't allocated: free() leaves
+// it alone
+static FALLBACK: &CStr = c"{\"status\": \"error\", \"code\": \"internal_error\", \"message\": \"The response couldn't be encoded\"}";
 
-    c_response.into_raw()
-}
+// most responses are serialized without growing the buffer
+const RESPONSE_CAPACITY: usize = 1024;
 
-fn send_via_gmail(
-    mail: &Mail,
-) -> Result<smtp::response::Response, smtp::Error> {
+// a C string ends at its first NUL, the NULs inside a response are replaced so
+// the host reads all of it; the buffer becomes the C string without a copy
+fn to_c_bytes(
+    mut bytes: Vec<u8>,
+    nul: &[u8],
+) -> *const c_char {
 
-    let email = Message::builder()
-        .from(mail.from.parse().unwrap())
-        .to(mail.to.parse().unwrap())
-        .subject(&mail.subject)
-        .singlepart(SinglePart::builder()
-        .header(ContentType::TEXT_PLAIN)
-        .body(mail.message.clone()))
-        .unwrap();
-
-    // Set up the SMTP client
-    let credentials = smtp::authentication::Credentials::new(
-        SMTP_CLIENT.username.to_owned(),
-        SMTP_CLIENT.password.to_owned(),
-    );
+    bytes.push(0);
+    let bytes = match CString::from_vec_with_nul(bytes) {
+        Ok(c_response) => return buffers::register(c_response.into_raw()),
+        Err(e) => e.into_bytes(),
+    };
 
-    let mailer = SmtpTransport::relay(&SMTP_CLIENT.server)
-        .unwrap()
-        .credentials(credentials)
-        .build();
+    let mut text = Vec::with_capacity(bytes.len() + nul.len());
+    for &byte in &bytes[..bytes.len() - 1] {
+        match byte {
+            0 => text.extend_from_slice(nul),
+            byte => text.push(byte),
+        }
+    }
+    text.push(0);
 
-    // Send the email
-    mailer.send(&email)
+    match CString::from_vec_with_nul(text) {
+        Ok(c_response) => buffers::register(c_response.into_raw()),
+        Err(_) => FALLBACK.as_ptr(),
+    }
 }
 
-#[no_mangle]
-pub extern "C" fn sendmail(
-    headers: *mut HeaderMap,
-    body: *const c_char,
-) -> *const c_char {
+// compact JSON, the host passes it on as is
+fn to_c_response<T: Serialize>(r: &T) -> *const c_char {
+    let mut json = Vec::with_capacity(RESPONSE_CAPACITY);
+    // serde_json escapes the NULs of the strings, a raw one would be escaped
+    // the same way
+    match serde_json::to_writer(&mut json, r) {
+        Ok(()) => to_c_bytes(json, b"\\u0000"),
+        Err(_) => FALLBACK.as_ptr(),
+    }
+}
 
-    if headers.is_null() || body.is_null() {
-        // Handle the null pointer case
-        return std::ptr::null_mut();
+// run the body of an exported handler, a panic must not unwind into the host:
+// it is reported and the caller gets an error response
+fn guarded(
+    function: &'static str,
+    handler: impl FnOnce() -> *const c_char,
+) -> *const c_char {
+    // the host reuses its threads, the tenant of a request ends with it
+    let _tenant = tenant::enter(None);
+    match std::panic::catch_unwind(std::panic::AssertUnwindSafe(handler)) {
+        Ok(response) => {
+            buffers::tag(response, function);
+            response
+        },
+        Err(payload) => {
+            // reporting reads the config, which may be what panicked
+            let _ = std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| {
+                report::panic(function, payload.as_ref());
+            }));
+            let mut response = Response::new();
+            response.code = Some("internal_error".to_string());
+            let response = to_c_response(&response);
+            buffers::tag(response, function);
+            response
+        },
     }
+}
 
-    // Convert headers pointer to a reference
-    let headers = unsafe { &*headers };
+// error response if the caller may not use the route
+fn denied(
+    headers: &HeaderMap,
+    scope: &str,
+) -> Option<*const c_char> {
+    auth::authorize(headers, scope).err().map(|error| {
+        let mut response = Response::new();
+        response.error(error);
+        to_c_response(&response)
+    })
+}
 
-    println!("Headers: {:?}", headers);
+// denied() for the routes of the whole instance, refused to the tenant keys
+fn instance_denied(
+    headers: &HeaderMap,
+    scope: &str,
+) -> Option<*const c_char> {
+    denied(headers, scope).or_else(|| tenant::instance_only().err().map(|error| {
+        let mut response = Response::new();
+        response.error(error);
+        to_c_response(&response)
+    }))
+}
 
-    let mut response = Response {
-        status: "error".to_string(),
-        message: "Internal plugin error".to_string(),
-    };
+fn to_c_text(text: &[u8]) -> *const c_char {
+    let mut bytes = Vec::with_capacity(text.len() + 1);
+    bytes.extend_from_slice(text);
+    to_c_bytes(bytes, "\u{FFFD}".as_bytes())
+}
 
-    // Check if the content type is JSON
-    if match headers.get("content-type") {
-        Some(value) => {
-            if value.to_str().unwrap_or("").to_string() != "application/json" {
-                response.message = format!("Invalid content type: {:?}", value);
-                true
-            } else {
-                false
-            }
-        },
-        None => {
-            response.message = "No content type".to_string();
-            true
-        },
-    } {
-        return to_c_response(&response);
-    }
+// query string parameters, the host passes them in the x-raw-query header
+fn query_params(headers: &HeaderMap) -> std::collections::HashMap<String, String> {
+    headers.get("x-raw-query")
+        .and_then(|value| value.to_str().ok())
+        .map(|query| form_urlencoded::parse(query.as_bytes())
+            .into_owned()
+            .collect())
+        .unwrap_or_default()
+}
 
-    // Convert body pointer to a Rust string
-    let body_str = unsafe {
-        CStr::from_ptr(body)
-            .to_str()
-            .unwrap_or("Invalid UTF-8 sequence") // Handle possible UTF-8 errors
-    };
+fn parse_mailbox(address: &str) -> Result<Mailbox, SendError> {
+    address.parse()
+        .map_err(|e| SendError::new("invalid_address", format!("Invalid address {:?}: {}", address, e)))
+}
 
-    // println!("Body Str: {}", body_str);
+fn parse_mailboxes(addresses: &str) -> Result<lettre::message::Mailboxes, SendError> {
+    addresses.parse()
+        .map_err(|e| SendError::new("invalid_address", format!("Invalid address {:?}: {}", addresses, e)))
+}
 
-    let mail: Mail = match serde_json::from_str(body_str) {
-        Ok(m) => m,
-        Err(e) => {
-            response.message = format!("Invalid JSON: {:?}", e);
-            return to_c_response(&response);
-        },
-    };
+fn build_message(
+    mail: &Mail,
+) -> Result<Message, SendError> {
 
-    for (field, message) in vec![
-        (&mail.from, "No from address"),
-        (&mail.to, "No to address"),
+    let identity = identity::headers(mail)?;
+    let mut builder = Message::builder()
+        .from(identity.from)
+        .to(parse_mailbox(&mail.to)?)
+        .subject(&mail.subject)
+        .message_id(None);
+    // the Bcc header isn't written, only its envelope recipients
+    for (list, bcc) in [(&mail.cc, false), (&mail.bcc, true)] {
+        let Some(list) = list.as_deref().filter(|list| !list.trim().is_empty()) else {
+            continue;
+        };
+        for mailbox in parse_mailboxes(list)? {
+            builder = match bcc {
+                true => builder.bcc(mailbox),
+                false => builder.cc(mailbox),
+            };
+        }
+    }
+    if let Some(sender) = identity.sender {
+        builder = builder.sender(sender);
+    }
+    if let Some(reply_to) = identity.reply_to {
+        builder = builder.reply_to(reply_to);
+    }
+
+    let text = content::text_part(mail)?;
+    let html = mail.html.as_deref()
+        .map(|html| content::html_part(mail, html))
+        .transpose()?;
+
+    let mut loaded = Vec::new();
+    for attachment in mail.attachments.iter().flatten() {
+        let mut span = telemetry::span("attachment.fetch");
+        let attachment = attachments::load(attachment, &SMTP_CLIENT)
+            .inspect_err(|e| span.error(&e.message))?;
+        loaded.push(attachment);
+    }
+    let mut rendered = None;
+    if let Some(request) = &mail.pdf {
+        let mut span = telemetry::span("attachment.pdf");
+        let pdf = pdf::rendered(request, mail)
+            .inspect_err(|e| span.error(&e.message))?;
+        rendered = Some((request, pdf));
+    }
+
+    let mut parts = Vec::new();
+    match &mail.encrypt_attachments {
+        // the attachments and the PDF in a single archive
+        Some(request) => {
+            let mut files = Vec::new();
+            for attachment in loaded {
+                let content = attachment.content()
+                    .map_err(|e| SendError::new("invalid_attachment", format!("Attachment {}: {}", attachment.filename, e)))?;
+                files.push((attachment.filename, content));
+            }
+            if let Some((request, pdf)) = rendered {
+                files.push((request.filename.clone(), pdf));
+            }
+            let mut span = telemetry::span("attachment.archive");
+            let part = archive::attachment(request, files)
+                .inspect_err(|e| span.error(&e.message))?;
+            parts.push(part);
+        },
+        None => {
+            parts.extend(loaded.into_iter().map(attachments::Loaded::part));
+            if let Some((request, pdf)) = rendered {
+                parts.push(pdf::attachment(request, pdf)?);
+            }
+        },
+    }
+    if let Some(card) = &mail.contact_card {
+        parts.push(vcard::attachment(card)?);
+    }
+
+    let email = match (parts.is_empty(), html) {
+        (false, html) => {
+            let mut multipart = match html {
+                Some(html) => MultiPart::mixed()
+                    .multipart(MultiPart::alternative().singlepart(text).singlepart(html)),
+                None => MultiPart::mixed()
+                    .singlepart(text),
+            };
+            for part in parts {
+                multipart = multipart.singlepart(part);
+            }
+            builder.multipart(multipart)
+        },
+        (true, Some(html)) => builder.multipart(MultiPart::alternative().singlepart(text).singlepart(html)),
+        _ => builder.singlepart(text),
+    };
+
+    let mut email = email.map_err(|e| SendError::new("invalid_message", format!("Failed to build email: {}", e)))?;
+    date::set(&mut email, mail.timezone.as_deref())?;
+    headers::apply(&mut email, mail)?;
+
+    Ok(email)
+}
+
+// send a built message and record the outcome in the history
+fn deliver(
+    mail: &Mail,
+    email: &Message,
+    response: &mut Response,
+) -> Result<(), pool::Failure> {
+
+    let mut span = telemetry::span("smtp.send");
+    let _confirming = consent::confirming(mail);
+    let transcript = mail.debug.unwrap_or(false).then(transcript::Transcript::new);
+    // formatted once rather than cloned with its attachments
+    let result = transport::send(
+        mail.account.as_deref(),
+        email.envelope(),
+        email.formatted(),
+        pool::timeout(mail.timeout_ms),
+        transcript.as_ref(),
+        mail.labels.as_deref().unwrap_or_default(),
+    );
+    response.transcript = transcript.map(|transcript| transcript.lines());
+    match &result {
+        Ok(sent) => {
+            response.status = "success".to_string();
+            response.message = format!("Email sent successfully: {}", sent.reply);
+            response.sent_from(sent);
+            report::smtp_success();
+            latency::record(mail);
+            duplicates::record(mail, email.headers().get_raw("Message-ID"));
+        },
+        Err(failure) => response.failure(failure, failure_message(failure, "Send", "send")),
+    };
+
+    if result.is_err() {
+        span.error(&response.message);
+    }
+    span.attribute("code", response.code.as_deref().unwrap_or(""));
+    drop(span);
+    telemetry::count("arp_gmail.sends", &[
+        ("status", &response.status),
+        ("code", response.code.as_deref().unwrap_or("")),
+    ]);
+
+    response.id = history::record(history::Record {
+        message_id: email.headers().get_raw("Message-ID"),
+        from: &mail.from,
+        to: &mail.to,
+        subject: &mail.subject,
+        status: &response.status,
+        response: &response.message,
+        eml: result.is_ok().then(|| email.formatted()).as_deref(),
+    });
+
+    if SMTP_CLIENT.hooks.is_some() {
+        let annotations = hooks::post_send(mail, &serde_json::json!({
+            "status": response.status,
+            "code": response.code,
+            "message": response.message,
+            "message_id": email.headers().get_raw("Message-ID"),
+            "id": response.id,
+        }));
+        response.annotate(annotations);
+    }
+
+    result.map(|_| ())
+}
+
+// the message of a failed send, "Send" and "send" or those of a forward
+fn failure_message(
+    failure: &pool::Failure,
+    label: &str,
+    verb: &str,
+) -> String {
+
+    match failure {
+        pool::Failure::Timeout(timeout) => format!("{} timed out after {} ms", label, timeout.as_millis()),
+        pool::Failure::CoolingDown(until) => format!(
+            "Sending is paused until {} after a Gmail sending limit error",
+            quota::format_time(*until),
+        ),
+        pool::Failure::LimitReached(reset) => format!(
+            "The daily sending limit is reached until {}",
+            quota::format_time(*reset),
+        ),
+        pool::Failure::RecipientLimited(reason) => format!("Recipient rate limit: {}", reason),
+        pool::Failure::Suppressed(reason) => format!("Suppressed recipient: {}", reason),
+        pool::Failure::KnownInvalid(reason) => format!("Known invalid recipient: {}", reason),
+        pool::Failure::Smtp(error) => format!("Failed to {} email: {}", verb, error),
+        pool::Failure::Dns(reason) => format!("Failed to {} email: {}", verb, reason),
+        pool::Failure::Connect(reason) => format!("Failed to {} email: {}", verb, reason),
+        pool::Failure::Tls(reason) => format!("Failed to {} email: {}", verb, reason),
+        pool::Failure::Api(error) => format!("Failed to {} email: {}", verb, error),
+        pool::Failure::Injected(reason) => format!("Failed to {} email: {}", verb, reason),
+        pool::Failure::Account(reason) => reason.to_string(),
+        pool::Failure::Busy(workers) => format!("All {} send workers are busy and their backlog is full", workers),
+    }
+}
+
+// send a message prebuilt or forwarded as is and record it in the history
+fn relay(
+    resent: &forward::Resent,
+    account: Option<&str>,
+    timeout_ms: Option<u64>,
+    forwarded: bool,
+    debug: bool,
+    response: &mut Response,
+) {
+
+    let (label, verb, done, counter) = match forwarded {
+        true => ("Forward", "forward", "forwarded", "arp_gmail.forwards"),
+        false => ("Send", "send", "sent", "arp_gmail.sends"),
+    };
+
+    let mut span = telemetry::span("smtp.send");
+    let transcript = debug.then(transcript::Transcript::new);
+    let result = transport::send(
+        account,
+        &resent.envelope,
+        resent.message.clone(),
+        pool::timeout(timeout_ms),
+        transcript.as_ref(),
+        &[],
+    );
+    response.transcript = transcript.map(|transcript| transcript.lines());
+    match &result {
+        Ok(sent) => {
+            response.status = "success".to_string();
+            response.message = format!("Email {} successfully: {}", done, sent.reply);
+            response.sent_from(sent);
+            report::smtp_success();
+        },
+        Err(failure) => response.failure(failure, failure_message(failure, label, verb)),
+    };
+
+    if result.is_err() {
+        span.error(&response.message);
+    }
+    span.attribute("code", response.code.as_deref().unwrap_or(""));
+    drop(span);
+    telemetry::count(counter, &[
+        ("status", &response.status),
+        ("code", response.code.as_deref().unwrap_or("")),
+    ]);
+
+    let recipients = resent.envelope.to()
+        .iter()
+        .map(|address| address.to_string())
+        .collect::<Vec<String>>()
+        .join(", ");
+    response.id = history::record(history::Record {
+        message_id: Some(&resent.message_id),
+        from: &SMTP_CLIENT.username,
+        to: &recipients,
+        subject: &resent.subject,
+        status: &response.status,
+        response: &response.message,
+        eml: result.is_ok().then_some(resent.message.as_slice()),
+    });
+}
+
+// check the content type and deserialize the JSON body of a request
+fn json_body<T: serde::de::DeserializeOwned>(
+    headers: &HeaderMap,
+    body: *const c_char,
+) -> Result<T, SendError> {
+
+    // Check if the content type is JSON
+    let content_type = headers.get("content-type")
+        .ok_or(SendError::new("invalid_request", "No content type"))?;
+    let (media_type, charset) = encoding::media_type(content_type.to_str().unwrap_or(""));
+    if media_type != "application/json" {
+        return Err(SendError::new("invalid_request", format!("Invalid content type: {:?}", content_type)));
+    }
+
+    let body = unsafe { CStr::from_ptr(body) }.to_bytes();
+    let body_str = encoding::decode(charset.as_deref(), body)?;
+
+    serde_json::from_str(&body_str)
+        .map_err(|e| SendError::new("invalid_request", format!("Invalid JSON: {:?}", e)))
+}
+
+// the JSON body, or the fields of an HTML form
+fn form_or_json_body<T: serde::de::DeserializeOwned>(
+    headers: &HeaderMap,
+    body: *const c_char,
+) -> Result<T, SendError> {
+
+    let content_type = headers.get("content-type")
+        .and_then(|value| value.to_str().ok())
+        .unwrap_or("");
+    let (media_type, charset) = encoding::media_type(content_type);
+    if media_type != "application/x-www-form-urlencoded" {
+        return json_body(headers, body);
+    }
+
+    let body = unsafe { CStr::from_ptr(body) }.to_bytes();
+    form::parse(&encoding::decode(charset.as_deref(), body)?)
+}
+
+// the recipients counted against the budget of the api key, none when the
+// request is refused over it
+fn spend(
+    recipients: usize,
+    response: &mut Response,
+) -> Option<Option<budget::Spent>> {
+    match budget::spend(recipients as u64) {
+        Ok(spent) => {
+            response.budget = spent.as_ref().map(|spent| spent.usage.clone());
+            Some(spent)
+        },
+        Err(error) => {
+            response.error(error);
+            response.retryable = Some(true);
+            None
+        },
+    }
+}
+
+// queued once an admin approves it; true when the mail is held or can't be
+fn held(
+    mail: &Mail,
+    approvals: Vec<String>,
+    response: &mut Response,
+) -> bool {
+    match review::hold(mail, approvals) {
+        Ok(Some((pending, reasons))) => {
+            response.status = "pending".to_string();
+            response.message = format!("Email held for review: {}", reasons.join(", "));
+            response.pending = Some(pending);
+            true
+        },
+        Ok(None) => false,
+        Err(error) => {
+            response.error(error);
+            true
+        },
+    }
+}
+
+// the checks and the send of a request, shared by the routes sending a mail
+// the bulk job of the mail, if any, is the one the plugin created for it
+fn submit(
+    mut mail: Mail,
+    batch: Option<i64>,
+    response: &mut Response,
+) {
+
+    // never the ones of the body
+    mail.tenant = tenant::current();
+    mail.batch = batch;
+    mail.policy_headers.clear();
+    mail.accepted_ms = Some(latency::now_ms());
+    mail.consent = consent::current();
+
+    // strict persistence, the sends wait for the history and audit rows
+    if let Err(error) = persistence::check() {
+        response.error(error);
+        response.retryable = Some(true);
+        return;
+    }
+    if let Err(error) = queue::lane(&mut mail) {
+        response.error(error);
+        return;
+    }
+    if let Err(error) = budget::attachments(mail.attachments.as_deref().unwrap_or_default()) {
+        response.error(error);
+        return;
+    }
+
+    if let Err(error) = transport::check(mail.account.as_deref()) {
+        response.error(error);
+        return;
+    }
+    if let Err(error) = gmail_api::check_labels(mail.labels.as_deref().unwrap_or_default()) {
+        response.error(error);
+        return;
+    }
+
+    if let Some(expires_at) = mail.expires_at.filter(|&expires_at| expires_at <= db::now()) {
+        response.error(SendError::new("expired", format!("The message expired at {}", quota::format_time(expires_at))));
+        return;
+    }
+
+    // the transcript is only returned to the request
+    if mail.debug.unwrap_or(false) {
+        if mail.queue.unwrap_or(false) || mail.digest.is_some() {
+            response.error(SendError::new("invalid_request", "A debug send can't be queued or digested"));
+            return;
+        }
+        if queue::paused() {
+            response.error(SendError::new("paused", "Sending is paused, a debug send isn't queued"));
+            response.retryable = Some(true);
+            return;
+        }
+    }
+
+    // a message built by the caller is only checked and relayed
+    if mail.raw_mime.is_some() {
+        if review::enabled() {
+            response.error(SendError::new("forbidden", "A raw_mime message can't be reviewed, it isn't sent while \"review\" is set"));
+            return;
+        }
+        if policy::enabled() {
+            response.error(SendError::new("forbidden", "A raw_mime message can't be matched by the content policies, it isn't sent while \"policies\" is set"));
+            return;
+        }
+        if hooks::pre_send_enabled() {
+            response.error(SendError::new("forbidden", "A raw_mime message can't go through the pre_send hooks, it isn't sent while one is set"));
+            return;
+        }
+        if mail.queue.unwrap_or(false) || mail.digest.is_some() {
+            response.error(SendError::new("invalid_request", "A raw_mime message can't be queued or digested"));
+            return;
+        }
+        if queue::paused() {
+            response.error(SendError::new("paused", "Sending is paused"));
+            response.retryable = Some(true);
+            return;
+        }
+        let prebuilt = match forward::prebuilt(&mail, &SMTP_CLIENT) {
+            Ok(prebuilt) => prebuilt,
+            Err(error) => {
+                response.error(error);
+                return;
+            },
+        };
+        let Some(spent) = spend(prebuilt.envelope.to().len(), response) else {
+            return;
+        };
+        relay(&prebuilt, mail.account.as_deref(), mail.timeout_ms, false, mail.debug.unwrap_or(false), response);
+        budget::settle(spent, response);
+        return;
+    }
+
+    let mut span = telemetry::span("validate");
+    match content::message_body(&mail, &SMTP_CLIENT) {
+        Ok(message) => mail.message = message,
+        Err(error) => {
+            span.error(&error.message);
+            response.error(error);
+            return;
+        },
+    };
+    if let Err(error) = templates::render_mail(&mut mail) {
+        span.error(&error.message);
+        response.error(error);
+        return;
+    }
+    // the html body of a template is rendered by now
+    if let Err(error) = pdf::check(&mail)
+        .and_then(|_| vcard::check(&mail))
+        .and_then(|_| archive::check(&mail)) {
+        span.error(&error.message);
+        response.error(error);
+        return;
+    }
+
+    // the hooks see the rendered mail, the policies and the checks the one
+    // they changed
+    match hooks::pre_send(&mut mail) {
+        Ok(annotations) => response.annotate(annotations),
+        Err(error) => {
+            span.error(&error.message);
+            response.retryable = Some(error.code == "hook_failed");
+            response.error(error);
+            return;
+        },
+    }
+
+    for (field, message) in [
+        (&mail.from, "No from address"),
+        (&mail.to, "No to address"),
         (&mail.subject, "No subject"),
         (&mail.message, "No message"),
     ] {
         if field.is_empty() {
+            span.error(message);
             response.message = message.to_string();
+            return;
+        }
+    }
+    drop(span);
+
+    let approvals = match policy::apply(&mut mail) {
+        Ok(applied) => {
+            response.policies = Some(applied.rules).filter(|rules| !rules.is_empty());
+            applied.approvals
+        },
+        Err(error) => {
+            response.error(error);
+            return;
+        },
+    };
+    // the account a policy forced
+    if let Err(error) = transport::check(mail.account.as_deref()) {
+        response.error(error);
+        return;
+    }
+
+    if let Some(duplicate) = duplicates::find(&mail) {
+        response.error(SendError::new(
+            "duplicate_suppressed",
+            format!(
+                "An identical message was sent to {} at {}",
+                mail.to,
+                quota::format_time(duplicate.sent_at),
+            ),
+        ));
+        response.duplicate_of = duplicate.message_id;
+        return;
+    }
+
+    if let Some(period) = &mail.digest {
+        // held like the other emails, added to the digest once approved
+        if held(&mail, approvals, response) {
+            return;
+        }
+        match digest::add(&mail, period) {
+            Ok(_) => {
+                scheduler::start();
+                response.status = "success".to_string();
+                response.message = format!("Email added to the {} digest", period);
+            },
+            Err(error) => response.error(error),
+        };
+        return;
+    }
+
+    let email = match build_message(&mail) {
+        Ok(email) => email,
+        Err(error) => {
+            response.error(error);
+            return;
+        },
+    };
+
+    if let Some(settings) = &SMTP_CLIENT.spam_check {
+        let _span = telemetry::span("spam_check");
+        match spam::check(settings, &email.formatted()) {
+            Ok(report) => {
+                let refused = report.score > report.threshold && !mail.force.unwrap_or(false);
+                if refused {
+                    response.error(SendError::new(
+                        "spam_threshold_exceeded",
+                        format!("Spam score {} is above the threshold {}", report.score, report.threshold),
+                    ));
+                }
+                response.spam = Some(report);
+                if refused {
+                    return;
+                }
+            },
+            // the check is only a deliverability aid, don't block sending on it
+            Err(e) => log!("Spam check skipped: {}", e),
+        }
+    }
+
+    let Some(spent) = spend(email.envelope().to().len(), response) else {
+        return;
+    };
+
+    if held(&mail, approvals, response) {
+        budget::settle(spent, response);
+        return;
+    }
+
+    // while sending is paused the emails are kept in the queue
+    if mail.queue.unwrap_or(false) || queue::paused() {
+        match queue::enqueue(&mail) {
+            Ok(job) => {
+                response.status = "queued".to_string();
+                response.message = match queue::paused() {
+                    true => "Sending is paused, email queued".to_string(),
+                    false => "Email queued".to_string(),
+                };
+                response.job = Some(job);
+            },
+            Err(error) => response.error(error),
+        };
+        budget::settle(spent, response);
+        return;
+    }
+
+    // https://myaccount.google.com/apppasswords
+
+    let greylisted = deliver(&mail, &email, response).is_err()
+        && response.code.as_deref() == Some("greylisted");
+    if !greylisted {
+        budget::settle(spent, response);
+        return;
+    }
+
+    // retried from the queue once the greylist window has passed
+    match queue::defer(&mail) {
+        Ok(job) => {
+            response.status = "queued".to_string();
+            response.message = format!(
+                "Email greylisted by the server, retrying in {} seconds",
+                queue::settings().greylist_secs,
+            );
+            response.job = Some(job);
+        },
+        Err(error) => {
+            log!("Error deferring greylisted email: {}", error.message);
+            budget::settle(spent, response);
+        },
+    };
+}
+
+#[no_mangle]
+pub extern "C" fn sendmail(
+    headers: *mut HeaderMap,
+    body: *const c_char,
+) -> *const c_char {
+
+    guarded("sendmail", || {
+        if headers.is_null() || body.is_null() {
+            // Handle the null pointer case
+            return std::ptr::null_mut();
+        }
+
+        // Convert headers pointer to a reference
+        let headers = unsafe { &*headers };
+
+        if let Some(denied) = denied(headers, "send") {
+            return denied;
+        }
+
+        let request_id = trace::request_id(headers);
+        let _trace = trace::enter(Some(request_id.clone()));
+
+        let _span = telemetry::span("sendmail");
+
+        let mut response = Response::new();
+        response.request_id = Some(request_id.clone());
+
+        if shutdown::stopping() {
+            response.error(SendError::new("shutting_down", "The plugin is shutting down"));
+            return to_c_response(&response);
+        }
+
+        let mut mail: Mail = match form_or_json_body(headers, body) {
+            Ok(mail) => mail,
+            Err(error) => {
+                response.error(error);
+                return to_c_response(&response);
+            },
+        };
+        mail.request_id = Some(request_id);
+        identity::apply(&mut mail);
+
+        // the transcript shows the account and the servers
+        if mail.debug.unwrap_or(false) {
+            if let Some(denied) = denied(headers, "admin") {
+                return denied;
+            }
+        }
+
+        submit(mail, None, &mut response);
+
+        to_c_response(&response)
+    })
+}
+
+// one submit per recipient of a templated request, the result of each in
+// "results"
+fn send_templated(
+    function: &'static str,
+    headers: *mut HeaderMap,
+    body: *const c_char,
+    queued: bool,
+) -> *const c_char {
+
+    guarded(function, || {
+        if headers.is_null() || body.is_null() {
+            return std::ptr::null_mut();
+        }
+
+        let headers = unsafe { &*headers };
+
+        if let Some(denied) = denied(headers, "send") {
+            return denied;
+        }
+
+        let request_id = trace::request_id(headers);
+        let _trace = trace::enter(Some(request_id.clone()));
+
+        let _span = telemetry::span(function);
+
+        let mut response = Response::new();
+        response.request_id = Some(request_id.clone());
+
+        if shutdown::stopping() {
+            response.error(SendError::new("shutting_down", "The plugin is shutting down"));
+            return to_c_response(&response);
+        }
+
+        let ndjson = headers.get("content-type")
+            .and_then(|value| value.to_str().ok())
+            .is_some_and(ndjson::is);
+        if ndjson {
+            return templated_lines(function, headers, body, queued, &mut response);
+        }
+
+        let request: bulk::BulkRequest = match json_body(headers, body) {
+            Ok(request) => request,
+            Err(error) => {
+                response.error(error);
+                return to_c_response(&response);
+            },
+        };
+        if request.mail.debug.unwrap_or(false) {
+            response.error(SendError::new("invalid_request", "Only /sendmail takes \"debug\""));
             return to_c_response(&response);
         }
-    }
+        let mails = match bulk::mails(&request.mail, &request.recipients) {
+            Ok(mails) => mails,
+            Err(error) => {
+                response.error(error);
+                return to_c_response(&response);
+            },
+        };
+
+        to_c_response(&submit_all(mails, &request_id, queued.then_some(function)))
+    })
+}
+
+// the mails of a list sent one by one, or queued as a bulk job of this kind
+// when it is set; the result of each is passed on as it is submitted, the
+// summary is returned, or the error when the job can't be created
+fn submit_each(
+    mails: impl Iterator<Item = Result<Mail, SendError>>,
+    total: usize,
+    request_id: &str,
+    job: Option<&str>,
+    mut result: impl FnMut(usize, Response),
+) -> Result<serde_json::Value, SendError> {
+
+    let batch = job.map(|kind| jobs::create(kind, total)).transpose()?;
+
+    let mut failed = 0;
+    for (row, mail) in mails.enumerate() {
+        let mut response = Response::new();
+        response.request_id = Some(request_id.to_string());
+        match mail {
+            Ok(mut mail) => {
+                mail.request_id = Some(request_id.to_string());
+                if batch.is_some() {
+                    mail.queue = Some(true);
+                    mail.priority.get_or_insert_with(|| queue::BULK.to_string());
+                }
+                identity::apply(&mut mail);
+
+                let to = mail.to.clone();
+                submit(mail, batch, &mut response);
+                if response.status == "error" {
+                    jobs::record(batch, &to, "failed", response.code.as_deref(), &response.message, None);
+                }
+            },
+            // a row of NDJSON that can't be read
+            Err(error) => {
+                response.error(error);
+                jobs::record(batch, "", "failed", response.code.as_deref(), &response.message, None);
+            },
+        }
+        if response.status == "error" {
+            failed += 1;
+        }
+        result(row + 1, response);
+    }
+    // refused before they were queued
+    jobs::failed(batch, failed);
+
+    let done = match batch {
+        Some(_) => "queued",
+        None => "sent",
+    };
+    Ok(serde_json::json!({
+        "status": if failed == 0 { "success" } else { "error" },
+        "message": format!("{} of {} emails {}", total - failed, total, done),
+        "request_id": request_id,
+        "bulk_job": batch,
+    }))
+}
+
+fn submit_all(
+    mails: Vec<Mail>,
+    request_id: &str,
+    job: Option<&str>,
+) -> serde_json::Value {
+
+    let total = mails.len();
+    let mut results = Vec::with_capacity(total);
+    match submit_each(mails.into_iter().map(Ok), total, request_id, job, |_, response| results.push(response)) {
+        Ok(mut summary) => {
+            summary["results"] = serde_json::json!(results);
+            summary
+        },
+        Err(error) => {
+            let mut response = Response::new();
+            response.request_id = Some(request_id.to_string());
+            response.error(error);
+            serde_json::json!(response)
+        },
+    }
+}
+
+// the rows of an NDJSON body submitted as they are read: a line per result,
+// the summary last
+fn submit_lines(
+    mails: impl Iterator<Item = Result<Mail, SendError>>,
+    total: usize,
+    request_id: &str,
+    job: Option<&str>,
+) -> *const c_char {
+
+    let mut out = Vec::with_capacity(RESPONSE_CAPACITY);
+    match submit_each(mails, total, request_id, job, |row, response| ndjson::result(&mut out, row, &response)) {
+        Ok(summary) => ndjson::write(&mut out, &summary),
+        Err(error) => {
+            let mut response = Response::new();
+            response.request_id = Some(request_id.to_string());
+            response.error(error);
+            ndjson::write(&mut out, &response);
+        },
+    }
+    to_c_bytes(out, b"\\u0000")
+}
+
+// the NDJSON body of a templated send, the request line and a recipient per
+// line, the request "to" when there are none
+fn templated_lines(
+    function: &'static str,
+    headers: &HeaderMap,
+    body: *const c_char,
+    queued: bool,
+    response: &mut Response,
+) -> *const c_char {
+
+    let content_type = headers.get("content-type")
+        .and_then(|value| value.to_str().ok())
+        .unwrap_or("");
+    let body = unsafe { CStr::from_ptr(body) }.to_bytes();
+    let text = match ndjson::decode(content_type, body) {
+        Ok(text) => text,
+        Err(error) => return ndjson::error(response, error),
+    };
+
+    let mut lines = ndjson::lines(&text);
+    let request: bulk::BulkRequest = match ndjson::header(&mut lines) {
+        Ok(request) => request,
+        Err(error) => return ndjson::error(response, error),
+    };
+    if request.mail.debug.unwrap_or(false) {
+        return ndjson::error(response, SendError::new("invalid_request", "Only /sendmail takes \"debug\""));
+    }
+    if !request.recipients.is_empty() {
+        return ndjson::error(response, SendError::new("invalid_request", "The recipients of NDJSON are the lines after the request, not \"recipients\""));
+    }
+    let request_id = response.request_id.clone().unwrap_or_default();
+    let job = queued.then_some(function);
+
+    let total = ndjson::lines(&text).count() - 1;
+    if total == 0 {
+        return match bulk::mails(&request.mail, &[]) {
+            Ok(mails) => submit_lines(mails.into_iter().map(Ok), 1, &request_id, job),
+            Err(error) => ndjson::error(response, error),
+        };
+    }
+    if let Err(error) = bulk::check(&request.mail) {
+        return ndjson::error(response, error);
+    }
+
+    let mails = lines.enumerate()
+        .map(|(row, line)| ndjson::row(row + 1, line)
+            .map(|recipient| bulk::personal(&request.mail, recipient)));
+    submit_lines(mails, total, &request_id, job)
+}
+
+#[no_mangle]
+pub extern "C" fn sendtemplate(
+    headers: *mut HeaderMap,
+    body: *const c_char,
+) -> *const c_char {
+    send_templated("sendtemplate", headers, body, false)
+}
+
+#[no_mangle]
+pub extern "C" fn sendbulk(
+    headers: *mut HeaderMap,
+    body: *const c_char,
+) -> *const c_char {
+    send_templated("sendbulk", headers, body, true)
+}
+
+#[no_mangle]
+pub extern "C" fn sendmerge(
+    headers: *mut HeaderMap,
+    body: *const c_char,
+) -> *const c_char {
+
+    guarded("sendmerge", || {
+        if headers.is_null() || body.is_null() {
+            return std::ptr::null_mut();
+        }
+
+        let headers = unsafe { &*headers };
+
+        if let Some(denied) = denied(headers, "send") {
+            return denied;
+        }
+
+        let request_id = trace::request_id(headers);
+        let _trace = trace::enter(Some(request_id.clone()));
+
+        let _span = telemetry::span("sendmerge");
+
+        let mut response = Response::new();
+        response.request_id = Some(request_id.clone());
+
+        if shutdown::stopping() {
+            response.error(SendError::new("shutting_down", "The plugin is shutting down"));
+            return to_c_response(&response);
+        }
+
+        // JSON or a multipart upload, the CSV may not be UTF-8
+        let content_type = headers.get("content-type")
+            .and_then(|value| value.to_str().ok())
+            .unwrap_or("");
+        let body = unsafe { CStr::from_ptr(body) }.to_bytes();
+
+        if ndjson::is(content_type) {
+            return merge_lines(content_type, body, &mut response);
+        }
+
+        let mails = merge::parse(content_type, body)
+            .and_then(|request| {
+                let recipients = merge::recipients(&request)?;
+                Ok((bulk::mails(&request.mail, &recipients)?, request))
+            });
+        let (mails, request) = match mails {
+            Ok(mails) => mails,
+            Err(error) => {
+                response.error(error);
+                return to_c_response(&response);
+            },
+        };
+
+        if request.dry_run {
+            return to_c_response(&serde_json::json!({
+                "status": "success",
+                "message": format!("Dry run of {} emails, nothing queued", mails.len()),
+                "request_id": request_id,
+                "rows": mails.len(),
+                "previews": merge::previews(&mails, request.previews),
+            }));
+        }
+
+        to_c_response(&submit_all(mails, &request_id, Some("sendmerge")))
+    })
+}
+
+// the NDJSON body of a merge, the request line and an object of the columns
+// per line; a dry run renders the first rows only
+fn merge_lines(
+    content_type: &str,
+    body: &[u8],
+    response: &mut Response,
+) -> *const c_char {
+
+    let text = match ndjson::decode(content_type, body) {
+        Ok(text) => text,
+        Err(error) => return ndjson::error(response, error),
+    };
+
+    let mut lines = ndjson::lines(&text);
+    let request: merge::MergeRequest = match ndjson::header(&mut lines) {
+        Ok(request) => request,
+        Err(error) => return ndjson::error(response, error),
+    };
+    let checked = merge::lines_only(&request)
+        .and_then(|_| bulk::check(&request.mail));
+    if let Err(error) = checked {
+        return ndjson::error(response, error);
+    }
+    let request_id = response.request_id.clone().unwrap_or_default();
+
+    let total = ndjson::lines(&text).count() - 1;
+    if total == 0 {
+        return ndjson::error(response, SendError::new("invalid_request", "The merge has no rows"));
+    }
+    let mut mails = lines.enumerate()
+        .map(|(row, line)| ndjson::row(row + 1, line)
+            .and_then(|columns| merge::row(row + 1, columns))
+            .map(|recipient| bulk::personal(&request.mail, recipient)));
+
+    if request.dry_run {
+        let mails: Result<Vec<Mail>, SendError> = mails.by_ref()
+            .take(merge::preview_count(request.previews))
+            .collect();
+        return match mails {
+            Ok(mails) => ndjson::respond(&serde_json::json!({
+                "status": "success",
+                "message": format!("Dry run of {} emails, nothing queued", total),
+                "request_id": request_id,
+                "rows": total,
+                "previews": merge::previews(&mails, request.previews),
+            })),
+            Err(error) => ndjson::error(response, error),
+        };
+    }
+
+    submit_lines(mails, total, &request_id, Some("sendmerge"))
+}
+
+// diagnostic message to the configured test recipients, a recipient in
+// the body is ignored so the route can't mail anyone else
+fn test_message(
+    mail: &Mail,
+    recipients: &[String],
+) -> Result<Message, SendError> {
+
+    let mut builder = Message::builder()
+        .from(identity::headers(mail)?.from)
+        .subject(&mail.subject)
+        .message_id(None);
+    for recipient in recipients {
+        builder = builder.to(parse_mailbox(recipient)?);
+    }
+
+    let mut email = builder.singlepart(SinglePart::builder()
+            .header(ContentType::TEXT_PLAIN)
+            .body(mail.message.clone()))
+        .map_err(|e| SendError::new("invalid_message", format!("Failed to build email: {}", e)))?;
+    date::set(&mut email, None)?;
+    headers::apply(&mut email, mail)?;
+
+    Ok(email)
+}
+
+#[no_mangle]
+pub extern "C" fn sendtest(
+    headers: *mut HeaderMap,
+    _body: *const c_char,
+) -> *const c_char {
+
+    guarded("sendtest", || {
+        if headers.is_null() {
+            return std::ptr::null_mut();
+        }
+
+        let headers = unsafe { &*headers };
+
+        if let Some(denied) = denied(headers, "send") {
+            return denied;
+        }
+
+        let request_id = trace::request_id(headers);
+        let _trace = trace::enter(Some(request_id.clone()));
+
+        let _span = telemetry::span("sendtest");
+
+        let mut response = Response::new();
+        response.request_id = Some(request_id.clone());
+
+        let recipients = match &SMTP_CLIENT.test_recipients {
+            Some(recipients) if !recipients.is_empty() => recipients,
+            _ => {
+                response.error(SendError::new("not_configured", "No test_recipients in the config"));
+                return to_c_response(&response);
+            },
+        };
+
+        if shutdown::stopping() {
+            response.error(SendError::new("shutting_down", "The plugin is shutting down"));
+            return to_c_response(&response);
+        }
+        if queue::paused() {
+            response.error(SendError::new("paused", "Sending is paused"));
+            response.retryable = Some(true);
+            return to_c_response(&response);
+        }
+        if let Err(error) = persistence::check() {
+            response.error(error);
+            response.retryable = Some(true);
+            return to_c_response(&response);
+        }
+
+        let mail = Mail {
+            from: SMTP_CLIENT.username.clone(),
+            to: recipients.join(", "),
+            subject: "arp-gmail test message".to_string(),
+            message: format!(
+                "This is a test message from arp-gmail {}.\n\n\
+                Sent at {} by {} through {}.\n\
+                Request id: {}\n",
+                VERSION,
+                quota::format_time(db::now()),
+                SMTP_CLIENT.username,
+                SMTP_CLIENT.server,
+                request_id,
+            ),
+            automated: Some(true),
+            request_id: Some(request_id),
+            ..Default::default()
+        };
+
+        let email = match test_message(&mail, recipients) {
+            Ok(email) => email,
+            Err(error) => {
+                response.error(error);
+                return to_c_response(&response);
+            },
+        };
+
+        let _ = deliver(&mail, &email, &mut response);
+
+        to_c_response(&response)
+    })
+}
+
+#[no_mangle]
+pub extern "C" fn forward(
+    headers: *mut HeaderMap,
+    body: *const c_char,
+) -> *const c_char {
+
+    guarded("forward", || {
+        if headers.is_null() || body.is_null() {
+            return std::ptr::null_mut();
+        }
+
+        let headers = unsafe { &*headers };
+
+        if let Some(denied) = denied(headers, "send") {
+            return denied;
+        }
+
+        let request_id = trace::request_id(headers);
+        let _trace = trace::enter(Some(request_id.clone()));
+
+        let _span = telemetry::span("forward");
+
+        let mut response = Response::new();
+        response.request_id = Some(request_id);
+
+        if queue::paused() {
+            response.error(SendError::new("paused", "Sending is paused"));
+            response.retryable = Some(true);
+            return to_c_response(&response);
+        }
+        if let Err(error) = persistence::check() {
+            response.error(error);
+            response.retryable = Some(true);
+            return to_c_response(&response);
+        }
+        if review::enabled() {
+            response.error(SendError::new("forbidden", "A forwarded message can't be reviewed, it isn't sent while \"review\" is set"));
+            return to_c_response(&response);
+        }
+        if policy::enabled() {
+            response.error(SendError::new("forbidden", "A forwarded message can't be matched by the content policies, it isn't sent while \"policies\" is set"));
+            return to_c_response(&response);
+        }
+        if hooks::pre_send_enabled() {
+            response.error(SendError::new("forbidden", "A forwarded message can't go through the pre_send hooks, it isn't sent while one is set"));
+            return to_c_response(&response);
+        }
+
+        let request: forward::Forward = match json_body(headers, body) {
+            Ok(request) => request,
+            Err(error) => {
+                response.error(error);
+                return to_c_response(&response);
+            },
+        };
+
+        let resent = match forward::resent_message(&request, &SMTP_CLIENT) {
+            Ok(resent) => resent,
+            Err(error) => {
+                response.error(error);
+                return to_c_response(&response);
+            },
+        };
+
+        let Some(spent) = spend(resent.envelope.to().len(), &mut response) else {
+            return to_c_response(&response);
+        };
+        relay(&resent, None, request.timeout_ms, true, false, &mut response);
+        budget::settle(spent, &mut response);
+
+        to_c_response(&response)
+    })
+}
+
+#[no_mangle]
+pub extern "C" fn history(
+    headers: *mut HeaderMap,
+    _body: *const c_char,
+) -> *const c_char {
+
+    guarded("history", || {
+        if headers.is_null() {
+            return std::ptr::null_mut();
+        }
+
+        let headers = unsafe { &*headers };
+
+        if let Some(denied) = denied(headers, "send") {
+            return denied;
+        }
+
+        let page = match Page::from_query(&query_params(headers), 50) {
+            Ok(page) => page,
+            Err(error) => {
+                let mut response = Response::new();
+                response.error(error);
+                return to_c_response(&response);
+            },
+        };
+
+        match history::list(&page) {
+            Ok((entries, total)) => to_c_response(&page.response("history", entries, total)),
+            Err(e) => {
+                let mut response = Response::new();
+                response.message = e;
+                to_c_response(&response)
+            },
+        }
+    })
+}
+
+#[no_mangle]
+pub extern "C" fn history_eml(
+    headers: *mut HeaderMap,
+    _body: *const c_char,
+) -> *const c_char {
+
+    guarded("history_eml", || {
+        if headers.is_null() {
+            return std::ptr::null_mut();
+        }
+
+        let headers = unsafe { &*headers };
+
+        if let Some(denied) = denied(headers, "send") {
+            return denied;
+        }
+
+        let id = match query_params(headers).get("id").and_then(|id| id.parse().ok()) {
+            Some(id) => id,
+            None => return to_c_text(b"Error: No history id"),
+        };
+
+        match history::eml(id) {
+            Ok(Some(eml)) => to_c_text(&eml),
+            Ok(None) => to_c_text(format!("Error: No stored message for history id {}", id).as_bytes()),
+            Err(e) => to_c_text(format!("Error: {}", e).as_bytes()),
+        }
+    })
+}
+
+#[no_mangle]
+pub extern "C" fn history_purge(
+    headers: *mut HeaderMap,
+    _body: *const c_char,
+) -> *const c_char {
+
+    guarded("history_purge", || {
+        if headers.is_null() {
+            return std::ptr::null_mut();
+        }
+
+        let headers = unsafe { &*headers };
+
+        if let Some(denied) = instance_denied(headers, "admin") {
+            return denied;
+        }
+
+        let mut response = Response::new();
+
+        match privacy::purge() {
+            Ok(message) => {
+                response.status = "success".to_string();
+                response.message = message;
+            },
+            Err(e) => response.message = e,
+        };
+        audit::record(headers, "history.purge", None, &response.status, &response.message);
+
+        to_c_response(&response)
+    })
+}
+
+#[no_mangle]
+pub extern "C" fn recurring(
+    headers: *mut HeaderMap,
+    body: *const c_char,
+) -> *const c_char {
+
+    guarded("recurring", || {
+        if headers.is_null() || body.is_null() {
+            return std::ptr::null_mut();
+        }
+
+        let headers = unsafe { &*headers };
+
+        if let Some(denied) = denied(headers, "send") {
+            return denied;
+        }
+
+        let mut response = Response::new();
+
+        let request: recurring::Request = match json_body(headers, body) {
+            Ok(request) => request,
+            Err(error) => {
+                response.error(error);
+                return to_c_response(&response);
+            },
+        };
+
+        match recurring::handle(&request) {
+            Ok((id, message)) => to_c_response(&serde_json::json!({
+                "status": "success",
+                "message": message,
+                "id": id,
+            })),
+            Err(error) => {
+                response.error(error);
+                to_c_response(&response)
+            },
+        }
+    })
+}
+
+#[no_mangle]
+pub extern "C" fn recurring_list(
+    headers: *mut HeaderMap,
+    _body: *const c_char,
+) -> *const c_char {
+
+    guarded("recurring_list", || {
+        if headers.is_null() {
+            return std::ptr::null_mut();
+        }
+
+        let headers = unsafe { &*headers };
+
+        if let Some(denied) = denied(headers, "send") {
+            return denied;
+        }
+
+        let page = match Page::from_query(&query_params(headers), 100) {
+            Ok(page) => page,
+            Err(error) => {
+                let mut response = Response::new();
+                response.error(error);
+                return to_c_response(&response);
+            },
+        };
+
+        match recurring::list(&page) {
+            Ok((entries, total)) => to_c_response(&page.response("recurring", entries, total)),
+            Err(e) => {
+                let mut response = Response::new();
+                response.message = e;
+                to_c_response(&response)
+            },
+        }
+    })
+}
+
+#[no_mangle]
+pub extern "C" fn deadletter_list(
+    headers: *mut HeaderMap,
+    _body: *const c_char,
+) -> *const c_char {
+
+    guarded("deadletter_list", || {
+        if headers.is_null() {
+            return std::ptr::null_mut();
+        }
+
+        let headers = unsafe { &*headers };
+
+        if let Some(denied) = denied(headers, "send") {
+            return denied;
+        }
+
+        let page = match Page::from_query(&query_params(headers), 100) {
+            Ok(page) => page,
+            Err(error) => {
+                let mut response = Response::new();
+                response.error(error);
+                return to_c_response(&response);
+            },
+        };
+
+        match queue::dead_letters(&page) {
+            Ok((entries, total)) => to_c_response(&page.response("deadletter", entries, total)),
+            Err(e) => {
+                let mut response = Response::new();
+                response.message = e;
+                to_c_response(&response)
+            },
+        }
+    })
+}
+
+#[no_mangle]
+pub extern "C" fn deadletter_entry(
+    headers: *mut HeaderMap,
+    _body: *const c_char,
+) -> *const c_char {
+
+    guarded("deadletter_entry", || {
+        if headers.is_null() {
+            return std::ptr::null_mut();
+        }
+
+        let headers = unsafe { &*headers };
+
+        if let Some(denied) = denied(headers, "send") {
+            return denied;
+        }
+
+        let mut response = Response::new();
+
+        let id = match query_params(headers).get("id").and_then(|id| id.parse().ok()) {
+            Some(id) => id,
+            None => {
+                response.message = "No dead-letter id".to_string();
+                return to_c_response(&response);
+            },
+        };
+
+        match queue::dead_letter_by_id(id) {
+            Ok(Some(entry)) => to_c_response(&serde_json::json!({
+                "status": "success",
+                "deadletter": entry,
+            })),
+            Ok(None) => {
+                response.error(SendError::new("not_found", format!("No dead-letter entry with id {}", id)));
+                to_c_response(&response)
+            },
+            Err(e) => {
+                response.message = e;
+                to_c_response(&response)
+            },
+        }
+    })
+}
+
+#[no_mangle]
+pub extern "C" fn deadletter(
+    headers: *mut HeaderMap,
+    body: *const c_char,
+) -> *const c_char {
+
+    guarded("deadletter", || {
+        if headers.is_null() || body.is_null() {
+            return std::ptr::null_mut();
+        }
+
+        let headers = unsafe { &*headers };
+
+        if let Some(denied) = denied(headers, "send") {
+            return denied;
+        }
+
+        let mut response = Response::new();
+
+        let request: queue::DeadLetterRequest = match json_body(headers, body) {
+            Ok(request) => request,
+            Err(error) => {
+                response.error(error);
+                return to_c_response(&response);
+            },
+        };
+
+        match queue::handle_dead_letter(&request) {
+            Ok(message) => {
+                response.status = "success".to_string();
+                response.message = message;
+            },
+            Err(error) => response.error(error),
+        };
+        audit::record(
+            headers,
+            &format!("deadletter.{}", request.action),
+            Some(&request.id.to_string()),
+            &response.status,
+            &response.message,
+        );
+
+        to_c_response(&response)
+    })
+}
+
+#[no_mangle]
+pub extern "C" fn pending_list(
+    headers: *mut HeaderMap,
+    _body: *const c_char,
+) -> *const c_char {
+
+    guarded("pending_list", || {
+        if headers.is_null() {
+            return std::ptr::null_mut();
+        }
+
+        let headers = unsafe { &*headers };
+
+        if let Some(denied) = denied(headers, "admin") {
+            return denied;
+        }
+
+        let page = match Page::from_query(&query_params(headers), 100) {
+            Ok(page) => page,
+            Err(error) => {
+                let mut response = Response::new();
+                response.error(error);
+                return to_c_response(&response);
+            },
+        };
+
+        match review::list(&page) {
+            Ok((entries, total)) => to_c_response(&page.response("pending", entries, total)),
+            Err(e) => {
+                let mut response = Response::new();
+                response.message = e;
+                to_c_response(&response)
+            },
+        }
+    })
+}
+
+#[no_mangle]
+pub extern "C" fn pending_entry(
+    headers: *mut HeaderMap,
+    _body: *const c_char,
+) -> *const c_char {
+
+    guarded("pending_entry", || {
+        if headers.is_null() {
+            return std::ptr::null_mut();
+        }
+
+        let headers = unsafe { &*headers };
+
+        if let Some(denied) = denied(headers, "admin") {
+            return denied;
+        }
+
+        let mut response = Response::new();
+
+        let id = match query_params(headers).get("id").and_then(|id| id.parse().ok()) {
+            Some(id) => id,
+            None => {
+                response.message = "No pending message id".to_string();
+                return to_c_response(&response);
+            },
+        };
+
+        match review::by_id(id) {
+            Ok(Some(entry)) => to_c_response(&serde_json::json!({
+                "status": "success",
+                "pending": entry,
+            })),
+            Ok(None) => {
+                response.error(SendError::new("not_found", format!("No pending message with id {}", id)));
+                to_c_response(&response)
+            },
+            Err(e) => {
+                response.message = e;
+                to_c_response(&response)
+            },
+        }
+    })
+}
+
+#[no_mangle]
+pub extern "C" fn pending(
+    headers: *mut HeaderMap,
+    body: *const c_char,
+) -> *const c_char {
+
+    guarded("pending", || {
+        if headers.is_null() || body.is_null() {
+            return std::ptr::null_mut();
+        }
+
+        let headers = unsafe { &*headers };
+
+        if let Some(denied) = denied(headers, "admin") {
+            return denied;
+        }
+
+        let mut response = Response::new();
+
+        let request: review::ReviewRequest = match json_body(headers, body) {
+            Ok(request) => request,
+            Err(error) => {
+                response.error(error);
+                return to_c_response(&response);
+            },
+        };
+
+        match review::decide(&request) {
+            Ok((message, job)) => {
+                response.status = "success".to_string();
+                response.message = message;
+                response.job = job;
+            },
+            Err(error) => response.error(error),
+        };
+        audit::record(
+            headers,
+            &format!("pending.{}", request.action),
+            Some(&request.id.to_string()),
+            &response.status,
+            &response.message,
+        );
+
+        to_c_response(&response)
+    })
+}
+
+#[no_mangle]
+pub extern "C" fn templates_list(
+    headers: *mut HeaderMap,
+    _body: *const c_char,
+) -> *const c_char {
+
+    guarded("templates_list", || {
+        if headers.is_null() {
+            return std::ptr::null_mut();
+        }
+
+        let headers = unsafe { &*headers };
+
+        if let Some(denied) = denied(headers, "send") {
+            return denied;
+        }
+
+        let page = match Page::from_query(&query_params(headers), 100) {
+            Ok(page) => page,
+            Err(error) => {
+                let mut response = Response::new();
+                response.error(error);
+                return to_c_response(&response);
+            },
+        };
+
+        match templates::list() {
+            Ok(entries) => {
+                let total = entries.len();
+                to_c_response(&page.response("templates", page.slice(entries), total))
+            },
+            Err(error) => {
+                let mut response = Response::new();
+                response.error(error);
+                to_c_response(&response)
+            },
+        }
+    })
+}
+
+#[no_mangle]
+pub extern "C" fn templates_entry(
+    headers: *mut HeaderMap,
+    _body: *const c_char,
+) -> *const c_char {
+
+    guarded("templates_entry", || {
+        if headers.is_null() {
+            return std::ptr::null_mut();
+        }
+
+        let headers = unsafe { &*headers };
+
+        if let Some(denied) = denied(headers, "send") {
+            return denied;
+        }
+
+        let mut response = Response::new();
+
+        let name = match query_params(headers).remove("name") {
+            Some(name) => name,
+            None => {
+                response.message = "No template name".to_string();
+                return to_c_response(&response);
+            },
+        };
+
+        match templates::source(&name) {
+            Ok(content) => to_c_response(&serde_json::json!({
+                "status": "success",
+                "template": {
+                    "name": name,
+                    "content": content,
+                },
+            })),
+            Err(error) => {
+                response.error(error);
+                to_c_response(&response)
+            },
+        }
+    })
+}
+
+#[no_mangle]
+pub extern "C" fn templates(
+    headers: *mut HeaderMap,
+    body: *const c_char,
+) -> *const c_char {
+
+    guarded("templates", || {
+        if headers.is_null() || body.is_null() {
+            return std::ptr::null_mut();
+        }
+
+        let headers = unsafe { &*headers };
+
+        // changing the templates changes what every caller sends
+        if let Some(denied) = denied(headers, "templates") {
+            return denied;
+        }
+
+        let mut response = Response::new();
+
+        let request: templates::TemplateRequest = match json_body(headers, body) {
+            Ok(request) => request,
+            Err(error) => {
+                response.error(error);
+                return to_c_response(&response);
+            },
+        };
+
+        let result = templates::handle(&request);
+        // a validate changes nothing
+        if request.action != "validate" {
+            let (status, message) = match &result {
+                Ok((message, _)) => ("success", message.as_str()),
+                Err(error) => ("error", error.message.as_str()),
+            };
+            audit::record(headers, &format!("templates.{}", request.action), Some(&request.name), status, message);
+        }
+
+        match result {
+            Ok((message, Some(rendered))) => to_c_response(&serde_json::json!({
+                "status": "success",
+                "message": message,
+                "rendered": rendered,
+            })),
+            Ok((message, None)) => {
+                response.status = "success".to_string();
+                response.message = message;
+                to_c_response(&response)
+            },
+            Err(error) => {
+                response.error(error);
+                to_c_response(&response)
+            },
+        }
+    })
+}
+
+#[no_mangle]
+pub extern "C" fn jobs_list(
+    headers: *mut HeaderMap,
+    _body: *const c_char,
+) -> *const c_char {
+
+    guarded("jobs_list", || {
+        if headers.is_null() {
+            return std::ptr::null_mut();
+        }
+
+        let headers = unsafe { &*headers };
+
+        if let Some(denied) = denied(headers, "send") {
+            return denied;
+        }
+
+        let page = match Page::from_query(&query_params(headers), 100) {
+            Ok(page) => page,
+            Err(error) => {
+                let mut response = Response::new();
+                response.error(error);
+                return to_c_response(&response);
+            },
+        };
+
+        match jobs::list(&page) {
+            Ok((entries, total)) => to_c_response(&page.response("jobs", entries, total)),
+            Err(e) => {
+                let mut response = Response::new();
+                response.message = e;
+                to_c_response(&response)
+            },
+        }
+    })
+}
+
+#[no_mangle]
+pub extern "C" fn jobs_entry(
+    headers: *mut HeaderMap,
+    _body: *const c_char,
+) -> *const c_char {
+
+    guarded("jobs_entry", || {
+        if headers.is_null() {
+            return std::ptr::null_mut();
+        }
+
+        let headers = unsafe { &*headers };
+
+        if let Some(denied) = denied(headers, "send") {
+            return denied;
+        }
+
+        let mut response = Response::new();
+
+        let id = match query_params(headers).get("id").and_then(|id| id.parse().ok()) {
+            Some(id) => id,
+            None => {
+                response.message = "No job id".to_string();
+                return to_c_response(&response);
+            },
+        };
+
+        match jobs::by_id(id) {
+            Ok(Some(entry)) => to_c_response(&serde_json::json!({
+                "status": "success",
+                "job": entry,
+            })),
+            Ok(None) => {
+                response.error(SendError::new("not_found", format!("No job with id {}", id)));
+                to_c_response(&response)
+            },
+            Err(e) => {
+                response.message = e;
+                to_c_response(&response)
+            },
+        }
+    })
+}
+
+fn job_report(
+    function: &'static str,
+    headers: *mut HeaderMap,
+    csv: bool,
+) -> *const c_char {
+
+    guarded(function, || {
+        if headers.is_null() {
+            return std::ptr::null_mut();
+        }
+
+        let headers = unsafe { &*headers };
+
+        if let Some(denied) = denied(headers, "send") {
+            return denied;
+        }
+
+        let mut response = Response::new();
+
+        let params = query_params(headers);
+        let id = match params.get("id").and_then(|id| id.parse().ok()) {
+            Some(id) => id,
+            None => {
+                response.message = "No job id".to_string();
+                return to_c_response(&response);
+            },
+        };
+
+        // the CSV export is the whole report
+        let page = match Page::from_query(&params, 100) {
+            Ok(page) => page,
+            Err(error) => {
+                response.error(error);
+                return to_c_response(&response);
+            },
+        };
+
+        let report = jobs::by_id(id)
+            .and_then(|job| Ok((job, jobs::results(id, (!csv).then_some(&page))?)));
+        match report {
+            Ok((Some(_), (results, _))) if csv => match jobs::csv(&results) {
+                Ok(csv) => to_c_text(&csv),
+                Err(e) => {
+                    response.message = e;
+                    to_c_response(&response)
+                },
+            },
+            Ok((Some(job), (results, total))) => {
+                let mut report = page.response("results", results, total);
+                report["job"] = serde_json::json!(job);
+                to_c_response(&report)
+            },
+            Ok((None, _)) => {
+                response.error(SendError::new("not_found", format!("No job with id {}", id)));
+                to_c_response(&response)
+            },
+            Err(e) => {
+                response.message = e;
+                to_c_response(&response)
+            },
+        }
+    })
+}
+
+#[no_mangle]
+pub extern "C" fn jobs_report(
+    headers: *mut HeaderMap,
+    _body: *const c_char,
+) -> *const c_char {
+    job_report("jobs_report", headers, false)
+}
+
+#[no_mangle]
+pub extern "C" fn jobs_report_csv(
+    headers: *mut HeaderMap,
+    _body: *const c_char,
+) -> *const c_char {
+    job_report("jobs_report_csv", headers, true)
+}
+
+#[no_mangle]
+pub extern "C" fn jobs(
+    headers: *mut HeaderMap,
+    body: *const c_char,
+) -> *const c_char {
+
+    guarded("jobs", || {
+        if headers.is_null() || body.is_null() {
+            return std::ptr::null_mut();
+        }
+
+        let headers = unsafe { &*headers };
+
+        if let Some(denied) = denied(headers, "send") {
+            return denied;
+        }
+
+        let mut response = Response::new();
+
+        let request: jobs::JobRequest = match json_body(headers, body) {
+            Ok(request) => request,
+            Err(error) => {
+                response.error(error);
+                return to_c_response(&response);
+            },
+        };
+
+        match jobs::handle(&request) {
+            Ok(message) => {
+                response.status = "success".to_string();
+                response.message = message;
+            },
+            Err(error) => response.error(error),
+        };
+        // the message has the number of emails removed from the queue
+        audit::record(
+            headers,
+            &format!("jobs.{}", request.action),
+            Some(&request.id.to_string()),
+            &response.status,
+            &response.message,
+        );
+
+        to_c_response(&response)
+    })
+}
+
+fn set_paused(
+    function: &'static str,
+    headers: *mut HeaderMap,
+    paused: bool,
+) -> *const c_char {
+
+    guarded(function, || {
+        if headers.is_null() {
+            return std::ptr::null_mut();
+        }
+
+        let headers = unsafe { &*headers };
+
+        if let Some(denied) = instance_denied(headers, "admin") {
+            return denied;
+        }
+
+        let mut response = Response::new();
+
+        match queue::set_paused(paused) {
+            Ok(()) => {
+                response.status = "success".to_string();
+                response.message = match paused {
+                    true => "Sending paused".to_string(),
+                    false => "Sending resumed".to_string(),
+                };
+            },
+            Err(e) => response.error(e),
+        };
+        let action = match paused {
+            true => "admin.pause",
+            false => "admin.resume",
+        };
+        audit::record(headers, action, None, &response.status, &response.message);
+
+        to_c_response(&response)
+    })
+}
+
+#[no_mangle]
+pub extern "C" fn admin_pause(
+    headers: *mut HeaderMap,
+    _body: *const c_char,
+) -> *const c_char {
+
+    set_paused("admin_pause", headers, true)
+}
+
+#[no_mangle]
+pub extern "C" fn admin_resume(
+    headers: *mut HeaderMap,
+    _body: *const c_char,
+) -> *const c_char {
+
+    set_paused("admin_resume", headers, false)
+}
+
+#[no_mangle]
+pub extern "C" fn admin_chaos(
+    headers: *mut HeaderMap,
+    _body: *const c_char,
+) -> *const c_char {
+
+    guarded("admin_chaos", || {
+        if headers.is_null() {
+            return std::ptr::null_mut();
+        }
+
+        let headers = unsafe { &*headers };
+
+        if let Some(denied) = instance_denied(headers, "admin") {
+            return denied;
+        }
 
-    // https://myaccount.google.com/apppasswords
+        to_c_response(&serde_json::json!({
+            "status": "success",
+            "chaos": chaos::current(),
+        }))
+    })
+}
 
-    match send_via_gmail(&mail) {
-        Ok(success) => {
-            response.status = "success".to_string();
-            response.message = format!("Email sent successfully: {:?}", success);
-        },
-        Err(error) => {
-            response.message = format!("Failed to send email: {:?}", error);
-        },
-    };
+#[no_mangle]
+pub extern "C" fn admin_chaos_set(
+    headers: *mut HeaderMap,
+    body: *const c_char,
+) -> *const c_char {
+
+    guarded("admin_chaos_set", || {
+        if headers.is_null() || body.is_null() {
+            return std::ptr::null_mut();
+        }
+
+        let headers = unsafe { &*headers };
+
+        if let Some(denied) = instance_denied(headers, "admin") {
+            return denied;
+        }
+
+        let mut response = Response::new();
+        let request: chaos::ChaosRequest = match json_body(headers, body) {
+            Ok(request) => request,
+            Err(error) => {
+                response.error(error);
+                return to_c_response(&response);
+            },
+        };
+
+        match chaos::handle(request) {
+            Ok(current) => {
+                audit::record(headers, "admin.chaos", None, "success", &serde_json::to_string(&current).unwrap_or_default());
+                to_c_response(&serde_json::json!({
+                    "status": "success",
+                    "chaos": current,
+                }))
+            },
+            Err(error) => {
+                response.error(error);
+                to_c_response(&response)
+            },
+        }
+    })
+}
+
+#[no_mangle]
+pub extern "C" fn suppressions_list(
+    headers: *mut HeaderMap,
+    _body: *const c_char,
+) -> *const c_char {
+
+    guarded("suppressions_list", || {
+        if headers.is_null() {
+            return std::ptr::null_mut();
+        }
+
+        let headers = unsafe { &*headers };
+
+        if let Some(denied) = denied(headers, "send") {
+            return denied;
+        }
+
+        let page = match Page::from_query(&query_params(headers), 100) {
+            Ok(page) => page,
+            Err(error) => {
+                let mut response = Response::new();
+                response.error(error);
+                return to_c_response(&response);
+            },
+        };
+
+        match suppressions::list(&page) {
+            Ok((entries, total)) => to_c_response(&page.response("suppressions", entries, total)),
+            Err(e) => {
+                let mut response = Response::new();
+                response.message = e;
+                to_c_response(&response)
+            },
+        }
+    })
+}
+
+#[no_mangle]
+pub extern "C" fn suppressions(
+    headers: *mut HeaderMap,
+    body: *const c_char,
+) -> *const c_char {
+
+    guarded("suppressions", || {
+        if headers.is_null() || body.is_null() {
+            return std::ptr::null_mut();
+        }
+
+        let headers = unsafe { &*headers };
+
+        if let Some(denied) = denied(headers, "send") {
+            return denied;
+        }
+
+        // of the consent trail
+        let _trace = trace::enter(Some(trace::request_id(headers)));
+
+        let mut response = Response::new();
+
+        let request: suppressions::SuppressionRequest = match json_body(headers, body) {
+            Ok(request) => request,
+            Err(error) => {
+                response.error(error);
+                return to_c_response(&response);
+            },
+        };
+
+        match suppressions::handle(&request) {
+            Ok(message) => {
+                response.status = "success".to_string();
+                response.message = message;
+            },
+            Err(error) => response.error(error),
+        };
+        audit::record(
+            headers,
+            &format!("suppressions.{}", request.action),
+            Some(&request.address),
+            &response.status,
+            &response.message,
+        );
+
+        to_c_response(&response)
+    })
+}
+
+#[no_mangle]
+pub extern "C" fn config_inspect(
+    headers: *mut HeaderMap,
+    _body: *const c_char,
+) -> *const c_char {
+
+    guarded("config_inspect", || {
+        if headers.is_null() {
+            return std::ptr::null_mut();
+        }
+
+        let headers = unsafe { &*headers };
+
+        if let Some(denied) = instance_denied(headers, "admin") {
+            return denied;
+        }
+
+        let mut effective = config::effective();
+        effective["status"] = serde_json::json!("success");
+        to_c_response(&effective)
+    })
+}
+
+#[no_mangle]
+pub extern "C" fn config_validate(
+    headers: *mut HeaderMap,
+    _body: *const c_char,
+) -> *const c_char {
+
+    guarded("config_validate", || {
+        if headers.is_null() {
+            return std::ptr::null_mut();
+        }
+
+        let headers = unsafe { &*headers };
+
+        if let Some(denied) = instance_denied(headers, "admin") {
+            return denied;
+        }
+
+        let connect = query_params(headers)
+            .get("connect")
+            .map(|connect| connect != "false")
+            .unwrap_or(true);
+
+        let report = config::validate(connect);
+        let (status, message) = match report.is_valid() {
+            true => ("success", "The config is valid"),
+            false => ("error", "The config has errors"),
+        };
+        to_c_response(&serde_json::json!({
+            "status": status,
+            "message": message,
+            "report": report,
+        }))
+    })
+}
+
+#[no_mangle]
+pub extern "C" fn audit_list(
+    headers: *mut HeaderMap,
+    _body: *const c_char,
+) -> *const c_char {
+
+    guarded("audit_list", || {
+        if headers.is_null() {
+            return std::ptr::null_mut();
+        }
+
+        let headers = unsafe { &*headers };
+
+        if let Some(denied) = instance_denied(headers, "admin") {
+            return denied;
+        }
+
+        let params = query_params(headers);
+        let since = params.get("since").and_then(|since| since.parse().ok());
+        let page = match Page::from_query(&params, 100) {
+            Ok(page) => page,
+            Err(error) => {
+                let mut response = Response::new();
+                response.error(error);
+                return to_c_response(&response);
+            },
+        };
+
+        match audit::list(params.get("action").map(String::as_str), since, &page) {
+            Ok((entries, total)) => to_c_response(&page.response("audit", entries, total)),
+            Err(e) => {
+                let mut response = Response::new();
+                response.message = e;
+                to_c_response(&response)
+            },
+        }
+    })
+}
+
+#[no_mangle]
+pub extern "C" fn consent_list(
+    headers: *mut HeaderMap,
+    _body: *const c_char,
+) -> *const c_char {
+
+    guarded("consent_list", || {
+        if headers.is_null() {
+            return std::ptr::null_mut();
+        }
+
+        let headers = unsafe { &*headers };
+
+        if let Some(denied) = instance_denied(headers, "admin") {
+            return denied;
+        }
+
+        let params = query_params(headers);
+        let since = params.get("since").and_then(|since| since.parse().ok());
+        let page = match Page::from_query(&params, 100) {
+            Ok(page) => page,
+            Err(error) => {
+                let mut response = Response::new();
+                response.error(error);
+                return to_c_response(&response);
+            },
+        };
+
+        match consent::list(params.get("address").map(String::as_str), since, &page) {
+            Ok((entries, total)) => to_c_response(&page.response("consent", entries, total)),
+            Err(e) => {
+                let mut response = Response::new();
+                response.message = e;
+                to_c_response(&response)
+            },
+        }
+    })
+}
+
+// signed by the provider instead of an api key
+#[no_mangle]
+pub extern "C" fn events(
+    headers: *mut HeaderMap,
+    body: *const c_char,
+) -> *const c_char {
+
+    guarded("events", || {
+        if headers.is_null() || body.is_null() {
+            return std::ptr::null_mut();
+        }
+
+        let headers = unsafe { &*headers };
+        let body = unsafe { CStr::from_ptr(body) }.to_bytes();
+
+        let provider = query_params(headers)
+            .get("provider")
+            .cloned()
+            .unwrap_or("generic".to_string());
+        // of the consent trail and the audit log
+        let _trace = trace::enter(Some(trace::request_id(headers)));
+
+        let mut response = Response::new();
+        let received = match events::receive(&provider, headers, body) {
+            Ok(received) => received,
+            Err(error) => {
+                response.error(error);
+                audit::record(headers, &format!("events.{}", provider), None, &response.status, &response.message);
+                return to_c_response(&response);
+            },
+        };
+
+        let message = format!(
+            "{} events, {} addresses suppressed",
+            received.events,
+            received.suppressed.len(),
+        );
+        audit::record(headers, &format!("events.{}", provider), None, "success", &message);
+        to_c_response(&serde_json::json!({
+            "status": "success",
+            "message": message,
+            "suppressed": received.suppressed,
+            "confirmed": received.confirmed,
+            "campaigns": received.campaigns,
+            "ignored": received.ignored,
+        }))
+    })
+}
+
+#[no_mangle]
+pub extern "C" fn quota(
+    headers: *mut HeaderMap,
+    _body: *const c_char,
+) -> *const c_char {
+
+    guarded("quota", || {
+        if headers.is_null() {
+            return std::ptr::null_mut();
+        }
+
+        let headers = unsafe { &*headers };
+
+        if let Some(denied) = instance_denied(headers, "send") {
+            return denied;
+        }
+
+        let accounts: Result<std::collections::BTreeMap<String, quota::Usage>, String> = transport::gmail_accounts()
+            .into_iter()
+            .map(|account| quota::usage(&account).map(|usage| (account, usage)))
+            .collect();
+        match quota::usage(transport::GMAIL).and_then(|usage| Ok((usage, accounts?))) {
+            Ok((usage, accounts)) if accounts.len() > 1 => to_c_response(&serde_json::json!({
+                "status": "success",
+                "quota": usage,
+                "accounts": accounts,
+            })),
+            Ok((usage, _)) => to_c_response(&serde_json::json!({
+                "status": "success",
+                "quota": usage,
+            })),
+            Err(e) => {
+                let mut response = Response::new();
+                response.message = e;
+                to_c_response(&response)
+            },
+        }
+    })
+}
+
+#[no_mangle]
+pub extern "C" fn latency(
+    headers: *mut HeaderMap,
+    _body: *const c_char,
+) -> *const c_char {
+
+    guarded("latency", || {
+        if headers.is_null() {
+            return std::ptr::null_mut();
+        }
+
+        let headers = unsafe { &*headers };
+
+        if let Some(denied) = instance_denied(headers, "admin") {
+            return denied;
+        }
+
+        to_c_response(&serde_json::json!({
+            "status": "success",
+            "lanes": latency::stats(),
+        }))
+    })
+}
+
+#[no_mangle]
+pub extern "C" fn aliases(
+    headers: *mut HeaderMap,
+    _body: *const c_char,
+) -> *const c_char {
+
+    guarded("aliases", || {
+        if headers.is_null() {
+            return std::ptr::null_mut();
+        }
+
+        let headers = unsafe { &*headers };
+
+        if let Some(denied) = instance_denied(headers, "send") {
+            return denied;
+        }
 
-    to_c_response(&response)
+        let params = query_params(headers);
+        let account = params.get("account")
+            .map(String::as_str)
+            .unwrap_or(transport::GMAIL);
+        let verified = params.get("verified")
+            .is_some_and(|verified| verified == "true");
+
+        match aliases::list(account) {
+            Ok(aliases) => to_c_response(&serde_json::json!({
+                "status": "success",
+                "account": account,
+                "aliases": aliases.into_iter()
+                    .filter(|alias| alias.verified || !verified)
+                    .collect::<Vec<_>>(),
+            })),
+            Err(error) => {
+                let mut response = Response::new();
+                response.error(error);
+                to_c_response(&response)
+            },
+        }
+    })
 }
 
 // mandatory function
 #[no_mangle]
 pub extern "C" fn routes() -> *const c_char {
 
+    // the host calls this when the plugin is loaded,
+    // the routes are returned even if the config is broken
+    let started = std::panic::catch_unwind(|| {
+        config::validate_at_load();
+        if SMTP_CLIENT.self_test.unwrap_or(false) {
+            health::self_test_at_load();
+        }
+        scheduler::start();
+        queue::start();
+        pool::start();
+        telemetry::start();
+    });
+    if started.is_err() {
+        eprintln!("arp-gmail: the background workers were not started");
+    }
+
     let json_routes = serde_json::to_string_pretty(ROUTES)
         .unwrap_or("[]".to_string());
 
-    let c_response = CString::new(json_routes.as_str())
-        .unwrap();
-    c_response
-        .into_raw()
+    to_c_text(json_routes.as_bytes())
+}
+
+// open to the probes of the load balancers, the self-test needs the admin scope
+#[no_mangle]
+pub extern "C" fn health(
+    headers: *mut HeaderMap,
+    _body: *const c_char,
+) -> *const c_char {
+
+    guarded("health", || {
+        if headers.is_null() {
+            return std::ptr::null_mut();
+        }
+
+        let headers = unsafe { &*headers };
+
+        let deep = query_params(headers)
+            .get("deep")
+            .is_some_and(|deep| deep == "true");
+        if !deep {
+            return to_c_response(&serde_json::json!({
+                "status": "success",
+                "version": VERSION,
+                "paused": queue::paused(),
+                "stats": health::stats(),
+            }));
+        }
+
+        if let Some(denied) = instance_denied(headers, "admin") {
+            return denied;
+        }
+
+        let checks = health::self_test();
+        let failed = checks.iter().filter(|check| !check.ok).count();
+        let (status, message) = match failed {
+            0 => ("success", "Every check passed".to_string()),
+            failed => ("error", format!("{} of {} checks failed", failed, checks.len())),
+        };
+        to_c_response(&serde_json::json!({
+            "status": status,
+            "message": message,
+            "version": VERSION,
+            "paused": queue::paused(),
+            "stats": health::stats(),
+            "checks": checks,
+        }))
+    })
+}
+
+#[no_mangle]
+pub extern "C" fn metrics(
+    headers: *mut HeaderMap,
+    _body: *const c_char,
+) -> *const c_char {
+
+    guarded("metrics", || {
+        if headers.is_null() {
+            return std::ptr::null_mut();
+        }
+
+        let headers = unsafe { &*headers };
+
+        if let Some(denied) = instance_denied(headers, "admin") {
+            return denied;
+        }
+
+        if !SMTP_CLIENT.debug_buffers.unwrap_or(false) {
+            let mut response = Response::new();
+            response.message = "Buffer metrics are disabled, set \"debug_buffers\": true".to_string();
+            return to_c_response(&response);
+        }
+
+        // this response is one of the outstanding buffers
+        to_c_response(&serde_json::json!({
+            "status": "success",
+            "buffers": buffers::stats(),
+        }))
+    })
 }
 
 #[no_mangle]
@@ -250,25 +3596,312 @@ pub extern "C" fn about(
     _body: *const c_char,
 ) -> *const c_char {
 
-    let info = format!(r#"Name: arp-gmail
+    guarded("about", || {
+        let info = format!(r#"Name: arp-gmail
 Version: {}
 authors = "Henrique Dias <mrhdias@gmail.com>"
 Description: Shared library for sending mail via Gmail
 License: MIT"#, VERSION);
 
-    let c_response = CString::new(info).unwrap();
-    c_response.into_raw()
+        to_c_text(info.as_bytes())
+    })
 }
 
-// mandatory function
+// the host calls this before unloading the library: new work is refused,
+// queued jobs stay persisted, the workers are joined within the deadline,
+// the pooled SMTP connections are closed and the buffered rows written; under its C name in a test or a
+// benchmark it would replace the shutdown() of the sockets
+#[cfg_attr(not(any(test, feature = "bench")), no_mangle)]
+pub extern "C" fn shutdown() {
+
+    let deadline = std::time::Duration::from_secs(SMTP_CLIENT.shutdown_timeout_secs.unwrap_or(10));
+
+    let drained = shutdown::run(deadline);
+    pool::close();
+
+    // the rows the database still refuses are lost with the library
+    match persistence::flush() {
+        0 => {},
+        waiting => log!("arp-gmail: {} buffered history and audit rows can't be written, they are dropped", waiting),
+    }
+
+    match drained {
+        true => log!("arp-gmail: shutdown complete"),
+        false => log!("arp-gmail: shutdown deadline of {}s reached", deadline.as_secs()),
+    };
+}
+
+// optional function: the status and headers of a response of a handler, which
+// the host passes before it frees it, as {"status": 429, "headers":
+// {"Content-Type": ..., "Retry-After": ..., "X-Request-Id": ...}}; the
+// returned buffer is freed with free() too. A host that doesn't call it keeps
+// the response_type of the route
 #[no_mangle]
+pub extern "C" fn response_headers(response: *const c_char) -> *const c_char {
+    if response.is_null() {
+        return std::ptr::null();
+    }
+    match std::panic::catch_unwind(|| http::of(response)) {
+        Ok(Some(meta)) => to_c_response(&meta),
+        Ok(None) => {
+            eprintln!("arp-gmail: no headers for the pointer {:#x}, it isn't a response buffer the host has", response as usize);
+            std::ptr::null()
+        },
+        Err(_) => std::ptr::null(),
+    }
+}
+
+// mandatory function
+#[cfg_attr(not(any(test, feature = "bench")), no_mangle)]
 pub extern "C" fn free(ptr: *mut c_char) {
     if ptr.is_null() { // Avoid dereferencing null pointers
         return;
     }
+    if std::ptr::eq(ptr.cast_const(), FALLBACK.as_ptr()) {
+        return;
+    }
+    // a double free or a pointer of someone else is only logged
+    if !buffers::release(ptr) {
+        return;
+    }
 
     // Convert the raw pointer back to a CString and drop it to free the memory
     unsafe {
         drop(CString::from_raw(ptr)); // Takes ownership of the memory and frees it when dropped
     }
-}
\ No newline at end of file
+}
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use hyper::header::{HeaderName, HeaderValue};
+
+    // a config.json without api keys, the server refuses the connections so a
+    // send that gets that far fails at once
+    pub(crate) fn setup() {
+        static SETUP: std::sync::Once = std::sync::Once::new();
+        SETUP.call_once(|| {
+            let dir = std::env::temp_dir().join(format!("arp-gmail-test-{}", std::process::id()));
+            std::fs::create_dir_all(dir.join("arp-gmail")).unwrap();
+            std::fs::write(dir.join("arp-gmail/config.json"), r#"{
+                "username": "test@example.com",
+                "password": "x",
+                "server": "127.0.0.1",
+                "timeout_ms": 200
+            }"#).unwrap();
+            std::env::set_var("PLUGINS_DIR", &dir);
+        });
+    }
+
+    // xorshift, the same inputs on every run
+    struct Rng(u64);
+
+    impl Rng {
+        fn next(&mut self) -> u64 {
+            self.0 ^= self.0 << 13;
+            self.0 ^= self.0 >> 7;
+            self.0 ^= self.0 << 17;
+            self.0
+        }
+
+        fn below(&mut self, n: usize) -> usize {
+            (self.next() % n as u64) as usize
+        }
+
+        fn pick<'a>(&mut self, choices: &[&'a str]) -> &'a str {
+            choices[self.below(choices.len())]
+        }
+
+        // bytes of every kind: controls, quotes, backslashes, invalid UTF-8
+        fn bytes(&mut self, max: usize) -> Vec<u8> {
+            let special = b"\"\\{}[]<>@;,=%&\r\n\t";
+            let len = self.below(max + 1);
+            (0..len).map(|_| match self.below(4) {
+                0 => special[self.below(special.len())],
+                1 => 0x80 + self.below(0x80) as u8,
+                _ => 1 + self.below(0x7f) as u8,
+            }).collect()
+        }
+
+        // a JSON string with escaped NULs and controls among the text
+        fn text(&mut self) -> String {
+            let parts = ["\\u0000", "\\u0001", "\\n", "\\\"", "a@example.com", "é", " ", "<script>", "%00", "x"];
+            match self.below(3) {
+                0 => "a@example.com".to_string(),
+                _ => (1..=self.below(8)).map(|_| self.pick(&parts)).collect(),
+            }
+        }
+    }
+
+    fn call(
+        handler: extern "C" fn(*mut HeaderMap, *const c_char) -> *const c_char,
+        headers: &mut HeaderMap,
+        body: &[u8],
+    ) -> serde_json::Value {
+
+        let body = CString::new(body).unwrap();
+        let response = handler(headers, body.as_ptr());
+        assert!(!response.is_null());
+
+        let text = unsafe { CStr::from_ptr(response) }.to_bytes().to_vec();
+        free(response.cast_mut());
+
+        serde_json::from_slice(&text)
+            .unwrap_or_else(|e| std::panic!("not JSON ({}): {}", e, String::from_utf8_lossy(&text)))
+    }
+
+    #[test]
+    fn nul_bytes_are_replaced() {
+        let response = to_c_text(b"a\0b");
+        let text = unsafe { CStr::from_ptr(response) }.to_str().unwrap().to_string();
+        free(response.cast_mut());
+        assert_eq!(text, "a\u{FFFD}b");
+
+        let response = to_c_response(&serde_json::json!({ "message": "a\0b" }));
+        let text = unsafe { CStr::from_ptr(response) }.to_bytes().to_vec();
+        free(response.cast_mut());
+        let value: serde_json::Value = serde_json::from_slice(&text).unwrap();
+        assert_eq!(value["message"], "a\0b");
+    }
+
+    #[test]
+    fn unencodable_response_is_the_fallback() {
+        // a map with keys that aren't strings can't be JSON
+        let response = to_c_response(&std::collections::HashMap::from([((1, 2), 3)]));
+        assert!(std::ptr::eq(response, FALLBACK.as_ptr()));
+
+        let value: serde_json::Value = serde_json::from_slice(unsafe { CStr::from_ptr(response) }.to_bytes()).unwrap();
+        assert_eq!(value["code"], "internal_error");
+        // never allocated, free() must not release it
+        free(response.cast_mut());
+        free(response.cast_mut());
+    }
+
+    #[test]
+    fn double_and_foreign_frees_are_refused() {
+        let before = buffers::stats();
+
+        let response = to_c_text(b"text");
+        free(response.cast_mut());
+        free(response.cast_mut());
+
+        // allocated here, not by the plugin
+        let foreign = CString::new("foreign").unwrap().into_raw();
+        free(foreign);
+        assert_eq!(unsafe { CStr::from_ptr(foreign) }.to_str().unwrap(), "foreign");
+        drop(unsafe { CString::from_raw(foreign) });
+
+        // a reused address can make the foreign pointer look like a double free
+        let refused = |stats: buffers::Stats| {
+            let stats = serde_json::to_value(stats).unwrap();
+            stats["double_frees"].as_u64().unwrap() + stats["foreign"].as_u64().unwrap()
+        };
+        assert!(refused(buffers::stats()) >= refused(before) + 2);
+    }
+
+    #[test]
+    fn response_headers_of_the_handlers() {
+        let headers = |response: *const c_char| {
+            let meta = response_headers(response);
+            let value: serde_json::Value = serde_json::from_slice(unsafe { CStr::from_ptr(meta) }.to_bytes()).unwrap();
+            free(meta.cast_mut());
+            value
+        };
+
+        let mut response = Response::new();
+        response.error(SendError::new("budget_exceeded", "over the budget").retry_at(db::now() + 30));
+        response.request_id = Some("req-1".to_string());
+        let refused = guarded("sendmail", || to_c_response(&response));
+        let value = headers(refused);
+        free(refused.cast_mut());
+        assert_eq!(value["status"], 429);
+        assert_eq!(value["headers"]["Content-Type"], "application/json");
+        assert!((29..=30).contains(&value["headers"]["Retry-After"].as_str().unwrap().parse::<i64>().unwrap()));
+        assert_eq!(value["headers"]["X-Request-Id"], "req-1");
+
+        let csv = guarded("jobs_report_csv", || to_c_text(b"to,status"));
+        let value = headers(csv);
+        free(csv.cast_mut());
+        assert_eq!(value["status"], 200);
+        assert_eq!(value["headers"]["Content-Type"], "text/csv; charset=utf-8");
+
+        // the status of an NDJSON response is the one of its summary line
+        let mut out = Vec::new();
+        ndjson::result(&mut out, 1, &response);
+        ndjson::write(&mut out, &serde_json::json!({ "status": "success", "message": "1 of 1 emails queued" }));
+        let lines = guarded("sendbulk", || to_c_bytes(out, b"\\u0000"));
+        let value = headers(lines);
+        free(lines.cast_mut());
+        assert_eq!(value["status"], 200);
+        assert_eq!(value["headers"]["Content-Type"], "application/x-ndjson");
+    }
+
+    #[test]
+    fn sendmail_from_many_threads() {
+        setup();
+
+        let threads: Vec<_> = (0..16).map(|thread| std::thread::spawn(move || {
+            for i in 0..25 {
+                let mut headers = HeaderMap::new();
+                headers.insert("content-type", HeaderValue::from_static("application/json"));
+                let body = format!(
+                    r#"{{"from": "a@example.com", "to": "t{}-{}@example.com", "subject": "s", "message": "m"}}"#,
+                    thread, i,
+                );
+                let response = call(sendmail, &mut headers, body.as_bytes());
+                // the server refuses the connection, or every worker is taken
+                assert!(
+                    ["connection_failed", "busy", "timeout"].contains(&response["code"].as_str().unwrap_or_default()),
+                    "{}", response,
+                );
+            }
+        })).collect();
+
+        for thread in threads {
+            thread.join().unwrap();
+        }
+    }
+
+    #[test]
+    fn sendmail_survives_fuzzed_requests() {
+        setup();
+        let mut rng = Rng(0x9e37_79b9_7f4a_7c15);
+
+        for _ in 0..500 {
+            let (content_type, body) = match rng.below(3) {
+                0 => ("application/json", rng.bytes(256)),
+                1 => ("application/json", format!(
+                    r#"{{"from": "{}", "to": "{}", "subject": "{}", "message": "{}", "headers": {{"{}": "{}"}}}}"#,
+                    rng.text(), rng.text(), rng.text(), rng.text(), rng.text(), rng.text(),
+                ).into_bytes()),
+                _ => ("application/x-www-form-urlencoded", format!(
+                    "from={}&to={}&subject={}&message={}",
+                    rng.text(), rng.text(), rng.text(), rng.text(),
+                ).into_bytes()),
+            };
+
+            let mut headers = HeaderMap::new();
+            headers.insert("content-type", HeaderValue::from_static(content_type));
+            let names = ["content-type", "x-api-key", "authorization", "x-request-id", "traceparent", "user-agent", "x-raw-query"];
+            for _ in 0..rng.below(4) {
+                let name = HeaderName::from_static(rng.pick(&names));
+                let value = match name.as_str() {
+                    "content-type" => rng.pick(&[
+                        "application/json; charset=iso-8859-1",
+                        "application/json; charset=utf-16",
+                        "application/x-www-form-urlencoded; charset=utf-8",
+                    ]).as_bytes().to_vec(),
+                    _ => rng.bytes(64),
+                };
+                // the host only passes the values hyper accepts
+                if let Ok(value) = HeaderValue::from_bytes(&value) {
+                    headers.insert(name, value);
+                }
+            }
+
+            let response = call(sendmail, &mut headers, &body);
+            assert!(response["status"].is_string(), "{}", response);
+            // a panic inside the handler is answered with internal_error
+            assert_ne!(response["code"], "internal_error", "{}", response);
+        }
+    }
+}
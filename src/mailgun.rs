@@ -0,0 +1,92 @@
+//
+// Mailgun accounts: the built message is posted as is to the messages.mime
+// API of the domain
+//
+
+use base64::{engine::general_purpose, Engine as _};
+use lettre::address::Envelope;
+
+use crate::pool::Failure;
+use crate::transport::{self, Transport};
+
+pub struct Mailgun {
+    api_key: String,
+    domain: String,
+    endpoint: String,
+}
+
+impl Mailgun {
+    pub fn new(
+        api_key: &str,
+        domain: &str,
+        region: Option<&str>,
+        endpoint: Option<&str>,
+    ) -> Result<Self, String> {
+
+        let regional = match region.unwrap_or("us") {
+            "us" => "https://api.mailgun.net",
+            "eu" => "https://api.eu.mailgun.net",
+            region => return Err(format!("Invalid Mailgun region: {}", region)),
+        };
+
+        Ok(Mailgun {
+            api_key: api_key.to_string(),
+            domain: domain.to_string(),
+            endpoint: endpoint.unwrap_or(regional).trim_end_matches('/').to_string(),
+        })
+    }
+}
+
+// a "to" field per envelope recipient, so the bcc ones are delivered too, and
+// the message as the "message" file
+fn form(
+    boundary: &str,
+    envelope: &Envelope,
+    raw: &[u8],
+) -> Vec<u8> {
+
+    let mut body = Vec::with_capacity(raw.len() + 512);
+    for address in envelope.to() {
+        body.extend_from_slice(format!(
+            "--{}\r\nContent-Disposition: form-data; name=\"to\"\r\n\r\n{}\r\n",
+            boundary, address,
+        ).as_bytes());
+    }
+    body.extend_from_slice(format!(
+        "--{}\r\nContent-Disposition: form-data; name=\"message\"; filename=\"message.eml\"\r\n\
+        Content-Type: message/rfc822\r\n\r\n",
+        boundary,
+    ).as_bytes());
+    body.extend_from_slice(raw);
+    body.extend_from_slice(format!("\r\n--{}--\r\n", boundary).as_bytes());
+
+    body
+}
+
+impl Transport for Mailgun {
+    fn send(
+        &self,
+        envelope: &Envelope,
+        raw: &[u8],
+    ) -> Result<String, Failure> {
+
+        let boundary = format!("arp-gmail-{}", uuid::Uuid::new_v4().simple());
+        let credentials = general_purpose::STANDARD.encode(format!("api:{}", self.api_key));
+        let response = transport::agent()
+            .post(&format!("{}/v3/{}/messages.mime", self.endpoint, self.domain))
+            .set("Authorization", &format!("Basic {}", credentials))
+            .set("Content-Type", &format!("multipart/form-data; boundary={}", boundary))
+            .send_bytes(&form(&boundary, envelope, raw))
+            .map_err(|e| transport::api_error("mailgun", e))?;
+
+        let status = response.status();
+        let reply: serde_json::Value = response.into_json()
+            .unwrap_or_default();
+        Ok(format!(
+            "Mailgun {}: {} {}",
+            status,
+            reply["message"].as_str().unwrap_or(""),
+            reply["id"].as_str().unwrap_or(""),
+        ))
+    }
+}
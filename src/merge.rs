@@ -0,0 +1,227 @@
+//
+// Mail merge: a CSV whose header row names the template variables and whose
+// rows are the recipients, sent as JSON with the CSV in base64, as a
+// multipart/form-data upload or as NDJSON with an object per row
+//
+
+use base64::{engine::general_purpose, Engine as _};
+use serde::{Deserialize, Serialize};
+use serde_json::{Map, Value};
+
+use crate::bulk::Recipient;
+use crate::{Mail, SendError};
+
+const DEFAULT_PREVIEWS: usize = 3;
+const MAX_PREVIEWS: usize = 50;
+
+#[derive(Default, Deserialize)]
+pub struct MergeRequest {
+    #[serde(flatten)]
+    pub mail: Mail,
+    // the CSV encoded as base64, the "csv" part of a multipart upload
+    csv: Option<String>,
+    // render the first messages instead of queueing them
+    #[serde(default)]
+    pub dry_run: bool,
+    // how many messages a dry run renders
+    pub previews: Option<usize>,
+    #[serde(skip)]
+    rows: Vec<u8>,
+}
+
+#[derive(Serialize)]
+pub struct Preview {
+    to: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    subject: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    text: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    html: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    error: Option<String>,
+}
+
+fn invalid(message: impl Into<String>) -> SendError {
+    SendError::new("invalid_request", message)
+}
+
+// the "request" part has the mail fields as JSON, the "csv" part the rows
+fn multipart(
+    content_type: &str,
+    body: &[u8],
+) -> Result<MergeRequest, SendError> {
+
+    let mut message = format!("Content-Type: {}\r\n\r\n", content_type).into_bytes();
+    message.extend_from_slice(body);
+    let parsed = mailparse::parse_mail(&message)
+        .map_err(|e| invalid(format!("Invalid multipart body: {}", e)))?;
+
+    let mut request = None;
+    let mut rows = None;
+    for part in &parsed.subparts {
+        let disposition = part.get_content_disposition();
+        let content = part.get_body_raw()
+            .map_err(|e| invalid(format!("Invalid multipart body: {}", e)))?;
+        match disposition.params.get("name").map(String::as_str) {
+            Some("request") => request = Some(serde_json::from_slice::<MergeRequest>(&content)
+                .map_err(|e| invalid(format!("Invalid JSON in the request part: {}", e)))?),
+            Some("csv") => rows = Some(content),
+            _ => {},
+        }
+    }
+
+    let mut request = request.unwrap_or_default();
+    request.rows = rows.ok_or(invalid("No csv part"))?;
+
+    Ok(request)
+}
+
+pub fn parse(
+    content_type: &str,
+    body: &[u8],
+) -> Result<MergeRequest, SendError> {
+
+    if content_type.starts_with("multipart/form-data") {
+        return multipart(content_type, body);
+    }
+    let (media_type, charset) = crate::encoding::media_type(content_type);
+    if media_type != "application/json" {
+        return Err(invalid(format!("Invalid content type: {:?}", content_type)));
+    }
+
+    let body = crate::encoding::decode(charset.as_deref(), body)?;
+    let mut request: MergeRequest = serde_json::from_str(&body)
+        .map_err(|e| invalid(format!("Invalid JSON: {}", e)))?;
+    let csv = request.csv.take().ok_or(invalid("No csv"))?;
+    request.rows = general_purpose::STANDARD
+        .decode(csv.trim())
+        .map_err(|e| invalid(format!("The csv isn't valid base64: {}", e)))?;
+
+    Ok(request)
+}
+
+// a recipient per row: the "to" column, the optional "locale" one and every
+// other column as a variable; an empty cell is a missing variable
+pub fn recipients(request: &MergeRequest) -> Result<Vec<Recipient>, SendError> {
+
+    let mut reader = csv::ReaderBuilder::new()
+        .trim(csv::Trim::All)
+        .from_reader(request.rows.as_slice());
+    let columns: Vec<String> = reader.headers()
+        .map_err(|e| invalid(format!("Invalid CSV header: {}", e)))?
+        .iter()
+        .map(str::to_string)
+        .collect();
+    if !columns.iter().any(|column| column == "to") {
+        return Err(invalid("The CSV has no \"to\" column"));
+    }
+
+    let mut recipients = Vec::new();
+    for (line, record) in reader.records().enumerate() {
+        let record = record.map_err(|e| invalid(format!("CSV line {}: {}", line + 2, e)))?;
+        let mut to = String::new();
+        let mut locale = None;
+        let mut data = Map::new();
+        for (column, value) in columns.iter().zip(record.iter()) {
+            match column.as_str() {
+                "to" => to = value.to_string(),
+                "locale" if !value.is_empty() => locale = Some(value.to_string()),
+                _ if !value.is_empty() => {
+                    data.insert(column.clone(), Value::String(value.to_string()));
+                },
+                _ => {},
+            }
+        }
+        if to.is_empty() {
+            return Err(invalid(format!("CSV line {}: no to address", line + 2)));
+        }
+        recipients.push(Recipient {
+            to,
+            locale,
+            data: Some(Value::Object(data)),
+        });
+    }
+    if recipients.is_empty() {
+        return Err(invalid("The CSV has no rows"));
+    }
+
+    Ok(recipients)
+}
+
+// the request line of an NDJSON merge, its rows are the lines that follow
+pub fn lines_only(request: &MergeRequest) -> Result<(), SendError> {
+    match request.csv {
+        Some(_) => Err(invalid("An NDJSON merge has its rows on the lines, not in \"csv\"")),
+        None => Ok(()),
+    }
+}
+
+// a row of an NDJSON merge, the columns of the CSV as keys: the "to", the
+// optional "locale" and every other key a variable; an empty or null value is a
+// missing variable
+pub fn row(
+    row: usize,
+    columns: Map<String, Value>,
+) -> Result<Recipient, SendError> {
+
+    let mut to = String::new();
+    let mut locale = None;
+    let mut data = Map::new();
+    for (column, value) in columns {
+        match (column.as_str(), value) {
+            (_, Value::Null) => {},
+            (_, Value::String(text)) if text.trim().is_empty() => {},
+            ("to", Value::String(text)) => to = text.trim().to_string(),
+            ("to", _) => return Err(invalid(format!("Row {}: \"to\" isn't a string", row))),
+            ("locale", Value::String(text)) => locale = Some(text.trim().to_string()),
+            (_, value) => {
+                data.insert(column, value);
+            },
+        }
+    }
+    if to.is_empty() {
+        return Err(invalid(format!("Row {}: no to address", row)));
+    }
+
+    Ok(Recipient {
+        to,
+        locale,
+        data: Some(Value::Object(data)),
+    })
+}
+
+pub fn preview_count(count: Option<usize>) -> usize {
+    count.unwrap_or(DEFAULT_PREVIEWS).min(MAX_PREVIEWS)
+}
+
+// the first messages as they would be sent, nothing is queued
+pub fn previews(
+    mails: &[Mail],
+    count: Option<usize>,
+) -> Vec<Preview> {
+
+    mails.iter()
+        .take(preview_count(count))
+        .map(|mail| {
+            let mut mail = mail.clone();
+            crate::identity::apply(&mut mail);
+            match crate::templates::render_mail(&mut mail) {
+                Ok(()) => Preview {
+                    to: mail.to,
+                    subject: Some(mail.subject),
+                    text: Some(mail.message),
+                    html: mail.html,
+                    error: None,
+                },
+                Err(e) => Preview {
+                    to: mail.to,
+                    subject: None,
+                    text: None,
+                    html: None,
+                    error: Some(e.message),
+                },
+            }
+        })
+        .collect()
+}
@@ -0,0 +1,235 @@
+//
+// Wire format of the built messages: golden .eml files of tests/golden, and
+// properties every message must keep whatever the request. After a deliberate
+// change of the format the files are written again with
+// UPDATE_GOLDEN=1 cargo test
+//
+
+use std::path::PathBuf;
+use base64::Engine;
+use proptest::prelude::*;
+
+use crate::{build_message, Mail};
+
+// a PNG of one transparent pixel
+const PIXEL: &str = "iVBORw0KGgoAAAANSUhEUgAAAAEAAAABCAYAAAAfFcSJAAAADUlEQVR42mNkYPhfDwAChwGA60e6kgAAAABJRU5ErkJggg==";
+
+fn mail(request: serde_json::Value) -> Mail {
+    serde_json::from_value(request).unwrap()
+}
+
+fn raw(mail: &Mail) -> Vec<u8> {
+    crate::tests::setup();
+    build_message(mail).unwrap().formatted()
+}
+
+// the Message-ID, the Date and the MIME boundaries change on every build
+fn normalized(raw: &[u8]) -> String {
+
+    let mut text = String::from_utf8(raw.to_vec()).unwrap();
+
+    let mut boundaries = Vec::new();
+    for part in text.split("boundary=\"").skip(1) {
+        if let Some((boundary, _)) = part.split_once('"') {
+            if !boundaries.contains(&boundary.to_string()) {
+                boundaries.push(boundary.to_string());
+            }
+        }
+    }
+    for (i, boundary) in boundaries.iter().enumerate() {
+        text = text.replace(boundary, &format!("BOUNDARY-{}", i + 1));
+    }
+
+    text.split("\r\n")
+        .map(|line| match line.split_once(": ") {
+            Some(("Message-ID", _)) => "Message-ID: <ID@example.com>".to_string(),
+            Some(("Date", _)) => "Date: DATE".to_string(),
+            _ => line.to_string(),
+        })
+        .collect::<Vec<String>>()
+        .join("\r\n")
+}
+
+fn golden(
+    name: &str,
+    request: serde_json::Value,
+) {
+
+    let file: PathBuf = [env!("CARGO_MANIFEST_DIR"), "tests", "golden", &format!("{}.eml", name)]
+        .iter()
+        .collect();
+    let actual = normalized(&raw(&mail(request)));
+
+    if std::env::var_os("UPDATE_GOLDEN").is_some() {
+        std::fs::create_dir_all(file.parent().unwrap()).unwrap();
+        std::fs::write(&file, &actual).unwrap();
+        return;
+    }
+
+    let expected = std::fs::read_to_string(&file)
+        .unwrap_or_else(|e| std::panic!("{}: {}, write it with UPDATE_GOLDEN=1", file.display(), e));
+    assert!(
+        expected == actual,
+        "the wire format of {} changed:\n--- expected\n{}\n--- actual\n{}",
+        name, expected, actual,
+    );
+}
+
+#[test]
+fn golden_plain() {
+    golden("plain", serde_json::json!({
+        "from": "Sender <sender@example.com>",
+        "to": "rcpt@example.com",
+        "subject": "Hello",
+        "message": "A plain text message.\nWith two lines.",
+    }));
+}
+
+#[test]
+fn golden_unicode() {
+    golden("unicode", serde_json::json!({
+        "from": "sender@example.com",
+        "to": "José <jose@example.com>",
+        "subject": "Olá, 世界 ✉️ — a subject long enough to be folded over more than one line of the header",
+        "message": "Ação, 中文, emoji 🎉\n",
+    }));
+}
+
+#[test]
+fn golden_long_lines() {
+    golden("long_lines", serde_json::json!({
+        "from": "sender@example.com",
+        "to": "rcpt@example.com",
+        "subject": "Long lines",
+        "message": format!("{}\nshort\n{}", "a".repeat(2000), "word ".repeat(300)),
+    }));
+}
+
+#[test]
+fn golden_html() {
+    golden("html", serde_json::json!({
+        "from": "sender@example.com",
+        "to": "rcpt@example.com",
+        "subject": "HTML",
+        "message": "Text alternative",
+        "html": "<html><body><h1>Título</h1><p>Paragraph</p></body></html>",
+    }));
+}
+
+#[test]
+fn golden_attachments() {
+    golden("attachments", serde_json::json!({
+        "from": "sender@example.com",
+        "to": "rcpt@example.com",
+        "subject": "Attachments",
+        "message": "Two attachments",
+        "attachments": [
+            { "content": base64::engine::general_purpose::STANDARD.encode("name,total\nA,1\n"), "filename": "report.csv", "content_type": "text/csv" },
+            { "content": PIXEL, "filename": "pixel.png", "content_type": "image/png" },
+        ],
+    }));
+}
+
+// without inline (cid:) parts, the images of an HTML message are attachments
+#[test]
+fn golden_html_images() {
+    golden("html_images", serde_json::json!({
+        "from": "sender@example.com",
+        "to": "rcpt@example.com",
+        "subject": "HTML with an image",
+        "message": "See the image",
+        "html": "<p>See the image <b>pixel.png</b></p>",
+        "attachments": [ { "content": PIXEL, "filename": "pixel.png", "content_type": "image/png" } ],
+    }));
+}
+
+#[test]
+fn golden_latin1() {
+    golden("latin1", serde_json::json!({
+        "from": "sender@example.com",
+        "to": "rcpt@example.com",
+        "subject": "Legacy",
+        "message": "Olá, não é ASCII\n",
+        "charset": "iso-8859-1",
+        "transfer_encoding": "quoted-printable",
+    }));
+}
+
+// the lines end with LF, the line ending a transfer encoding adds after the
+// last line isn't part of the text
+fn text(part: &mailparse::ParsedMail) -> String {
+    unterminated(&part.get_body().unwrap())
+}
+
+fn unterminated(text: &str) -> String {
+    text.replace("\r\n", "\n")
+        .trim_end_matches('\n')
+        .to_string()
+}
+
+proptest! {
+    #![proptest_config(ProptestConfig::with_cases(64))]
+
+    // whatever the subject, the body and the attachments, the message parses
+    // back to them and keeps the line limit of SMTP
+    #[test]
+    fn built_messages_parse_back(
+        subject in "\\PC{0,120}",
+        message in "(\\PC{0,60}\n?){0,20}|a{900,3000}",
+        html in proptest::option::of("<p>\\PC{0,200}</p>"),
+        files in proptest::collection::vec(("[a-z]{1,12}\\.(bin|txt|png)", proptest::collection::vec(any::<u8>(), 0..3000)), 0..3),
+    ) {
+        let attachments: Vec<serde_json::Value> = files.iter()
+            .map(|(filename, bytes)| serde_json::json!({
+                "content": base64::engine::general_purpose::STANDARD.encode(bytes),
+                "filename": filename,
+                "content_type": "application/octet-stream",
+            }))
+            .collect();
+        let request = serde_json::json!({
+            "from": "sender@example.com",
+            "to": "rcpt@example.com",
+            "subject": subject,
+            "message": message,
+            "html": html,
+            "attachments": attachments,
+        });
+        let raw = raw(&mail(request));
+
+        for line in raw.split(|&byte| byte == b'\n') {
+            prop_assert!(line.len() <= 1000, "a line of {} bytes", line.len());
+        }
+
+        let parsed = mailparse::parse_mail(&raw).unwrap();
+        // the spaces around a header value aren't part of it on the wire
+        let decoded = parsed.headers.iter()
+            .find(|header| header.get_key() == "Subject")
+            .map(|header| header.get_value());
+        prop_assert_eq!(decoded.as_deref().map(|subject| subject.trim_matches(' ')), Some(subject.trim_matches(' ')));
+
+        // the text, the html alternative and the attachments, in order
+        let mut leaves = Vec::new();
+        let mut pending = vec![&parsed];
+        while let Some(part) = pending.pop() {
+            match part.subparts.is_empty() {
+                true => leaves.push(part),
+                false => pending.extend(part.subparts.iter().rev()),
+            }
+        }
+
+        prop_assert_eq!(text(leaves[0]), unterminated(&message));
+        let attached = match &html {
+            Some(html) => {
+                prop_assert_eq!(text(leaves[1]), unterminated(html));
+                &leaves[2..]
+            },
+            None => &leaves[1..],
+        };
+        prop_assert_eq!(attached.len(), files.len());
+        for (part, (filename, bytes)) in attached.iter().zip(&files) {
+            let disposition = part.get_content_disposition();
+            prop_assert_eq!(disposition.params.get("filename"), Some(filename));
+            prop_assert_eq!(&part.get_body_raw().unwrap(), bytes);
+        }
+    }
+}
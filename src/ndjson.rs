@@ -0,0 +1,90 @@
+//
+// Newline-delimited JSON for the large templated sends: the request fields on
+// the first line and a row per line after it, read and submitted one at a time
+// instead of parsing a single array; the response is a result per row and the
+// summary on the last line, each line ending with a newline
+//
+
+use std::borrow::Cow;
+use std::ffi::c_char;
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+
+use crate::{encoding, Response, SendError};
+
+pub const MEDIA_TYPE: &str = "application/x-ndjson";
+
+fn invalid(message: impl Into<String>) -> SendError {
+    SendError::new("invalid_request", message)
+}
+
+pub fn is(content_type: &str) -> bool {
+    encoding::media_type(content_type).0 == MEDIA_TYPE
+}
+
+pub fn decode<'a>(
+    content_type: &str,
+    body: &'a [u8],
+) -> Result<Cow<'a, str>, SendError> {
+    let (_, charset) = encoding::media_type(content_type);
+    encoding::decode(charset.as_deref(), body)
+}
+
+// the lines that aren't blank, the request first
+pub fn lines(text: &str) -> impl Iterator<Item = &str> {
+    text.lines().filter(|line| !line.trim().is_empty())
+}
+
+// the request fields of the first line
+pub fn header<'a, T: DeserializeOwned>(lines: &mut impl Iterator<Item = &'a str>) -> Result<T, SendError> {
+    let line = lines.next().ok_or(invalid("No request line"))?;
+    serde_json::from_str(line)
+        .map_err(|e| invalid(format!("Invalid JSON in the request line: {}", e)))
+}
+
+// a row, from 1 after the request line
+pub fn row<T: DeserializeOwned>(
+    row: usize,
+    line: &str,
+) -> Result<T, SendError> {
+    serde_json::from_str(line)
+        .map_err(|e| invalid(format!("Row {}: Invalid JSON: {}", row, e)))
+}
+
+// a line of the response, serde_json escapes the newlines of the strings
+pub fn write<T: Serialize>(
+    out: &mut Vec<u8>,
+    value: &T,
+) {
+    if serde_json::to_writer(&mut *out, value).is_ok() {
+        out.push(b'\n');
+    }
+}
+
+// the result of a row, with its number
+pub fn result(
+    out: &mut Vec<u8>,
+    row: usize,
+    response: &Response,
+) {
+    let mut value = serde_json::json!(response);
+    if let Some(fields) = value.as_object_mut() {
+        fields.insert("row".to_string(), row.into());
+    }
+    write(out, &value);
+}
+
+// a response of a single line, as the errors of the request
+pub fn respond<T: Serialize>(value: &T) -> *const c_char {
+    let mut out = Vec::with_capacity(crate::RESPONSE_CAPACITY);
+    write(&mut out, value);
+    crate::to_c_bytes(out, b"\\u0000")
+}
+
+pub fn error(
+    response: &mut Response,
+    error: SendError,
+) -> *const c_char {
+    response.error(error);
+    respond(response)
+}
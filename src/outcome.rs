@@ -0,0 +1,136 @@
+//
+// Classification of failed sends from the SMTP reply and enhanced status codes
+//
+
+use lettre::transport::smtp;
+
+use crate::pool::Failure;
+
+pub struct Outcome {
+    pub code: &'static str,
+    pub retryable: bool,
+}
+
+impl Outcome {
+    fn new(code: &'static str, retryable: bool) -> Self {
+        Outcome {
+            code,
+            retryable,
+        }
+    }
+}
+
+// enhanced status code (RFC 3463) at the start of the reply text, "5.1.1"
+fn enhanced_status(text: &str) -> Option<(u8, u16, u16)> {
+    let mut parts = text.split_whitespace()
+        .next()?
+        .split('.');
+    let class = parts.next()?.parse().ok()?;
+    let subject = parts.next()?.parse().ok()?;
+    let detail = parts.next()?.parse().ok()?;
+    match parts.next() {
+        None => Some((class, subject, detail)),
+        Some(_) => None,
+    }
+}
+
+pub fn reply_text(error: &smtp::Error) -> String {
+    std::error::Error::source(error)
+        .map(|source| source.to_string())
+        .unwrap_or_default()
+}
+
+// deferrals of a greylisting relay ask the sender to come back in a few minutes
+fn is_greylisting(
+    reply: u16,
+    text: &str,
+) -> bool {
+    let text = text.to_lowercase();
+    matches!(reply, 450 | 451)
+        && ["greylist", "graylist", "try again later", "try later"]
+            .iter()
+            .any(|phrase| text.contains(phrase))
+}
+
+// daily sending limit (550 5.4.5) or an account locked for suspicious activity (454 4.7.0)
+fn is_gmail_limit(
+    reply: u16,
+    enhanced: Option<(u8, u16, u16)>,
+    text: &str,
+) -> bool {
+    let text = text.to_lowercase();
+    matches!(enhanced, Some((5, 4, 5)))
+        || (reply == 454 && matches!(enhanced, Some((4, 7, 0))))
+        || ["sending limit exceeded", "suspicious activity", "unusual activity"]
+            .iter()
+            .any(|phrase| text.contains(phrase))
+}
+
+fn classify_reply(
+    reply: u16,
+    text: &str,
+) -> Outcome {
+
+    let transient = reply / 100 == 4;
+    let enhanced = enhanced_status(text);
+    if is_greylisting(reply, text) {
+        return Outcome::new("greylisted", true);
+    }
+    // retryable once the cool-down has passed
+    if is_gmail_limit(reply, enhanced, text) {
+        return Outcome::new("quota_exceeded", true);
+    }
+
+    match (reply, enhanced) {
+        (530 | 534 | 535, Some((_, 7, 0 | 8 | 9 | 14))) | (534 | 535, None) => Outcome::new("auth_failed", false),
+        (_, Some((_, 2, 2))) | (452 | 552, None) => Outcome::new("mailbox_full", transient),
+        (_, Some((_, 7, _))) | (554, None) => Outcome::new("policy_rejected", transient),
+        (_, Some((_, 1, _))) | (550 | 551 | 553, None) => Outcome::new("recipient_rejected", transient),
+        _ if transient => Outcome::new("temporary_failure", true),
+        _ => Outcome::new("permanent_failure", false),
+    }
+}
+
+// the HTTP status of the reply of a provider API
+fn classify_status(status: Option<u16>) -> Outcome {
+    match status {
+        Some(401 | 403) => Outcome::new("auth_failed", false),
+        Some(429) => Outcome::new("temporary_failure", true),
+        Some(status) if status >= 500 => Outcome::new("temporary_failure", true),
+        Some(_) => Outcome::new("permanent_failure", false),
+        None => Outcome::new("connection_failed", true),
+    }
+}
+
+pub fn classify(failure: &Failure) -> Outcome {
+
+    let error = match failure {
+        Failure::Timeout(_) => return Outcome::new("timeout", true),
+        Failure::CoolingDown(_) | Failure::LimitReached(_) => return Outcome::new("quota_exceeded", true),
+        // a deliberate cap, the queue must not keep trying
+        Failure::RecipientLimited(_) => return Outcome::new("recipient_rate_limited", false),
+        Failure::Suppressed(_) => return Outcome::new("suppressed", false),
+        Failure::KnownInvalid(_) => return Outcome::new("known_invalid_recipient", false),
+        Failure::Injected(_) => return Outcome::new("temporary_failure", true),
+        Failure::Account(_) => return Outcome::new("account_unavailable", false),
+        Failure::Busy(_) => return Outcome::new("busy", true),
+        Failure::Dns(_) => return Outcome::new("dns_failed", true),
+        Failure::Connect(_) => return Outcome::new("connection_failed", true),
+        // the server isn't the one of the policy, retrying won't change it
+        Failure::Tls(_) => return Outcome::new("tls_failed", false),
+        Failure::Api(error) => return classify_status(error.status),
+        Failure::Smtp(error) => error,
+    };
+
+    match error.status() {
+        Some(code) => {
+            let reply = code.to_string()
+                .parse()
+                .unwrap_or(0);
+            classify_reply(reply, &reply_text(error))
+        },
+        // the server could not be reached or the connection was lost
+        None if !error.is_response() && !error.is_client() => Outcome::new("connection_failed", true),
+        None => Outcome::new("send_failed", false),
+    }
+}
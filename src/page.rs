@@ -0,0 +1,76 @@
+//
+// Pagination of the listing routes: ?limit= and ?offset=, a default page size
+// per route and a hard cap so a response never carries a whole table through
+// the host
+//
+
+use std::collections::HashMap;
+use serde::Serialize;
+
+use crate::SendError;
+
+// the largest page of any listing route
+pub const MAX_LIMIT: usize = 1000;
+
+pub struct Page {
+    pub limit: usize,
+    pub offset: usize,
+}
+
+impl Page {
+    // a limit over the cap is lowered to it rather than refused
+    pub fn from_query(
+        params: &HashMap<String, String>,
+        default: usize,
+    ) -> Result<Self, SendError> {
+
+        let number = |name: &str, default: usize| match params.get(name) {
+            Some(value) => value.trim().parse::<usize>()
+                .map_err(|_| SendError::new("invalid_request", format!("Invalid {} {:?}", name, value))),
+            None => Ok(default),
+        };
+
+        Ok(Page {
+            limit: number("limit", default)?.clamp(1, MAX_LIMIT),
+            offset: number("offset", 0)?,
+        })
+    }
+
+    // bound as the LIMIT and OFFSET of a query
+    pub fn sql(&self) -> (i64, i64) {
+        (self.limit as i64, self.offset.min(i64::MAX as usize) as i64)
+    }
+
+    // the entries of a slice already in memory
+    pub fn slice<T>(
+        &self,
+        entries: Vec<T>,
+    ) -> Vec<T> {
+        entries.into_iter()
+            .skip(self.offset)
+            .take(self.limit)
+            .collect()
+    }
+
+    // {"status": "success", "<name>": [...], "total", "limit", "offset", "next_offset"},
+    // next_offset is null on the last page
+    pub fn response<T: Serialize>(
+        &self,
+        name: &str,
+        entries: Vec<T>,
+        total: usize,
+    ) -> serde_json::Value {
+
+        let next = self.offset.saturating_add(entries.len());
+        let mut response = serde_json::json!({
+            "status": "success",
+            "total": total,
+            "limit": self.limit,
+            "offset": self.offset,
+            "next_offset": (!entries.is_empty() && next < total).then_some(next),
+        });
+        response[name] = serde_json::json!(entries);
+
+        response
+    }
+}
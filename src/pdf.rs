@@ -0,0 +1,110 @@
+//
+// PDF attachments rendered from an HTML document, or the html body of the
+// message, by an external command that reads the HTML on stdin and writes the
+// PDF to stdout (wkhtmltopdf - -, weasyprint - -)
+//
+
+use std::time::Duration;
+use lettre::message::{Attachment as MimeAttachment, Body, SinglePart};
+use lettre::message::header::{ContentTransferEncoding, ContentType};
+use serde::{Deserialize, Serialize};
+
+use crate::{Mail, SendError, SMTP_CLIENT};
+
+fn default_timeout_secs() -> u64 {
+    60
+}
+
+fn default_max_bytes() -> usize {
+    10 * 1024 * 1024
+}
+
+fn default_filename() -> String {
+    "document.pdf".to_string()
+}
+
+#[derive(Clone, Deserialize, Serialize)]
+pub struct PdfSettings {
+    // the renderer and its arguments
+    pub command: Vec<String>,
+    #[serde(default = "default_timeout_secs")]
+    pub timeout_secs: u64,
+    // a bigger PDF fails the message
+    #[serde(default = "default_max_bytes")]
+    pub max_bytes: usize,
+}
+
+// the "pdf" of a request
+#[derive(Clone, Deserialize, Serialize)]
+pub struct PdfRequest {
+    // the document to render, the html body of the message if not set
+    pub html: Option<String>,
+    #[serde(default = "default_filename")]
+    pub filename: String,
+}
+
+fn failed(message: impl Into<String>) -> SendError {
+    SendError::new("render_failed", message)
+}
+
+fn settings() -> Result<&'static PdfSettings, SendError> {
+    SMTP_CLIENT.pdf.as_ref()
+        .ok_or_else(|| SendError::new("invalid_attachment", "PDF attachments are disabled: \"pdf\" is not set"))
+}
+
+fn document<'a>(
+    request: &'a PdfRequest,
+    mail: &'a Mail,
+) -> Result<&'a str, SendError> {
+    request.html.as_deref()
+        .or(mail.html.as_deref())
+        .filter(|html| !html.trim().is_empty())
+        .ok_or_else(|| SendError::new("invalid_attachment", "The PDF has no \"html\" and the message no html body"))
+}
+
+// before the mail is queued, so it doesn't fail on its first send
+pub fn check(mail: &Mail) -> Result<(), SendError> {
+    match &mail.pdf {
+        Some(request) => {
+            settings()?;
+            document(request, mail).map(|_| ())
+        },
+        None => Ok(()),
+    }
+}
+
+fn render(
+    settings: &PdfSettings,
+    html: &str,
+) -> Result<Vec<u8>, SendError> {
+
+    let pdf = crate::pipe::run(&settings.command, html.as_bytes(), Duration::from_secs(settings.timeout_secs), settings.max_bytes)
+        .map_err(failed)?;
+    if !pdf.starts_with(b"%PDF-") {
+        return Err(failed(format!("{} didn't write a PDF", settings.command[0])));
+    }
+
+    Ok(pdf)
+}
+
+pub fn rendered(
+    request: &PdfRequest,
+    mail: &Mail,
+) -> Result<Vec<u8>, SendError> {
+    render(settings()?, document(request, mail)?)
+}
+
+pub fn attachment(
+    request: &PdfRequest,
+    pdf: Vec<u8>,
+) -> Result<SinglePart, SendError> {
+
+    let content_type = ContentType::parse("application/pdf")
+        .map_err(|e| failed(e.to_string()))?;
+
+    // base64 even when the renderer wrote a PDF of ASCII only
+    let body = Body::new_with_encoding(pdf, ContentTransferEncoding::Base64)
+        .map_err(|_| failed("The PDF can't be encoded"))?;
+
+    Ok(MimeAttachment::new(request.filename.clone()).body(body, content_type))
+}
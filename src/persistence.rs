@@ -0,0 +1,188 @@
+//
+// The history and audit rows the database can't write, locked or with a full
+// disk: kept in memory up to "max_buffered", the oldest dropped past it, and
+// written in order once the database takes them again. The sends go on in the
+// meantime, or stop with the "strict" mode until every row is written
+//
+
+use std::collections::VecDeque;
+use std::sync::Mutex;
+use serde::{Deserialize, Serialize};
+
+use crate::{SendError, SMTP_CLIENT};
+
+pub const BUFFER: &str = "buffer";
+pub const STRICT: &str = "strict";
+
+fn default_mode() -> String {
+    BUFFER.to_string()
+}
+
+fn default_max_buffered() -> usize {
+    10000
+}
+
+#[derive(Clone, Deserialize, Serialize)]
+pub struct PersistenceSettings {
+    // "buffer" keeps sending while the rows wait, "strict" refuses the sends
+    #[serde(default = "default_mode")]
+    pub mode: String,
+    // the rows kept in memory, the oldest are dropped past it
+    #[serde(default = "default_max_buffered")]
+    pub max_buffered: usize,
+}
+
+impl Default for PersistenceSettings {
+    fn default() -> Self {
+        PersistenceSettings {
+            mode: default_mode(),
+            max_buffered: default_max_buffered(),
+        }
+    }
+}
+
+// a history entry, its raw MIME isn't kept while it waits
+pub struct History {
+    pub message_id: Option<String>,
+    pub created_at: i64,
+    pub sender: String,
+    pub recipients: String,
+    pub subject: String,
+    pub status: String,
+    pub response: String,
+    pub eml: Option<Vec<u8>>,
+    pub request_id: Option<String>,
+    pub tenant: String,
+}
+
+pub enum Row {
+    History(History),
+    Audit(crate::audit::Row),
+}
+
+impl Row {
+    fn kind(&self) -> &'static str {
+        match self {
+            Row::History(_) => "history",
+            Row::Audit(_) => "audit",
+        }
+    }
+
+    // the id of a history entry
+    fn write(&self) -> Result<Option<i64>, String> {
+        match self {
+            Row::History(row) => crate::history::insert(row).map(Some),
+            Row::Audit(row) => crate::audit::insert(row).map(|_| None),
+        }
+    }
+}
+
+struct Buffer {
+    rows: VecDeque<Row>,
+    dropped: u64,
+}
+
+static BUFFERED: Mutex<Buffer> = Mutex::new(Buffer { rows: VecDeque::new(), dropped: 0 });
+
+#[derive(Serialize)]
+pub struct Status {
+    mode: String,
+    // the rows waiting for the database and the ones dropped since the start
+    buffered: usize,
+    dropped: u64,
+}
+
+fn settings() -> PersistenceSettings {
+    SMTP_CLIENT.persistence.clone().unwrap_or_default()
+}
+
+fn buffer() -> std::sync::MutexGuard<'static, Buffer> {
+    BUFFERED.lock().unwrap_or_else(|e| e.into_inner())
+}
+
+// the buffered rows in order, up to the first the database still refuses
+fn write_buffered(buffer: &mut Buffer) {
+
+    if buffer.rows.is_empty() {
+        return;
+    }
+
+    let mut written = 0;
+    while let Some(row) = buffer.rows.front() {
+        if row.write().is_err() {
+            break;
+        }
+        buffer.rows.pop_front();
+        written += 1;
+    }
+    if written > 0 {
+        log!("Wrote {} buffered history and audit rows to the database, {} still waiting", written, buffer.rows.len());
+    }
+}
+
+// the rows still waiting
+pub fn flush() -> usize {
+    let mut buffer = buffer();
+    write_buffered(&mut buffer);
+    buffer.rows.len()
+}
+
+// written now when no row is waiting before it, buffered otherwise; the id of
+// a history entry that was written. Only the oldest waiting row is tried, the
+// scheduler writes the others
+pub fn save(mut row: Row) -> Option<i64> {
+
+    let mut buffer = buffer();
+    if buffer.rows.front().is_some_and(|oldest| oldest.write().is_ok()) {
+        buffer.rows.pop_front();
+    }
+    if buffer.rows.is_empty() {
+        match row.write() {
+            Ok(id) => return id,
+            Err(e) => log!("Warning: the database can't write the {} rows, they are kept in memory: {}", row.kind(), e),
+        }
+    }
+
+    crate::telemetry::count("arp_gmail.persistence.buffered", &[("kind", row.kind())]);
+    if buffer.rows.len() >= settings().max_buffered {
+        if let Some(dropped) = buffer.rows.pop_front() {
+            buffer.dropped += 1;
+            crate::telemetry::count("arp_gmail.persistence.dropped", &[("kind", dropped.kind())]);
+            if buffer.dropped == 1 {
+                log!("Warning: the buffer of the rows the database can't write is full, the oldest are dropped");
+            }
+        }
+    }
+    if let Row::History(history) = &mut row {
+        history.eml = None;
+    }
+    buffer.rows.push_back(row);
+
+    None
+}
+
+// in strict mode the sends wait for the buffered rows to be written
+pub fn check() -> Result<(), SendError> {
+
+    if settings().mode != STRICT {
+        return Ok(());
+    }
+    let mut buffer = buffer();
+    write_buffered(&mut buffer);
+    match buffer.rows.len() {
+        0 => Ok(()),
+        waiting => Err(SendError::new(
+            "storage_unavailable",
+            format!("{} history and audit rows are waiting for the database, sending is stopped", waiting),
+        )),
+    }
+}
+
+pub fn status() -> Status {
+    let buffer = buffer();
+    Status {
+        mode: settings().mode,
+        buffered: buffer.rows.len(),
+        dropped: buffer.dropped,
+    }
+}
@@ -0,0 +1,78 @@
+//
+// External commands that read a document on stdin and write the converted one
+// to stdout, with a timeout and a limit on the output
+//
+
+use std::io::{Read, Write};
+use std::process::{Command, Stdio};
+use std::time::{Duration, Instant};
+
+pub fn run(
+    command: &[String],
+    input: &[u8],
+    timeout: Duration,
+    max_bytes: usize,
+) -> Result<Vec<u8>, String> {
+
+    let (program, args) = command.split_first()
+        .ok_or("The command is empty".to_string())?;
+
+    let mut child = Command::new(program)
+        .args(args)
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+        .map_err(|e| format!("{}: {}", program, e))?;
+
+    // the pipes on threads of their own, a full one can't block the others;
+    // stdout is closed past the limit, the command stops on it
+    let mut stdin = child.stdin.take().unwrap();
+    let mut stdout = child.stdout.take().unwrap();
+    let mut stderr = child.stderr.take().unwrap();
+    let (status, output, errors) = std::thread::scope(|scope| {
+        scope.spawn(move || {
+            let _ = stdin.write_all(input);
+        });
+        let reader = scope.spawn(move || {
+            let mut output = Vec::new();
+            let _ = (&mut stdout).take(max_bytes as u64 + 1).read_to_end(&mut output);
+            output
+        });
+        let errors = scope.spawn(move || {
+            let mut errors = Vec::new();
+            let _ = stderr.read_to_end(&mut errors);
+            errors
+        });
+
+        let deadline = Instant::now() + timeout;
+        let status = loop {
+            match child.try_wait() {
+                Ok(Some(status)) => break Ok(status),
+                Ok(None) if Instant::now() < deadline => std::thread::sleep(Duration::from_millis(20)),
+                Ok(None) => {
+                    // the readers end on the closed pipes
+                    let _ = child.kill();
+                    let _ = child.wait();
+                    break Err(format!("{}: timed out after {}s", program, timeout.as_secs()));
+                },
+                Err(e) => break Err(format!("{}: {}", program, e)),
+            }
+        };
+        (status, reader.join().unwrap_or_default(), errors.join().unwrap_or_default())
+    });
+
+    if output.len() > max_bytes {
+        return Err(format!("{}: the output is bigger than {} bytes", program, max_bytes));
+    }
+    let status = status?;
+    if !status.success() {
+        let errors: String = String::from_utf8_lossy(&errors).trim().chars().take(500).collect();
+        return Err(match errors.is_empty() {
+            true => format!("{}: {}", program, status),
+            false => format!("{}: {} {}", program, status, errors),
+        });
+    }
+
+    Ok(output)
+}
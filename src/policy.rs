@@ -0,0 +1,304 @@
+//
+// Content policies of the config: rules matching the recipients, subject,
+// attachments and size of a message, applied in order before it is sent
+//
+
+use once_cell::sync::Lazy;
+use regex::Regex;
+use serde::{Deserialize, Serialize};
+
+use crate::{attachments, Mail, SendError, SMTP_CLIENT};
+
+// every condition that is set must match, a rule without any matches all the
+// messages
+#[derive(Clone, Default, Deserialize, Serialize)]
+pub struct Conditions {
+    // a recipient in one of these domains or their subdomains
+    #[serde(default)]
+    pub recipient_domains: Vec<String>,
+    // a regular expression, "(?i)" makes it case insensitive
+    pub subject: Option<String>,
+    // an attachment of one of these types, "application/pdf" or "image/*", or
+    // extensions, ".exe"
+    #[serde(default)]
+    pub attachment_types: Vec<String>,
+    // of the text and html bodies together
+    pub min_body_bytes: Option<usize>,
+    pub max_body_bytes: Option<usize>,
+}
+
+#[derive(Clone, Deserialize, Serialize)]
+#[serde(tag = "action", rename_all = "snake_case")]
+pub enum Action {
+    Reject {
+        // returned to the caller, the name of the rule if not set
+        message: Option<String>,
+    },
+    // held until an admin approves it, see the review settings
+    RequireApproval,
+    AddBcc {
+        address: String,
+    },
+    ForceAccount {
+        account: String,
+    },
+    // "header" as "name" is the one of the rule
+    AddHeader {
+        header: String,
+        value: String,
+    },
+}
+
+#[derive(Clone, Deserialize, Serialize)]
+pub struct Rule {
+    pub name: String,
+    #[serde(rename = "match", default)]
+    pub conditions: Conditions,
+    #[serde(flatten)]
+    pub action: Action,
+}
+
+// the subject expressions of the rules, by the position of the rule; one that
+// doesn't compile matches nothing, the config check reports it
+static SUBJECTS: Lazy<Vec<Option<Regex>>> = Lazy::new(|| {
+    SMTP_CLIENT.policies.iter()
+        .flatten()
+        .map(|rule| rule.conditions.subject.as_deref().and_then(|subject| Regex::new(subject)
+            .inspect_err(|e| log!("The subject of the policy {} doesn't compile: {}", rule.name, e))
+            .ok()))
+        .collect()
+});
+
+// the rules that matched and the reasons of those that ask for an approval
+#[derive(Default)]
+pub struct Applied {
+    pub rules: Vec<String>,
+    pub approvals: Vec<String>,
+}
+
+fn in_domains(
+    address: &str,
+    domains: &[String],
+) -> bool {
+    let domain = address.rsplit_once('@')
+        .map(|(_, domain)| domain.to_lowercase())
+        .unwrap_or_default();
+    domains.iter()
+        .map(|suffix| suffix.trim().trim_start_matches('.').to_lowercase())
+        .any(|suffix| domain == suffix || domain.ends_with(&format!(".{}", suffix)))
+}
+
+fn type_matches(
+    (name, content_type): &(String, String),
+    pattern: &str,
+) -> bool {
+    let pattern = pattern.trim();
+    let content_type = content_type.split(';').next().unwrap_or_default().trim();
+    if pattern.starts_with('.') {
+        return name.to_lowercase().ends_with(&pattern.to_lowercase());
+    }
+    match pattern.strip_suffix("/*") {
+        Some(top) => content_type.split('/').next().is_some_and(|kind| kind.eq_ignore_ascii_case(top)),
+        None => content_type.eq_ignore_ascii_case(pattern),
+    }
+}
+
+pub fn recipients(mail: &Mail) -> Vec<String> {
+    [Some(&mail.to), mail.cc.as_ref(), mail.bcc.as_ref()]
+        .into_iter()
+        .flatten()
+        .flat_map(|list| list.parse::<lettre::message::Mailboxes>().ok())
+        .flat_map(|mailboxes| mailboxes.into_iter().map(|mailbox| mailbox.email.to_string()))
+        .collect()
+}
+
+fn matches(
+    conditions: &Conditions,
+    subject: Option<&Regex>,
+    mail: &Mail,
+) -> bool {
+
+    if !conditions.recipient_domains.is_empty()
+        && !recipients(mail).iter().any(|recipient| in_domains(recipient, &conditions.recipient_domains)) {
+        return false;
+    }
+
+    if conditions.subject.is_some() && !subject.is_some_and(|subject| subject.is_match(&mail.subject)) {
+        return false;
+    }
+
+    if !conditions.attachment_types.is_empty() {
+        let types: Vec<(String, String)> = mail.attachments.iter()
+            .flatten()
+            .map(attachments::name_and_type)
+            .chain(mail.pdf.as_ref().map(|pdf| (pdf.filename.clone(), "application/pdf".to_string())))
+            .chain(mail.contact_card.as_ref().map(|_| (".vcf".to_string(), "text/vcard".to_string())))
+            .collect();
+        let found = types.iter()
+            .any(|attachment| conditions.attachment_types.iter().any(|pattern| type_matches(attachment, pattern)));
+        if !found {
+            return false;
+        }
+    }
+
+    let body_bytes = mail.message.len() + mail.html.as_ref().map_or(0, String::len);
+    if conditions.min_body_bytes.is_some_and(|min| body_bytes < min)
+        || conditions.max_body_bytes.is_some_and(|max| body_bytes > max) {
+        return false;
+    }
+
+    true
+}
+
+// the prebuilt and forwarded messages can't be matched, they aren't sent
+pub fn enabled() -> bool {
+    SMTP_CLIENT.policies.as_ref().is_some_and(|rules| !rules.is_empty())
+}
+
+// the actions of the matching rules, a rejection stops at its rule
+pub fn apply(mail: &mut Mail) -> Result<Applied, SendError> {
+    apply_rules(SMTP_CLIENT.policies.as_deref().unwrap_or_default(), &SUBJECTS, mail)
+}
+
+fn apply_rules(
+    rules: &[Rule],
+    subjects: &[Option<Regex>],
+    mail: &mut Mail,
+) -> Result<Applied, SendError> {
+
+    let mut applied = Applied::default();
+    for (rule, subject) in rules.iter().zip(subjects) {
+        if !matches(&rule.conditions, subject.as_ref(), mail) {
+            continue;
+        }
+        applied.rules.push(rule.name.clone());
+
+        match &rule.action {
+            Action::Reject { message } => return Err(SendError::new(
+                "policy_violation",
+                format!("Refused by the policy {}: {}", rule.name, message.as_deref().unwrap_or("the message isn't allowed")),
+            )),
+            Action::RequireApproval => applied.approvals.push(format!("the policy {}", rule.name)),
+            Action::AddBcc { address } => {
+                mail.bcc = Some(match mail.bcc.as_deref().filter(|bcc| !bcc.trim().is_empty()) {
+                    Some(bcc) => format!("{}, {}", bcc, address),
+                    None => address.clone(),
+                });
+            },
+            Action::ForceAccount { account } => mail.account = Some(account.clone()),
+            Action::AddHeader { header, value } => mail.policy_headers.push((header.clone(), value.clone())),
+        }
+    }
+
+    Ok(applied)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn mail(fields: serde_json::Value) -> Mail {
+        let mut mail = json!({ "to": "a@shop.example.com", "subject": "Your receipt", "message": "Thanks" });
+        mail.as_object_mut().unwrap().extend(fields.as_object().unwrap().clone());
+        serde_json::from_value(mail).unwrap()
+    }
+
+    // the names of the rules that matched, or the error of a rejection
+    fn run(
+        rule: serde_json::Value,
+        mail: &mut Mail,
+    ) -> Result<Vec<String>, String> {
+        let rule: Rule = serde_json::from_value(rule).unwrap();
+        let subject = rule.conditions.subject.as_deref().map(|subject| Regex::new(subject).unwrap());
+        apply_rules(&[rule], &[subject], mail)
+            .map(|applied| applied.rules)
+            .map_err(|error| format!("{}: {}", error.code, error.message))
+    }
+
+    #[test]
+    fn matchers() {
+        let cases = [
+            // the recipient domains and their subdomains, of to, cc and bcc
+            (json!({ "recipient_domains": ["example.com"] }), json!({}), true),
+            (json!({ "recipient_domains": [".EXAMPLE.com"] }), json!({}), true),
+            (json!({ "recipient_domains": ["ample.com"] }), json!({}), false),
+            (json!({ "recipient_domains": ["partner.org"] }), json!({ "bcc": "b@partner.org" }), true),
+            // the subject expression
+            (json!({ "subject": "(?i)^your RECEIPT" }), json!({}), true),
+            (json!({ "subject": "^Invoice" }), json!({}), false),
+            // the types and extensions of the attachments, the PDF and the contact card
+            (json!({ "attachment_types": ["application/pdf"] }), json!({ "attachments": ["q3.PDF"] }), true),
+            (json!({ "attachment_types": ["image/*"] }), json!({ "attachments": [{ "content": "aGk=", "filename": "x", "content_type": "image/png; name=x" }] }), true),
+            (json!({ "attachment_types": [".exe"] }), json!({ "attachments": ["setup.EXE"] }), true),
+            (json!({ "attachment_types": [".exe"] }), json!({ "attachments": ["q3.pdf"] }), false),
+            (json!({ "attachment_types": [".exe"] }), json!({}), false),
+            (json!({ "attachment_types": ["text/vcard"] }), json!({ "contact_card": { "name": "Ann" } }), true),
+            // the text and html bodies together
+            (json!({ "min_body_bytes": 10 }), json!({ "html": "<p>hi</p>" }), true),
+            (json!({ "min_body_bytes": 10 }), json!({}), false),
+            (json!({ "max_body_bytes": 6 }), json!({}), true),
+            (json!({ "max_body_bytes": 5 }), json!({}), false),
+            // every condition that is set, none matches every message
+            (json!({ "recipient_domains": ["example.com"], "subject": "^Invoice" }), json!({}), false),
+            (json!({}), json!({}), true),
+        ];
+        for (conditions, fields, expected) in cases {
+            let rule = json!({ "name": "rule", "match": conditions, "action": "add_header", "header": "X-Rule", "value": "1" });
+            let matched = run(rule, &mut mail(fields.clone())).unwrap();
+            assert_eq!(!matched.is_empty(), expected, "{} {}", conditions, fields);
+        }
+    }
+
+    #[test]
+    fn actions() {
+        let mut sent = mail(json!({}));
+        assert_eq!(
+            run(json!({ "name": "no-receipts", "action": "reject" }), &mut sent),
+            Err("policy_violation: Refused by the policy no-receipts: the message isn't allowed".to_string()),
+        );
+        assert_eq!(
+            run(json!({ "name": "no-receipts", "action": "reject", "message": "use the portal" }), &mut sent),
+            Err("policy_violation: Refused by the policy no-receipts: use the portal".to_string()),
+        );
+
+        let rule: Rule = serde_json::from_value(json!({ "name": "legal", "action": "require_approval" })).unwrap();
+        let applied = apply_rules(&[rule], &[None], &mut sent).unwrap();
+        assert_eq!(applied.approvals, ["the policy legal"]);
+
+        run(json!({ "name": "archive", "action": "add_bcc", "address": "archive@example.com" }), &mut sent).unwrap();
+        assert_eq!(sent.bcc.as_deref(), Some("archive@example.com"));
+        run(json!({ "name": "audit", "action": "add_bcc", "address": "audit@example.com" }), &mut sent).unwrap();
+        assert_eq!(sent.bcc.as_deref(), Some("archive@example.com, audit@example.com"));
+
+        run(json!({ "name": "relay", "action": "force_account", "account": "mailgun" }), &mut sent).unwrap();
+        assert_eq!(sent.account.as_deref(), Some("mailgun"));
+
+        run(json!({ "name": "tag", "action": "add_header", "header": "X-Policy", "value": "receipts" }), &mut sent).unwrap();
+        assert_eq!(sent.policy_headers, [("X-Policy".to_string(), "receipts".to_string())]);
+    }
+
+    // a rejection stops at its rule, the earlier actions are applied
+    #[test]
+    fn rules_in_order() {
+        let rules: Vec<Rule> = serde_json::from_value(json!([
+            { "name": "tag", "action": "add_header", "header": "X-Policy", "value": "1" },
+            { "name": "other", "match": { "subject": "^Invoice" }, "action": "reject" },
+            { "name": "archive", "action": "add_bcc", "address": "archive@example.com" },
+        ])).unwrap();
+        let subjects = [None, Some(Regex::new("^Invoice").unwrap()), None];
+        let mut sent = mail(json!({}));
+        let applied = apply_rules(&rules, &subjects, &mut sent).unwrap();
+        assert_eq!(applied.rules, ["tag", "archive"]);
+
+        let rules: Vec<Rule> = serde_json::from_value(json!([
+            { "name": "tag", "action": "add_header", "header": "X-Policy", "value": "1" },
+            { "name": "stop", "action": "reject" },
+            { "name": "archive", "action": "add_bcc", "address": "archive@example.com" },
+        ])).unwrap();
+        let mut sent = mail(json!({}));
+        assert!(apply_rules(&rules, &[None, None, None], &mut sent).is_err());
+        assert_eq!(sent.policy_headers.len(), 1);
+        assert_eq!(sent.bcc, None);
+    }
+}
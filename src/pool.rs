@@ -0,0 +1,633 @@
+//
+// Shared pooled SMTP transport kept warm between sends, and the worker threads
+// the sends of every request run on
+//
+
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{mpsc, Arc, Mutex, Once};
+use std::time::{Duration, Instant};
+use lettre::address::Envelope;
+use lettre::transport::smtp::authentication::{Credentials, DEFAULT_MECHANISMS};
+use lettre::transport::smtp::client::SmtpConnection;
+use lettre::transport::smtp::extension::ClientId;
+use lettre::transport::smtp::response::Response;
+use lettre::transport::smtp::{self, PoolConfig};
+use lettre::SmtpTransport;
+use once_cell::sync::Lazy;
+use serde::{Deserialize, Serialize};
+
+use crate::SMTP_CLIENT;
+use crate::shutdown;
+use crate::transcript::Transcript;
+
+pub const DEFAULT_TIMEOUT_MS: u64 = 30000;
+
+fn default_max_size() -> u32 {
+    4
+}
+
+fn default_keepalive_secs() -> u64 {
+    60
+}
+
+fn default_idle_timeout_secs() -> u64 {
+    300
+}
+
+fn default_workers() -> usize {
+    8
+}
+
+fn default_backlog() -> usize {
+    64
+}
+
+#[derive(Clone, Deserialize, Serialize)]
+pub struct PoolSettings {
+    // maximum number of open connections to the server
+    #[serde(default = "default_max_size")]
+    max_size: u32,
+    // seconds between the NOOPs that keep an idle connection open, 0 disables it
+    #[serde(default = "default_keepalive_secs")]
+    keepalive_secs: u64,
+    // idle connections not used or kept alive for this long are closed
+    #[serde(default = "default_idle_timeout_secs")]
+    idle_timeout_secs: u64,
+    // threads running the sends, however many threads of the host call in
+    #[serde(default = "default_workers")]
+    workers: usize,
+    // sends waiting for a worker, beyond it a send is refused as busy
+    #[serde(default = "default_backlog")]
+    backlog: usize,
+}
+
+impl Default for PoolSettings {
+    fn default() -> Self {
+        PoolSettings {
+            max_size: default_max_size(),
+            keepalive_secs: default_keepalive_secs(),
+            idle_timeout_secs: default_idle_timeout_secs(),
+            workers: default_workers(),
+            backlog: default_backlog(),
+        }
+    }
+}
+
+// failure of a send: the SMTP error, the timeout that aborted it, the end of
+// the cool-down after a Gmail limit error, the reset of the daily limit, the
+// recipient over its rate limit, the error of a provider API, an account
+// that can't be used or every worker busy with a full backlog
+#[derive(Debug)]
+pub enum Failure {
+    Smtp(smtp::Error),
+    Timeout(Duration),
+    CoolingDown(i64),
+    LimitReached(i64),
+    RecipientLimited(String),
+    Suppressed(String),
+    // rejected as unknown by an earlier send, see invalid.rs
+    KnownInvalid(String),
+    // a temporary failure of the chaos settings
+    Injected(String),
+    // only built with the provider features
+    #[cfg_attr(not(any(feature = "sendgrid", feature = "mailgun", feature = "ses")), allow(dead_code))]
+    Api(crate::transport::ApiError),
+    Account(String),
+    Busy(usize),
+    // the server name could not be resolved with the DNS settings
+    Dns(String),
+    // none of the addresses of the server raced by the plugin connected
+    Connect(String),
+    // the handshake or the certificate of the server failed the TLS policy
+    Tls(String),
+}
+
+// the use of the workers reported by /health
+#[derive(Serialize)]
+pub struct Usage {
+    workers: usize,
+    busy: usize,
+    // sends waiting for a worker
+    waiting: usize,
+    backlog: usize,
+    // busy workers, percent
+    utilization: usize,
+    // the parked connections of the plugin, the pool of lettre doesn't count
+    // its own
+    idle_connections: usize,
+}
+
+// a send waiting for a worker, dropped unrun if its caller has given up
+struct Task {
+    deadline: Instant,
+    send: Box<dyn FnOnce() + Send>,
+}
+
+// the transport of each Gmail account, built on its first send
+static MAILERS: Mutex<Option<HashMap<String, SmtpTransport>>> = Mutex::new(None);
+
+// the idle connections of the accounts connected by the plugin, with the time
+// each was parked: the transport of lettre can't choose the local address of
+// its sockets nor resolve their server with the DNS settings, these are made
+// and pooled here
+type Parked = Vec<(SmtpConnection, Instant)>;
+static DIRECT: Mutex<Option<HashMap<String, Parked>>> = Mutex::new(None);
+static STARTED: Once = Once::new();
+
+static BUSY: AtomicUsize = AtomicUsize::new(0);
+static WAITING: AtomicUsize = AtomicUsize::new(0);
+
+// started on the first send, none if no worker could be started
+static WORKERS: Lazy<Option<mpsc::SyncSender<Task>>> = Lazy::new(|| {
+
+    let settings = settings();
+    let (sender, receiver) = mpsc::sync_channel::<Task>(settings.backlog);
+    let receiver = Arc::new(Mutex::new(receiver));
+
+    let mut started = 0;
+    for i in 0..settings.workers.max(1) {
+        let receiver = Arc::clone(&receiver);
+        let result = std::thread::Builder::new()
+            .name(format!("arp-gmail-send-{}", i))
+            .spawn(move || work(&receiver));
+        match result {
+            Ok(handle) => {
+                shutdown::register("send", handle);
+                started += 1;
+            },
+            Err(e) => log!("Error starting the send worker {}: {}", i, e),
+        }
+    }
+
+    (started > 0).then_some(sender)
+});
+
+fn work(receiver: &Mutex<mpsc::Receiver<Task>>) {
+
+    while !shutdown::stopping() {
+        // one worker waits on the channel at a time, the others on the lock
+        let task = match receiver.lock() {
+            Ok(receiver) if !shutdown::stopping() => receiver.recv_timeout(Duration::from_millis(100)),
+            _ => return,
+        };
+        if task.is_ok() {
+            WAITING.fetch_sub(1, Ordering::Relaxed);
+        }
+        match task {
+            Ok(task) if Instant::now() < task.deadline => {
+                // a panic of a send ends the send, not the worker: its caller
+                // sees the channel closed
+                BUSY.fetch_add(1, Ordering::Relaxed);
+                let _ = std::panic::catch_unwind(std::panic::AssertUnwindSafe(task.send));
+                BUSY.fetch_sub(1, Ordering::Relaxed);
+            },
+            Ok(_) => {},
+            Err(mpsc::RecvTimeoutError::Timeout) => {},
+            Err(mpsc::RecvTimeoutError::Disconnected) => return,
+        }
+    }
+}
+
+fn settings() -> PoolSettings {
+    SMTP_CLIENT.pool.clone().unwrap_or_default()
+}
+
+// timeout of a request, or the configured default
+pub fn timeout(timeout_ms: Option<u64>) -> Duration {
+    Duration::from_millis(timeout_ms
+        .or(SMTP_CLIENT.timeout_ms)
+        .unwrap_or(DEFAULT_TIMEOUT_MS))
+}
+
+fn build(account: &crate::transport::Gmail) -> SmtpTransport {
+
+    let settings = settings();
+
+    // Set up the SMTP client
+    let credentials = smtp::authentication::Credentials::new(
+        account.username.to_owned(),
+        account.password.to_owned(),
+    );
+
+    // the idle timeout must outlast the keepalive interval or the
+    // connection is closed between two NOOPs
+    let idle_timeout = settings.idle_timeout_secs
+        .max(settings.keepalive_secs.saturating_mul(2));
+
+    // socket timeout of every step of the dialogue, so a send abandoned
+    // by a request timeout doesn't hold its thread forever
+    let socket_timeout = timeout(None);
+
+    // plain text to a dev relay, it has no certificate
+    let mut builder = match crate::profile::dev() {
+        true => SmtpTransport::builder_dangerous(&account.server).port(crate::profile::port()),
+        false => SmtpTransport::relay(&account.server).unwrap(),
+    };
+    if crate::profile::authenticates(&account.username) {
+        builder = builder.credentials(credentials);
+    }
+
+    builder
+        .timeout(Some(socket_timeout))
+        .pool_config(PoolConfig::new()
+            .max_size(settings.max_size.max(1))
+            .idle_timeout(Duration::from_secs(idle_timeout)))
+        .build()
+}
+
+// the transport shares its pool between all clones
+pub fn mailer(account: &crate::transport::Gmail) -> SmtpTransport {
+    let mut mailers = MAILERS.lock()
+        .unwrap_or_else(|e| e.into_inner());
+    mailers.get_or_insert_with(HashMap::new)
+        .entry(account.name.to_string())
+        .or_insert_with(|| build(account))
+        .clone()
+}
+
+// the accounts with a bind_address, or all of them with the DNS, connect or
+// TLS settings
+pub fn direct(account: &crate::transport::Gmail) -> bool {
+    account.bind_address.is_some()
+        || crate::dns::configured()
+        || crate::connect::settings().is_some()
+        || crate::tls::settings().is_some()
+}
+
+// a parked connection of the account that still answers a NOOP, or a new one
+fn direct_connection(account: &crate::transport::Gmail) -> Result<SmtpConnection, Failure> {
+
+    let idle_timeout = Duration::from_secs(settings().idle_timeout_secs);
+    loop {
+        let parked = DIRECT.lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .as_mut()
+            .and_then(|direct| direct.get_mut(&account.name))
+            .and_then(Vec::pop);
+        match parked {
+            Some((mut connection, parked)) => match parked.elapsed() < idle_timeout && connection.test_connected() {
+                true => return Ok(connection),
+                false => connection.abort(),
+            },
+            None => break,
+        }
+    }
+
+    let mut connection = open(account, None)?;
+    if crate::profile::authenticates(&account.username) {
+        login(account, &mut connection, None)?;
+    }
+
+    Ok(connection)
+}
+
+// a new connection to the server of the account, through TLS and EHLO, its
+// steps written to the transcript of a debug send; in plain text to a dev relay
+pub fn open(
+    account: &crate::transport::Gmail,
+    transcript: Option<&Transcript>,
+) -> Result<SmtpConnection, Failure> {
+
+    let note = |text: String| if let Some(transcript) = transcript {
+        transcript.note(text);
+    };
+
+    // the certificate is checked against the name whichever address it came from
+    let mut addresses = crate::dns::lookup_from(&crate::dns::settings(), &account.server, crate::profile::port(), account.bind_address)
+        .map_err(Failure::Dns)?;
+    note(format!("{} is {}", account.server, addresses.iter()
+        .map(|addr| addr.ip().to_string())
+        .collect::<Vec<String>>()
+        .join(", ")));
+    let tls = match crate::profile::dev() {
+        true => None,
+        false => Some(crate::tls::parameters(&account.server)
+            .map_err(|e| Failure::Tls(format!("TLS policy: {}", e)))?),
+    };
+    let handshake = |e: smtp::Error| match e.is_tls() {
+        true => Failure::Tls(format!("TLS handshake with {} failed ({}): {}", account.server, crate::tls::describe(), e)),
+        false => Failure::Smtp(e),
+    };
+    let mut connection = match crate::connect::settings() {
+        // the session that answers first is the one kept, the handshake
+        // failures of the TLS policy end the race
+        Some(settings) if settings.happy_eyeballs => {
+            let parameters = tls.clone();
+            let local = account.bind_address;
+            let (_, connection) = crate::connect::race_with(
+                &crate::connect::order(&addresses, settings.prefer.as_deref()),
+                timeout(None),
+                Duration::from_millis(settings.attempt_delay_ms),
+                move |addr, left| match SmtpConnection::connect(addr, Some(left), &ClientId::default(), parameters.as_ref(), local) {
+                    Err(e) if !e.is_tls() => Err(e.to_string()),
+                    connected => Ok(connected),
+                },
+            ).map_err(|e| Failure::Connect(format!("{}: {}", account.server, e)))?;
+            let mut connection = connection.map_err(handshake)?;
+            connection.set_timeout(Some(timeout(None)))
+                .map_err(|e| Failure::Connect(format!("{}: {}", account.server, e)))?;
+            connection
+        },
+        settings => {
+            if let Some(settings) = settings {
+                addresses = crate::connect::order(&addresses, settings.prefer.as_deref());
+            }
+            SmtpConnection::connect(
+                &addresses[..],
+                Some(timeout(None)),
+                &ClientId::default(),
+                tls.as_ref(),
+                account.bind_address,
+            ).map_err(handshake)?
+        },
+    };
+    if tls.is_none() {
+        note(format!("Plain SMTP session with the dev relay {}, EHLO {}", account.server, connection.server_info()));
+        return Ok(connection);
+    }
+    note(format!("TLS session with {} ({}), EHLO {}", account.server, crate::tls::describe(), connection.server_info()));
+    // before the credentials are sent
+    if let Err(e) = crate::tls::check_pins(&connection) {
+        connection.abort();
+        return Err(Failure::Tls(format!("TLS with {}: {}", account.server, e)));
+    }
+
+    Ok(connection)
+}
+
+// AUTH with the credentials of the account, only the mechanism is written to
+// the transcript
+pub fn login(
+    account: &crate::transport::Gmail,
+    connection: &mut SmtpConnection,
+    transcript: Option<&Transcript>,
+) -> Result<Response, Failure> {
+
+    if let Some(transcript) = transcript {
+        let mechanism = connection.server_info()
+            .get_auth_mechanism(DEFAULT_MECHANISMS)
+            .map(|mechanism| mechanism.to_string())
+            .unwrap_or_default();
+        transcript.client(format!("AUTH {} <redacted>", mechanism));
+    }
+
+    let credentials = Credentials::new(account.username.to_owned(), account.password.to_owned());
+    let result = connection.auth(DEFAULT_MECHANISMS, &credentials);
+    if let Some(transcript) = transcript {
+        transcript.reply(&result);
+    }
+
+    result.map_err(|e| {
+        connection.abort();
+        Failure::Smtp(e)
+    })
+}
+
+// the connection is parked again for the next send unless it broke or the
+// pool is full
+pub fn send_direct(
+    account: &crate::transport::Gmail,
+    envelope: &Envelope,
+    raw: &[u8],
+) -> Result<Response, Failure> {
+
+    let mut connection = direct_connection(account)?;
+    let result = connection.send(envelope, raw)
+        .map_err(Failure::Smtp);
+    if connection.has_broken() {
+        connection.abort();
+        return result;
+    }
+
+    let mut direct = DIRECT.lock()
+        .unwrap_or_else(|e| e.into_inner());
+    let parked = direct.get_or_insert_with(HashMap::new)
+        .entry(account.name.to_string())
+        .or_default();
+    match parked.len() < settings().max_size.max(1) as usize {
+        true => parked.push((connection, Instant::now())),
+        false => {
+            drop(direct);
+            let _ = connection.quit();
+        },
+    }
+
+    result
+}
+
+// the recipients of a send admitted but not yet recorded, counted by the
+// admissions of the other threads until the send ends; owned by the task, a
+// send abandoned on its timeout keeps them until it ends too
+struct Reservation {
+    account: Option<String>,
+    recipients: Vec<lettre::Address>,
+}
+
+impl Reservation {
+    // the send went through, counted in the window of the limits
+    fn record(&self) {
+        if let Some(account) = &self.account {
+            crate::quota::record(account, self.recipients.len() as u64);
+        }
+        crate::ratelimit::record(&self.recipients);
+    }
+}
+
+impl Drop for Reservation {
+    fn drop(&mut self) {
+        if let Some(account) = &self.account {
+            crate::quota::release(account, self.recipients.len() as u64);
+        }
+        crate::ratelimit::release(&self.recipients);
+    }
+}
+
+// run a send on a worker so that DNS, connect and the dialogue with the server
+// together can't take longer than the timeout; only the sends of a Gmail
+// account count against its limits
+pub fn run<T: Send + 'static>(
+    timeout: Duration,
+    recipients: &[lettre::Address],
+    gmail: Option<&str>,
+    f: impl FnOnce() -> Result<T, Failure> + Send + 'static,
+) -> Result<T, Failure> {
+
+    // a dev relay has none of the limits of Gmail
+    let gmail = gmail.filter(|_| !crate::profile::dev());
+    if let Some(account) = gmail {
+        if let Some(until) = crate::quota::cooling_down(account) {
+            return Err(Failure::CoolingDown(until));
+        }
+    }
+    crate::suppressions::check(recipients)
+        .map_err(Failure::Suppressed)?;
+    crate::invalid::check(recipients)
+        .map_err(Failure::KnownInvalid)?;
+
+    // reserved before the check of the rate limit, released on every return
+    let mut reservation = Reservation { account: None, recipients: Vec::new() };
+    if let Some(account) = gmail {
+        crate::quota::admit(account, recipients.len() as u64)
+            .map_err(Failure::LimitReached)?;
+        reservation.account = Some(account.to_string());
+    }
+    crate::ratelimit::check(recipients)
+        .map_err(Failure::RecipientLimited)?;
+    reservation.recipients = recipients.to_vec();
+
+    let workers = match WORKERS.as_ref() {
+        Some(workers) => workers,
+        None => return Err(Failure::Busy(0)),
+    };
+    if crate::chaos::backlog_full() {
+        return Err(Failure::Busy(settings().workers.max(1)));
+    }
+    let (sender, receiver) = mpsc::channel();
+    let task = Task {
+        deadline: Instant::now() + timeout,
+        // a task dropped before a worker took it releases the reservation
+        send: Box::new(move || {
+            let result = match crate::chaos::inject() {
+                Some(failure) => Err(failure),
+                None => f(),
+            };
+            if result.is_ok() {
+                reservation.record();
+            }
+            drop(reservation);
+            let _ = sender.send(result);
+        }),
+    };
+    // counted before the send, a worker may take it at once
+    WAITING.fetch_add(1, Ordering::Relaxed);
+    if workers.try_send(task).is_err() {
+        WAITING.fetch_sub(1, Ordering::Relaxed);
+        return Err(Failure::Busy(settings().workers.max(1)));
+    }
+
+    // the abandoned send ends on the socket timeout, one still waiting for a
+    // worker isn't sent
+    let response = receiver.recv_timeout(timeout)
+        .map_err(|_| Failure::Timeout(timeout))?
+        .inspect_err(|failure| crate::invalid::record(recipients, failure))?;
+
+    Ok(response)
+}
+
+pub fn usage() -> Usage {
+
+    let settings = settings();
+    let workers = settings.workers.max(1);
+    let busy = BUSY.load(Ordering::Relaxed);
+    let idle_connections = DIRECT.lock()
+        .unwrap_or_else(|e| e.into_inner())
+        .iter()
+        .flat_map(HashMap::values)
+        .map(Vec::len)
+        .sum();
+
+    Usage {
+        workers,
+        busy,
+        waiting: WAITING.load(Ordering::Relaxed),
+        backlog: settings.backlog,
+        utilization: busy * 100 / workers,
+        idle_connections,
+    }
+}
+
+// drop the pools, the idle connections are closed with a QUIT
+pub fn close() {
+    if let Ok(mut mailers) = MAILERS.lock() {
+        mailers.take();
+    }
+    let direct = DIRECT.lock()
+        .map(|mut direct| direct.take())
+        .unwrap_or_default();
+    for (mut connection, _) in direct.into_iter().flat_map(HashMap::into_values).flatten() {
+        let _ = connection.quit();
+    }
+}
+
+// a parked connection is checked with a NOOP when it is taken from the pool
+// and replaced by a new one if the server dropped it; the accounts of the
+// rotation are kept warm once they have sent
+fn keepalive() {
+
+    let mailers: Vec<(String, SmtpTransport)> = MAILERS.lock()
+        .unwrap_or_else(|e| e.into_inner())
+        .iter()
+        .flatten()
+        .map(|(name, mailer)| (name.clone(), mailer.clone()))
+        .collect();
+    let primary = crate::transport::Gmail::primary();
+    let mailers = match mailers.is_empty() && !direct(&primary) {
+        true => vec![(crate::transport::GMAIL.to_string(), mailer(&primary))],
+        false => mailers,
+    };
+
+    for (name, mailer) in mailers {
+        let server = crate::transport::Gmail::find(&name)
+            .map(|account| account.server)
+            .unwrap_or_default();
+        match mailer.test_connection() {
+            Ok(true) => {},
+            Ok(false) => log!("SMTP keepalive: the connection of {} to {} is not responding", name, server),
+            Err(e) => log!("SMTP keepalive: {} failed to connect to {}: {}", name, server, e),
+        }
+    }
+
+    // the parked connections of the plugin are taken out for their NOOPs, the
+    // sends don't wait on them; the ones that still answer are parked again as
+    // just used, the others are dropped
+    let taken: Vec<(String, SmtpConnection)> = DIRECT.lock()
+        .unwrap_or_else(|e| e.into_inner())
+        .iter_mut()
+        .flat_map(HashMap::iter_mut)
+        .flat_map(|(name, parked)| parked.drain(..)
+            .map(|(connection, _)| (name.clone(), connection))
+            .collect::<Vec<_>>())
+        .collect();
+    let alive: Vec<(String, SmtpConnection)> = taken.into_iter()
+        .filter_map(|(name, mut connection)| connection.test_connected().then_some((name, connection)))
+        .collect();
+
+    // the sends may have parked others meanwhile, and the pool is gone after
+    // a shutdown
+    let mut surplus = Vec::new();
+    let mut direct = DIRECT.lock()
+        .unwrap_or_else(|e| e.into_inner());
+    for (name, connection) in alive {
+        match direct.as_mut().map(|direct| direct.entry(name).or_default()) {
+            Some(parked) if parked.len() < settings().max_size.max(1) as usize => parked.push((connection, Instant::now())),
+            _ => surplus.push(connection),
+        }
+    }
+    drop(direct);
+    for mut connection in surplus {
+        let _ = connection.quit();
+    }
+}
+
+// safe to call more than once, the thread is only started the first time
+pub fn start() {
+
+    let interval = settings().keepalive_secs;
+    if interval == 0 {
+        return;
+    }
+
+    STARTED.call_once(|| {
+        let result = std::thread::Builder::new()
+            .name("arp-gmail-keepalive".to_string())
+            .spawn(move || while !shutdown::stopping() {
+                keepalive();
+                shutdown::sleep(Duration::from_secs(interval));
+            });
+        match result {
+            Ok(handle) => shutdown::register("keepalive", handle),
+            Err(e) => log!("Error starting the SMTP keepalive: {}", e),
+        }
+    });
+}
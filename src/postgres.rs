@@ -0,0 +1,438 @@
+//
+// Postgres storage of the shared state, for the instances of the plugin on
+// different hosts; the tables are created on the first connection and the
+// connection is opened again after it is lost
+//
+
+use std::sync::Mutex;
+use postgres::{Client, Row};
+use postgres_native_tls::MakeTlsConnector;
+
+use crate::duplicates::Duplicate;
+use crate::history::Entry;
+use crate::storage::{DeadLetterRow, Job, JobUpdate, NewJob, HistoryRow, Purge, Purged, QueueCounts, Store};
+use crate::suppressions::Suppression;
+
+const SCHEMA: &str = "CREATE TABLE IF NOT EXISTS queue (
+    id BIGSERIAL PRIMARY KEY,
+    mail TEXT NOT NULL,
+    attempts BIGINT NOT NULL DEFAULT 0,
+    next_attempt BIGINT NOT NULL,
+    last_error TEXT,
+    created_at BIGINT NOT NULL,
+    deferrals BIGINT NOT NULL DEFAULT 0,
+    lane TEXT NOT NULL DEFAULT 'transactional'
+);
+ALTER TABLE queue ADD COLUMN IF NOT EXISTS locked_by TEXT;
+ALTER TABLE queue ADD COLUMN IF NOT EXISTS locked_until BIGINT;
+CREATE INDEX IF NOT EXISTS queue_lane ON queue (lane, next_attempt);
+CREATE TABLE IF NOT EXISTS deadletter (
+    id BIGSERIAL PRIMARY KEY,
+    job_id BIGINT NOT NULL,
+    mail TEXT NOT NULL,
+    attempts BIGINT NOT NULL,
+    last_error TEXT NOT NULL,
+    created_at BIGINT NOT NULL,
+    failed_at BIGINT NOT NULL,
+    status TEXT NOT NULL DEFAULT 'failed'
+);
+CREATE TABLE IF NOT EXISTS history (
+    id BIGSERIAL PRIMARY KEY,
+    message_id TEXT,
+    created_at BIGINT NOT NULL,
+    sender TEXT NOT NULL,
+    recipients TEXT NOT NULL,
+    subject TEXT NOT NULL,
+    status TEXT NOT NULL,
+    response TEXT NOT NULL,
+    eml BYTEA,
+    request_id TEXT,
+    tenant TEXT NOT NULL DEFAULT ''
+);
+CREATE TABLE IF NOT EXISTS suppressions (
+    tenant TEXT NOT NULL,
+    address TEXT NOT NULL,
+    reason TEXT,
+    created_at BIGINT NOT NULL,
+    PRIMARY KEY (tenant, address)
+);
+CREATE TABLE IF NOT EXISTS sent_hashes (
+    hash TEXT NOT NULL,
+    message_id TEXT,
+    sent_at BIGINT NOT NULL
+);
+CREATE INDEX IF NOT EXISTS sent_hashes_hash ON sent_hashes (hash, sent_at);";
+
+// a job no instance holds, or whose lease ended
+const UNLOCKED: &str = "(locked_until IS NULL OR locked_until <= $2)";
+
+// the mails of the tenant of the request, all of them for the instance
+const TENANT: &str = "($1 = '' OR COALESCE(mail::jsonb->>'tenant', '') = $1)";
+
+pub struct Postgres {
+    url: String,
+    client: Mutex<Option<Client>>,
+}
+
+fn error(e: postgres::Error) -> String {
+    format!("Postgres: {}", e)
+}
+
+fn dead_letter_row(row: &Row) -> DeadLetterRow {
+    DeadLetterRow {
+        id: row.get(0),
+        job_id: row.get(1),
+        mail: row.get(2),
+        attempts: row.get::<_, i64>(3) as u32,
+        last_error: row.get(4),
+        created_at: row.get(5),
+        failed_at: row.get(6),
+        status: row.get(7),
+    }
+}
+
+impl Postgres {
+    pub fn new(url: String) -> Self {
+        Postgres { url, client: Mutex::new(None) }
+    }
+
+    fn connect(&self) -> Result<Client, String> {
+        let connector = native_tls::TlsConnector::new()
+            .map_err(|e| format!("Postgres: {}", e))?;
+        let mut client = Client::connect(&self.url, MakeTlsConnector::new(connector))
+            .map_err(error)?;
+        client.batch_execute(SCHEMA)
+            .map_err(error)?;
+        Ok(client)
+    }
+
+    // one connection for the plugin, like the SQLite one
+    fn with<T>(
+        &self,
+        f: impl FnOnce(&mut Client) -> Result<T, postgres::Error>,
+    ) -> Result<T, String> {
+
+        let mut client = self.client.lock()
+            .unwrap_or_else(|e| e.into_inner());
+        if client.as_ref().is_none_or(Client::is_closed) {
+            *client = Some(self.connect()?);
+        }
+        let Some(conn) = client.as_mut() else {
+            return Err("Postgres: not connected".to_string());
+        };
+
+        let result = f(conn);
+        if conn.is_closed() {
+            *client = None;
+        }
+        result.map_err(error)
+    }
+}
+
+impl Store for Postgres {
+    fn insert_job(&self, job: &NewJob) -> Result<i64, String> {
+        self.with(|client| client.query_one(
+            "INSERT INTO queue (mail, next_attempt, deferrals, created_at, lane) VALUES ($1, $2, $3, $4, $5) RETURNING id",
+            &[&job.mail, &job.next_attempt, &(job.deferrals as i64), &job.created_at, &job.lane],
+        ))
+            .map(|row| row.get(0))
+    }
+
+    fn due_jobs(&self, lane: &str, now: i64) -> Result<Vec<Job>, String> {
+        let rows = self.with(|client| client.query(
+            &format!(
+                "SELECT id, attempts, deferrals FROM queue WHERE lane = $1 AND next_attempt <= $2 AND {} ORDER BY next_attempt, id",
+                UNLOCKED,
+            ),
+            &[&lane, &now],
+        ))?;
+        Ok(rows.iter()
+            .map(|row| (row.get(0), row.get::<_, i64>(1) as u32, row.get::<_, i64>(2) as u32))
+            .collect())
+    }
+
+    fn has_due(&self, lane: &str, now: i64) -> Result<bool, String> {
+        self.with(|client| client.query_one(
+            &format!("SELECT EXISTS (SELECT 1 FROM queue WHERE lane = $1 AND next_attempt <= $2 AND {})", UNLOCKED),
+            &[&lane, &now],
+        ))
+            .map(|row| row.get(0))
+    }
+
+    fn job_mail(&self, id: i64) -> Result<Option<String>, String> {
+        self.with(|client| client.query_opt("SELECT mail FROM queue WHERE id = $1", &[&id]))
+            .map(|row| row.map(|row| row.get(0)))
+    }
+
+    // a single update of the row, only one instance sees it unlocked
+    fn lock_job(&self, id: i64, owner: &str, now: i64, until: i64) -> Result<bool, String> {
+        self.with(|client| client.execute(
+            &format!(
+                "UPDATE queue SET locked_by = $3, locked_until = $4 WHERE id = $1 AND next_attempt <= $2 AND {}",
+                UNLOCKED,
+            ),
+            &[&id, &now, &owner, &until],
+        ))
+            .map(|locked| locked > 0)
+    }
+
+    fn update_job(&self, id: i64, owner: &str, update: &JobUpdate) -> Result<bool, String> {
+        self.with(|client| client.execute(
+            "UPDATE queue SET attempts = COALESCE($3, attempts), deferrals = COALESCE($4, deferrals),
+            last_error = COALESCE($5, last_error), next_attempt = COALESCE($6, next_attempt),
+            locked_by = CASE WHEN $6::BIGINT IS NULL THEN locked_by END,
+            locked_until = CASE WHEN $6::BIGINT IS NULL THEN locked_until END
+            WHERE id = $1 AND locked_by = $2",
+            &[
+                &id,
+                &owner,
+                &update.attempts.map(i64::from),
+                &update.deferrals.map(i64::from),
+                &update.last_error,
+                &update.next_attempt,
+            ],
+        ))
+            .map(|updated| updated > 0)
+    }
+
+    fn delete_job(&self, id: i64) -> Result<(), String> {
+        self.with(|client| client.execute("DELETE FROM queue WHERE id = $1", &[&id]))
+            .map(|_| ())
+    }
+
+    fn release_jobs(&self, owner: &str) -> Result<usize, String> {
+        self.with(|client| client.execute(
+            "UPDATE queue SET locked_by = NULL, locked_until = NULL WHERE locked_by = $1",
+            &[&owner],
+        ))
+            .map(|released| released as usize)
+    }
+
+    fn cancel_batch(&self, batch: i64) -> Result<Vec<String>, String> {
+        let rows = self.with(|client| client.query(
+            "DELETE FROM queue WHERE (mail::jsonb->>'batch')::BIGINT = $1 RETURNING COALESCE(mail::jsonb->>'to', '')",
+            &[&batch],
+        ))?;
+        Ok(rows.iter().map(|row| row.get(0)).collect())
+    }
+
+    fn queue_counts(&self, now: i64) -> Result<QueueCounts, String> {
+        let row = self.with(|client| client.query_one(
+            "SELECT COUNT(*), COUNT(CASE WHEN next_attempt <= $1 THEN 1 END),
+            COUNT(CASE WHEN locked_until > $1 THEN 1 END), MIN(created_at),
+            (SELECT COUNT(*) FROM deadletter) FROM queue",
+            &[&now],
+        ))?;
+        Ok(QueueCounts {
+            depth: row.get(0),
+            due: row.get(1),
+            locked: row.get(2),
+            oldest: row.get(3),
+            dead_letters: row.get(4),
+        })
+    }
+
+    fn dead_letter(&self, id: i64, owner: &str, status: &str, reason: &str, now: i64) -> Result<(), String> {
+        self.with(|client| {
+            let mut transaction = client.transaction()?;
+            transaction.execute(
+                "INSERT INTO deadletter (job_id, mail, attempts, status, last_error, created_at, failed_at)
+                SELECT id, mail, attempts, $3, $4, created_at, $5 FROM queue WHERE id = $1 AND locked_by = $2",
+                &[&id, &owner, &status, &reason, &now],
+            )?;
+            transaction.execute("DELETE FROM queue WHERE id = $1 AND locked_by = $2", &[&id, &owner])?;
+            transaction.commit()
+        })
+    }
+
+    fn dead_letters(&self, tenant: &str, limit: i64, offset: i64) -> Result<(Vec<DeadLetterRow>, usize), String> {
+        self.with(|client| {
+            let total: i64 = client.query_one(&format!("SELECT COUNT(*) FROM deadletter WHERE {}", TENANT), &[&tenant])?
+                .get(0);
+            let rows = client.query(
+                &format!(
+                    "SELECT id, job_id, mail, attempts, last_error, created_at, failed_at, status
+                    FROM deadletter WHERE {} ORDER BY id DESC LIMIT $2 OFFSET $3",
+                    TENANT,
+                ),
+                &[&tenant, &limit, &offset],
+            )?;
+            Ok((rows.iter().map(dead_letter_row).collect(), total as usize))
+        })
+    }
+
+    fn dead_letter_by_id(&self, tenant: &str, id: i64) -> Result<Option<DeadLetterRow>, String> {
+        self.with(|client| client.query_opt(
+            &format!(
+                "SELECT id, job_id, mail, attempts, last_error, created_at, failed_at, status
+                FROM deadletter WHERE {} AND id = $2",
+                TENANT,
+            ),
+            &[&tenant, &id],
+        ))
+            .map(|row| row.as_ref().map(dead_letter_row))
+    }
+
+    fn requeue_dead_letter(&self, id: i64, now: i64) -> Result<bool, String> {
+        self.with(|client| {
+            let mut transaction = client.transaction()?;
+            let requeued = transaction.execute(
+                "INSERT INTO queue (mail, next_attempt, created_at, lane)
+                SELECT mail, $2, created_at, COALESCE(mail::jsonb->>'priority', 'transactional')
+                FROM deadletter WHERE id = $1",
+                &[&id, &now],
+            )?;
+            let deleted = transaction.execute("DELETE FROM deadletter WHERE id = $1", &[&id])?;
+            transaction.commit()?;
+            Ok(requeued > 0 && deleted > 0)
+        })
+    }
+
+    fn delete_dead_letter(&self, id: i64) -> Result<bool, String> {
+        self.with(|client| client.execute("DELETE FROM deadletter WHERE id = $1", &[&id]))
+            .map(|deleted| deleted > 0)
+    }
+
+    fn insert_history(&self, row: &HistoryRow) -> Result<i64, String> {
+        self.with(|client| client.query_one(
+            "INSERT INTO history (message_id, created_at, sender, recipients, subject, status, response, eml, request_id, tenant)
+            VALUES ($1, $2, $3, $4, $5, $6, $7, $8, $9, $10) RETURNING id",
+            &[
+                &row.message_id,
+                &row.created_at,
+                &row.sender,
+                &row.recipients,
+                &row.subject,
+                &row.status,
+                &row.response,
+                &row.eml,
+                &row.request_id,
+                &row.tenant,
+            ],
+        ))
+            .map(|row| row.get(0))
+    }
+
+    fn history(&self, tenant: &str, limit: i64, offset: i64) -> Result<(Vec<Entry>, usize), String> {
+        self.with(|client| {
+            let total: i64 = client.query_one("SELECT COUNT(*) FROM history WHERE $1 = '' OR tenant = $1", &[&tenant])?
+                .get(0);
+            let rows = client.query(
+                "SELECT id, message_id, created_at, sender, recipients, subject, status, response, octet_length(eml)::BIGINT, request_id
+                FROM history WHERE $1 = '' OR tenant = $1 ORDER BY id DESC LIMIT $2 OFFSET $3",
+                &[&tenant, &limit, &offset],
+            )?;
+            let entries = rows.iter()
+                .map(|row| Entry {
+                    id: row.get(0),
+                    message_id: row.get(1),
+                    created_at: row.get(2),
+                    from: row.get(3),
+                    to: row.get(4),
+                    subject: row.get(5),
+                    status: row.get(6),
+                    response: row.get(7),
+                    eml_size: row.get(8),
+                    request_id: row.get(9),
+                })
+                .collect();
+            Ok((entries, total as usize))
+        })
+    }
+
+    fn history_eml(&self, tenant: &str, id: i64) -> Result<Option<Vec<u8>>, String> {
+        self.with(|client| client.query_opt(
+            "SELECT eml FROM history WHERE id = $1 AND ($2 = '' OR tenant = $2)",
+            &[&id, &tenant],
+        ))
+            .map(|row| row.and_then(|row| row.get(0)))
+    }
+
+    fn suppression(&self, tenant: &str, address: &str) -> Result<Option<Option<String>>, String> {
+        self.with(|client| client.query_opt(
+            "SELECT reason FROM suppressions WHERE tenant IN ('', $1) AND address = $2 LIMIT 1",
+            &[&tenant, &address],
+        ))
+            .map(|row| row.map(|row| row.get(0)))
+    }
+
+    fn suppressions(&self, tenant: &str, limit: i64, offset: i64) -> Result<(Vec<Suppression>, usize), String> {
+        self.with(|client| {
+            let total: i64 = client.query_one("SELECT COUNT(*) FROM suppressions WHERE $1 = '' OR tenant = $1", &[&tenant])?
+                .get(0);
+            let rows = client.query(
+                "SELECT address, reason, created_at, tenant FROM suppressions
+                WHERE $1 = '' OR tenant = $1 ORDER BY created_at DESC, address LIMIT $2 OFFSET $3",
+                &[&tenant, &limit, &offset],
+            )?;
+            let entries = rows.iter()
+                .map(|row| Suppression {
+                    address: row.get(0),
+                    reason: row.get(1),
+                    created_at: row.get(2),
+                    tenant: Some(row.get::<_, String>(3)).filter(|tenant| !tenant.is_empty()),
+                })
+                .collect();
+            Ok((entries, total as usize))
+        })
+    }
+
+    fn suppress(&self, tenant: &str, address: &str, reason: Option<&str>, now: i64) -> Result<(), String> {
+        self.with(|client| client.execute(
+            "INSERT INTO suppressions (tenant, address, reason, created_at) VALUES ($1, $2, $3, $4)
+            ON CONFLICT (tenant, address) DO UPDATE SET reason = excluded.reason",
+            &[&tenant, &address, &reason, &now],
+        ))
+            .map(|_| ())
+    }
+
+    fn unsuppress(&self, tenant: &str, address: &str) -> Result<bool, String> {
+        self.with(|client| client.execute(
+            "DELETE FROM suppressions WHERE tenant = $1 AND address = $2",
+            &[&tenant, &address],
+        ))
+            .map(|removed| removed > 0)
+    }
+
+    fn sent_hash(&self, hash: &str, after: i64) -> Result<Option<Duplicate>, String> {
+        self.with(|client| client.query_opt(
+            "SELECT message_id, sent_at FROM sent_hashes
+            WHERE hash = $1 AND sent_at > $2 ORDER BY sent_at DESC LIMIT 1",
+            &[&hash, &after],
+        ))
+            .map(|row| row.map(|row| Duplicate {
+                message_id: row.get(0),
+                sent_at: row.get(1),
+            }))
+    }
+
+    fn record_hash(&self, hash: &str, message_id: Option<&str>, now: i64, before: i64) -> Result<(), String> {
+        self.with(|client| {
+            client.execute(
+                "INSERT INTO sent_hashes (hash, message_id, sent_at) VALUES ($1, $2, $3)",
+                &[&hash, &message_id, &now],
+            )?;
+            client.execute("DELETE FROM sent_hashes WHERE sent_at <= $1", &[&before])?;
+            Ok(())
+        })
+    }
+
+    fn purge(&self, purge: &Purge) -> Result<Purged, String> {
+        self.with(|client| {
+            let mut purged = Purged::default();
+            if let Some(before) = purge.history {
+                purged.history = client.execute("DELETE FROM history WHERE created_at < $1", &[&before])? as usize;
+            }
+            if let Some(before) = purge.eml {
+                purged.eml = client.execute(
+                    "UPDATE history SET eml = NULL WHERE eml IS NOT NULL AND created_at < $1",
+                    &[&before],
+                )? as usize;
+            }
+            if let Some(before) = purge.deadletter {
+                purged.deadletter = client.execute("DELETE FROM deadletter WHERE failed_at < $1", &[&before])? as usize;
+            }
+            Ok(purged)
+        })
+    }
+}
@@ -0,0 +1,139 @@
+//
+// Privacy mode: redaction of addresses in logs and history, retention of stored data
+//
+
+use rusqlite::params;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+
+use crate::SMTP_CLIENT;
+use crate::db;
+
+const DAY: i64 = 86400;
+
+fn default_addresses() -> String {
+    "mask".to_string()
+}
+
+#[derive(Clone, Deserialize, Serialize)]
+pub struct PrivacySettings {
+    // "mask" (j***@example.com) or "hash" (sha256:...) the recipient addresses
+    // written to the logs and the history
+    #[serde(default = "default_addresses")]
+    pub addresses: String,
+    // mixed into the hashes so they can't be matched against a list of addresses
+    #[serde(default)]
+    salt: String,
+    // subjects are not recorded in the history unless set
+    #[serde(default)]
+    store_subjects: bool,
+    #[serde(default)]
+    retention: Retention,
+}
+
+// days the stored data is kept, forever if not set
+#[derive(Clone, Default, Deserialize, Serialize)]
+pub struct Retention {
+    // history entries
+    history_days: Option<u64>,
+    // raw MIME of the history entries
+    eml_days: Option<u64>,
+    // dead-letter entries with their message
+    deadletter_days: Option<u64>,
+}
+
+pub fn enabled() -> bool {
+    SMTP_CLIENT.privacy.is_some()
+}
+
+pub fn store_subjects() -> bool {
+    SMTP_CLIENT.privacy.as_ref()
+        .is_none_or(|settings| settings.store_subjects)
+}
+
+fn redact_address(
+    settings: &PrivacySettings,
+    address: &str,
+) -> String {
+
+    let (local, domain) = address.rsplit_once('@')
+        .unwrap_or((address, ""));
+
+    match settings.addresses.as_str() {
+        "hash" => {
+            let digest = Sha256::digest(format!("{}{}", settings.salt, address.to_lowercase()));
+            format!("sha256:{}", &format!("{:x}", digest)[..16])
+        },
+        _ => format!("{}***@{}", local.chars().next().unwrap_or('*'), domain),
+    }
+}
+
+fn is_address_char(c: char) -> bool {
+    c.is_alphanumeric() || "._%+-".contains(c)
+}
+
+// replace every address found in a text, used for the logs and the SMTP replies
+pub fn redact(text: &str) -> String {
+
+    let settings = match &SMTP_CLIENT.privacy {
+        Some(settings) => settings,
+        None => return text.to_string(),
+    };
+
+    let chars: Vec<char> = text.chars().collect();
+    let mut redacted = String::with_capacity(text.len());
+    let mut copied = 0;
+    for at in 0..chars.len() {
+        if chars[at] != '@' || at < copied {
+            continue;
+        }
+        let start = (copied..at).rev()
+            .take_while(|&i| is_address_char(chars[i]))
+            .last();
+        let end = (at + 1..chars.len())
+            .take_while(|&i| is_address_char(chars[i]))
+            .last();
+        if let (Some(start), Some(mut end)) = (start, end) {
+            // the full stop ending a sentence isn't part of the domain
+            while end > at + 1 && chars[end] == '.' {
+                end -= 1;
+            }
+            let address: String = chars[start..=end].iter().collect();
+            redacted.extend(&chars[copied..start]);
+            redacted.push_str(&redact_address(settings, &address));
+            copied = end + 1;
+        }
+    }
+    redacted.extend(&chars[copied..]);
+
+    redacted
+}
+
+// apply the retention periods, called by the scheduler and by /history/purge
+pub fn purge() -> Result<String, String> {
+
+    let retention = match &SMTP_CLIENT.privacy {
+        Some(settings) => &settings.retention,
+        None => return Err("Privacy mode is disabled: privacy is not set".to_string()),
+    };
+
+    let before = |days: u64| db::now() - days as i64 * DAY;
+
+    let purged = crate::storage::store()?.purge(&crate::storage::Purge {
+        history: retention.history_days.map(before),
+        eml: retention.eml_days.map(before),
+        deadletter: retention.deadletter_days.map(before),
+    })?;
+    let mut results = 0;
+    if let Some(days) = retention.history_days {
+        // the outcomes of the bulk jobs are kept as long as the history, in
+        // the database of the instance
+        results = db::conn()?.execute("DELETE FROM job_results WHERE created_at < ?1", params![before(days)])
+            .map_err(|e| e.to_string())?;
+    }
+
+    Ok(format!(
+        "Purged {} history entries, {} raw messages, {} dead-letter entries and {} bulk job results",
+        purged.history, purged.eml, purged.deadletter, results,
+    ))
+}
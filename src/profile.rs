@@ -0,0 +1,49 @@
+//
+// The profile of the relay: "gmail", the default, or "dev" for a local
+// development relay like MailHog, Mailpit or smtp4dev: plain SMTP without TLS
+// on its own port, AUTH only with a username, and none of the rules of Gmail,
+// the quota isn't tracked and the From isn't enforced or rewritten
+//
+
+use lettre::transport::smtp::SUBMISSIONS_PORT;
+use serde::{Deserialize, Serialize};
+
+use crate::SMTP_CLIENT;
+
+pub const GMAIL: &str = "gmail";
+pub const DEV: &str = "dev";
+
+fn default_port() -> u16 {
+    1025
+}
+
+#[derive(Clone, Deserialize, Serialize)]
+pub struct DevSettings {
+    // 1025 for MailHog and Mailpit, 25 for smtp4dev
+    #[serde(default = "default_port")]
+    pub port: u16,
+}
+
+impl Default for DevSettings {
+    fn default() -> Self {
+        DevSettings {
+            port: default_port(),
+        }
+    }
+}
+
+pub fn dev() -> bool {
+    SMTP_CLIENT.profile.as_deref() == Some(DEV)
+}
+
+pub fn port() -> u16 {
+    match dev() {
+        true => SMTP_CLIENT.dev.as_ref().map_or(default_port(), |dev| dev.port),
+        false => SUBMISSIONS_PORT,
+    }
+}
+
+// a dev relay takes any credentials, or none when the username is empty
+pub fn authenticates(username: &str) -> bool {
+    !dev() || !username.is_empty()
+}
@@ -0,0 +1,518 @@
+//
+// Send queue with retries and a dead-letter store for exhausted jobs
+//
+
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Condvar, Mutex, Once};
+use std::time::Duration;
+use once_cell::sync::Lazy;
+use serde::{Deserialize, Serialize};
+
+use crate::{Mail, Response, SendError, SMTP_CLIENT};
+use crate::{db, shutdown};
+use crate::page::Page;
+use crate::outcome::{self, Outcome};
+use crate::storage::{self, DeadLetterRow, Job, JobUpdate, NewJob};
+
+pub const SCHEMA: &str = "CREATE TABLE IF NOT EXISTS queue (
+    id INTEGER PRIMARY KEY AUTOINCREMENT,
+    mail TEXT NOT NULL,
+    attempts INTEGER NOT NULL DEFAULT 0,
+    next_attempt INTEGER NOT NULL,
+    last_error TEXT,
+    created_at INTEGER NOT NULL
+);
+CREATE TABLE IF NOT EXISTS deadletter (
+    id INTEGER PRIMARY KEY AUTOINCREMENT,
+    job_id INTEGER NOT NULL,
+    mail TEXT NOT NULL,
+    attempts INTEGER NOT NULL,
+    last_error TEXT NOT NULL,
+    created_at INTEGER NOT NULL,
+    failed_at INTEGER NOT NULL
+);";
+
+const POLL: Duration = Duration::from_secs(1);
+
+// the lanes of the queue, in the order they are sent
+pub const TRANSACTIONAL: &str = "transactional";
+pub const BULK: &str = "bulk";
+const LANES: [&str; 2] = [TRANSACTIONAL, BULK];
+
+fn default_max_attempts() -> u32 {
+    5
+}
+
+fn default_backoff_secs() -> u64 {
+    60
+}
+
+fn default_greylist_secs() -> u64 {
+    300
+}
+
+fn default_greylist_max() -> u32 {
+    3
+}
+
+#[derive(Clone, Deserialize, Serialize)]
+pub struct QueueSettings {
+    // attempts before a job is moved to the dead-letter store
+    #[serde(default = "default_max_attempts")]
+    max_attempts: u32,
+    // delay before the first retry, doubled on every attempt
+    #[serde(default = "default_backoff_secs")]
+    backoff_secs: u64,
+    // delay before retrying a message deferred by a greylisting relay
+    #[serde(default = "default_greylist_secs")]
+    pub greylist_secs: u64,
+    // greylisting deferrals that don't count as attempts
+    #[serde(default = "default_greylist_max")]
+    greylist_max: u32,
+}
+
+impl Default for QueueSettings {
+    fn default() -> Self {
+        QueueSettings {
+            max_attempts: default_max_attempts(),
+            backoff_secs: default_backoff_secs(),
+            greylist_secs: default_greylist_secs(),
+            greylist_max: default_greylist_max(),
+        }
+    }
+}
+
+#[derive(Serialize)]
+pub struct DeadLetter {
+    id: i64,
+    job_id: i64,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    mail: Option<Mail>,
+    to: String,
+    subject: String,
+    attempts: u32,
+    // failed, or expired before it could be sent
+    status: String,
+    last_error: String,
+    created_at: i64,
+    failed_at: i64,
+}
+
+// the depth of the queue reported by /health
+#[derive(Serialize)]
+pub struct Depth {
+    depth: usize,
+    // the mails due now, the others are scheduled or waiting for a retry
+    due: usize,
+    // the ones an instance is sending
+    locked: usize,
+    dead_letters: usize,
+    // the age of the oldest mail of the queue
+    oldest_secs: Option<i64>,
+}
+
+#[derive(Deserialize)]
+pub struct DeadLetterRequest {
+    // requeue or delete
+    pub action: String,
+    pub id: i64,
+}
+
+static STARTED: Once = Once::new();
+static PAUSED: Lazy<AtomicBool> = Lazy::new(|| AtomicBool::new(db::state("queue_paused").as_deref() == Some("1")));
+static WAKE: (Mutex<bool>, Condvar) = (Mutex::new(false), Condvar::new());
+
+pub fn settings() -> QueueSettings {
+    SMTP_CLIENT.queue.clone().unwrap_or_default()
+}
+
+fn db_error(e: impl ToString) -> SendError {
+    SendError::new("internal_error", e.to_string())
+}
+
+fn wake() {
+    let (lock, condvar) = &WAKE;
+    if let Ok(mut pending) = lock.lock() {
+        *pending = true;
+        condvar.notify_one();
+    }
+}
+
+fn insert(
+    mail: &Mail,
+    next_attempt: i64,
+    deferrals: u32,
+) -> Result<i64, SendError> {
+
+    if shutdown::stopping() {
+        return Err(SendError::new("shutting_down", "The plugin is shutting down"));
+    }
+
+    let id = storage::store()
+        .and_then(|store| store.insert_job(&NewJob {
+            mail: &serde_json::to_string(mail).map_err(|e| e.to_string())?,
+            lane: mail.priority.as_deref().unwrap_or(TRANSACTIONAL),
+            next_attempt,
+            deferrals,
+            created_at: db::now(),
+        }))
+        .map_err(db_error)?;
+
+    start();
+    wake();
+
+    Ok(id)
+}
+
+// the lane of the request, the bulk one for the keys limited to it
+pub fn lane(mail: &mut Mail) -> Result<(), SendError> {
+
+    match mail.priority.as_deref() {
+        None | Some(TRANSACTIONAL | BULK) => {},
+        Some(priority) => return Err(SendError::new(
+            "invalid_request",
+            format!("Invalid priority {:?}, expected \"transactional\" or \"bulk\"", priority),
+        )),
+    }
+
+    if crate::auth::bulk_only() {
+        if mail.priority.as_deref() == Some(TRANSACTIONAL) {
+            return Err(SendError::new("forbidden", "The api key can only send in the bulk lane"));
+        }
+        mail.priority = Some(BULK.to_string());
+    }
+
+    Ok(())
+}
+
+pub fn enqueue(mail: &Mail) -> Result<i64, SendError> {
+    insert(mail, db::now(), 0)
+}
+
+// park a message the relay greylisted, it is retried after the greylist window
+pub fn defer(mail: &Mail) -> Result<i64, SendError> {
+    insert(mail, retry_at(mail, db::now() + settings().greylist_secs as i64), 1)
+}
+
+// permanent failures go straight to the dead-letter store, a sent message
+// returns its Message-ID and the reply of the server
+fn attempt(mail: &Mail) -> Result<(Option<String>, String), (String, Outcome)> {
+
+    let email = crate::build_message(mail)
+        .map_err(|e| (e.message, Outcome { code: e.code, retryable: false }))?;
+
+    let mut response = Response::new();
+    crate::deliver(mail, &email, &mut response)
+        .map(|_| (email.headers().get_raw("Message-ID").map(str::to_string), response.message.clone()))
+        .map_err(|e| (response.message, outcome::classify(&e)))
+}
+
+fn dead_letter(
+    id: i64,
+    status: &str,
+    reason: &str,
+) -> Result<(), String> {
+
+    storage::store()?.dead_letter(id, storage::instance(), status, reason, db::now())
+}
+
+// a retry after the expiration happens at it, the job expires then
+fn retry_at(
+    mail: &Mail,
+    next_attempt: i64,
+) -> i64 {
+    mail.expires_at.map_or(next_attempt, |expires_at| next_attempt.min(expires_at))
+}
+
+// the writes of an instance whose lease ended are dropped, the job is the one
+// of the instance that took it over
+fn update(
+    id: i64,
+    update: &JobUpdate,
+) -> Result<(), String> {
+    if !storage::store()?.update_job(id, storage::instance(), update)? {
+        log!("Job {} was taken over by another instance after its lease ended", id);
+    }
+    Ok(())
+}
+
+fn process(
+    id: i64,
+    attempts: u32,
+    deferrals: u32,
+) -> Result<(), String> {
+
+    let settings = settings();
+    let store = storage::store()?;
+
+    // removed by a cancel after it was listed
+    let Some(mail) = store.job_mail(id)? else {
+        return Ok(());
+    };
+    let mail: Mail = match serde_json::from_str(&mail) {
+        Ok(mail) => mail,
+        Err(e) => return dead_letter(id, "failed", &format!("Invalid job: {}", e)),
+    };
+    let _trace = crate::trace::enter(mail.request_id.clone());
+    let _tenant = crate::tenant::enter(mail.tenant.clone());
+    let mut span = crate::telemetry::span("queue.job");
+    span.attribute("job", id);
+
+    // cancelled after the worker picked it up, or requeued from a cancelled job
+    if crate::jobs::cancelled(mail.batch) {
+        return store.delete_job(id);
+    }
+    // a message that is late is worse than none, a one-time password
+    if let Some(expires_at) = mail.expires_at.filter(|&expires_at| expires_at <= db::now()) {
+        let error = format!("Expired at {} before it could be sent", crate::quota::format_time(expires_at));
+        log!("Job {} moved to the dead-letter store: {}", id, error);
+        crate::jobs::failed(mail.batch, 1);
+        crate::jobs::record(mail.batch, &mail.to, "failed", Some("expired"), &error, None);
+        return dead_letter(id, "expired", &error);
+    }
+    crate::jobs::started(mail.batch);
+
+    match attempt(&mail) {
+        Ok((message_id, reply)) => {
+            store.delete_job(id)?;
+            crate::jobs::sent(mail.batch);
+            crate::jobs::record(mail.batch, &mail.to, "sent", None, &reply, message_id.as_deref());
+        },
+        // the relay accepts the message once the window has passed
+        Err((error, outcome)) if outcome.code == "greylisted" && deferrals < settings.greylist_max => {
+            update(id, &JobUpdate {
+                deferrals: Some(deferrals + 1),
+                last_error: Some(&error),
+                next_attempt: Some(retry_at(&mail, db::now() + settings.greylist_secs as i64)),
+                ..Default::default()
+            })?;
+        },
+        // not an attempt, the job waits for the account to have room again
+        Err((error, outcome)) if outcome.code == "quota_exceeded" => {
+            update(id, &JobUpdate {
+                last_error: Some(&error),
+                next_attempt: Some(retry_at(&mail, db::now() + settings.backoff_secs as i64)),
+                ..Default::default()
+            })?;
+        },
+        Err((error, outcome)) => {
+            let attempts = attempts + 1;
+            update(id, &JobUpdate {
+                attempts: Some(attempts),
+                last_error: Some(&error),
+                ..Default::default()
+            })?;
+
+            if !outcome.retryable || attempts >= settings.max_attempts {
+                log!("Job {} moved to the dead-letter store after {} attempts: {}", id, attempts, error);
+                crate::jobs::failed(mail.batch, 1);
+                crate::jobs::record(mail.batch, &mail.to, "failed", Some(outcome.code), &error, None);
+                return dead_letter(id, "failed", &error);
+            }
+
+            let delay = settings.backoff_secs.saturating_mul(1 << (attempts - 1).min(16)) as i64;
+            update(id, &JobUpdate {
+                next_attempt: Some(retry_at(&mail, db::now() + delay)),
+                ..Default::default()
+            })?;
+        },
+    };
+
+    Ok(())
+}
+
+// the kill switch survives a restart, the jobs are kept until the resume
+pub fn paused() -> bool {
+    PAUSED.load(Ordering::SeqCst)
+}
+
+pub fn depth() -> Result<Depth, String> {
+
+    let now = db::now();
+    let counts = storage::store()?.queue_counts(now)?;
+
+    Ok(Depth {
+        depth: counts.depth as usize,
+        due: counts.due as usize,
+        locked: counts.locked as usize,
+        dead_letters: counts.dead_letters as usize,
+        oldest_secs: counts.oldest.map(|oldest| (now - oldest).max(0)),
+    })
+}
+
+pub fn set_paused(paused: bool) -> Result<(), SendError> {
+
+    db::set_state("queue_paused", if paused { "1" } else { "0" })
+        .map_err(db_error)?;
+    PAUSED.store(paused, Ordering::SeqCst);
+
+    if !paused {
+        start();
+        wake();
+    }
+
+    Ok(())
+}
+
+fn run() -> Result<(), String> {
+
+    if paused() {
+        return Ok(());
+    }
+    // the jobs wait for the history rows to be written in strict mode
+    if crate::persistence::check().is_err() {
+        return Ok(());
+    }
+
+    // the jobs wait for the end of the cool-down of every Gmail account
+    if crate::transport::cooling_down() {
+        return Ok(());
+    }
+
+    // a bulk batch gives way to the transactional mails that fall due while
+    // it is sent, they wait for one bulk send at most; a job that failed to
+    // process is left for the next run
+    let store = storage::store()?;
+    let lease = storage::settings().lease_secs as i64;
+    let mut failed = false;
+    'lanes: loop {
+        for lane in LANES {
+            for (id, attempts, deferrals) in due(lane)? {
+                // the remaining jobs stay persisted for the next start or the resume
+                if shutdown::stopping() || paused() {
+                    return Ok(());
+                }
+                if lane == BULK && !failed && waiting(TRANSACTIONAL)? {
+                    continue 'lanes;
+                }
+                // another instance sharing the store took it first
+                let now = db::now();
+                if !store.lock_job(id, storage::instance(), now, now + lease)? {
+                    continue;
+                }
+                if let Err(e) = process(id, attempts, deferrals) {
+                    log!("Error processing job {}: {}", id, e);
+                    failed |= lane == TRANSACTIONAL;
+                }
+            }
+        }
+        return Ok(());
+    }
+}
+
+fn due(lane: &str) -> Result<Vec<Job>, String> {
+    storage::store()?.due_jobs(lane, db::now())
+}
+
+fn waiting(lane: &str) -> Result<bool, String> {
+    storage::store()?.has_due(lane, db::now())
+}
+
+fn release() {
+    match storage::store().and_then(|store| store.release_jobs(storage::instance())) {
+        Ok(0) => {},
+        Ok(released) => log!("Released the locks of {} jobs of instance {}", released, storage::instance()),
+        Err(e) => log!("Error releasing the locks of the queue: {}", e),
+    }
+}
+
+// safe to call more than once, the worker is only started the first time
+pub fn start() {
+    STARTED.call_once(|| {
+        let result = std::thread::Builder::new()
+            .name("arp-gmail-queue".to_string())
+            .spawn(|| {
+                // the jobs this instance held when it crashed or restarted
+                release();
+                while !shutdown::stopping() {
+                    if let Err(e) = run() {
+                        log!("Error running the queue: {}", e);
+                    }
+                    let (lock, condvar) = &WAKE;
+                    if let Ok(pending) = lock.lock() {
+                        if let Ok(mut pending) = condvar.wait_timeout_while(pending, POLL, |pending| !*pending) {
+                            *pending.0 = false;
+                        }
+                    }
+                }
+                // the other instances don't wait for the end of the leases
+                release();
+            });
+        match result {
+            Ok(handle) => shutdown::register("queue", handle),
+            Err(e) => log!("Error starting the queue worker: {}", e),
+        }
+    });
+}
+
+// the password of an encrypted archive isn't shown
+pub fn redacted(mut mail: Mail) -> Mail {
+    if let Some(request) = mail.encrypt_attachments.as_mut() {
+        request.password = crate::archive::MASKED.to_string();
+    }
+    mail
+}
+
+fn dead_letter_entry(
+    row: DeadLetterRow,
+    with_mail: bool,
+) -> DeadLetter {
+    let mail: Option<Mail> = serde_json::from_str(&row.mail).ok();
+    DeadLetter {
+        id: row.id,
+        job_id: row.job_id,
+        to: mail.as_ref().map(|mail| mail.to.clone()).unwrap_or_default(),
+        subject: mail.as_ref().map(|mail| mail.subject.clone()).unwrap_or_default(),
+        mail: mail.filter(|_| with_mail).map(redacted),
+        attempts: row.attempts,
+        last_error: row.last_error,
+        created_at: row.created_at,
+        failed_at: row.failed_at,
+        status: row.status,
+    }
+}
+
+pub fn dead_letters(page: &Page) -> Result<(Vec<DeadLetter>, usize), String> {
+    let (limit, offset) = page.sql();
+    let (rows, total) = storage::store()?.dead_letters(&crate::tenant::key(), limit, offset)?;
+    Ok((rows.into_iter().map(|row| dead_letter_entry(row, false)).collect(), total))
+}
+
+pub fn dead_letter_by_id(id: i64) -> Result<Option<DeadLetter>, String> {
+    Ok(storage::store()?
+        .dead_letter_by_id(&crate::tenant::key(), id)?
+        .map(|row| dead_letter_entry(row, true)))
+}
+
+// requeue or delete a dead-letter entry
+pub fn handle_dead_letter(request: &DeadLetterRequest) -> Result<String, SendError> {
+
+    let store = storage::store().map_err(db_error)?;
+
+    // only an entry of the tenant, its bulk job counts it as pending again
+    let row = store.dead_letter_by_id(&crate::tenant::key(), request.id)
+        .map_err(db_error)?
+        .ok_or(SendError::new("not_found", format!("No dead-letter entry with id {}", request.id)))?;
+    let batch = serde_json::from_str::<Mail>(&row.mail)
+        .ok()
+        .and_then(|mail| mail.batch);
+
+    let changed = match request.action.as_str() {
+        // the job starts over with a fresh attempt count
+        "requeue" => store.requeue_dead_letter(request.id, db::now()),
+        "delete" => store.delete_dead_letter(request.id),
+        action => return Err(SendError::new("invalid_request", format!("Invalid action: {}", action))),
+    }.map_err(db_error)?;
+
+    if !changed {
+        return Err(SendError::new("not_found", format!("No dead-letter entry with id {}", request.id)));
+    }
+
+    if request.action == "requeue" {
+        crate::jobs::requeued(batch);
+        start();
+        wake();
+    }
+
+    Ok(format!("Dead-letter entry {}: {}", request.id, request.action))
+}
@@ -0,0 +1,229 @@
+//
+// Gmail sending limits: the account is rested for a cool-down after a limit error
+//
+
+use std::collections::HashMap;
+use std::sync::Mutex;
+use chrono::DateTime;
+use rusqlite::params;
+use serde::{Deserialize, Serialize};
+
+use crate::SMTP_CLIENT;
+use crate::db;
+
+pub const SCHEMA: &str = "CREATE TABLE IF NOT EXISTS sends (
+    id INTEGER PRIMARY KEY AUTOINCREMENT,
+    recipients INTEGER NOT NULL,
+    sent_at INTEGER NOT NULL
+);
+CREATE INDEX IF NOT EXISTS sends_sent_at ON sends (sent_at);";
+
+// Gmail counts its limits over a rolling 24 hours
+const WINDOW: i64 = 86400;
+
+fn default_cooldown_secs() -> u64 {
+    3600
+}
+
+#[derive(Clone, Deserialize, Serialize)]
+pub struct QuotaSettings {
+    // no message is sent for this long after a limit error
+    #[serde(default = "default_cooldown_secs")]
+    cooldown_secs: u64,
+    // recipients per rolling 24 hours, 500 for Gmail and 2000 for Workspace accounts
+    pub daily_limit: Option<u64>,
+}
+
+impl Default for QuotaSettings {
+    fn default() -> Self {
+        QuotaSettings {
+            cooldown_secs: default_cooldown_secs(),
+            daily_limit: None,
+        }
+    }
+}
+
+#[derive(Clone, Serialize)]
+pub struct Usage {
+    limit: Option<u64>,
+    // recipients sent to in the last 24 hours
+    used: u64,
+    remaining: Option<u64>,
+    // when the oldest send of the window stops counting
+    reset_at: Option<i64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    paused_until: Option<i64>,
+}
+
+// end of the cool-down of each Gmail account
+static PAUSED_UNTIL: Mutex<Option<HashMap<String, i64>>> = Mutex::new(None);
+
+// recipients of the sends of each account admitted and not yet ended, so two
+// concurrent sends can't both take the last of the limit
+static RESERVED: Mutex<Option<HashMap<String, u64>>> = Mutex::new(None);
+
+fn settings() -> QuotaSettings {
+    SMTP_CLIENT.quota.clone().unwrap_or_default()
+}
+
+pub fn format_time(time: i64) -> String {
+    DateTime::from_timestamp(time, 0)
+        .map(|time| time.format("%Y-%m-%d %H:%M:%S UTC").to_string())
+        .unwrap_or_default()
+}
+
+// the limit of the account, or the one of the config
+fn daily_limit(account: &str) -> Option<u64> {
+    crate::transport::daily_limit(account)
+        .or(settings().daily_limit)
+}
+
+pub fn usage(account: &str) -> Result<Usage, String> {
+
+    let since = db::now() - WINDOW;
+    let (used, oldest): (u64, Option<i64>) = db::conn()?
+        .query_row(
+            "SELECT COALESCE(SUM(recipients), 0), MIN(sent_at) FROM sends WHERE account = ?1 AND sent_at > ?2",
+            params![account, since],
+            |row| Ok((row.get(0)?, row.get(1)?)),
+        ).map_err(|e| e.to_string())?;
+
+    let limit = daily_limit(account);
+    Ok(Usage {
+        limit,
+        used,
+        remaining: limit.map(|limit| limit.saturating_sub(used)),
+        reset_at: oldest.map(|oldest| oldest + WINDOW),
+        paused_until: cooling_down(account),
+    })
+}
+
+// Err with the time the window has room again if the send would go over the
+// limit, with the recipients already reserved
+fn room(
+    account: &str,
+    recipients: u64,
+    reserved: u64,
+) -> Result<(), i64> {
+
+    if daily_limit(account).is_none() {
+        return Ok(());
+    }
+    match usage(account) {
+        Ok(Usage { remaining: Some(remaining), reset_at, .. }) if recipients > remaining.saturating_sub(reserved) => {
+            Err(reset_at.unwrap_or(db::now() + WINDOW))
+        },
+        Ok(_) => Ok(()),
+        // the accounting is only a budget, don't block sending on it
+        Err(e) => {
+            log!("Quota check skipped: {}", e);
+            Ok(())
+        },
+    }
+}
+
+// whether the account could take the send now, nothing is reserved
+pub fn has_room(
+    account: &str,
+    recipients: u64,
+) -> bool {
+    let reserved = RESERVED.lock()
+        .unwrap_or_else(|e| e.into_inner())
+        .as_ref()
+        .and_then(|reserved| reserved.get(account).copied())
+        .unwrap_or(0);
+    room(account, recipients, reserved).is_ok()
+}
+
+// Err with the time the window has room again if the send would go over the
+// limit; the recipients are reserved until release()
+pub fn admit(
+    account: &str,
+    recipients: u64,
+) -> Result<(), i64> {
+
+    // held until the send is reserved, the admissions of an account are one
+    // at a time
+    let mut reserved = RESERVED.lock()
+        .unwrap_or_else(|e| e.into_inner());
+    let reserved = reserved.get_or_insert_with(HashMap::new)
+        .entry(account.to_string())
+        .or_insert(0);
+
+    room(account, recipients, *reserved)?;
+    *reserved += recipients;
+
+    Ok(())
+}
+
+// the end of an admitted send, recorded or not
+pub fn release(
+    account: &str,
+    recipients: u64,
+) {
+    let mut reserved = RESERVED.lock()
+        .unwrap_or_else(|e| e.into_inner());
+    if let Some(count) = reserved.as_mut().and_then(|reserved| reserved.get_mut(account)) {
+        *count = count.saturating_sub(recipients);
+    }
+}
+
+pub fn record(
+    account: &str,
+    recipients: u64,
+) {
+
+    let result = db::conn().and_then(|conn| {
+        conn.execute(
+            "INSERT INTO sends (account, recipients, sent_at) VALUES (?1, ?2, ?3)",
+            params![account, recipients, db::now()],
+        ).map_err(|e| e.to_string())?;
+        conn.execute("DELETE FROM sends WHERE sent_at <= ?1", params![db::now() - WINDOW])
+            .map_err(|e| e.to_string())
+    });
+    if let Err(e) = result {
+        log!("Error recording the send in the quota: {}", e);
+    }
+}
+
+// end of the current cool-down
+pub fn cooling_down(account: &str) -> Option<i64> {
+    let paused = PAUSED_UNTIL.lock()
+        .unwrap_or_else(|e| e.into_inner());
+    paused.as_ref()
+        .and_then(|paused| paused.get(account).copied())
+        .filter(|until| *until > crate::db::now())
+}
+
+// called when Gmail answers with a daily limit or suspicious activity error,
+// retrying into a locked account only extends the lock
+pub fn exceeded(
+    account: &str,
+    reply: &str,
+) {
+
+    // the reply isn't the one of Gmail on a dev relay
+    if crate::profile::dev() {
+        return;
+    }
+    let until = crate::db::now() + settings().cooldown_secs as i64;
+    {
+        let mut paused = PAUSED_UNTIL.lock()
+            .unwrap_or_else(|e| e.into_inner());
+        let account_until = paused.get_or_insert_with(HashMap::new)
+            .entry(account.to_string())
+            .or_insert(0);
+        let already = *account_until > crate::db::now();
+        *account_until = (*account_until).max(until);
+        if already {
+            // already cooling down, the alert was sent
+            return;
+        }
+    }
+
+    let message = match account {
+        crate::transport::GMAIL => format!("Sending is paused until {}: {}", format_time(until), reply),
+        account => format!("Sending from the {} account is paused until {}: {}", account, format_time(until), reply),
+    };
+    crate::alert::send("quota_exceeded", &message);
+}
@@ -0,0 +1,129 @@
+//
+// Cap on the emails a single recipient can receive per hour and per day
+//
+
+use std::collections::HashMap;
+use std::sync::Mutex;
+use rusqlite::params;
+use serde::{Deserialize, Serialize};
+
+use crate::SMTP_CLIENT;
+use crate::db;
+
+pub const SCHEMA: &str = "CREATE TABLE IF NOT EXISTS recipient_sends (
+    recipient TEXT NOT NULL,
+    sent_at INTEGER NOT NULL
+);
+CREATE INDEX IF NOT EXISTS recipient_sends_recipient ON recipient_sends (recipient, sent_at);";
+
+const HOUR: i64 = 3600;
+const DAY: i64 = 86400;
+
+#[derive(Clone, Deserialize, Serialize)]
+pub struct RecipientLimits {
+    pub per_hour: Option<u32>,
+    pub per_day: Option<u32>,
+}
+
+// sends to each recipient admitted and not yet ended, by tenant and address,
+// counted with the recorded ones
+static RESERVED: Mutex<Option<HashMap<(String, String), u32>>> = Mutex::new(None);
+
+fn key(recipient: &lettre::Address) -> String {
+    recipient.to_string().to_lowercase()
+}
+
+// the limits of the tenant of the send, or the ones of the config; the sends
+// of each tenant are counted apart
+fn limits() -> Option<&'static RecipientLimits> {
+    crate::tenant::settings()
+        .and_then(|tenant| tenant.recipient_limits.as_ref())
+        .or(SMTP_CLIENT.recipient_limits.as_ref())
+}
+
+// Err with a description of the first recipient over its cap
+pub fn check(recipients: &[lettre::Address]) -> Result<(), String> {
+
+    let limits = match limits() {
+        Some(limits) => limits,
+        None => return Ok(()),
+    };
+
+    // held until the sends are reserved, the admissions are one at a time
+    let mut reserved = RESERVED.lock()
+        .unwrap_or_else(|e| e.into_inner());
+    let reserved = reserved.get_or_insert_with(HashMap::new);
+    let tenant = crate::tenant::key();
+
+    match db::conn() {
+        Ok(conn) => for recipient in recipients {
+            let pending = reserved.get(&(tenant.clone(), key(recipient))).copied().unwrap_or(0);
+            for (limit, window, name) in [(limits.per_hour, HOUR, "hour"), (limits.per_day, DAY, "day")] {
+                let limit = match limit {
+                    Some(limit) => limit,
+                    None => continue,
+                };
+                let count: u32 = conn.query_row(
+                    "SELECT COUNT(*) FROM recipient_sends WHERE tenant = ?1 AND recipient = ?2 AND sent_at > ?3",
+                    params![tenant, key(recipient), db::now() - window],
+                    |row| row.get(0),
+                ).unwrap_or(0);
+                if count + pending >= limit {
+                    return Err(format!("{} already received {} emails in the last {}", recipient, count + pending, name));
+                }
+            }
+        },
+        Err(e) => log!("Recipient rate limit skipped: {}", e),
+    }
+
+    for recipient in recipients {
+        *reserved.entry((tenant.clone(), key(recipient))).or_insert(0) += 1;
+    }
+
+    Ok(())
+}
+
+// the end of an admitted send, recorded or not
+pub fn release(recipients: &[lettre::Address]) {
+
+    if limits().is_none() {
+        return;
+    }
+
+    let mut reserved = RESERVED.lock()
+        .unwrap_or_else(|e| e.into_inner());
+    let Some(reserved) = reserved.as_mut() else {
+        return;
+    };
+    let tenant = crate::tenant::key();
+    for recipient in recipients {
+        let entry = (tenant.clone(), key(recipient));
+        if let Some(count) = reserved.get_mut(&entry) {
+            *count = count.saturating_sub(1);
+            if *count == 0 {
+                reserved.remove(&entry);
+            }
+        }
+    }
+}
+
+pub fn record(recipients: &[lettre::Address]) {
+
+    if limits().is_none() {
+        return;
+    }
+
+    let result = db::conn().and_then(|conn| {
+        for recipient in recipients {
+            conn.execute(
+                "INSERT INTO recipient_sends (tenant, recipient, sent_at) VALUES (?1, ?2, ?3)",
+                params![crate::tenant::key(), key(recipient), db::now()],
+            ).map_err(|e| e.to_string())?;
+        }
+        conn.execute("DELETE FROM recipient_sends WHERE sent_at <= ?1", params![db::now() - DAY])
+            .map_err(|e| e.to_string())
+    });
+    if let Err(e) = result {
+        log!("Error recording the recipient sends: {}", e);
+    }
+}
@@ -0,0 +1,406 @@
+//
+// Recurring emails sent by the scheduler following a cron expression
+//
+
+use std::str::FromStr;
+use chrono::{DateTime, Utc};
+use cron::Schedule;
+use rusqlite::params;
+use serde::{Deserialize, Serialize};
+
+use crate::{Mail, Response, SendError, SMTP_CLIENT};
+use crate::db;
+use crate::page::Page;
+
+pub const SCHEMA: &str = "CREATE TABLE IF NOT EXISTS recurring (
+    id INTEGER PRIMARY KEY AUTOINCREMENT,
+    name TEXT NOT NULL,
+    cron TEXT NOT NULL,
+    mail TEXT NOT NULL,
+    recipients TEXT NOT NULL,
+    paused INTEGER NOT NULL DEFAULT 0,
+    next_run INTEGER,
+    last_run INTEGER,
+    created_at INTEGER NOT NULL
+);";
+
+#[derive(Deserialize)]
+pub struct Request {
+    // create, pause, resume or delete
+    action: String,
+    id: Option<i64>,
+    name: Option<String>,
+    // "0 8 * * 1" (UTC, Unix numbering of the days), or with a leading
+    // seconds field in the syntax of the cron crate
+    cron: Option<String>,
+    // the message sent to every recipient, its "to" is ignored
+    mail: Option<Mail>,
+    recipients: Option<Vec<String>>,
+}
+
+#[derive(Serialize)]
+pub struct Entry {
+    id: i64,
+    name: String,
+    cron: String,
+    subject: String,
+    recipients: Vec<String>,
+    paused: bool,
+    next_run: Option<i64>,
+    last_run: Option<i64>,
+    created_at: i64,
+}
+
+// the mails of the tenant of the request, all of them for the instance
+const TENANT: &str = "(?1 = '' OR COALESCE(json_extract(mail, '$.tenant'), '') = ?1)";
+
+// a day of the week of Unix cron, 0 or 7 for Sunday, as the cron crate numbers
+// them from 1 for Sunday to 7 for Saturday
+fn weekday(day: &str) -> Result<u32, String> {
+    match day.parse::<u32>() {
+        Ok(day) if day <= 7 => Ok(day % 7 + 1),
+        Ok(day) => Err(format!("Invalid day of the week {}, expected 0 to 7", day)),
+        // the names are the same in both
+        Err(_) => Err(String::new()),
+    }
+}
+
+// the day of the week field of a Unix expression in the numbering of the
+// cron crate: "1-5" (Monday to Friday) is "2-6", "5-7" is "6-7,1"
+fn weekdays(field: &str) -> Result<String, String> {
+
+    let mut translated = Vec::new();
+    for part in field.split(',') {
+        let (range, step) = match part.split_once('/') {
+            Some((range, step)) => (range, Some(step)),
+            None => (part, None),
+        };
+        let step = step.map(|step| format!("/{}", step)).unwrap_or_default();
+        let numbered = match range.split_once('-') {
+            Some((first, last)) => weekday(first).and_then(|first| Ok((first, weekday(last)?))),
+            None => weekday(range).map(|day| (day, day)),
+        };
+        match numbered {
+            // a name or "*"
+            Err(e) if e.is_empty() => translated.push(part.to_string()),
+            Err(e) => return Err(e),
+            Ok((first, last)) if range.contains('-') => match (first, last) {
+                // 0-7, every day
+                (1, 1) if range.starts_with('0') && range.ends_with('7') => translated.push(format!("1-7{}", step)),
+                // Sunday at the end of a range is the first day here
+                (first, 1) if first > 1 && step.is_empty() => translated.push(format!("{}-7,1", first)),
+                (first, 1) if first > 1 => return Err(format!("Invalid range {:?}, end it on 6 to step over it", part)),
+                (first, last) => translated.push(format!("{}-{}{}", first, last, step)),
+            },
+            Ok((day, _)) => translated.push(format!("{}{}", day, step)),
+        }
+    }
+
+    Ok(translated.join(","))
+}
+
+// a Unix expression of 5 fields, or one of the cron crate with the seconds
+fn schedule(expression: &str) -> Result<Schedule, SendError> {
+
+    let invalid = |e: String| SendError::new("invalid_request", format!("Invalid cron expression {:?}: {}", expression, e));
+
+    let fields: Vec<&str> = expression.split_whitespace().collect();
+    let converted = match fields.as_slice() {
+        [minute, hour, day, month, weekday] => format!("0 {} {} {} {} {}", minute, hour, day, month, weekdays(weekday).map_err(invalid)?),
+        _ => expression.to_string(),
+    };
+    Schedule::from_str(&converted)
+        .map_err(|e| invalid(e.to_string()))
+}
+
+fn next_run(
+    schedule: &Schedule,
+    after: i64,
+) -> Option<i64> {
+    let after = DateTime::<Utc>::from_timestamp(after, 0)?;
+    schedule.after(&after)
+        .next()
+        .map(|time| time.timestamp())
+}
+
+fn db_error(e: impl ToString) -> SendError {
+    SendError::new("internal_error", e.to_string())
+}
+
+fn create(request: &Request) -> Result<i64, SendError> {
+
+    let name = request.name.clone().unwrap_or_default();
+    let expression = request.cron.as_deref()
+        .ok_or(SendError::new("invalid_request", "No cron expression"))?;
+    let schedule = schedule(expression)?;
+
+    let mut mail = request.mail.clone()
+        .ok_or(SendError::new("invalid_request", "No mail"))?;
+    crate::identity::apply(&mut mail);
+    mail.tenant = crate::tenant::current();
+    crate::transport::check(mail.account.as_deref())?;
+    let mail = &mail;
+    if mail.from.is_empty() || mail.subject.is_empty() {
+        return Err(SendError::new("invalid_request", "The mail must have a from address and a subject"));
+    }
+    crate::identity::headers(mail)?;
+    // fail now rather than on every run
+    let mut rendered = mail.clone();
+    rendered.message = crate::content::message_body(mail, &SMTP_CLIENT)?;
+    crate::templates::render_mail(&mut rendered)?;
+    if rendered.message.is_empty() {
+        return Err(SendError::new("invalid_request", "No message"));
+    }
+
+    let recipients = request.recipients.clone().unwrap_or_default();
+    if recipients.is_empty() {
+        return Err(SendError::new("invalid_request", "No recipients"));
+    }
+    for recipient in &recipients {
+        crate::parse_mailbox(recipient)?;
+    }
+
+    let conn = db::conn().map_err(db_error)?;
+    conn.execute(
+        "INSERT INTO recurring (name, cron, mail, recipients, next_run, created_at, api_key)
+        VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7)",
+        params![
+            name,
+            expression,
+            serde_json::to_string(mail).map_err(db_error)?,
+            serde_json::to_string(&recipients).map_err(db_error)?,
+            next_run(&schedule, db::now()),
+            db::now(),
+            crate::auth::current().map(|key| key.name.as_str()),
+        ],
+    ).map_err(db_error)?;
+
+    Ok(conn.last_insert_rowid())
+}
+
+fn update(
+    id: i64,
+    action: &str,
+) -> Result<(), SendError> {
+
+    let conn = db::conn().map_err(db_error)?;
+
+    // only a recurring email of the tenant
+    let expression: String = conn.query_row(
+        &format!("SELECT cron FROM recurring WHERE {} AND id = ?2", TENANT),
+        params![crate::tenant::key(), id],
+        |row| row.get(0),
+    ).map_err(|_| SendError::new("not_found", format!("No recurring email with id {}", id)))?;
+
+    let changed = match action {
+        "pause" => conn.execute("UPDATE recurring SET paused = 1 WHERE id = ?1", params![id]),
+        // don't send the runs missed while paused
+        "resume" => conn.execute(
+            "UPDATE recurring SET paused = 0, next_run = ?2 WHERE id = ?1",
+            params![id, next_run(&schedule(&expression)?, db::now())],
+        ),
+        "delete" => conn.execute("DELETE FROM recurring WHERE id = ?1", params![id]),
+        _ => return Err(SendError::new("invalid_request", format!("Invalid action: {}", action))),
+    }.map_err(db_error)?;
+
+    match changed {
+        0 => Err(SendError::new("not_found", format!("No recurring email with id {}", id))),
+        _ => Ok(()),
+    }
+}
+
+// create, pause, resume or delete a recurring email
+pub fn handle(request: &Request) -> Result<(i64, String), SendError> {
+    match request.action.as_str() {
+        "create" => create(request)
+            .map(|id| (id, "Recurring email created".to_string())),
+        action => {
+            let id = request.id
+                .ok_or(SendError::new("invalid_request", "No recurring email id"))?;
+            update(id, action)
+                .map(|_| (id, format!("Recurring email {}: {}", id, action)))
+        },
+    }
+}
+
+// the recurring emails of the tenant of the request, all of them for the instance
+pub fn list(page: &Page) -> Result<(Vec<Entry>, usize), String> {
+
+    let conn = db::conn()?;
+
+    let total: i64 = conn.query_row(
+        &format!("SELECT COUNT(*) FROM recurring WHERE {}", TENANT),
+        params![crate::tenant::key()],
+        |row| row.get(0),
+    ).map_err(|e| e.to_string())?;
+
+    let (limit, offset) = page.sql();
+    let mut stmt = conn.prepare(&format!(
+        "SELECT id, name, cron, mail, recipients, paused, next_run, last_run, created_at
+        FROM recurring WHERE {} ORDER BY id LIMIT ?2 OFFSET ?3",
+        TENANT,
+    )).map_err(|e| e.to_string())?;
+
+    let entries = stmt.query_map(params![crate::tenant::key(), limit, offset], |row| {
+        let mail: String = row.get(3)?;
+        let recipients: String = row.get(4)?;
+        Ok(Entry {
+            id: row.get(0)?,
+            name: row.get(1)?,
+            cron: row.get(2)?,
+            subject: serde_json::from_str::<Mail>(&mail)
+                .map(|mail| mail.subject)
+                .unwrap_or_default(),
+            recipients: serde_json::from_str(&recipients).unwrap_or_default(),
+            paused: row.get(5)?,
+            next_run: row.get(6)?,
+            last_run: row.get(7)?,
+            created_at: row.get(8)?,
+        })
+    }).map_err(|e| e.to_string())?;
+
+    let entries = entries.collect::<Result<Vec<Entry>, _>>()
+        .map_err(|e| e.to_string())?;
+
+    Ok((entries, total as usize))
+}
+
+// submitted like a request of its key: rendered with the template as it is at
+// the time of the run, checked by the policies, the hooks and the review rules
+fn send(
+    mail: &Mail,
+    recipient: &str,
+) -> Result<(), String> {
+
+    let mut mail = mail.clone();
+    mail.to = recipient.to_string();
+
+    let mut response = Response::new();
+    crate::submit(mail, None, &mut response);
+    match response.status.as_str() {
+        "success" | "queued" | "pending" => Ok(()),
+        _ => Err(response.message),
+    }
+}
+
+// called periodically by the scheduler
+pub fn run() {
+
+    let now = db::now();
+
+    let due = match db::conn().and_then(|conn| {
+        let mut stmt = conn.prepare(
+            "SELECT id, cron, mail, recipients, api_key FROM recurring
+            WHERE paused = 0 AND next_run IS NOT NULL AND next_run <= ?1"
+        ).map_err(|e| e.to_string())?;
+        let rows = stmt.query_map(params![now], |row| Ok((
+            row.get::<_, i64>(0)?,
+            row.get::<_, String>(1)?,
+            row.get::<_, String>(2)?,
+            row.get::<_, String>(3)?,
+            row.get::<_, Option<String>>(4)?,
+        ))).map_err(|e| e.to_string())?;
+        rows.collect::<Result<Vec<_>, _>>()
+            .map_err(|e| e.to_string())
+    }) {
+        Ok(due) => due,
+        Err(e) => {
+            log!("Error loading recurring emails: {}", e);
+            return;
+        },
+    };
+
+    for (id, expression, mail, recipients, api_key) in due {
+        let mail: Mail = match serde_json::from_str(&mail) {
+            Ok(mail) => mail,
+            Err(e) => {
+                log!("Error in recurring email {}: {}", id, e);
+                continue;
+            },
+        };
+        let recipients: Vec<String> = serde_json::from_str(&recipients).unwrap_or_default();
+        let _tenant = crate::tenant::enter(mail.tenant.clone());
+        let _key = crate::auth::enter(api_key.as_deref());
+
+        for recipient in &recipients {
+            if let Err(e) = send(&mail, recipient) {
+                log!("Error sending recurring email {} to {}: {}", id, recipient, e);
+            }
+        }
+
+        let next = schedule(&expression)
+            .ok()
+            .and_then(|schedule| next_run(&schedule, now));
+        let result = db::conn().and_then(|conn| conn.execute(
+            "UPDATE recurring SET last_run = ?2, next_run = ?3 WHERE id = ?1",
+            params![id, now, next],
+        ).map_err(|e| e.to_string()));
+        if let Err(e) = result {
+            log!("Error updating recurring email {}: {}", id, e);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Sunday 2024-01-07 00:00:00 UTC
+    const SUNDAY: i64 = 1704585600;
+    const DAY: i64 = 86400;
+    const EIGHT: i64 = 8 * 3600;
+
+    fn runs(
+        expression: &str,
+        count: usize,
+    ) -> Vec<i64> {
+        let schedule = schedule(expression).unwrap();
+        let mut runs = Vec::new();
+        let mut after = SUNDAY - 1;
+        for _ in 0..count {
+            after = next_run(&schedule, after).unwrap();
+            runs.push(after);
+        }
+        runs
+    }
+
+    #[test]
+    fn unix_weekdays() {
+        // Monday at 8
+        assert_eq!(runs("0 8 * * 1", 2), [SUNDAY + DAY + EIGHT, SUNDAY + 8 * DAY + EIGHT]);
+        assert_eq!(runs("0 8 * * Mon", 1), [SUNDAY + DAY + EIGHT]);
+        // Sunday is 0 and 7
+        assert_eq!(runs("0 8 * * 0", 1), [SUNDAY + EIGHT]);
+        assert_eq!(runs("0 8 * * 7", 1), [SUNDAY + EIGHT]);
+        // Saturday
+        assert_eq!(runs("0 8 * * 6", 1), [SUNDAY + 6 * DAY + EIGHT]);
+    }
+
+    #[test]
+    fn unix_weekday_ranges() {
+        let working: Vec<i64> = (1..=5).map(|day| SUNDAY + day * DAY + EIGHT).collect();
+        assert_eq!(runs("0 8 * * 1-5", 5), working);
+        // Friday to Sunday
+        assert_eq!(runs("0 8 * * 5-7", 3), [SUNDAY + EIGHT, SUNDAY + 5 * DAY + EIGHT, SUNDAY + 6 * DAY + EIGHT]);
+        let every: Vec<i64> = (0..7).map(|day| SUNDAY + day * DAY + EIGHT).collect();
+        assert_eq!(runs("0 8 * * 0-6", 7), every);
+        assert_eq!(runs("0 8 * * 0-7", 7), every);
+        // Monday, Wednesday and Friday
+        assert_eq!(runs("0 8 * * 1-5/2", 3), [SUNDAY + DAY + EIGHT, SUNDAY + 3 * DAY + EIGHT, SUNDAY + 5 * DAY + EIGHT]);
+        assert_eq!(runs("0 8 * * 0,3", 2), [SUNDAY + EIGHT, SUNDAY + 3 * DAY + EIGHT]);
+    }
+
+    #[test]
+    fn every_day_and_seconds() {
+        assert_eq!(runs("30 9 * * *", 2), [SUNDAY + 9 * 3600 + 1800, SUNDAY + DAY + 9 * 3600 + 1800]);
+        // 6 fields are the syntax of the cron crate, Monday is 2
+        assert_eq!(runs("15 0 8 * * 2", 1), [SUNDAY + DAY + EIGHT + 15]);
+    }
+
+    #[test]
+    fn invalid_expressions() {
+        for expression in ["0 8 * * 8", "0 8 * * 5-7/2", "0 25 * * *", "not cron", ""] {
+            assert!(schedule(expression).is_err(), "{:?}", expression);
+        }
+    }
+}
@@ -0,0 +1,165 @@
+//
+// Error reporting to Sentry or a webhook: panics and repeated SMTP failures
+//
+
+use std::sync::atomic::{AtomicU32, Ordering};
+use std::time::Duration;
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+
+use crate::SMTP_CLIENT;
+
+fn default_failure_threshold() -> u32 {
+    3
+}
+
+fn default_timeout() -> u64 {
+    10
+}
+
+#[derive(Clone, Deserialize, Serialize)]
+pub struct ReportSettings {
+    // https://<key>@<host>/<project>
+    sentry_dsn: Option<String>,
+    // url receiving a POST with {"kind", "message", "context", "time", "request_id"}
+    pub webhook: Option<String>,
+    // consecutive failed sends before they are reported
+    #[serde(default = "default_failure_threshold")]
+    failure_threshold: u32,
+    #[serde(default = "default_timeout")]
+    timeout_secs: u64,
+}
+
+static FAILURES: AtomicU32 = AtomicU32::new(0);
+
+// store endpoint and auth header of a Sentry DSN
+fn sentry_endpoint(dsn: &str) -> Option<(String, String)> {
+    let (scheme, rest) = dsn.split_once("://")?;
+    let (key, rest) = rest.split_once('@')?;
+    let (host, project) = rest.rsplit_once('/')?;
+    let key = key.split(':').next()?;
+    Some((
+        format!("{}://{}/api/{}/store/", scheme, host, project),
+        format!(
+            "Sentry sentry_version=7, sentry_key={}, sentry_client=arp-gmail/{}",
+            key, crate::VERSION,
+        ),
+    ))
+}
+
+fn post_sentry(
+    url: &str,
+    auth: &str,
+    payload: Value,
+    timeout: Duration,
+) {
+    let request = ureq::post(url)
+        .timeout(timeout)
+        .set("X-Sentry-Auth", auth);
+    if let Err(e) = request.send_json(payload) {
+        log!("Error reporting to {}: {}", url, e);
+    }
+}
+
+// the report is sent from its own thread, it never delays a request
+fn send(
+    kind: &'static str,
+    level: &'static str,
+    message: String,
+    context: Value,
+) {
+
+    log!("Reported {}: {}", kind, message);
+
+    let settings = match &SMTP_CLIENT.error_reporting {
+        Some(settings) => settings.clone(),
+        None => return,
+    };
+    let request_id = crate::trace::current();
+
+    let result = std::thread::Builder::new()
+        .name("arp-gmail-report".to_string())
+        .spawn(move || {
+            let timeout = Duration::from_secs(settings.timeout_secs);
+            let time = crate::db::now();
+
+            if let Some((url, auth)) = settings.sentry_dsn.as_deref().and_then(sentry_endpoint) {
+                post_sentry(&url, &auth, json!({
+                    "event_id": uuid::Uuid::new_v4().simple().to_string(),
+                    "timestamp": time,
+                    "level": level,
+                    "logger": kind,
+                    "platform": "other",
+                    "release": format!("arp-gmail@{}", crate::VERSION),
+                    "message": { "formatted": message },
+                    "tags": { "kind": kind, "request_id": request_id },
+                    "extra": context,
+                }), timeout);
+            }
+
+            if let Some(url) = &settings.webhook {
+                let payload = json!({
+                    "kind": kind,
+                    "message": message,
+                    "context": context,
+                    "time": time,
+                    "request_id": request_id,
+                });
+                if let Err(e) = crate::callbacks::post(url, &payload, timeout) {
+                    log!("Error reporting to {}: {}", url, e);
+                }
+            }
+        });
+    if let Err(e) = result {
+        log!("Error starting the report thread: {}", e);
+    }
+}
+
+// a panic caught at the FFI boundary, the host gets an error response instead
+pub fn panic(
+    function: &'static str,
+    payload: &(dyn std::any::Any + Send),
+) {
+    let message = payload.downcast_ref::<&str>()
+        .map(|message| message.to_string())
+        .or_else(|| payload.downcast_ref::<String>().cloned())
+        .unwrap_or("unknown panic".to_string());
+
+    send(
+        "panic",
+        "fatal",
+        format!("Panic in {}: {}", function, message),
+        json!({ "function": function }),
+    );
+}
+
+pub fn smtp_success() {
+    FAILURES.store(0, Ordering::SeqCst);
+}
+
+// reported once per streak of failures, when it reaches the threshold
+pub fn smtp_failure(
+    code: &str,
+    message: &str,
+) {
+
+    let threshold = SMTP_CLIENT.error_reporting.as_ref()
+        .map(|settings| settings.failure_threshold.max(1))
+        .unwrap_or(default_failure_threshold());
+
+    let failures = FAILURES.fetch_add(1, Ordering::SeqCst) + 1;
+    if failures != threshold {
+        return;
+    }
+
+    send(
+        "smtp_failures",
+        "error",
+        format!("{} consecutive failed sends, the last one: {}", failures, crate::privacy::redact(message)),
+        json!({
+            "code": code,
+            "server": SMTP_CLIENT.server,
+            "username": SMTP_CLIENT.username,
+        }),
+    );
+}
@@ -0,0 +1,256 @@
+//
+// Messages held for review: the ones matching the rules of the config wait in
+// the pending table until an admin approves them, they are queued then, or
+// rejects them
+//
+
+use rusqlite::{params, OptionalExtension};
+use serde::{Deserialize, Serialize};
+
+use crate::{attachments, db, queue};
+use crate::{Mail, SendError, SMTP_CLIENT};
+use crate::page::Page;
+
+pub const SCHEMA: &str = "CREATE TABLE IF NOT EXISTS pending (
+    id INTEGER PRIMARY KEY AUTOINCREMENT,
+    tenant TEXT NOT NULL,
+    mail TEXT NOT NULL,
+    reasons TEXT NOT NULL,
+    created_at INTEGER NOT NULL
+);";
+
+#[derive(Clone, Default, Deserialize, Serialize)]
+pub struct ReviewSettings {
+    // a recipient outside of these domains is external, the domain of the
+    // From address if not set
+    #[serde(default)]
+    pub internal_domains: Vec<String>,
+    // hold the messages to an external recipient
+    #[serde(default)]
+    pub external_recipients: bool,
+    // hold the messages with an attachment bigger than this
+    pub max_attachment_bytes: Option<u64>,
+    // hold the messages with one of these in the subject or the body, in any case
+    #[serde(default)]
+    pub keywords: Vec<String>,
+}
+
+#[derive(Deserialize)]
+pub struct ReviewRequest {
+    // approve or reject
+    pub action: String,
+    pub id: i64,
+    // of a rejection, recorded in the audit log
+    pub reason: Option<String>,
+}
+
+#[derive(Serialize)]
+pub struct Pending {
+    id: i64,
+    to: String,
+    subject: String,
+    reasons: Vec<String>,
+    created_at: i64,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    mail: Option<Mail>,
+}
+
+fn db_error(e: impl ToString) -> SendError {
+    SendError::new("internal_error", e.to_string())
+}
+
+pub fn enabled() -> bool {
+    SMTP_CLIENT.review.is_some()
+}
+
+fn domain(address: &str) -> Option<String> {
+    address.rsplit_once('@')
+        .map(|(_, domain)| domain.trim_end_matches('>').trim().to_lowercase())
+}
+
+// the rules the message matches, none lets it through
+fn reasons(
+    settings: &ReviewSettings,
+    mail: &Mail,
+) -> Result<Vec<String>, SendError> {
+
+    let mut reasons = Vec::new();
+
+    if settings.external_recipients {
+        let internal: Vec<String> = match settings.internal_domains.is_empty() {
+            true => domain(&mail.from).into_iter().collect(),
+            false => settings.internal_domains.iter().map(|domain| domain.trim().to_lowercase()).collect(),
+        };
+        let external = crate::policy::recipients(mail).iter()
+            .filter(|recipient| domain(recipient).is_none_or(|domain| !internal.contains(&domain)))
+            .count();
+        match external {
+            0 => {},
+            1 => reasons.push("an external recipient".to_string()),
+            n => reasons.push(format!("{} external recipients", n)),
+        }
+    }
+
+    if let Some(max_bytes) = settings.max_attachment_bytes {
+        for attachment in mail.attachments.iter().flatten() {
+            let size = attachments::size(attachment, &SMTP_CLIENT)?;
+            if size > max_bytes {
+                reasons.push(format!("an attachment of {} bytes, over {}", size, max_bytes));
+            }
+        }
+    }
+
+    let text = [Some(&mail.subject), Some(&mail.message), mail.html.as_ref()]
+        .into_iter()
+        .flatten()
+        .map(|text| text.to_lowercase())
+        .collect::<Vec<String>>();
+    for keyword in &settings.keywords {
+        if text.iter().any(|text| text.contains(&keyword.to_lowercase())) {
+            reasons.push(format!("the keyword {:?}", keyword));
+        }
+    }
+
+    Ok(reasons)
+}
+
+// the id of the pending entry and the reasons, when the message is held by
+// the rules or by the content policies that ask for an approval
+pub fn hold(
+    mail: &Mail,
+    approvals: Vec<String>,
+) -> Result<Option<(i64, Vec<String>)>, SendError> {
+
+    let mut reasons = approvals;
+    if let Some(settings) = &SMTP_CLIENT.review {
+        reasons.extend(self::reasons(settings, mail)?);
+    }
+    if reasons.is_empty() {
+        return Ok(None);
+    }
+
+    let conn = db::conn().map_err(db_error)?;
+    conn.execute(
+        "INSERT INTO pending (tenant, mail, reasons, created_at) VALUES (?1, ?2, ?3, ?4)",
+        params![
+            crate::tenant::key(),
+            serde_json::to_string(mail).map_err(db_error)?,
+            serde_json::to_string(&reasons).map_err(db_error)?,
+            db::now(),
+        ],
+    ).map_err(db_error)?;
+
+    Ok(Some((conn.last_insert_rowid(), reasons)))
+}
+
+fn pending_entry(row: &rusqlite::Row, with_mail: bool) -> rusqlite::Result<Pending> {
+    let mail: Option<Mail> = serde_json::from_str(&row.get::<_, String>(1)?).ok();
+    Ok(Pending {
+        id: row.get(0)?,
+        to: mail.as_ref().map(|mail| mail.to.clone()).unwrap_or_default(),
+        subject: mail.as_ref().map(|mail| mail.subject.clone()).unwrap_or_default(),
+        mail: mail.filter(|_| with_mail).map(queue::redacted),
+        reasons: serde_json::from_str(&row.get::<_, String>(2)?).unwrap_or_default(),
+        created_at: row.get(3)?,
+    })
+}
+
+// the entries of the tenant of the request, all of them for the instance
+const TENANT: &str = "(?1 = '' OR tenant = ?1)";
+
+pub fn list(page: &Page) -> Result<(Vec<Pending>, usize), String> {
+
+    let conn = db::conn()?;
+
+    let total: i64 = conn.query_row(
+        &format!("SELECT COUNT(*) FROM pending WHERE {}", TENANT),
+        params![crate::tenant::key()],
+        |row| row.get(0),
+    ).map_err(|e| e.to_string())?;
+
+    let (limit, offset) = page.sql();
+    let mut stmt = conn.prepare(&format!(
+        "SELECT id, mail, reasons, created_at FROM pending WHERE {} ORDER BY id LIMIT ?2 OFFSET ?3",
+        TENANT,
+    )).map_err(|e| e.to_string())?;
+
+    let entries = stmt.query_map(params![crate::tenant::key(), limit, offset], |row| pending_entry(row, false))
+        .map_err(|e| e.to_string())?;
+
+    let entries = entries.collect::<Result<Vec<Pending>, _>>()
+        .map_err(|e| e.to_string())?;
+
+    Ok((entries, total as usize))
+}
+
+pub fn by_id(id: i64) -> Result<Option<Pending>, String> {
+    db::conn()?
+        .query_row(
+            &format!("SELECT id, mail, reasons, created_at FROM pending WHERE {} AND id = ?2", TENANT),
+            params![crate::tenant::key(), id],
+            |row| pending_entry(row, true),
+        )
+        .optional()
+        .map_err(|e| e.to_string())
+}
+
+// an approved message is queued, the job of the queue is returned
+pub fn decide(request: &ReviewRequest) -> Result<(String, Option<i64>), SendError> {
+
+    let not_found = || SendError::new("not_found", format!("No pending message with id {}", request.id));
+
+    match request.action.as_str() {
+        "approve" | "reject" => {},
+        action => return Err(SendError::new("invalid_request", format!("Invalid action: {}", action))),
+    }
+
+    // removed first, two admins can't both approve it
+    let conn = db::conn().map_err(db_error)?;
+    let (tenant, raw, reasons, created_at) = conn.query_row(
+        &format!("SELECT tenant, mail, reasons, created_at FROM pending WHERE {} AND id = ?2", TENANT),
+        params![crate::tenant::key(), request.id],
+        |row| Ok((row.get::<_, String>(0)?, row.get::<_, String>(1)?, row.get::<_, String>(2)?, row.get::<_, i64>(3)?)),
+    ).optional()
+        .map_err(db_error)?
+        .ok_or_else(not_found)?;
+    let mut mail: Mail = serde_json::from_str(&raw)
+        .map_err(db_error)?;
+    // the wait for the reviewer isn't delivery latency
+    mail.accepted_ms = Some(crate::latency::now_ms());
+    if conn.execute("DELETE FROM pending WHERE id = ?1", params![request.id])
+        .map_err(db_error)? == 0 {
+        return Err(not_found());
+    }
+    drop(conn);
+
+    // a message that can't be queued stays pending
+    let restore = || {
+        let result = db::conn().and_then(|conn| conn.execute(
+            "INSERT INTO pending (id, tenant, mail, reasons, created_at) VALUES (?1, ?2, ?3, ?4, ?5)",
+            params![request.id, tenant, raw, reasons, created_at],
+        ).map_err(|e| e.to_string()));
+        if let Err(e) = result {
+            log!("Error restoring the pending message {}: {}", request.id, e);
+        }
+    };
+
+    match request.action.as_str() {
+        "approve" => {
+            if let Some(period) = &mail.digest {
+                crate::digest::add(&mail, period)
+                    .inspect_err(|_| restore())?;
+                crate::scheduler::start();
+                return Ok((format!("Pending message {} approved, added to the {} digest", request.id, period), None));
+            }
+            let job = queue::enqueue(&mail)
+                .inspect_err(|_| restore())?;
+            Ok((format!("Pending message {} approved, queued as job {}", request.id, job), Some(job)))
+        },
+        _ => {
+            let reason = request.reason.as_deref().unwrap_or("rejected by the reviewer");
+            crate::jobs::failed(mail.batch, 1);
+            crate::jobs::record(mail.batch, &mail.to, "failed", Some("rejected"), reason, None);
+            Ok((format!("Pending message {} rejected: {}", request.id, reason), None))
+        },
+    }
+}
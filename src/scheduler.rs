@@ -0,0 +1,42 @@
+//
+// Background thread running the periodic jobs of the plugin
+//
+
+use std::sync::Once;
+use std::time::Duration;
+
+use crate::shutdown;
+
+const TICK: Duration = Duration::from_secs(30);
+
+static STARTED: Once = Once::new();
+
+fn tick() {
+    crate::persistence::flush();
+    // the digests and recurring emails stay pending while sending is paused
+    if !crate::queue::paused() && crate::persistence::check().is_ok() {
+        crate::digest::run();
+        crate::recurring::run();
+    }
+    if crate::privacy::enabled() {
+        if let Err(e) = crate::privacy::purge() {
+            log!("Error applying the retention periods: {}", e);
+        }
+    }
+}
+
+// safe to call more than once, the thread is only started the first time
+pub fn start() {
+    STARTED.call_once(|| {
+        let result = std::thread::Builder::new()
+            .name("arp-gmail-scheduler".to_string())
+            .spawn(|| while !shutdown::stopping() {
+                tick();
+                shutdown::sleep(TICK);
+            });
+        match result {
+            Ok(handle) => shutdown::register("scheduler", handle),
+            Err(e) => log!("Error starting the scheduler: {}", e),
+        }
+    });
+}
@@ -0,0 +1,206 @@
+//
+// SendGrid accounts: the v3 mail send API doesn't take a MIME message, the
+// built one is converted to its JSON
+//
+
+use base64::{engine::general_purpose, Engine as _};
+use lettre::address::Envelope;
+use mailparse::{DispositionType, MailHeaderMap, ParsedMail};
+use serde_json::{json, Map, Value};
+
+use crate::pool::Failure;
+use crate::transport::{self, Transport};
+
+const ENDPOINT: &str = "https://api.sendgrid.com";
+
+// set from the fields of the JSON, SendGrid refuses them as custom headers
+const RESERVED: [&str; 12] = [
+    "from",
+    "to",
+    "cc",
+    "bcc",
+    "subject",
+    "reply-to",
+    "content-type",
+    "content-transfer-encoding",
+    "mime-version",
+    "date",
+    "received",
+    "dkim-signature",
+];
+
+pub struct Sendgrid {
+    api_key: String,
+    endpoint: String,
+}
+
+impl Sendgrid {
+    pub fn new(
+        api_key: &str,
+        endpoint: Option<&str>,
+    ) -> Self {
+        Sendgrid {
+            api_key: api_key.to_string(),
+            endpoint: endpoint.unwrap_or(ENDPOINT).trim_end_matches('/').to_string(),
+        }
+    }
+}
+
+// the message can't be sent through this account, retrying won't help
+fn invalid(message: impl ToString) -> Failure {
+    Failure::Account(format!("The message can't be converted for SendGrid: {}", message.to_string()))
+}
+
+// the addresses of a header as {"email", "name"} objects
+fn addresses(
+    parsed: &ParsedMail,
+    name: &str,
+) -> Vec<Value> {
+
+    let list = match parsed.headers.get_first_header(name).map(mailparse::addrparse_header) {
+        Some(Ok(list)) => list,
+        _ => return Vec::new(),
+    };
+
+    list.iter()
+        .flat_map(|address| match address {
+            mailparse::MailAddr::Single(single) => vec![single.clone()],
+            mailparse::MailAddr::Group(group) => group.addrs.clone(),
+        })
+        .map(|single| match single.display_name {
+            Some(display_name) => json!({ "email": single.addr, "name": display_name }),
+            None => json!({ "email": single.addr }),
+        })
+        .collect()
+}
+
+// the text and html bodies in their order, the other parts as attachments
+fn parts(
+    part: &ParsedMail,
+    content: &mut Vec<Value>,
+    attachments: &mut Vec<Value>,
+) -> Result<(), Failure> {
+
+    if !part.subparts.is_empty() {
+        for subpart in &part.subparts {
+            parts(subpart, content, attachments)?;
+        }
+        return Ok(());
+    }
+
+    let disposition = part.get_content_disposition();
+    let filename = disposition.params.get("filename")
+        .or(part.ctype.params.get("name"));
+    let mimetype = part.ctype.mimetype.as_str();
+    if filename.is_none()
+        && disposition.disposition == DispositionType::Inline
+        && matches!(mimetype, "text/plain" | "text/html") {
+        content.push(json!({
+            "type": mimetype,
+            "value": part.get_body().map_err(invalid)?,
+        }));
+        return Ok(());
+    }
+
+    let mut attachment = Map::new();
+    attachment.insert("content".to_string(), json!(general_purpose::STANDARD.encode(part.get_body_raw().map_err(invalid)?)));
+    attachment.insert("filename".to_string(), json!(filename.map(String::as_str).unwrap_or("attachment")));
+    attachment.insert("type".to_string(), json!(mimetype));
+    match (&disposition.disposition, part.headers.get_first_value("Content-ID")) {
+        (DispositionType::Inline, Some(content_id)) => {
+            attachment.insert("disposition".to_string(), json!("inline"));
+            attachment.insert("content_id".to_string(), json!(content_id.trim_matches(['<', '>', ' '])));
+        },
+        _ => {
+            attachment.insert("disposition".to_string(), json!("attachment"));
+        },
+    };
+    attachments.push(Value::Object(attachment));
+
+    Ok(())
+}
+
+// the envelope recipients not in the To and Cc headers are the bcc ones
+fn request(
+    envelope: &Envelope,
+    raw: &[u8],
+) -> Result<Value, Failure> {
+
+    let parsed = mailparse::parse_mail(raw).map_err(invalid)?;
+
+    let to = addresses(&parsed, "To");
+    let cc = addresses(&parsed, "Cc");
+    let listed = |address: &str| to.iter()
+        .chain(cc.iter())
+        .any(|entry| entry["email"].as_str().is_some_and(|email| email.eq_ignore_ascii_case(address)));
+    let bcc: Vec<Value> = envelope.to()
+        .iter()
+        .map(|address| address.to_string())
+        .filter(|address| !listed(address))
+        .map(|address| json!({ "email": address }))
+        .collect();
+
+    let mut personalization = Map::new();
+    personalization.insert("to".to_string(), json!(to));
+    if !cc.is_empty() {
+        personalization.insert("cc".to_string(), json!(cc));
+    }
+    if !bcc.is_empty() {
+        personalization.insert("bcc".to_string(), json!(bcc));
+    }
+
+    let from = addresses(&parsed, "From")
+        .into_iter()
+        .next()
+        .or(envelope.from().map(|address| json!({ "email": address.to_string() })))
+        .ok_or(invalid("no from address"))?;
+
+    let (mut content, mut attachments) = (Vec::new(), Vec::new());
+    parts(&parsed, &mut content, &mut attachments)?;
+
+    let headers: Map<String, Value> = parsed.headers
+        .iter()
+        .filter(|header| !RESERVED.contains(&header.get_key().to_lowercase().as_str()))
+        .map(|header| (header.get_key(), json!(header.get_value())))
+        .collect();
+
+    let mut request = Map::new();
+    request.insert("personalizations".to_string(), json!([personalization]));
+    request.insert("from".to_string(), from);
+    if let Some(reply_to) = addresses(&parsed, "Reply-To").into_iter().next() {
+        request.insert("reply_to".to_string(), reply_to);
+    }
+    request.insert("subject".to_string(), json!(parsed.headers.get_first_value("Subject").unwrap_or_default()));
+    request.insert("content".to_string(), json!(content));
+    if !attachments.is_empty() {
+        request.insert("attachments".to_string(), json!(attachments));
+    }
+    if !headers.is_empty() {
+        request.insert("headers".to_string(), Value::Object(headers));
+    }
+
+    Ok(Value::Object(request))
+}
+
+impl Transport for Sendgrid {
+    fn send(
+        &self,
+        envelope: &Envelope,
+        raw: &[u8],
+    ) -> Result<String, Failure> {
+
+        let request = request(envelope, raw)?;
+        let response = transport::agent()
+            .post(&format!("{}/v3/mail/send", self.endpoint))
+            .set("Authorization", &format!("Bearer {}", self.api_key))
+            .send_json(request)
+            .map_err(|e| transport::api_error("sendgrid", e))?;
+
+        Ok(format!(
+            "SendGrid {} {}, X-Message-Id: {}",
+            response.status(),
+            response.status_text(),
+            response.header("X-Message-Id").unwrap_or(""),
+        ))
+    }
+}
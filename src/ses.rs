@@ -0,0 +1,129 @@
+//
+// Amazon SES accounts: the built message is sent as raw content with the v2
+// SendEmail API, signed with Signature Version 4
+//
+
+use base64::{engine::general_purpose, Engine as _};
+use hmac::{Hmac, Mac};
+use lettre::address::Envelope;
+use serde_json::json;
+use sha2::{Digest, Sha256};
+
+use crate::pool::Failure;
+use crate::transport::{self, Transport};
+
+const PATH: &str = "/v2/email/outbound-emails";
+
+pub struct Ses {
+    access_key_id: String,
+    secret_access_key: String,
+    region: String,
+    endpoint: String,
+}
+
+impl Ses {
+    pub fn new(
+        access_key_id: &str,
+        secret_access_key: &str,
+        region: &str,
+        endpoint: Option<&str>,
+    ) -> Self {
+        Ses {
+            access_key_id: access_key_id.to_string(),
+            secret_access_key: secret_access_key.to_string(),
+            region: region.to_string(),
+            endpoint: endpoint
+                .map(str::to_string)
+                .unwrap_or(format!("https://email.{}.amazonaws.com", region))
+                .trim_end_matches('/')
+                .to_string(),
+        }
+    }
+
+    // the authorization header of a request at the date, "20240101T000000Z"
+    fn authorization(
+        &self,
+        host: &str,
+        amz_date: &str,
+        body: &[u8],
+    ) -> String {
+
+        let date = &amz_date[..8];
+        let scope = format!("{}/{}/ses/aws4_request", date, self.region);
+        let canonical = format!(
+            "POST\n{}\n\nhost:{}\nx-amz-date:{}\n\nhost;x-amz-date\n{:x}",
+            PATH, host, amz_date, Sha256::digest(body),
+        );
+        let to_sign = format!(
+            "AWS4-HMAC-SHA256\n{}\n{}\n{:x}",
+            amz_date, scope, Sha256::digest(canonical.as_bytes()),
+        );
+
+        let key = [date, &self.region, "ses", "aws4_request"]
+            .iter()
+            .fold(format!("AWS4{}", self.secret_access_key).into_bytes(), |key, part| hmac(&key, part.as_bytes()));
+        let signature: String = hmac(&key, to_sign.as_bytes())
+            .iter()
+            .map(|byte| format!("{:02x}", byte))
+            .collect();
+
+        format!(
+            "AWS4-HMAC-SHA256 Credential={}/{}, SignedHeaders=host;x-amz-date, Signature={}",
+            self.access_key_id, scope, signature,
+        )
+    }
+}
+
+fn hmac(
+    key: &[u8],
+    data: &[u8],
+) -> Vec<u8> {
+    let mut mac = Hmac::<Sha256>::new_from_slice(key)
+        .expect("HMAC takes a key of any size");
+    mac.update(data);
+    mac.finalize().into_bytes().to_vec()
+}
+
+impl Transport for Ses {
+    fn send(
+        &self,
+        envelope: &Envelope,
+        raw: &[u8],
+    ) -> Result<String, Failure> {
+
+        // the envelope recipients, the headers of the message are sent as is
+        let body = serde_json::to_vec(&json!({
+            "FromEmailAddress": envelope.from().map(|address| address.to_string()),
+            "Destination": {
+                "ToAddresses": envelope.to()
+                    .iter()
+                    .map(|address| address.to_string())
+                    .collect::<Vec<String>>(),
+            },
+            "Content": {
+                "Raw": { "Data": general_purpose::STANDARD.encode(raw) },
+            },
+        })).map_err(|e| Failure::Account(e.to_string()))?;
+
+        let host = self.endpoint
+            .split("://")
+            .last()
+            .unwrap_or_default()
+            .split('/')
+            .next()
+            .unwrap_or_default();
+        let amz_date = chrono::Utc::now().format("%Y%m%dT%H%M%SZ").to_string();
+        let response = transport::agent()
+            .post(&format!("{}{}", self.endpoint, PATH))
+            .set("Content-Type", "application/json")
+            .set("X-Amz-Date", &amz_date)
+            .set("Authorization", &self.authorization(host, &amz_date, &body))
+            .send_bytes(&body)
+            .map_err(|e| transport::api_error("ses", e))?;
+
+        let status = response.status();
+        let reply: serde_json::Value = response.into_json()
+            .unwrap_or_default();
+        Ok(format!("SES {}: MessageId {}", status, reply["MessageId"].as_str().unwrap_or("")))
+    }
+}
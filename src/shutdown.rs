@@ -0,0 +1,63 @@
+//
+// Graceful shutdown of the background workers before the host unloads the library
+//
+
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Condvar, Mutex};
+use std::thread::JoinHandle;
+use std::time::{Duration, Instant};
+
+static STOPPING: AtomicBool = AtomicBool::new(false);
+static WORKERS: Mutex<Vec<(&'static str, JoinHandle<()>)>> = Mutex::new(Vec::new());
+static SIGNAL: (Mutex<()>, Condvar) = (Mutex::new(()), Condvar::new());
+
+pub fn stopping() -> bool {
+    STOPPING.load(Ordering::SeqCst)
+}
+
+// worker threads joined on shutdown
+pub fn register(
+    name: &'static str,
+    handle: JoinHandle<()>,
+) {
+    if let Ok(mut workers) = WORKERS.lock() {
+        workers.push((name, handle));
+    }
+}
+
+// sleep that returns early when the shutdown starts
+pub fn sleep(duration: Duration) {
+    let (lock, condvar) = &SIGNAL;
+    if let Ok(guard) = lock.lock() {
+        let _ = condvar.wait_timeout_while(guard, duration, |_| !stopping());
+    }
+}
+
+// stop accepting new work and wait for the workers until the deadline,
+// returns false if some worker is still running
+pub fn run(deadline: Duration) -> bool {
+
+    STOPPING.store(true, Ordering::SeqCst);
+    SIGNAL.1.notify_all();
+
+    let workers = match WORKERS.lock() {
+        Ok(mut workers) => std::mem::take(&mut *workers),
+        Err(_) => return false,
+    };
+
+    let deadline = Instant::now() + deadline;
+    let mut drained = true;
+    for (name, handle) in workers {
+        while !handle.is_finished() && Instant::now() < deadline {
+            std::thread::sleep(Duration::from_millis(20));
+        }
+        if handle.is_finished() {
+            let _ = handle.join();
+        } else {
+            log!("Shutdown deadline reached: worker {} is still running", name);
+            drained = false;
+        }
+    }
+
+    drained
+}
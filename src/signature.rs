@@ -0,0 +1,219 @@
+//
+// The detached ed25519 signature of config.json: config.json.sig next to it,
+// the base64 signature of the bytes of the file, checked with the public key
+// of the PEM file named by ARP_GMAIL_CONFIG_KEY, kept out of the plugin
+// directory more people can write to. Without the variable the config isn't
+// signed, with it a missing or wrong signature refuses the config
+//
+
+use std::path::Path;
+use base64::Engine;
+use openssl::pkey::{Id, PKey, Public};
+use openssl::sign::Verifier;
+use sha2::{Digest, Sha256};
+
+pub const KEY_VAR: &str = "ARP_GMAIL_CONFIG_KEY";
+
+// the raw signature, when the file isn't base64
+const SIGNATURE_LEN: usize = 64;
+
+// the file of the signature of a config file
+pub fn signature_file(file: &Path) -> std::path::PathBuf {
+    let mut name = file.as_os_str().to_owned();
+    name.push(".sig");
+    name.into()
+}
+
+// the public key the config is signed with, None when it isn't required
+fn key() -> Result<Option<PKey<Public>>, String> {
+    match std::env::var_os(KEY_VAR) {
+        Some(path) => key_from(Path::new(&path)).map(Some),
+        None => Ok(None),
+    }
+}
+
+fn key_from(path: &Path) -> Result<PKey<Public>, String> {
+
+    let pem = std::fs::read(path)
+        .map_err(|e| format!("The public key {} can't be read: {}", path.display(), e))?;
+    let key = PKey::public_key_from_pem(&pem)
+        .map_err(|e| format!("The public key {} is invalid: {}", path.display(), e))?;
+    if key.id() != Id::ED25519 {
+        return Err(format!("The public key {} isn't an ed25519 key", path.display()));
+    }
+
+    Ok(key)
+}
+
+// the SHA-256 of the raw public key, as logged and on GET /config
+fn fingerprint(key: &PKey<Public>) -> String {
+    let raw = key.raw_public_key().unwrap_or_default();
+    let digest: String = Sha256::digest(raw)
+        .iter()
+        .map(|byte| format!("{:02x}", byte))
+        .collect();
+    format!("SHA256:{}", digest)
+}
+
+// the text of a config file and the fingerprint of the key that signed it,
+// None when no key is set; the config is only read from disk through here, at
+// load and by the checks of /config/validate
+pub fn read(file: &Path) -> Result<(String, Option<String>), String> {
+    read_with(key()?.as_ref(), file)
+}
+
+fn read_with(
+    key: Option<&PKey<Public>>,
+    file: &Path,
+) -> Result<(String, Option<String>), String> {
+
+    let text = std::fs::read_to_string(file)
+        .map_err(|e| format!("Config file can't be read: {}", e))?;
+    let signed_by = match key {
+        Some(key) => Some(verify(key, file, text.as_bytes())?),
+        None => None,
+    };
+
+    Ok((text, signed_by))
+}
+
+// the fingerprint of the key that signed the config
+fn verify(
+    key: &PKey<Public>,
+    file: &Path,
+    text: &[u8],
+) -> Result<String, String> {
+
+    let fingerprint = fingerprint(key);
+
+    let sig_file = signature_file(file);
+    let bytes = std::fs::read(&sig_file)
+        .map_err(|e| format!("{} is required by {} but can't be read: {}", sig_file.display(), KEY_VAR, e))?;
+    let signature = match base64::engine::general_purpose::STANDARD.decode(bytes.trim_ascii()) {
+        Ok(signature) => signature,
+        Err(_) if bytes.len() == SIGNATURE_LEN => bytes,
+        Err(e) => return Err(format!("{} is not a base64 signature: {}", sig_file.display(), e)),
+    };
+
+    let verified = Verifier::new_without_digest(key)
+        .and_then(|mut verifier| verifier.verify_oneshot(&signature, text))
+        .unwrap_or(false);
+    if !verified {
+        return Err(format!("{} doesn't match its signature for the key {}, it was changed after it was signed", file.display(), fingerprint));
+    }
+
+    Ok(fingerprint)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use openssl::pkey::Private;
+    use openssl::sign::Signer;
+
+    const CONFIG: &str = r#"{ "username": "test@example.com", "password": "x" }"#;
+
+    // a config.json in a directory of its own
+    fn config(name: &str) -> std::path::PathBuf {
+        let dir = std::env::temp_dir().join(format!("arp-gmail-signature-{}-{}", std::process::id(), name));
+        std::fs::create_dir_all(&dir).unwrap();
+        let file = dir.join("config.json");
+        std::fs::write(&file, CONFIG).unwrap();
+        file
+    }
+
+    fn keys() -> (PKey<Private>, PKey<Public>) {
+        let private = PKey::generate_ed25519().unwrap();
+        let public = PKey::public_key_from_pem(&private.public_key_to_pem().unwrap()).unwrap();
+        (private, public)
+    }
+
+    fn sign(
+        key: &PKey<Private>,
+        text: &[u8],
+    ) -> Vec<u8> {
+        Signer::new_without_digest(key)
+            .and_then(|mut signer| signer.sign_oneshot_to_vec(text))
+            .unwrap()
+    }
+
+    fn base64(signature: &[u8]) -> String {
+        base64::engine::general_purpose::STANDARD.encode(signature)
+    }
+
+    #[test]
+    fn valid_signature() {
+        let file = config("valid");
+        let (private, public) = keys();
+        std::fs::write(signature_file(&file), base64(&sign(&private, CONFIG.as_bytes())) + "\n").unwrap();
+
+        let (text, signed_by) = read_with(Some(&public), &file).unwrap();
+        assert_eq!(text, CONFIG);
+        assert_eq!(signed_by, Some(fingerprint(&public)));
+    }
+
+    #[test]
+    fn raw_signature() {
+        let file = config("raw");
+        let (private, public) = keys();
+        let signature = sign(&private, CONFIG.as_bytes());
+        assert_eq!(signature.len(), SIGNATURE_LEN);
+        std::fs::write(signature_file(&file), &signature).unwrap();
+
+        assert_eq!(read_with(Some(&public), &file).unwrap().1, Some(fingerprint(&public)));
+    }
+
+    #[test]
+    fn tampered_config() {
+        let file = config("tampered");
+        let (private, public) = keys();
+        std::fs::write(signature_file(&file), base64(&sign(&private, CONFIG.as_bytes()))).unwrap();
+        std::fs::write(&file, CONFIG.replace("\"x\"", "\"y\"")).unwrap();
+
+        let error = read_with(Some(&public), &file).unwrap_err();
+        assert!(error.contains("changed after it was signed"), "{}", error);
+    }
+
+    #[test]
+    fn wrong_key() {
+        let file = config("wrong");
+        let (private, _) = keys();
+        let (_, other) = keys();
+        std::fs::write(signature_file(&file), base64(&sign(&private, CONFIG.as_bytes()))).unwrap();
+
+        let error = read_with(Some(&other), &file).unwrap_err();
+        assert!(error.contains(&fingerprint(&other)), "{}", error);
+    }
+
+    #[test]
+    fn missing_or_invalid_signature() {
+        let file = config("missing");
+        let (_, public) = keys();
+        let error = read_with(Some(&public), &file).unwrap_err();
+        assert!(error.contains("can't be read"), "{}", error);
+
+        // neither base64 nor the length of a raw signature
+        std::fs::write(signature_file(&file), "not a signature!").unwrap();
+        let error = read_with(Some(&public), &file).unwrap_err();
+        assert!(error.contains("is not a base64 signature"), "{}", error);
+    }
+
+    #[test]
+    fn unsigned_without_key() {
+        let file = config("unsigned");
+        assert_eq!(read_with(None, &file).unwrap(), (CONFIG.to_string(), None));
+    }
+
+    #[test]
+    fn only_ed25519_keys() {
+        let dir = config("keys").with_file_name("");
+        let (private, _) = keys();
+        std::fs::write(dir.join("ed25519.pem"), private.public_key_to_pem().unwrap()).unwrap();
+        assert!(key_from(&dir.join("ed25519.pem")).is_ok());
+
+        let rsa = PKey::from_rsa(openssl::rsa::Rsa::generate(2048).unwrap()).unwrap();
+        std::fs::write(dir.join("rsa.pem"), rsa.public_key_to_pem().unwrap()).unwrap();
+        let error = key_from(&dir.join("rsa.pem")).unwrap_err();
+        assert!(error.contains("isn't an ed25519 key"), "{}", error);
+    }
+}
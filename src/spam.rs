@@ -0,0 +1,123 @@
+//
+// Spam score self check of the built message (spamd or rspamd)
+//
+
+use std::io::{Read, Write};
+use std::time::Duration;
+use serde::{Deserialize, Serialize};
+
+fn default_timeout() -> u64 {
+    10
+}
+
+#[derive(Clone, Deserialize, Serialize)]
+pub struct SpamCheckSettings {
+    // spamassassin spamd address: host:port
+    spamd: Option<String>,
+    // rspamd controller url: http://127.0.0.1:11333
+    rspamd: Option<String>,
+    // refuse to send above this score, the checker's required score if not set
+    threshold: Option<f64>,
+    #[serde(default = "default_timeout")]
+    timeout_secs: u64,
+}
+
+#[derive(Clone, Debug, Serialize)]
+pub struct SpamReport {
+    pub score: f64,
+    pub threshold: f64,
+    pub rules: Vec<String>,
+}
+
+fn check_spamd(
+    address: &str,
+    message: &[u8],
+    timeout: Duration,
+) -> Result<(f64, f64, Vec<String>), String> {
+
+    let mut stream = std::net::TcpStream::connect(address)
+        .map_err(|e| format!("spamd {}: {}", address, e))?;
+    stream.set_read_timeout(Some(timeout)).ok();
+    stream.set_write_timeout(Some(timeout)).ok();
+
+    let mut reply = String::new();
+    write!(stream, "SYMBOLS SPAMC/1.5\r\nContent-length: {}\r\n\r\n", message.len())
+        .and_then(|_| stream.write_all(message))
+        .and_then(|_| stream.shutdown(std::net::Shutdown::Write))
+        .and_then(|_| stream.read_to_string(&mut reply))
+        .map_err(|e| format!("spamd {}: {}", address, e))?;
+
+    // SPAMD/1.1 0 EX_OK
+    // Spam: True ; 15.0 / 5.0
+    //
+    // RULE_A,RULE_B
+    let (head, body) = reply.split_once("\r\n\r\n")
+        .unwrap_or((&reply, ""));
+    if !head.starts_with("SPAMD/") || !head.contains("EX_OK") {
+        return Err(format!("spamd {}: {}", address, head.lines().next().unwrap_or("")));
+    }
+
+    let spam = head.lines()
+        .find_map(|line| line.strip_prefix("Spam:"))
+        .ok_or(format!("spamd {}: no Spam header in reply", address))?;
+    let (score, required) = spam.split_once(';')
+        .and_then(|(_, scores)| scores.split_once('/'))
+        .and_then(|(score, required)| Some((
+            score.trim().parse::<f64>().ok()?,
+            required.trim().parse::<f64>().ok()?,
+        )))
+        .ok_or(format!("spamd {}: invalid Spam header: {}", address, spam))?;
+
+    let rules = body.trim()
+        .split(',')
+        .filter(|rule| !rule.is_empty())
+        .map(|rule| rule.to_string())
+        .collect();
+
+    Ok((score, required, rules))
+}
+
+#[derive(Deserialize)]
+struct RspamdReply {
+    score: f64,
+    required_score: f64,
+    #[serde(default)]
+    symbols: std::collections::BTreeMap<String, serde_json::Value>,
+}
+
+fn check_rspamd(
+    url: &str,
+    message: &[u8],
+    timeout: Duration,
+) -> Result<(f64, f64, Vec<String>), String> {
+
+    let endpoint = format!("{}/checkv2", url.trim_end_matches('/'));
+    let reply: RspamdReply = ureq::post(&endpoint)
+        .timeout(timeout)
+        .send_bytes(message)
+        .map_err(|e| format!("rspamd {}: {}", url, e))?
+        .into_json()
+        .map_err(|e| format!("rspamd {}: {}", url, e))?;
+
+    Ok((reply.score, reply.required_score, reply.symbols.into_keys().collect()))
+}
+
+pub fn check(
+    settings: &SpamCheckSettings,
+    message: &[u8],
+) -> Result<SpamReport, String> {
+
+    let timeout = Duration::from_secs(settings.timeout_secs);
+
+    let (score, required, rules) = match (&settings.spamd, &settings.rspamd) {
+        (Some(address), _) => check_spamd(address, message, timeout)?,
+        (None, Some(url)) => check_rspamd(url, message, timeout)?,
+        (None, None) => return Err("Spam check is enabled but neither spamd nor rspamd is set".to_string()),
+    };
+
+    Ok(SpamReport {
+        score,
+        threshold: settings.threshold.unwrap_or(required),
+        rules,
+    })
+}
@@ -0,0 +1,145 @@
+//
+// Suppression list: the addresses that must not be sent to, kept per tenant
+//
+
+use serde::{Deserialize, Serialize};
+
+use crate::{db, storage, SendError};
+use crate::page::Page;
+
+pub const SCHEMA: &str = "CREATE TABLE IF NOT EXISTS suppressions (
+    tenant TEXT NOT NULL,
+    address TEXT NOT NULL,
+    reason TEXT,
+    created_at INTEGER NOT NULL,
+    PRIMARY KEY (tenant, address)
+);";
+
+#[derive(Deserialize)]
+pub struct SuppressionRequest {
+    // add or remove
+    pub action: String,
+    pub address: String,
+    // "unsubscribed", "complaint", "bounced"
+    reason: Option<String>,
+}
+
+#[derive(Serialize)]
+pub struct Suppression {
+    pub address: String,
+    pub reason: Option<String>,
+    pub created_at: i64,
+    // the instance-wide entries apply to every tenant
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub tenant: Option<String>,
+}
+
+fn db_error(e: impl ToString) -> SendError {
+    SendError::new("internal_error", e.to_string())
+}
+
+fn key(address: &str) -> String {
+    address.trim().to_lowercase()
+}
+
+// Err with the first recipient on the list of the tenant or of the instance;
+// the sends aren't blocked when the database can't be read
+pub fn check(recipients: &[lettre::Address]) -> Result<(), String> {
+
+    let store = match storage::store() {
+        Ok(store) => store,
+        Err(e) => {
+            log!("Suppression check skipped: {}", e);
+            return Ok(());
+        },
+    };
+
+    for recipient in recipients {
+        let reason = match store.suppression(&crate::tenant::key(), &key(recipient.as_ref())) {
+            Ok(reason) => reason,
+            Err(e) => {
+                log!("Suppression check skipped: {}", e);
+                return Ok(());
+            },
+        };
+        if let Some(reason) = reason {
+            // the confirmation of an unsubscribe goes to the address
+            let confirmation = reason.as_deref() == Some(crate::consent::UNSUBSCRIBED)
+                && crate::consent::exempt(&key(recipient.as_ref()));
+            if confirmation {
+                continue;
+            }
+            return Err(match reason {
+                Some(reason) => format!("{} is on the suppression list: {}", recipient, reason),
+                None => format!("{} is on the suppression list", recipient),
+            });
+        }
+    }
+
+    Ok(())
+}
+
+// the list of the tenant of the request, every entry for the instance
+pub fn list(page: &Page) -> Result<(Vec<Suppression>, usize), String> {
+
+    let (limit, offset) = page.sql();
+    storage::store()?.suppressions(&crate::tenant::key(), limit, offset)
+}
+
+fn valid(address: &str) -> Result<String, SendError> {
+    let key = key(address);
+    key.parse::<lettre::Address>()
+        .map_err(|e| SendError::new("invalid_address", format!("Invalid address {:?}: {}", address, e)))?;
+    Ok(key)
+}
+
+// on the list of the tenant of the request, the reason replaced if it was
+// already there; the address as it is stored
+pub fn add(
+    address: &str,
+    reason: &str,
+) -> Result<String, SendError> {
+    let address = valid(address)?;
+    insert(&address, Some(reason))?;
+    Ok(address)
+}
+
+fn insert(
+    address: &str,
+    reason: Option<&str>,
+) -> Result<(), SendError> {
+    storage::store()
+        .and_then(|store| store.suppress(&crate::tenant::key(), address, reason, db::now()))
+        .map_err(db_error)
+}
+
+pub fn handle(request: &SuppressionRequest) -> Result<String, SendError> {
+
+    let address = valid(&request.address)?;
+
+    // the unsubscribes an admin adds or removes are in the consent trail,
+    // without a confirmation
+    let unsubscribed = crate::consent::unsubscribed_already(&address);
+    match request.action.as_str() {
+        "add" => {
+            insert(&address, request.reason.as_deref())?;
+            if request.reason.as_deref() == Some(crate::consent::UNSUBSCRIBED) && !unsubscribed {
+                crate::consent::unsubscribe(&address, "suppressions", None, false);
+            }
+            Ok(format!("{} added to the suppression list", address))
+        },
+        "remove" => {
+            let removed = storage::store()
+                .and_then(|store| store.unsuppress(&crate::tenant::key(), &address))
+                .map_err(db_error)?;
+            if removed && unsubscribed {
+                crate::consent::resubscribe(&address, "suppressions");
+            }
+            match removed {
+                false => Err(SendError::new("not_found", format!("{} is not on the suppression list", address))),
+                true => Ok(format!("{} removed from the suppression list", address)),
+            }
+        },
+        action => Err(SendError::new("invalid_request", format!("Invalid action: {}", action))),
+    }
+}
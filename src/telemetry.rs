@@ -0,0 +1,304 @@
+//
+// OpenTelemetry export (OTLP/HTTP JSON) of spans and counters
+//
+
+use std::cell::RefCell;
+use std::collections::BTreeMap;
+use std::sync::{Mutex, Once};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use once_cell::sync::Lazy;
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+use sha2::{Digest, Sha256};
+
+use crate::SMTP_CLIENT;
+use crate::shutdown;
+
+// spans kept between two exports, the newest are dropped beyond it
+const MAX_PENDING: usize = 2048;
+
+fn default_service_name() -> String {
+    "arp-gmail".to_string()
+}
+
+fn default_interval_secs() -> u64 {
+    10
+}
+
+fn default_timeout() -> u64 {
+    10
+}
+
+#[derive(Clone, Deserialize, Serialize)]
+pub struct TelemetrySettings {
+    // OTLP/HTTP collector: http://127.0.0.1:4318
+    endpoint: String,
+    #[serde(default = "default_service_name")]
+    service_name: String,
+    // seconds between two exports
+    #[serde(default = "default_interval_secs")]
+    interval_secs: u64,
+    #[serde(default = "default_timeout")]
+    timeout_secs: u64,
+}
+
+struct SpanData {
+    trace_id: String,
+    span_id: String,
+    parent_id: Option<String>,
+    name: &'static str,
+    start: u128,
+    end: u128,
+    attributes: Vec<(&'static str, String)>,
+    error: Option<String>,
+}
+
+thread_local! {
+    // open spans of the current thread, the last one is the parent of a new span
+    static STACK: RefCell<Vec<(String, String)>> = const { RefCell::new(Vec::new()) };
+}
+
+static PENDING: Mutex<Vec<SpanData>> = Mutex::new(Vec::new());
+// value of every counter by name and attributes
+type Counters = BTreeMap<(&'static str, Vec<(&'static str, String)>), u64>;
+
+static COUNTERS: Mutex<Counters> = Mutex::new(BTreeMap::new());
+static STARTED: Once = Once::new();
+static START_TIME: Lazy<u128> = Lazy::new(now_nanos);
+
+fn now_nanos() -> u128 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_nanos())
+        .unwrap_or(0)
+}
+
+fn random_hex(bytes: usize) -> String {
+    uuid::Uuid::new_v4()
+        .simple()
+        .to_string()[..bytes * 2]
+        .to_string()
+}
+
+// the trace of a request is derived from its request id
+fn trace_id() -> String {
+    match crate::trace::current() {
+        Some(id) => format!("{:x}", Sha256::digest(id.as_bytes()))[..32].to_string(),
+        None => random_hex(16),
+    }
+}
+
+pub struct Span {
+    data: Option<SpanData>,
+}
+
+// the span ends when it is dropped
+pub fn span(name: &'static str) -> Span {
+
+    if SMTP_CLIENT.telemetry.is_none() {
+        return Span { data: None };
+    }
+
+    let parent = STACK.with(|stack| stack.borrow().last().cloned());
+    let (trace_id, parent_id) = match parent {
+        Some((trace_id, span_id)) => (trace_id, Some(span_id)),
+        None => (trace_id(), None),
+    };
+    let span_id = random_hex(8);
+    STACK.with(|stack| stack.borrow_mut().push((trace_id.clone(), span_id.clone())));
+
+    Span {
+        data: Some(SpanData {
+            trace_id,
+            span_id,
+            parent_id,
+            name,
+            start: now_nanos(),
+            end: 0,
+            attributes: Vec::new(),
+            error: None,
+        }),
+    }
+}
+
+impl Span {
+    pub fn attribute(
+        &mut self,
+        key: &'static str,
+        value: impl ToString,
+    ) {
+        if let Some(data) = &mut self.data {
+            data.attributes.push((key, value.to_string()));
+        }
+    }
+
+    pub fn error(
+        &mut self,
+        message: impl ToString,
+    ) {
+        if let Some(data) = &mut self.data {
+            data.error = Some(message.to_string());
+        }
+    }
+}
+
+impl Drop for Span {
+    fn drop(&mut self) {
+        let mut data = match self.data.take() {
+            Some(data) => data,
+            None => return,
+        };
+        STACK.with(|stack| stack.borrow_mut().pop());
+        data.end = now_nanos();
+        if let Ok(mut pending) = PENDING.lock() {
+            if pending.len() < MAX_PENDING {
+                pending.push(data);
+            }
+        }
+    }
+}
+
+// add one to a counter, exported as a cumulative sum
+pub fn count(
+    name: &'static str,
+    attributes: &[(&'static str, &str)],
+) {
+    if SMTP_CLIENT.telemetry.is_none() {
+        return;
+    }
+    let attributes = attributes.iter()
+        .map(|(key, value)| (*key, value.to_string()))
+        .collect();
+    if let Ok(mut counters) = COUNTERS.lock() {
+        *counters.entry((name, attributes)).or_insert(0) += 1;
+    }
+}
+
+fn attributes(list: &[(&'static str, String)]) -> Vec<Value> {
+    list.iter()
+        .map(|(key, value)| json!({ "key": key, "value": { "stringValue": value } }))
+        .collect()
+}
+
+fn resource(settings: &TelemetrySettings) -> Value {
+    json!({
+        "attributes": attributes(&[("service.name", settings.service_name.clone())]),
+    })
+}
+
+fn spans_payload(
+    settings: &TelemetrySettings,
+    spans: &[SpanData],
+) -> Value {
+
+    let spans: Vec<Value> = spans.iter()
+        .map(|span| json!({
+            "traceId": span.trace_id,
+            "spanId": span.span_id,
+            "parentSpanId": span.parent_id.clone().unwrap_or_default(),
+            "name": span.name,
+            // internal
+            "kind": 1,
+            "startTimeUnixNano": span.start.to_string(),
+            "endTimeUnixNano": span.end.to_string(),
+            "attributes": attributes(&span.attributes),
+            "status": match &span.error {
+                Some(message) => json!({ "code": 2, "message": message }),
+                None => json!({ "code": 1 }),
+            },
+        }))
+        .collect();
+
+    json!({
+        "resourceSpans": [{
+            "resource": resource(settings),
+            "scopeSpans": [{ "scope": { "name": "arp-gmail" }, "spans": spans }],
+        }],
+    })
+}
+
+fn metrics_payload(settings: &TelemetrySettings) -> Option<Value> {
+
+    let counters = COUNTERS.lock().ok()?.clone();
+    if counters.is_empty() {
+        return None;
+    }
+
+    let mut metrics: BTreeMap<&'static str, Vec<Value>> = BTreeMap::new();
+    for ((name, list), value) in counters {
+        metrics.entry(name).or_default().push(json!({
+            "attributes": attributes(&list),
+            "startTimeUnixNano": START_TIME.to_string(),
+            "timeUnixNano": now_nanos().to_string(),
+            "asInt": value.to_string(),
+        }));
+    }
+
+    let metrics: Vec<Value> = metrics.into_iter()
+        .map(|(name, points)| json!({
+            "name": name,
+            // cumulative
+            "sum": { "aggregationTemporality": 2, "isMonotonic": true, "dataPoints": points },
+        }))
+        .collect();
+
+    Some(json!({
+        "resourceMetrics": [{
+            "resource": resource(settings),
+            "scopeMetrics": [{ "scope": { "name": "arp-gmail" }, "metrics": metrics }],
+        }],
+    }))
+}
+
+fn post(
+    settings: &TelemetrySettings,
+    path: &str,
+    payload: Value,
+) {
+    let url = format!("{}{}", settings.endpoint.trim_end_matches('/'), path);
+    if let Err(e) = ureq::post(&url)
+        .timeout(Duration::from_secs(settings.timeout_secs))
+        .send_json(payload) {
+        log!("Error exporting telemetry to {}: {}", url, e);
+    }
+}
+
+fn export(settings: &TelemetrySettings) {
+
+    let spans = match PENDING.lock() {
+        Ok(mut pending) => std::mem::take(&mut *pending),
+        Err(_) => Vec::new(),
+    };
+    if !spans.is_empty() {
+        post(settings, "/v1/traces", spans_payload(settings, &spans));
+    }
+
+    if let Some(payload) = metrics_payload(settings) {
+        post(settings, "/v1/metrics", payload);
+    }
+}
+
+// safe to call more than once, the exporter is only started the first time
+pub fn start() {
+
+    let settings = match &SMTP_CLIENT.telemetry {
+        Some(settings) => settings.clone(),
+        None => return,
+    };
+
+    STARTED.call_once(|| {
+        Lazy::force(&START_TIME);
+        let result = std::thread::Builder::new()
+            .name("arp-gmail-telemetry".to_string())
+            .spawn(move || {
+                while !shutdown::stopping() {
+                    shutdown::sleep(Duration::from_secs(settings.interval_secs.max(1)));
+                    export(&settings);
+                }
+            });
+        match result {
+            Ok(handle) => shutdown::register("telemetry", handle),
+            Err(e) => log!("Error starting the telemetry exporter: {}", e),
+        }
+    });
+}
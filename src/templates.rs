@@ -0,0 +1,496 @@
+//
+// Message templates stored on the plugin host, managed at runtime and
+// rendered with minijinja
+//
+
+use std::path::{Path, PathBuf};
+use minijinja::{Environment, ErrorKind, UndefinedBehavior};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+
+use crate::{Mail, SendError, SMTP_CLIENT};
+
+const DEFAULT_DIR: &str = "templates/mail";
+const MAX_SIZE: usize = 1024 * 1024;
+
+#[derive(Deserialize)]
+pub struct TemplateRequest {
+    // upload, validate or delete
+    pub action: String,
+    // file name with its extension: "welcome.html"
+    pub name: String,
+    content: Option<String>,
+    // sample data rendered by validate
+    #[serde(default)]
+    data: Value,
+    // "strict" or "lenient" missing variables in the validate
+    template_variables: Option<String>,
+}
+
+#[derive(Serialize)]
+pub struct Template {
+    name: String,
+    size: u64,
+    modified: i64,
+}
+
+fn io_error(e: impl ToString) -> SendError {
+    SendError::new("internal_error", e.to_string())
+}
+
+fn render_error(e: minijinja::Error) -> SendError {
+    SendError::new("template_error", format!("Template error: {}", e))
+}
+
+// a variable path of the data, "user.name"
+fn lookup<'a>(
+    data: &'a Value,
+    path: &str,
+) -> Option<&'a Value> {
+    path.split('.')
+        .try_fold(data, |value, key| value.get(key))
+}
+
+// the undefined variable of a strict render is named, "Dear ," isn't sent:
+// the expression of the error if it is one of the missing variables of its
+// template, else all of them
+fn undefined(
+    env: &Environment,
+    e: &minijinja::Error,
+    data: &Value,
+) -> Option<SendError> {
+
+    // the error of an include or a layout is wrapped in the one of the template
+    let mut e = e;
+    while let Some(inner) = std::error::Error::source(e).and_then(|inner| inner.downcast_ref::<minijinja::Error>()) {
+        e = inner;
+    }
+    if e.kind() != ErrorKind::UndefinedError {
+        return None;
+    }
+
+    let expression = e.template_source()
+        .zip(e.range())
+        .and_then(|(source, range)| source.get(range))
+        .map(str::trim);
+    let mut missing: Vec<String> = e.name()
+        .and_then(|name| env.get_template(name).ok())
+        .map(|template| template.undeclared_variables(true))
+        .unwrap_or_default()
+        .into_iter()
+        .filter(|variable| lookup(data, variable).is_none())
+        .filter(|variable| {
+            let root = variable.split('.').next().unwrap_or_default();
+            !env.globals().any(|(global, _)| global == root)
+        })
+        .collect();
+    missing.sort();
+
+    let variable = match expression {
+        Some(expression) if missing.is_empty() || missing.iter().any(|variable| variable == expression) => expression.to_string(),
+        _ if !missing.is_empty() => missing.join(", "),
+        _ => return None,
+    };
+
+    Some(SendError::new("template_error", format!(
+        "Template error: undefined variable {} (in {}:{})",
+        variable,
+        e.name().unwrap_or("template"),
+        e.line().unwrap_or(0),
+    )))
+}
+
+fn render(
+    env: &Environment,
+    name: &str,
+    data: &Value,
+) -> Result<String, SendError> {
+    env.get_template(name)
+        .and_then(|template| template.render(data))
+        .map_err(|e| undefined(env, &e, data).unwrap_or_else(|| render_error(e)))
+}
+
+// a tenant only sees its own directory
+pub fn dir() -> Result<PathBuf, SendError> {
+
+    let absolute = |dir: &str| -> Result<PathBuf, SendError> {
+        Ok(match Path::new(dir).is_absolute() {
+            true => PathBuf::from(dir),
+            false => crate::plugin_path()
+                .map_err(io_error)?
+                .join(dir),
+        })
+    };
+    let instance = absolute(SMTP_CLIENT.templates_dir.as_deref().unwrap_or(DEFAULT_DIR))?;
+    let dir = match crate::tenant::settings() {
+        Some(tenant) => match &tenant.templates_dir {
+            Some(dir) => absolute(dir)?,
+            None if tenant.name.starts_with('.') || tenant.name.contains(['/', '\\']) => {
+                return Err(io_error(format!("Invalid tenant name for a templates directory: {:?}", tenant.name)));
+            },
+            None => instance.join(&tenant.name),
+        },
+        None => instance,
+    };
+    std::fs::create_dir_all(&dir)
+        .map_err(|e| io_error(format!("Templates directory {}: {}", dir.display(), e)))?;
+
+    Ok(dir)
+}
+
+// a relative path of plain names, "layouts/base.html", the templates can't be
+// written outside the directory
+fn check_name(
+    name: &str,
+    extension: bool,
+) -> Result<(), SendError> {
+
+    let valid = name.split('/').all(|segment| {
+        !segment.is_empty()
+            && !segment.starts_with('.')
+            && segment.chars().all(|c| c.is_ascii_alphanumeric() || "._-".contains(c))
+    });
+    match valid && (!extension || name.ends_with(".html") || name.ends_with(".txt")) {
+        true => Ok(()),
+        false => Err(SendError::new("invalid_request", format!(
+            "Invalid template name {:?}: letters, digits, \".\", \"_\", \"-\" and \"/\", ending in .html or .txt",
+            name,
+        ))),
+    }
+}
+
+// the templates of the directory, a template can {% extends "layouts/base.html" %}
+// and {% include "partials/footer.html" %}; the .html ones are auto-escaped
+// with "strict" a missing variable fails the render, with "lenient" it is
+// an empty string; it can still be tested with {% if name %}
+fn environment(
+    dir: &Path,
+    variables: Option<&str>,
+) -> Result<Environment<'static>, SendError> {
+
+    let behavior = match variables.or(SMTP_CLIENT.template_variables.as_deref()).unwrap_or("lenient") {
+        "strict" => UndefinedBehavior::SemiStrict,
+        "lenient" => UndefinedBehavior::Chainable,
+        other => return Err(SendError::new(
+            "invalid_request",
+            format!("Invalid template_variables {:?}: \"strict\" or \"lenient\"", other),
+        )),
+    };
+
+    let mut env = Environment::new();
+    env.set_loader(minijinja::path_loader(dir));
+    env.set_undefined_behavior(behavior);
+    // the source of the errors, to name the undefined variable
+    env.set_debug(true);
+
+    Ok(env)
+}
+
+// the templates of a directory and of its subdirectories, the layouts and
+// the partials
+fn walk(
+    root: &Path,
+    dir: &Path,
+    templates: &mut Vec<Template>,
+) -> Result<(), SendError> {
+
+    for entry in std::fs::read_dir(dir).map_err(io_error)? {
+        let entry = entry.map_err(io_error)?;
+        let metadata = entry.metadata().map_err(io_error)?;
+        let path = entry.path();
+        let name = path.strip_prefix(root)
+            .map(|name| name.to_string_lossy().replace('\\', "/"))
+            .unwrap_or_default();
+        if metadata.is_dir() && check_name(&name, false).is_ok() {
+            walk(root, &path, templates)?;
+            continue;
+        }
+        if !metadata.is_file() || check_name(&name, true).is_err() {
+            continue;
+        }
+        let modified = metadata.modified()
+            .ok()
+            .and_then(|time| time.duration_since(std::time::UNIX_EPOCH).ok())
+            .map(|time| time.as_secs() as i64)
+            .unwrap_or(0);
+        templates.push(Template {
+            name,
+            size: metadata.len(),
+            modified,
+        });
+    }
+
+    Ok(())
+}
+
+pub fn list() -> Result<Vec<Template>, SendError> {
+
+    let dir = dir()?;
+    let mut templates = Vec::new();
+    walk(&dir, &dir, &mut templates)?;
+    templates.sort_by(|a, b| a.name.cmp(&b.name));
+
+    Ok(templates)
+}
+
+pub fn source(name: &str) -> Result<String, SendError> {
+
+    check_name(name, true)?;
+    match std::fs::read_to_string(dir()?.join(name)) {
+        Ok(source) => Ok(source),
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => Err(SendError::new(
+            "not_found",
+            format!("No template {}", name),
+        )),
+        Err(e) => Err(io_error(e)),
+    }
+}
+
+// written to a temporary file renamed over the old one, a send never reads
+// half a template
+fn upload(
+    name: &str,
+    content: &str,
+) -> Result<(), SendError> {
+
+    if content.len() > MAX_SIZE {
+        return Err(SendError::new("invalid_request", format!("The template is over {} bytes", MAX_SIZE)));
+    }
+
+    let dir = dir()?;
+    let mut env = environment(&dir, None)?;
+    env.add_template_owned(name.to_string(), content.to_string())
+        .map_err(render_error)?;
+
+    let path = dir.join(name);
+    let parent = path.parent().unwrap_or(&dir);
+    std::fs::create_dir_all(parent).map_err(io_error)?;
+    let file_name = path.file_name().unwrap_or_default().to_string_lossy();
+    let temporary = parent.join(format!(".{}.{}.tmp", file_name, uuid::Uuid::new_v4().simple()));
+    std::fs::write(&temporary, content)
+        .and_then(|_| std::fs::rename(&temporary, &path))
+        .map_err(|e| {
+            let _ = std::fs::remove_file(&temporary);
+            io_error(e)
+        })
+}
+
+// the stored template, or the content of the request before it is uploaded
+fn validate(
+    request: &TemplateRequest,
+) -> Result<String, SendError> {
+
+    let (name, content) = (request.name.as_str(), request.content.as_deref());
+    let mut env = environment(&dir()?, request.template_variables.as_deref())?;
+    if let Some(content) = content {
+        env.add_template_owned(name.to_string(), content.to_string())
+            .map_err(render_error)?;
+    }
+
+    let rendered = render(&env, name, &request.data)?;
+
+    // the preview of what is sent
+    let source = match content {
+        Some(content) => content.to_string(),
+        None => source(name).unwrap_or_default(),
+    };
+    match name.ends_with(".html") && inlined(None, &source) {
+        true => inline_css(&rendered),
+        false => Ok(rendered),
+    }
+}
+
+// the message of an upload or a delete, the rendered output of a validate
+pub fn handle(request: &TemplateRequest) -> Result<(String, Option<String>), SendError> {
+
+    check_name(&request.name, true)?;
+
+    match request.action.as_str() {
+        "upload" => {
+            let content = request.content.as_deref()
+                .ok_or(SendError::new("invalid_request", "No content"))?;
+            upload(&request.name, content)?;
+            Ok((format!("Template {} saved", request.name), None))
+        },
+        "validate" => {
+            let rendered = validate(request)?;
+            Ok((format!("Template {} is valid", request.name), Some(rendered)))
+        },
+        "delete" => match std::fs::remove_file(dir()?.join(&request.name)) {
+            Ok(()) => Ok((format!("Template {} deleted", request.name), None)),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Err(SendError::new(
+                "not_found",
+                format!("No template {}", request.name),
+            )),
+            Err(e) => Err(io_error(e)),
+        },
+        action => Err(SendError::new("invalid_request", format!("Invalid action: {}", action))),
+    }
+}
+
+// readable text of an html body, for the text part of the message
+fn html_to_text(html: &str) -> String {
+
+    let mut text = String::with_capacity(html.len());
+    let mut rest = html;
+    while let Some(start) = rest.find('<') {
+        text.push_str(&rest[..start]);
+        let end = match rest[start..].find('>') {
+            Some(end) => start + end + 1,
+            None => break,
+        };
+        let tag = rest[start + 1..end - 1].trim_start_matches('/').to_ascii_lowercase();
+        let tag = tag.split(|c: char| c.is_whitespace() || c == '/').next().unwrap_or("");
+        rest = &rest[end..];
+        match tag {
+            // their content isn't text
+            "style" | "script" | "head" => {
+                if let Some(close) = rest.to_ascii_lowercase().find(&format!("</{}", tag)) {
+                    rest = &rest[close..];
+                }
+            },
+            "br" | "p" | "div" | "tr" | "li" | "h1" | "h2" | "h3" | "h4" | "h5" | "h6" | "table" => text.push('\n'),
+            _ => {},
+        }
+    }
+    if !rest.contains('<') {
+        text.push_str(rest);
+    }
+
+    let text = text.replace("&nbsp;", " ")
+        .replace("&lt;", "<")
+        .replace("&gt;", ">")
+        .replace("&quot;", "\"")
+        .replace("&#39;", "'")
+        .replace("&#x27;", "'")
+        .replace("&amp;", "&");
+
+    // one blank line at most between paragraphs
+    let mut lines: Vec<&str> = Vec::new();
+    for line in text.lines().map(str::trim) {
+        if line.is_empty() && lines.last().is_none_or(|last| last.is_empty()) {
+            continue;
+        }
+        lines.push(line);
+    }
+
+    lines.join("\n").trim().to_string()
+}
+
+// "pt-BR", "pt", the locale_fallback of the config and "" for the template
+// without a locale
+fn locales(locale: Option<&str>) -> Result<Vec<String>, SendError> {
+
+    let mut chain = Vec::new();
+    if let Some(locale) = locale.map(str::trim).filter(|locale| !locale.is_empty()) {
+        if !locale.chars().all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_') {
+            return Err(SendError::new("invalid_request", format!("Invalid locale: {:?}", locale)));
+        }
+        chain.push(locale.to_string());
+        if let Some((language, _)) = locale.split_once(['-', '_']) {
+            chain.push(language.to_string());
+        }
+    }
+    for locale in SMTP_CLIENT.locale_fallback.iter().flatten() {
+        chain.push(locale.clone());
+    }
+    chain.push(String::new());
+    let mut seen = std::collections::HashSet::new();
+    chain.retain(|locale| seen.insert(locale.to_ascii_lowercase()));
+
+    Ok(chain)
+}
+
+// {# inline_css: false #} at the top of a template overrides the config
+fn inline_marker(source: &str) -> Option<bool> {
+
+    let comment = source.trim_start()
+        .strip_prefix("{#")?
+        .split_once("#}")?
+        .0
+        .trim_matches(|c: char| c == '-' || c.is_whitespace());
+    match comment.strip_prefix("inline_css:")?.trim() {
+        "true" => Some(true),
+        "false" => Some(false),
+        _ => None,
+    }
+}
+
+// the choice of the request, of the template and of the config
+fn inlined(
+    request: Option<bool>,
+    source: &str,
+) -> bool {
+    request
+        .or_else(|| inline_marker(source))
+        .or(SMTP_CLIENT.inline_css)
+        .unwrap_or(false)
+}
+
+// the css of the <style> blocks moved to the style attributes of the elements,
+// Gmail strips the blocks in some contexts; they are kept for the @media rules
+fn inline_css(html: &str) -> Result<String, SendError> {
+    css_inline::CSSInliner::options()
+        .keep_style_tags(true)
+        .load_remote_stylesheets(false)
+        .build()
+        .inline(html)
+        .map_err(|e| SendError::new("template_error", format!("CSS inlining failed: {}", e)))
+}
+
+// the template of a request rendered into its subject, text and html: the
+// name.html and name.txt variants of its locale, the text made from the html
+// if there is no .txt
+pub fn render_mail(mail: &mut Mail) -> Result<(), SendError> {
+
+    let name = match mail.template.take() {
+        Some(name) => name,
+        None => return Ok(()),
+    };
+    check_name(&name, false)?;
+    if !mail.message.is_empty() || mail.message_file.is_some() || mail.snippet.is_some() {
+        return Err(SendError::new(
+            "invalid_message",
+            "Only one of message, message_file, snippet or template can be set",
+        ));
+    }
+
+    let data = mail.data.take().unwrap_or(Value::Null);
+    let dir = dir()?;
+    let mut env = environment(&dir, mail.template_variables.as_deref())?;
+    // a template name without an extension, it can't be a file
+    env.add_template_owned("subject".to_string(), mail.subject.clone())
+        .map_err(render_error)?;
+    let variant_of = |file: String| match dir.join(&file).is_file() {
+        true => render(&env, &file, &data).map(Some),
+        false => Ok(None),
+    };
+
+    // the variant of the locale, else the first found in the fallback chain
+    let variant = locales(mail.locale.as_deref())?.iter()
+        .map(|locale| match locale.is_empty() {
+            true => name.clone(),
+            false => format!("{}.{}", name, locale),
+        })
+        .find(|base| dir.join(format!("{}.html", base)).is_file() || dir.join(format!("{}.txt", base)).is_file())
+        .unwrap_or(name.clone());
+
+    let mut html = variant_of(format!("{}.html", variant))?;
+    let text = variant_of(format!("{}.txt", variant))?;
+    if let Some(rendered) = &html {
+        let source = std::fs::read_to_string(dir.join(format!("{}.html", variant)))
+            .unwrap_or_default();
+        if inlined(mail.inline_css, &source) {
+            html = Some(inline_css(rendered)?);
+        }
+    }
+    mail.message = match (&text, &html) {
+        (Some(text), _) => text.clone(),
+        (None, Some(html)) => html_to_text(html),
+        (None, None) => return Err(SendError::new("not_found", format!("No template {}.html or {}.txt", name, name))),
+    };
+    mail.html = html;
+    mail.subject = render(&env, "subject", &data)?;
+
+    Ok(())
+}
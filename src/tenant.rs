@@ -0,0 +1,103 @@
+//
+// Tenants: the products sharing the plugin, each with its api keys, accounts,
+// templates, limits, history and suppression list
+//
+
+use std::cell::RefCell;
+use serde::{Deserialize, Serialize};
+
+use crate::{SendError, SMTP_CLIENT};
+
+#[derive(Clone, Deserialize, Serialize)]
+pub struct TenantSettings {
+    pub name: String,
+    // the accounts the tenant can send from, the first one when a request
+    // doesn't name one; a tenant without accounts can't send
+    #[serde(default)]
+    pub accounts: Vec<String>,
+    // the <templates_dir>/<name> directory if not set
+    pub templates_dir: Option<String>,
+    // the recipient_limits of the config if not set
+    pub recipient_limits: Option<crate::ratelimit::RecipientLimits>,
+}
+
+thread_local! {
+    static CURRENT: RefCell<Option<String>> = const { RefCell::new(None) };
+}
+
+// the tenant of the current thread is restored when the scope is dropped
+pub struct Scope(Option<String>);
+
+impl Drop for Scope {
+    fn drop(&mut self) {
+        let previous = self.0.take();
+        CURRENT.with(|current| *current.borrow_mut() = previous);
+    }
+}
+
+pub fn enter(tenant: Option<String>) -> Scope {
+    Scope(CURRENT.with(|current| current.replace(tenant)))
+}
+
+// set by the api key of the request, for the rest of its handler
+pub fn set(tenant: Option<String>) {
+    CURRENT.with(|current| *current.borrow_mut() = tenant);
+}
+
+pub fn current() -> Option<String> {
+    CURRENT.with(|current| current.borrow().clone())
+}
+
+// the partition of the stored rows, empty for the instance
+pub fn key() -> String {
+    current().unwrap_or_default()
+}
+
+pub fn find(name: &str) -> Option<&'static TenantSettings> {
+    SMTP_CLIENT.tenants
+        .iter()
+        .flatten()
+        .find(|tenant| tenant.name == name)
+}
+
+pub fn settings() -> Option<&'static TenantSettings> {
+    find(&current()?)
+}
+
+// the routes of the whole instance are refused to the keys of a tenant
+pub fn instance_only() -> Result<(), SendError> {
+    match current() {
+        Some(tenant) => Err(SendError::new(
+            "forbidden",
+            format!("The route is for the whole instance, not for tenant {}", tenant),
+        )),
+        None => Ok(()),
+    }
+}
+
+// the account of a send: the one of the request, which must be one of the
+// tenant, or the first account of the tenant; a tenant without accounts
+// can't send from those of the instance
+pub fn account(requested: Option<&str>) -> Result<Option<String>, SendError> {
+
+    let tenant = match settings() {
+        Some(tenant) => tenant,
+        None => return Ok(requested.map(str::to_string)),
+    };
+
+    if tenant.accounts.is_empty() {
+        return Err(SendError::new(
+            "forbidden",
+            format!("Tenant {} has no accounts to send from", tenant.name),
+        ));
+    }
+
+    match requested {
+        Some(name) if !tenant.accounts.iter().any(|account| account == name) => Err(SendError::new(
+            "invalid_request",
+            format!("The account {} isn't one of tenant {}", name, tenant.name),
+        )),
+        Some(name) => Ok(Some(name.to_string())),
+        None => Ok(tenant.accounts.first().cloned()),
+    }
+}
@@ -0,0 +1,228 @@
+//
+// TLS policy of the connections to the SMTP servers: the minimum version, the
+// certificates trusted to sign the one of the server and the pins of its public
+// key, checked before the credentials are sent. The ciphers are those of the
+// OpenSSL of the host, native-tls doesn't choose them. The IMAP connections
+// have the same version and roots, the pins are of the SMTP servers
+//
+
+use base64::Engine;
+use lettre::transport::smtp::client::{Certificate, CertificateStore, SmtpConnection, TlsParameters, TlsVersion};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+
+use crate::SMTP_CLIENT;
+
+const PIN_PREFIX: &str = "sha256/";
+
+fn default_system_roots() -> bool {
+    true
+}
+
+#[derive(Clone, Deserialize, Serialize)]
+pub struct TlsSettings {
+    // "1.0", "1.1" or "1.2" (the default)
+    pub min_version: Option<String>,
+    // PEM files of the certificate authorities trusted for the servers,
+    // relative to the plugin directory
+    #[serde(default)]
+    pub roots: Vec<String>,
+    // false trusts only "roots"
+    #[serde(default = "default_system_roots")]
+    pub system_roots: bool,
+    // "sha256/<base64>" of the public key of the certificate of the server,
+    // any one of them matches
+    #[serde(default)]
+    pub spki_pins: Vec<String>,
+}
+
+pub fn settings() -> Option<&'static TlsSettings> {
+    SMTP_CLIENT.tls.as_ref()
+}
+
+pub fn version(version: &str) -> Result<TlsVersion, String> {
+    match version {
+        "1.0" => Ok(TlsVersion::Tlsv10),
+        "1.1" => Ok(TlsVersion::Tlsv11),
+        "1.2" => Ok(TlsVersion::Tlsv12),
+        "1.3" => Err("TLS 1.3 can't be the minimum with the native-tls backend of the plugin".to_string()),
+        version => Err(format!("Unknown TLS version {:?}, expected 1.0, 1.1 or 1.2", version)),
+    }
+}
+
+fn pem(path: &str) -> Result<(std::path::PathBuf, Vec<u8>), String> {
+
+    let file = match std::path::Path::new(path).is_absolute() {
+        true => std::path::PathBuf::from(path),
+        false => crate::plugin_path()
+            .map_err(|e| e.to_string())?
+            .join(path),
+    };
+    let pem = std::fs::read(&file)
+        .map_err(|e| format!("{}: {}", file.display(), e))?;
+
+    Ok((file, pem))
+}
+
+pub fn root(path: &str) -> Result<Certificate, String> {
+    let (file, pem) = pem(path)?;
+    Certificate::from_pem(&pem)
+        .map_err(|e| format!("{}: {}", file.display(), e))
+}
+
+// the 32 bytes of a pin
+pub fn pin(pin: &str) -> Result<Vec<u8>, String> {
+    pin.strip_prefix(PIN_PREFIX)
+        .and_then(|digest| base64::engine::general_purpose::STANDARD.decode(digest).ok())
+        .filter(|digest| digest.len() == 32)
+        .ok_or(format!("Invalid pin {:?}, expected sha256/ and the base64 of a SHA-256 digest", pin))
+}
+
+// the parameters of the policy for the name of the server
+pub fn parameters(server: &str) -> Result<TlsParameters, String> {
+
+    let Some(settings) = settings() else {
+        return TlsParameters::new(server.to_string())
+            .map_err(|e| e.to_string());
+    };
+
+    let mut builder = TlsParameters::builder(server.to_string())
+        .set_min_tls_version(version(settings.min_version.as_deref().unwrap_or("1.2"))?);
+    if !settings.system_roots {
+        builder = builder.certificate_store(CertificateStore::None);
+    }
+    for path in &settings.roots {
+        builder = builder.add_root_certificate(root(path)?);
+    }
+
+    builder.build()
+        .map_err(|e| e.to_string())
+}
+
+// the connector of the IMAP connections, with the version and roots of the policy
+pub fn connector() -> Result<native_tls::TlsConnector, String> {
+
+    let mut builder = native_tls::TlsConnector::builder();
+    builder.min_protocol_version(Some(native_tls::Protocol::Tlsv12));
+
+    if let Some(settings) = settings() {
+        let version = match version(settings.min_version.as_deref().unwrap_or("1.2"))? {
+            TlsVersion::Tlsv10 => native_tls::Protocol::Tlsv10,
+            TlsVersion::Tlsv11 => native_tls::Protocol::Tlsv11,
+            _ => native_tls::Protocol::Tlsv12,
+        };
+        builder.min_protocol_version(Some(version));
+        builder.disable_built_in_roots(!settings.system_roots);
+        for path in &settings.roots {
+            let (file, pem) = pem(path)?;
+            let certificate = native_tls::Certificate::from_pem(&pem)
+                .map_err(|e| format!("{}: {}", file.display(), e))?;
+            builder.add_root_certificate(certificate);
+        }
+    }
+
+    builder.build()
+        .map_err(|e| e.to_string())
+}
+
+// the policy in the errors of a handshake
+pub fn describe() -> String {
+
+    let Some(settings) = settings() else {
+        return "the default TLS policy".to_string();
+    };
+
+    let mut policy = vec![format!("minimum TLS {}", settings.min_version.as_deref().unwrap_or("1.2"))];
+    match (settings.system_roots, settings.roots.len()) {
+        (true, 0) => {},
+        (true, roots) => policy.push(format!("the system roots and {} more", roots)),
+        (false, roots) => policy.push(format!("only {} roots", roots)),
+    }
+    if !settings.spki_pins.is_empty() {
+        policy.push(format!("{} pinned keys", settings.spki_pins.len()));
+    }
+
+    policy.join(", ")
+}
+
+// the DER SubjectPublicKeyInfo of an X.509 certificate
+fn spki(certificate: &[u8]) -> Result<Vec<u8>, openssl::error::ErrorStack> {
+    openssl::x509::X509::from_der(certificate)?
+        .public_key()?
+        .public_key_to_der()
+}
+
+// the pin of the key of the server, checked against the pins of the policy
+pub fn check_pins(connection: &SmtpConnection) -> Result<(), String> {
+
+    let Some(settings) = settings().filter(|settings| !settings.spki_pins.is_empty()) else {
+        return Ok(());
+    };
+
+    let certificate = connection.peer_certificate()
+        .map_err(|e| format!("the certificate of the server can't be read: {}", e))?;
+    pinned(&settings.spki_pins, &certificate)
+}
+
+fn pinned(
+    pins: &[String],
+    certificate: &[u8],
+) -> Result<(), String> {
+
+    let key = spki(certificate)
+        .map_err(|e| format!("the public key of the certificate of the server can't be read: {}", e))?;
+    let digest = Sha256::digest(key);
+
+    match pins.iter().any(|expected| pin(expected).is_ok_and(|expected| expected == digest.as_slice())) {
+        true => Ok(()),
+        false => Err(format!(
+            "the key of the server, {}{}, matches none of the pins",
+            PIN_PREFIX,
+            base64::engine::general_purpose::STANDARD.encode(digest),
+        )),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use openssl::asn1::Asn1Time;
+    use openssl::hash::MessageDigest;
+    use openssl::pkey::{PKey, Private};
+    use openssl::x509::{X509, X509NameBuilder};
+
+    fn certificate(key: &PKey<Private>) -> Vec<u8> {
+        let mut name = X509NameBuilder::new().unwrap();
+        name.append_entry_by_text("CN", "smtp.example.com").unwrap();
+        let name = name.build();
+        let mut builder = X509::builder().unwrap();
+        builder.set_version(2).unwrap();
+        builder.set_subject_name(&name).unwrap();
+        builder.set_issuer_name(&name).unwrap();
+        builder.set_pubkey(key).unwrap();
+        builder.set_not_before(&Asn1Time::days_from_now(0).unwrap()).unwrap();
+        builder.set_not_after(&Asn1Time::days_from_now(1).unwrap()).unwrap();
+        builder.sign(key, MessageDigest::sha256()).unwrap();
+        builder.build().to_der().unwrap()
+    }
+
+    fn key_pin(key: &PKey<Private>) -> String {
+        let digest = Sha256::digest(key.public_key_to_der().unwrap());
+        format!("{}{}", PIN_PREFIX, base64::engine::general_purpose::STANDARD.encode(digest))
+    }
+
+    #[test]
+    fn pinned_keys() {
+        let key = PKey::from_ec_key(openssl::ec::EcKey::generate(
+            &openssl::ec::EcGroup::from_curve_name(openssl::nid::Nid::X9_62_PRIME256V1).unwrap(),
+        ).unwrap()).unwrap();
+        let other = PKey::from_rsa(openssl::rsa::Rsa::generate(2048).unwrap()).unwrap();
+        let der = certificate(&key);
+
+        assert_eq!(pinned(&[key_pin(&other), key_pin(&key)], &der), Ok(()));
+        let refused = pinned(&[key_pin(&other)], &der).unwrap_err();
+        assert!(refused.contains(&key_pin(&key)), "{}", refused);
+        assert!(pinned(&[key_pin(&other)], &certificate(&other)).is_ok());
+        assert!(pinned(&[key_pin(&key)], b"not a certificate").is_err());
+    }
+}
@@ -0,0 +1,38 @@
+//
+// Correlation id of the request being handled, used in the logs and callbacks
+//
+
+use std::cell::RefCell;
+use hyper::HeaderMap;
+
+thread_local! {
+    static CURRENT: RefCell<Option<String>> = const { RefCell::new(None) };
+}
+
+// the id of the current thread is restored when the scope is dropped
+pub struct Scope(Option<String>);
+
+impl Drop for Scope {
+    fn drop(&mut self) {
+        let previous = self.0.take();
+        CURRENT.with(|current| *current.borrow_mut() = previous);
+    }
+}
+
+pub fn enter(id: Option<String>) -> Scope {
+    Scope(CURRENT.with(|current| current.replace(id)))
+}
+
+pub fn current() -> Option<String> {
+    CURRENT.with(|current| current.borrow().clone())
+}
+
+// the X-Request-Id of the caller, or a new one
+pub fn request_id(headers: &HeaderMap) -> String {
+    headers.get("x-request-id")
+        .and_then(|value| value.to_str().ok())
+        .map(|value| value.trim())
+        .filter(|value| !value.is_empty() && value.len() <= 200)
+        .map(|value| value.to_string())
+        .unwrap_or_else(|| uuid::Uuid::new_v4().to_string())
+}
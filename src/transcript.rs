@@ -0,0 +1,144 @@
+//
+// SMTP dialog of a send made with "debug": true, returned with its response
+// so a rejection can be read without a packet capture. The credentials of
+// AUTH are never written
+//
+
+use std::sync::{Arc, Mutex};
+use std::time::Instant;
+use lettre::address::Envelope;
+use lettre::transport::smtp::commands::{Data, Mail, Quit, Rcpt};
+use lettre::transport::smtp::extension::{Extension, MailBodyParameter, MailParameter};
+use lettre::transport::smtp::response::Response;
+use lettre::transport::smtp;
+use serde::Serialize;
+
+use crate::pool::{self, Failure};
+use crate::transport::Gmail;
+
+#[derive(Clone, Serialize)]
+pub struct Line {
+    // since the start of the send
+    ms: u128,
+    // "client", "server" or "note" for a step without a line of its own
+    from: &'static str,
+    text: String,
+}
+
+#[derive(Clone)]
+pub struct Transcript {
+    started: Instant,
+    lines: Arc<Mutex<Vec<Line>>>,
+}
+
+impl Transcript {
+    pub fn new() -> Self {
+        Transcript {
+            started: Instant::now(),
+            lines: Arc::new(Mutex::new(Vec::new())),
+        }
+    }
+
+    fn push(
+        &self,
+        from: &'static str,
+        text: String,
+    ) {
+        self.lines.lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .push(Line { ms: self.started.elapsed().as_millis(), from, text });
+    }
+
+    pub fn client(
+        &self,
+        text: String,
+    ) {
+        self.push("client", text);
+    }
+
+    // the reply, or the error that stood for it
+    pub fn reply(
+        &self,
+        result: &Result<Response, smtp::Error>,
+    ) {
+        let text = match result {
+            Ok(response) => format!("{} {}", response.code(), response.message().collect::<Vec<&str>>().join(" / ")),
+            Err(e) => e.to_string(),
+        };
+        self.push("server", text);
+    }
+
+    pub fn note(
+        &self,
+        text: String,
+    ) {
+        self.push("note", text);
+    }
+
+    pub fn lines(&self) -> Vec<Line> {
+        self.lines.lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .clone()
+    }
+}
+
+// each command with its reply
+fn command<C: std::fmt::Display>(
+    connection: &mut smtp::client::SmtpConnection,
+    transcript: &Transcript,
+    command: C,
+) -> Result<Response, Failure> {
+
+    transcript.client(command.to_string().trim_end().to_string());
+    let result = connection.command(command);
+    transcript.reply(&result);
+
+    result.map_err(|e| {
+        connection.abort();
+        Failure::Smtp(e)
+    })
+}
+
+// the send of SmtpConnection::send, one command at a time on a connection of
+// its own so the whole dialog is in the transcript
+pub fn send(
+    account: &Gmail,
+    envelope: &Envelope,
+    raw: &[u8],
+    transcript: &Transcript,
+) -> Result<Response, Failure> {
+
+    let mut connection = pool::open(account, Some(transcript))?;
+    if crate::profile::authenticates(&account.username) {
+        pool::login(account, &mut connection, Some(transcript))?;
+    }
+
+    let mut options = Vec::new();
+    let non_ascii = envelope.from()
+        .into_iter()
+        .chain(envelope.to())
+        .any(|address| !address.to_string().is_ascii());
+    if non_ascii && connection.server_info().supports_feature(Extension::SmtpUtfEight) {
+        options.push(MailParameter::SmtpUtfEight);
+    }
+    if !raw.is_ascii() && connection.server_info().supports_feature(Extension::EightBitMime) {
+        options.push(MailParameter::Body(MailBodyParameter::EightBitMime));
+    }
+    command(&mut connection, transcript, Mail::new(envelope.from().cloned(), options))?;
+    for to in envelope.to() {
+        command(&mut connection, transcript, Rcpt::new(to.clone(), vec![]))?;
+    }
+    command(&mut connection, transcript, Data)?;
+
+    transcript.client(format!("<message of {} bytes>", raw.len()));
+    let result = connection.message(raw);
+    transcript.reply(&result);
+    let response = result.map_err(|e| {
+        connection.abort();
+        Failure::Smtp(e)
+    })?;
+
+    let _ = command(&mut connection, transcript, Quit);
+
+    Ok(response)
+}
@@ -0,0 +1,577 @@
+//
+// Transports of the messages: the Gmail accounts, rotated by weight, and the
+// named accounts of the HTTP API providers, each provider built with its
+// cargo feature
+//
+
+use std::collections::HashMap;
+use std::fmt;
+use std::net::IpAddr;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use lettre::address::Envelope;
+use lettre::Transport as _;
+use serde::{Deserialize, Serialize};
+
+use crate::pool::{self, Failure};
+use crate::transcript::Transcript;
+use crate::{db, SendError, SMTP_CLIENT};
+
+// the account of the username and password of the config
+pub const GMAIL: &str = "gmail";
+
+fn default_weight() -> u32 {
+    1
+}
+
+fn default_backoff_secs() -> u64 {
+    60
+}
+
+fn default_max_backoff_secs() -> u64 {
+    3600
+}
+
+#[derive(Clone, Deserialize, Serialize)]
+pub struct RotationSettings {
+    // share of the sends of the Gmail account of the config
+    #[serde(default = "default_weight")]
+    weight: u32,
+    // an account that fails to connect or log in is left out of the rotation
+    // for this long, doubled on every failure in a row
+    #[serde(default = "default_backoff_secs")]
+    backoff_secs: u64,
+    #[serde(default = "default_max_backoff_secs")]
+    max_backoff_secs: u64,
+}
+
+impl Default for RotationSettings {
+    fn default() -> Self {
+        RotationSettings {
+            weight: default_weight(),
+            backoff_secs: default_backoff_secs(),
+            max_backoff_secs: default_max_backoff_secs(),
+        }
+    }
+}
+
+#[derive(Clone, Deserialize, Serialize)]
+pub struct Account {
+    // what the requests name in "account"
+    pub name: String,
+    #[serde(flatten)]
+    pub provider: Provider,
+}
+
+// the settings of every provider are read even when it isn't built in, so a
+// send to its account fails with a clear error
+#[derive(Clone, Deserialize, Serialize)]
+#[serde(tag = "provider", rename_all = "lowercase")]
+#[cfg_attr(not(all(feature = "sendgrid", feature = "mailgun", feature = "ses")), allow(dead_code))]
+pub enum Provider {
+    Gmail {
+        username: String,
+        password: String,
+        // the server of the config if not set
+        server: Option<String>,
+        // the bind_address of the config if not set
+        bind_address: Option<IpAddr>,
+        // share of the sends of the rotation, 0 only sends the requests naming the account
+        #[serde(default = "default_weight")]
+        weight: u32,
+        // recipients per rolling 24 hours, the one of "quota" if not set
+        daily_limit: Option<u64>,
+    },
+    Sendgrid {
+        api_key: String,
+        // https://api.sendgrid.com if not set
+        endpoint: Option<String>,
+    },
+    Mailgun {
+        api_key: String,
+        // sending domain of the account
+        domain: String,
+        // "us" (the default) or "eu"
+        region: Option<String>,
+        // the API of the region if not set
+        endpoint: Option<String>,
+    },
+    Ses {
+        access_key_id: String,
+        secret_access_key: String,
+        // "eu-west-1"
+        region: String,
+        // https://email.<region>.amazonaws.com if not set
+        endpoint: Option<String>,
+    },
+}
+
+impl Provider {
+    pub fn name(&self) -> &'static str {
+        match self {
+            Provider::Gmail { .. } => "gmail",
+            Provider::Sendgrid { .. } => "sendgrid",
+            Provider::Mailgun { .. } => "mailgun",
+            Provider::Ses { .. } => "ses",
+        }
+    }
+}
+
+// the reply of a provider API other than a success, or the error reaching it
+#[derive(Debug)]
+pub struct ApiError {
+    pub provider: &'static str,
+    // none if the API could not be reached
+    pub status: Option<u16>,
+    pub message: String,
+}
+
+impl fmt::Display for ApiError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.status {
+            Some(status) => write!(f, "{} API error ({}): {}", self.provider, status, self.message),
+            None => write!(f, "{} API unreachable: {}", self.provider, self.message),
+        }
+    }
+}
+
+// a message accepted for delivery
+pub struct Sent {
+    // the account that sent it
+    pub account: String,
+    // the reply of the server or the API
+    pub reply: String,
+    // the ids of a message sent through the Gmail API
+    pub gmail: Option<crate::gmail_api::Thread>,
+}
+
+pub trait Transport: Send {
+    // the Gmail account whose daily limit and cool-down the sends count against
+    fn quota_account(&self) -> Option<&str> {
+        None
+    }
+
+    // the sends of a debug request write their dialog to the transcript
+    fn debug(
+        &mut self,
+        transcript: Transcript,
+    ) {
+        transcript.note("The account sends through an HTTP API, there is no SMTP dialog".to_string());
+    }
+
+    // the labels of a message sent through the Gmail API and where its ids
+    // are left, the other transports can't label a message
+    fn gmail(
+        &mut self,
+        _labels: &[String],
+        _ids: crate::gmail_api::Ids,
+    ) {
+    }
+
+    // the reply of the server or the API to a message accepted for delivery
+    fn send(
+        &self,
+        envelope: &Envelope,
+        raw: &[u8],
+    ) -> Result<String, Failure>;
+}
+
+// the Gmail account of the config or one of the accounts
+#[derive(Clone)]
+pub struct Gmail {
+    pub name: String,
+    pub username: String,
+    pub password: String,
+    pub server: String,
+    pub bind_address: Option<IpAddr>,
+    weight: u32,
+    daily_limit: Option<u64>,
+    transcript: Option<Transcript>,
+}
+
+impl Gmail {
+    pub fn primary() -> Self {
+        Gmail {
+            name: GMAIL.to_string(),
+            username: SMTP_CLIENT.username.clone(),
+            password: SMTP_CLIENT.password.clone(),
+            server: SMTP_CLIENT.server.clone(),
+            bind_address: SMTP_CLIENT.bind_address,
+            weight: settings().weight,
+            daily_limit: None,
+            transcript: None,
+        }
+    }
+
+    pub fn find(name: &str) -> Option<Self> {
+
+        if name == GMAIL {
+            return Some(Self::primary());
+        }
+
+        match &find(name)?.provider {
+            Provider::Gmail { username, password, server, bind_address, weight, daily_limit } => Some(Gmail {
+                name: name.to_string(),
+                username: username.clone(),
+                password: password.clone(),
+                server: server.clone().unwrap_or(SMTP_CLIENT.server.clone()),
+                bind_address: bind_address.or(SMTP_CLIENT.bind_address),
+                weight: *weight,
+                daily_limit: *daily_limit,
+                transcript: None,
+            }),
+            _ => None,
+        }
+    }
+
+    // every Gmail account, the one of the config first
+    pub fn all() -> Vec<Self> {
+        std::iter::once(Self::primary())
+            .chain(SMTP_CLIENT.accounts
+                .iter()
+                .flatten()
+                .filter_map(|account| Self::find(&account.name)))
+            .collect()
+    }
+
+    // the accounts sharing the sends
+    fn rotation() -> Vec<Self> {
+        Self::all()
+            .into_iter()
+            .filter(|account| account.weight > 0)
+            .collect()
+    }
+}
+
+impl Transport for Gmail {
+    fn quota_account(&self) -> Option<&str> {
+        Some(&self.name)
+    }
+
+    fn debug(
+        &mut self,
+        transcript: Transcript,
+    ) {
+        self.transcript = Some(transcript);
+    }
+
+    fn send(
+        &self,
+        envelope: &Envelope,
+        raw: &[u8],
+    ) -> Result<String, Failure> {
+        if let Some(transcript) = &self.transcript {
+            return crate::transcript::send(self, envelope, raw, transcript)
+                .map(|response| format!("{:?}", response));
+        }
+        match pool::direct(self) {
+            true => pool::send_direct(self, envelope, raw),
+            false => pool::mailer(self).send_raw(envelope, raw).map_err(Failure::Smtp),
+        }
+        .map(|response| format!("{:?}", response))
+    }
+}
+
+// failures in a row and the end of the backoff of each account
+static BACKOFF: Mutex<Option<HashMap<String, (u32, i64)>>> = Mutex::new(None);
+
+// the running weights of the smooth weighted round-robin
+static CURRENT: Mutex<Option<HashMap<String, i64>>> = Mutex::new(None);
+
+fn settings() -> RotationSettings {
+    SMTP_CLIENT.rotation.clone().unwrap_or_default()
+}
+
+// the HTTP client of the providers and the Gmail API, with the socket timeout
+// of the SMTP connections
+pub fn agent() -> ureq::Agent {
+    ureq::AgentBuilder::new()
+        .timeout(pool::timeout(None))
+        .build()
+}
+
+pub fn api_error(
+    provider: &'static str,
+    error: ureq::Error,
+) -> Failure {
+    Failure::Api(match error {
+        ureq::Error::Status(status, response) => ApiError {
+            provider,
+            status: Some(status),
+            message: response.into_string()
+                .unwrap_or_default()
+                .chars()
+                .take(500)
+                .collect(),
+        },
+        ureq::Error::Transport(e) => ApiError {
+            provider,
+            status: None,
+            message: e.to_string(),
+        },
+    })
+}
+
+fn find(name: &str) -> Option<&'static Account> {
+    SMTP_CLIENT.accounts
+        .iter()
+        .flatten()
+        .find(|account| account.name == name)
+}
+
+// the limit of a Gmail account that has its own
+pub fn daily_limit(account: &str) -> Option<u64> {
+    Gmail::find(account)
+        .and_then(|account| account.daily_limit)
+}
+
+// the Gmail accounts of the rotation, to report their usage
+pub fn gmail_accounts() -> Vec<String> {
+    Gmail::rotation()
+        .into_iter()
+        .map(|account| account.name)
+        .collect()
+}
+
+// every account of the rotation is resting after a limit error
+pub fn cooling_down() -> bool {
+    let accounts = Gmail::rotation();
+    match accounts.is_empty() {
+        true => crate::quota::cooling_down(GMAIL).is_some(),
+        false => accounts.iter().all(|account| crate::quota::cooling_down(&account.name).is_some()),
+    }
+}
+
+fn select(name: &str) -> Result<Box<dyn Transport>, String> {
+
+    if let Some(gmail) = Gmail::find(name) {
+        return match crate::gmail_api::sends(name) {
+            true => Ok(Box::new(crate::gmail_api::GmailApi::new(name))),
+            false => Ok(Box::new(gmail)),
+        };
+    }
+
+    let account = find(name)
+        .ok_or_else(|| format!("Unknown account: {}", name))?;
+    match &account.provider {
+        #[cfg(feature = "sendgrid")]
+        Provider::Sendgrid { api_key, endpoint } => Ok(Box::new(
+            crate::sendgrid::Sendgrid::new(api_key, endpoint.as_deref()),
+        )),
+        #[cfg(feature = "mailgun")]
+        Provider::Mailgun { api_key, domain, region, endpoint } => Ok(Box::new(
+            crate::mailgun::Mailgun::new(api_key, domain, region.as_deref(), endpoint.as_deref())?,
+        )),
+        #[cfg(feature = "ses")]
+        Provider::Ses { access_key_id, secret_access_key, region, endpoint } => Ok(Box::new(
+            crate::ses::Ses::new(access_key_id, secret_access_key, region, endpoint.as_deref()),
+        )),
+        #[allow(unreachable_patterns)]
+        provider => Err(format!(
+            "The {} provider of account {} isn't built in, build the plugin with the \"{}\" feature",
+            provider.name(), name, provider.name(),
+        )),
+    }
+}
+
+// the account named by a request, or the one of its tenant, checked before
+// the mail is queued
+pub fn check(account: Option<&str>) -> Result<(), SendError> {
+    match crate::tenant::account(account)? {
+        Some(name) => select(&name)
+            .map(|_| ())
+            .map_err(|e| SendError::new("invalid_request", e)),
+        None => Ok(()),
+    }
+}
+
+fn backing_off(name: &str) -> bool {
+    BACKOFF.lock()
+        .unwrap_or_else(|e| e.into_inner())
+        .as_ref()
+        .and_then(|backoff| backoff.get(name))
+        .is_some_and(|(_, until)| *until > db::now())
+}
+
+// a limit error starts the cool-down of the account, a connection or login
+// failure its backoff and a send ends it
+fn track(
+    name: &str,
+    result: &Result<String, Failure>,
+) {
+
+    let code = match result {
+        Ok(_) => None,
+        Err(failure) => Some(crate::outcome::classify(failure).code),
+    };
+    if let (Err(Failure::Smtp(error)), Some("quota_exceeded")) = (result, code) {
+        crate::quota::exceeded(name, &format!("Failed to send email: {}", error));
+    }
+
+    let mut backoff = BACKOFF.lock()
+        .unwrap_or_else(|e| e.into_inner());
+    let backoff = backoff.get_or_insert_with(HashMap::new);
+    match code {
+        None => {
+            backoff.remove(name);
+        },
+        Some("connection_failed" | "dns_failed" | "tls_failed" | "auth_failed" | "timeout") => {
+            let settings = settings();
+            let (failures, until) = backoff.entry(name.to_string())
+                .or_insert((0, 0));
+            *failures += 1;
+            let delay = settings.backoff_secs
+                .saturating_mul(1 << (*failures - 1).min(16))
+                .min(settings.max_backoff_secs);
+            *until = db::now() + delay as i64;
+        },
+        Some(_) => {},
+    };
+}
+
+fn attempt(
+    name: &str,
+    envelope: &Envelope,
+    raw: Arc<Vec<u8>>,
+    timeout: Duration,
+    transcript: Option<&Transcript>,
+    labels: &[String],
+) -> Result<Sent, Failure> {
+
+    let mut transport = select(name)
+        .map_err(Failure::Account)?;
+    if let Some(transcript) = transcript {
+        transcript.note(format!("Sending through the {} account", name));
+        transport.debug(transcript.clone());
+    }
+    let ids = crate::gmail_api::Ids::default();
+    transport.gmail(labels, ids.clone());
+    let recipients = envelope.to().to_vec();
+    let quota = transport.quota_account().map(str::to_string);
+    let envelope = envelope.clone();
+    let result = pool::run(timeout, &recipients, quota.as_deref(), move || transport.send(&envelope, &raw));
+    track(name, &result);
+
+    result.map(|reply| Sent {
+        account: name.to_string(),
+        reply,
+        gmail: ids.take(),
+    })
+}
+
+// smooth weighted round-robin over the accounts of the rotation that have
+// room for the recipients and aren't backing off
+fn pick(
+    recipients: u64,
+    tried: &[String],
+) -> Option<String> {
+
+    let accounts: Vec<Gmail> = Gmail::rotation()
+        .into_iter()
+        .filter(|account| !tried.contains(&account.name))
+        .filter(|account| crate::quota::cooling_down(&account.name).is_none()
+            && crate::quota::has_room(&account.name, recipients)
+            && !backing_off(&account.name))
+        .collect();
+    let total: i64 = accounts.iter()
+        .map(|account| account.weight as i64)
+        .sum();
+
+    let mut current = CURRENT.lock()
+        .unwrap_or_else(|e| e.into_inner());
+    let current = current.get_or_insert_with(HashMap::new);
+    let mut best: Option<(&str, i64)> = None;
+    for account in &accounts {
+        let weight = current.entry(account.name.clone())
+            .or_insert(0);
+        *weight += account.weight as i64;
+        match best {
+            Some((_, best_weight)) if best_weight >= *weight => {},
+            _ => best = Some((&account.name, *weight)),
+        };
+    }
+
+    let (name, _) = best?;
+    if let Some(weight) = current.get_mut(name) {
+        *weight -= total;
+    }
+
+    Some(name.to_string())
+}
+
+// a failure that left the message unsent moves it to the next account
+fn rotate(
+    envelope: &Envelope,
+    raw: &Arc<Vec<u8>>,
+    timeout: Duration,
+    transcript: Option<&Transcript>,
+    labels: &[String],
+) -> Result<Sent, Failure> {
+
+    let recipients = envelope.to().len() as u64;
+    let mut tried = Vec::new();
+    let mut last = None;
+    while let Some(name) = pick(recipients, &tried) {
+        let result = attempt(&name, envelope, raw.clone(), timeout, transcript, labels);
+        match &result {
+            Err(failure) if matches!(
+                crate::outcome::classify(failure).code,
+                "quota_exceeded" | "connection_failed" | "auth_failed",
+            ) => {
+                log!("The {} account failed, trying the next account of the rotation", name);
+                tried.push(name);
+                last = Some(result);
+            },
+            _ => return result,
+        };
+    }
+
+    // no account has room, the one of the config reports why
+    last.unwrap_or_else(|| attempt(GMAIL, envelope, raw.clone(), timeout, transcript, labels))
+}
+
+// send through the account of the request or the default one; a request
+// that doesn't name an account is shared by the Gmail accounts and goes to
+// the overflow one while they are all at their limit
+pub fn send(
+    account: Option<&str>,
+    envelope: &Envelope,
+    raw: Vec<u8>,
+    timeout: Duration,
+    transcript: Option<&Transcript>,
+    labels: &[String],
+) -> Result<Sent, Failure> {
+
+    let account = crate::tenant::account(account)
+        .map_err(|e| Failure::Account(e.message))?;
+    let account = account.as_deref();
+    let name = account
+        .or(SMTP_CLIENT.default_account.as_deref())
+        .unwrap_or(GMAIL);
+    let raw = Arc::new(raw);
+    let shared = account.is_none() && name == GMAIL;
+    let result = match shared && Gmail::rotation().len() > 1 {
+        true => rotate(envelope, &raw, timeout, transcript, labels),
+        false => attempt(name, envelope, raw.clone(), timeout, transcript, labels),
+    };
+
+    let overflow = match (&result, SMTP_CLIENT.overflow_account.as_deref()) {
+        (Err(failure), Some(overflow)) if shared => {
+            (crate::outcome::classify(failure).code == "quota_exceeded").then_some(overflow)
+        },
+        _ => None,
+    };
+    let result = match overflow {
+        Some(overflow) => {
+            log!("The Gmail accounts are at their limit, sending through the {} account", overflow);
+            attempt(overflow, envelope, raw.clone(), timeout, transcript, labels)
+        },
+        None => result,
+    };
+    crate::health::record(&result);
+    if let Ok(sent) = &result {
+        crate::imap::copy(&sent.account, raw);
+    }
+
+    result
+}
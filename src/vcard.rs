@@ -0,0 +1,155 @@
+//
+// The "contact_card" of a request, attached as a vCard 4.0 (RFC 6350)
+//
+
+use lettre::message::{Attachment as MimeAttachment, SinglePart};
+use lettre::message::header::ContentType;
+use serde::{Deserialize, Serialize};
+
+use crate::{Mail, SendError};
+
+// octets of a line before it is folded
+const LINE_OCTETS: usize = 75;
+
+const PHONE_TYPES: &[&str] = &["text", "voice", "fax", "cell", "video", "pager", "textphone", "home", "work"];
+
+// a bare number or one with its type: work, home, cell...
+#[derive(Clone, Deserialize, Serialize)]
+#[serde(untagged)]
+pub enum Phone {
+    Number(String),
+    Typed {
+        number: String,
+        #[serde(rename = "type")]
+        kind: Option<String>,
+    },
+}
+
+#[derive(Clone, Deserialize, Serialize)]
+pub struct ContactCard {
+    // the formatted name, "Example Support"
+    pub name: String,
+    pub org: Option<String>,
+    pub title: Option<String>,
+    #[serde(default)]
+    pub phones: Vec<Phone>,
+    #[serde(default)]
+    pub emails: Vec<String>,
+    pub url: Option<String>,
+    pub note: Option<String>,
+    // after the name if not set, "Example Support.vcf"
+    pub filename: Option<String>,
+}
+
+fn invalid(message: impl Into<String>) -> SendError {
+    SendError::new("invalid_request", message)
+}
+
+// the backslashes, commas, semicolons and line breaks of a text value
+fn escape(text: &str) -> String {
+    text.trim()
+        .replace('\\', "\\\\")
+        .replace(',', "\\,")
+        .replace(';', "\\;")
+        .replace("\r\n", "\\n")
+        .replace(['\r', '\n'], "\\n")
+}
+
+// a line of 75 octets at most, the next ones after a space, without
+// splitting a character
+fn fold(line: &str) -> String {
+
+    let mut folded = String::with_capacity(line.len() + 8);
+    let mut octets = 0;
+    for c in line.chars() {
+        if octets + c.len_utf8() > LINE_OCTETS {
+            folded.push_str("\r\n ");
+            octets = 1;
+        }
+        folded.push(c);
+        octets += c.len_utf8();
+    }
+    folded.push_str("\r\n");
+
+    folded
+}
+
+pub fn check(mail: &Mail) -> Result<(), SendError> {
+
+    let Some(card) = &mail.contact_card else {
+        return Ok(());
+    };
+    if card.name.trim().is_empty() {
+        return Err(invalid("The contact card has no name"));
+    }
+    for email in &card.emails {
+        email.trim().parse::<lettre::Address>()
+            .map_err(|e| invalid(format!("Invalid contact card email {:?}: {}", email, e)))?;
+    }
+    for phone in &card.phones {
+        let (number, kind) = match phone {
+            Phone::Number(number) => (number, None),
+            Phone::Typed { number, kind } => (number, kind.as_deref()),
+        };
+        if number.trim().is_empty() || !number.trim().chars().all(|c| c.is_ascii_digit() || " +-().".contains(c)) {
+            return Err(invalid(format!("Invalid contact card phone {:?}", number)));
+        }
+        if let Some(kind) = kind.filter(|kind| !PHONE_TYPES.contains(&kind.to_ascii_lowercase().as_str())) {
+            return Err(invalid(format!("Invalid contact card phone type {:?}, expected one of {}", kind, PHONE_TYPES.join(", "))));
+        }
+    }
+
+    Ok(())
+}
+
+pub fn serialize(card: &ContactCard) -> String {
+
+    let mut lines = vec![
+        "BEGIN:VCARD".to_string(),
+        "VERSION:4.0".to_string(),
+        format!("FN:{}", escape(&card.name)),
+    ];
+    if let Some(org) = &card.org {
+        lines.push(format!("ORG:{}", escape(org)));
+    }
+    if let Some(title) = &card.title {
+        lines.push(format!("TITLE:{}", escape(title)));
+    }
+    for (i, phone) in card.phones.iter().enumerate() {
+        let (number, kind) = match phone {
+            Phone::Number(number) => (number, None),
+            Phone::Typed { number, kind } => (number, kind.as_deref()),
+        };
+        // the first number is the preferred one
+        let mut parameters = format!(";VALUE=uri;PREF={}", i + 1);
+        if let Some(kind) = kind {
+            parameters.push_str(&format!(";TYPE={}", kind.to_ascii_lowercase()));
+        }
+        let number: String = number.chars().filter(|c| c.is_ascii_digit() || *c == '+').collect();
+        lines.push(format!("TEL{}:tel:{}", parameters, number));
+    }
+    for (i, email) in card.emails.iter().enumerate() {
+        lines.push(format!("EMAIL;PREF={}:{}", i + 1, escape(email)));
+    }
+    if let Some(url) = &card.url {
+        lines.push(format!("URL:{}", url.trim()));
+    }
+    if let Some(note) = &card.note {
+        lines.push(format!("NOTE:{}", escape(note)));
+    }
+    lines.push("END:VCARD".to_string());
+
+    lines.iter()
+        .map(|line| fold(line))
+        .collect()
+}
+
+pub fn attachment(card: &ContactCard) -> Result<SinglePart, SendError> {
+
+    let filename = card.filename.clone()
+        .unwrap_or_else(|| format!("{}.vcf", card.name.trim().replace(['/', '\\', '"'], "")));
+    let content_type = ContentType::parse("text/vcard; charset=utf-8")
+        .map_err(|e| invalid(e.to_string()))?;
+
+    Ok(MimeAttachment::new(filename).body(serialize(card), content_type))
+}